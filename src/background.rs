@@ -0,0 +1,54 @@
+use macroquad::prelude::*;
+use tcp_drawing::entity::EntityColor;
+use tcp_drawing::network::DEFAULT_BACKGROUND;
+
+/// Backgrounds the host cycles through with B: white, paper, slate and
+/// chalkboard green.
+const PRESETS: [EntityColor; 4] = [
+    DEFAULT_BACKGROUND,
+    EntityColor::rgb(0xF3EAD7),
+    EntityColor::rgb(0x2E3440),
+    EntityColor::rgb(0x1F3A2E),
+];
+/// A brush whose brightness is closer than this to the background's has its
+/// outline drawn in the UI color instead, so it can still be found.
+const MIN_CONTRAST: f32 = 0.25;
+
+/// The preset after `current`, or the first one after a background that is
+/// not a preset, such as one from a saved canvas.
+pub fn next(current: EntityColor) -> EntityColor {
+    PRESETS
+        .iter()
+        .position(|&preset| preset == current)
+        .map_or(PRESETS[0], |i| PRESETS[(i + 1) % PRESETS.len()])
+}
+
+/// Perceived brightness, from 0 for black to 1 for white.
+fn brightness(color: Color) -> f32 {
+    0.299 * color.r + 0.587 * color.g + 0.114 * color.b
+}
+
+/// Color of the text and outlines drawn over `background`: black, or white
+/// once the background is dark.
+pub fn ink(background: EntityColor) -> Color {
+    if brightness(Color::from(background)) < 0.5 {
+        WHITE
+    } else {
+        BLACK
+    }
+}
+
+/// `ink` at `alpha`, for faint marks such as grid lines.
+pub fn faint(ink: Color, alpha: f32) -> Color {
+    Color { a: alpha, ..ink }
+}
+
+/// `color`, or the ink of `background` where `color` would hardly show on it.
+pub fn against(color: Color, background: EntityColor) -> Color {
+    let gap = brightness(color) - brightness(Color::from(background));
+    if gap.abs() < MIN_CONTRAST {
+        faint(ink(background), color.a)
+    } else {
+        color
+    }
+}
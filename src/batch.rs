@@ -0,0 +1,204 @@
+use macroquad::models::Vertex;
+use macroquad::prelude::*;
+use tcp_drawing::smooth;
+
+/// Texels across the disk texture dots and stroke joins are drawn with.
+const DISK_SIZE: u16 = 256;
+/// Most indices in one `draw_mesh`, under what macroquad takes in a draw call.
+const MAX_INDICES: usize = 4800;
+/// Texture coordinates of the middle of the disk, solid white: shapes other
+/// than dots sample it so everything shares one texture and one mesh.
+const SOLID: Vec2 = Vec2::new(0.5, 0.5);
+/// Cosine of the sharpest turn a smoothed stroke takes without a round join;
+/// past it the two sides of the strip would cross.
+const SHARP_TURN: f32 = 0.5;
+
+/// Triangles for the canvas, gathered into a few large meshes instead of a
+/// draw call per dot. Shapes land in the order they are added; anything the
+/// batch cannot draw itself, text for one, goes between two `flush`es.
+pub struct Batch {
+    disk: Texture2D,
+    vertices: Vec<Vertex>,
+    indices: Vec<u16>,
+    /// Strokes go down as smoothed curves rather than as segments between dots.
+    smooth: bool,
+    /// Kept between strokes so smoothing does not allocate every frame.
+    samples: Vec<(f32, f32, f32)>,
+}
+
+impl Batch {
+    pub fn new(smooth: bool) -> Self {
+        let mut image = Image::gen_image_color(DISK_SIZE, DISK_SIZE, BLANK);
+        let half = DISK_SIZE as f32 / 2.0;
+        for y in 0..DISK_SIZE {
+            for x in 0..DISK_SIZE {
+                let offset = Vec2::new(x as f32 + 0.5 - half, y as f32 + 0.5 - half);
+                if offset.length() <= half {
+                    image.set_pixel(x as u32, y as u32, WHITE);
+                }
+            }
+        }
+        let disk = Texture2D::from_image(&image);
+        disk.set_filter(FilterMode::Linear);
+        Self {
+            disk,
+            vertices: Vec::with_capacity(MAX_INDICES),
+            indices: Vec::with_capacity(MAX_INDICES),
+            smooth,
+            samples: Vec::new(),
+        }
+    }
+
+    pub fn toggle_smoothing(&mut self) {
+        self.smooth = !self.smooth;
+    }
+
+    /// A stroke through `points`, `radius_at` each of them wide either side:
+    /// a smoothed curve with round ends, or segments with a dot at every point.
+    pub fn stroke(
+        &mut self,
+        points: &[(f32, f32)],
+        radius_at: impl Fn(usize) -> f32,
+        color: Color,
+    ) {
+        if !self.smooth {
+            for (i, segment) in points.windows(2).enumerate() {
+                let width = radius_at(i) + radius_at(i + 1);
+                self.line(segment[0], segment[1], width, color);
+            }
+            // round caps and joins
+            for (i, (x, y)) in points.iter().enumerate() {
+                self.circle(*x, *y, radius_at(i), color);
+            }
+            return;
+        }
+        let mut samples = std::mem::take(&mut self.samples);
+        smooth::catmull_rom(points, radius_at, &mut samples);
+        self.strip(&samples, color);
+        self.samples = samples;
+    }
+
+    /// A band through `samples` of `(x, y, radius)` as one triangle strip, its
+    /// edges `radius` either side, with round ends and round joins where it
+    /// turns too sharply for the strip to hold.
+    fn strip(&mut self, samples: &[(f32, f32, f32)], color: Color) {
+        let (Some(first), Some(last)) = (samples.first(), samples.last()) else {
+            return;
+        };
+        self.circle(first.0, first.1, first.2, color);
+        if samples.len() == 1 {
+            return;
+        }
+        self.circle(last.0, last.1, last.2, color);
+
+        let center = |i: usize| Vec2::new(samples[i].0, samples[i].1);
+        let end = samples.len() - 1;
+        let mut normal = Vec2::ZERO;
+        let mut previous = [Vec2::ZERO; 2];
+        for (i, &(.., radius)) in samples.iter().enumerate() {
+            let along = center((i + 1).min(end)) - center(i.saturating_sub(1));
+            // repeated points keep the direction the stroke had
+            if along.length() > f32::EPSILON {
+                normal = along.perp().normalize();
+            }
+            let edges = [center(i) + normal * radius, center(i) - normal * radius];
+            if i > 0 {
+                if self.indices.len() + 6 > MAX_INDICES {
+                    self.flush();
+                }
+                // the strip carries on from the last pair in a fresh mesh
+                if self.vertices.is_empty() {
+                    self.push_vertices(previous, color);
+                }
+            }
+            self.push_vertices(edges, color);
+            if i > 0 {
+                let n = self.vertices.len() as u16;
+                self.indices
+                    .extend([n - 4, n - 3, n - 1, n - 4, n - 1, n - 2]);
+            }
+            previous = edges;
+        }
+
+        for turn in samples.windows(3) {
+            let [before, (x, y, radius), after] = [turn[0], turn[1], turn[2]];
+            let into = Vec2::new(x - before.0, y - before.1);
+            let out = Vec2::new(after.0 - x, after.1 - y);
+            if into.dot(out) < SHARP_TURN * into.length() * out.length() {
+                self.circle(x, y, radius, color);
+            }
+        }
+    }
+
+    fn push_vertices(&mut self, positions: [Vec2; 2], color: Color) {
+        for position in positions {
+            self.vertices.push(Vertex::new(
+                position.x, position.y, 0.0, SOLID.x, SOLID.y, color,
+            ));
+        }
+    }
+
+    /// A filled circle, as a quad over the disk texture.
+    pub fn circle(&mut self, x: f32, y: f32, radius: f32, color: Color) {
+        let corners = [(-1.0, -1.0), (1.0, -1.0), (1.0, 1.0), (-1.0, 1.0)];
+        self.quad(
+            corners.map(|(dx, dy)| {
+                let position = Vec2::new(x + dx * radius, y + dy * radius);
+                (position, Vec2::new((dx + 1.0) / 2.0, (dy + 1.0) / 2.0))
+            }),
+            color,
+        );
+    }
+
+    /// A segment `thickness` wide, cut square at the ends like `draw_line`.
+    pub fn line(&mut self, from: (f32, f32), to: (f32, f32), thickness: f32, color: Color) {
+        let (from, to) = (Vec2::from(from), Vec2::from(to));
+        let along = to - from;
+        if along.length() < f32::EPSILON {
+            return;
+        }
+        let side = along.perp().normalize() * (thickness / 2.0);
+        self.quad(
+            [from + side, to + side, to - side, from - side].map(|corner| (corner, SOLID)),
+            color,
+        );
+    }
+
+    pub fn rect(&mut self, x: f32, y: f32, w: f32, h: f32, color: Color) {
+        self.quad(
+            [(x, y), (x + w, y), (x + w, y + h), (x, y + h)].map(|corner| (corner.into(), SOLID)),
+            color,
+        );
+    }
+
+    fn quad(&mut self, corners: [(Vec2, Vec2); 4], color: Color) {
+        if self.indices.len() + 6 > MAX_INDICES {
+            self.flush();
+        }
+        let first = self.vertices.len() as u16;
+        for (position, uv) in corners {
+            self.vertices
+                .push(Vertex::new(position.x, position.y, 0.0, uv.x, uv.y, color));
+        }
+        self.indices
+            .extend([0, 1, 2, 0, 2, 3].map(|corner| first + corner));
+    }
+
+    /// Draws what has been added so far.
+    pub fn flush(&mut self) {
+        if self.indices.is_empty() {
+            return;
+        }
+        let mesh = Mesh {
+            vertices: std::mem::take(&mut self.vertices),
+            indices: std::mem::take(&mut self.indices),
+            texture: Some(self.disk.clone()),
+        };
+        draw_mesh(&mesh);
+        // keep the buffers for the next batch
+        self.vertices = mesh.vertices;
+        self.indices = mesh.indices;
+        self.vertices.clear();
+        self.indices.clear();
+    }
+}
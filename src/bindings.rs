@@ -0,0 +1,91 @@
+//! The actions the keys and buttons held this frame call for, by the keymap
+//! the window started with.
+
+use crate::{alt_down, ctrl_down, shift_down};
+use macroquad::prelude::*;
+use std::path::Path;
+use std::sync::OnceLock;
+use tcp_drawing::keymap::{self, Action, Binding, Input, Keymap};
+
+static BINDINGS: OnceLock<Bindings> = OnceLock::new();
+
+pub struct Bindings {
+    keymap: Keymap,
+}
+
+impl Bindings {
+    /// Whether a binding of `action` went down this frame.
+    pub fn pressed(&self, action: Action) -> bool {
+        self.matching(action).any(|binding| match binding.input {
+            Input::Key(key) => is_key_pressed(key),
+            Input::Mouse(button) => is_mouse_button_pressed(button),
+        })
+    }
+
+    /// Whether a binding of `action` is held.
+    pub fn down(&self, action: Action) -> bool {
+        self.matching(action).any(|binding| match binding.input {
+            Input::Key(key) => is_key_down(key),
+            Input::Mouse(button) => is_mouse_button_down(button),
+        })
+    }
+
+    /// The bindings of `action` whose modifiers are the ones held.
+    fn matching(&self, action: Action) -> impl Iterator<Item = &Binding> {
+        let held = (ctrl_down(), shift_down(), alt_down());
+        self.keymap
+            .bindings(action)
+            .iter()
+            .filter(move |binding| (binding.ctrl, binding.shift, binding.alt) == held)
+    }
+}
+
+/// The keymap in `path`, or in the file next to the binary if there is one,
+/// with a warning for anything in it that could not be used.
+pub fn load(path: Option<&Path>) -> Keymap {
+    let Some(path) = path
+        .map(Path::to_path_buf)
+        .or_else(|| keymap::beside_binary().filter(|path| path.exists()))
+    else {
+        return Keymap::default();
+    };
+    match Keymap::load(&path) {
+        Ok((keymap, problems)) => {
+            if !problems.is_empty() {
+                eprintln!(
+                    "Ignoring these entries of {}, which keep their default keys:",
+                    path.display()
+                );
+                for problem in problems {
+                    eprintln!("  {}", problem);
+                }
+            }
+            keymap
+        }
+        Err(e) => {
+            eprintln!("{}; using the default keys", e);
+            Keymap::default()
+        }
+    }
+}
+
+/// Makes `keymap` the one `pressed` and `down` go by; the defaults until then.
+pub fn init(keymap: Keymap) {
+    if BINDINGS.set(Bindings { keymap }).is_err() {
+        eprintln!("Key bindings were already set up");
+    }
+}
+
+pub fn pressed(action: Action) -> bool {
+    bindings().pressed(action)
+}
+
+pub fn down(action: Action) -> bool {
+    bindings().down(action)
+}
+
+fn bindings() -> &'static Bindings {
+    BINDINGS.get_or_init(|| Bindings {
+        keymap: Keymap::default(),
+    })
+}
@@ -0,0 +1,86 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Longest message accepted from the input box or the network, in characters.
+pub const MAX_CHAT_LEN: usize = 200;
+/// Messages kept for the overlay; older ones are dropped.
+pub const CHAT_HISTORY: usize = 6;
+/// How long a message stays on screen, the last second of it fading out.
+pub const CHAT_VISIBLE: Duration = Duration::from_secs(8);
+
+#[derive(Debug, Clone)]
+pub struct ChatLine {
+    pub from: String,
+    pub text: String,
+    pub received: Instant,
+}
+
+impl ChatLine {
+    /// Opacity for the overlay: 1 while fresh, fading to 0 at CHAT_VISIBLE.
+    pub fn opacity(&self) -> f32 {
+        let left = CHAT_VISIBLE.saturating_sub(self.received.elapsed());
+        left.as_secs_f32().min(1.0)
+    }
+}
+
+pub type ChatLog = Arc<Mutex<VecDeque<ChatLine>>>;
+
+/// Shortens `text` to MAX_CHAT_LEN characters and drops control characters.
+pub fn clean(text: &str) -> String {
+    text.chars()
+        .filter(|c| !c.is_control())
+        .take(MAX_CHAT_LEN)
+        .collect::<String>()
+        .trim()
+        .to_string()
+}
+
+pub fn push(log: &ChatLog, from: &str, text: &str) {
+    if let Ok(mut log) = log.lock() {
+        log.push_back(ChatLine {
+            from: from.to_string(),
+            text: text.to_string(),
+            received: Instant::now(),
+        });
+        while log.len() > CHAT_HISTORY {
+            log.pop_front();
+        }
+    }
+}
+
+/// Breaks `text` into lines no wider than `max_width`, as measured by `width`.
+/// Words wider than a line on their own are split between characters.
+pub fn wrap(text: &str, max_width: f32, width: impl Fn(&str) -> f32) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut line = String::new();
+
+    for word in text.split_whitespace() {
+        let candidate = if line.is_empty() {
+            word.to_string()
+        } else {
+            format!("{} {}", line, word)
+        };
+        if width(&candidate) <= max_width {
+            line = candidate;
+            continue;
+        }
+
+        if !line.is_empty() {
+            lines.push(std::mem::take(&mut line));
+        }
+        for c in word.chars() {
+            line.push(c);
+            if width(&line) > max_width && line.chars().count() > 1 {
+                line.pop();
+                lines.push(std::mem::take(&mut line));
+                line.push(c);
+            }
+        }
+    }
+
+    if !line.is_empty() {
+        lines.push(line);
+    }
+    lines
+}
@@ -0,0 +1,71 @@
+//! Regions of the canvas copied to be pasted elsewhere, in this window or, as
+//! JSON on the system clipboard, in another running instance.
+
+use crate::entity::{Entity, HOST_OWNER, next_id, next_seq, now_millis};
+use crate::transform::Transform;
+use serde::{Deserialize, Serialize};
+
+/// Copied entities, placed around the center of the region they came from so
+/// a paste puts that center wherever it goes.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Clipboard {
+    /// In their stacking order. The key tells our JSON apart from whatever
+    /// else the system clipboard holds.
+    #[serde(rename = "tcp-drawing-clipboard")]
+    entities: Vec<Entity>,
+}
+
+impl Clipboard {
+    /// `entities` relative to `center`, as they stack.
+    pub fn copy(entities: impl IntoIterator<Item = Entity>, center: (f32, f32)) -> Self {
+        let to_center = Transform::translate(-center.0, -center.1);
+        let mut entities: Vec<Entity> = entities
+            .into_iter()
+            .map(|mut entity| {
+                to_center.apply_to(&mut entity);
+                entity
+            })
+            .collect();
+        entities.sort_unstable_by_key(|entity| (entity.seq, entity.id));
+        Self { entities }
+    }
+
+    pub fn entities(&self) -> &[Entity] {
+        &self.entities
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entities.is_empty()
+    }
+
+    /// New entities with the copied region's center at `at`, on `layer`. Each
+    /// has an id and a place at the top of the stacking order of its own, so
+    /// every paste is a copy of its own, and is drawn by this peer now.
+    pub fn pasted_at(&self, at: (f32, f32), layer: u8) -> Vec<Entity> {
+        let to_paste = Transform::translate(at.0, at.1);
+        let created_at = now_millis();
+        self.entities
+            .iter()
+            .map(|entity| {
+                let mut copy = entity.clone();
+                to_paste.apply_to(&mut copy);
+                copy.id = next_id();
+                copy.seq = next_seq();
+                copy.owner = HOST_OWNER;
+                copy.created_at = created_at;
+                copy.layer = layer;
+                copy
+            })
+            .collect()
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+
+    /// A clipboard another instance put on the system clipboard, or an error
+    /// for any other text.
+    pub fn from_json(json: &str) -> Result<Self, String> {
+        serde_json::from_str(json).map_err(|e| format!("not a copied region: {}", e))
+    }
+}
@@ -0,0 +1,609 @@
+use crate::entity;
+use crate::fill;
+use crate::network::{self, WireFormat, bots, tls};
+use crate::storage;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+pub const DEFAULT_ADDR: &str = "127.0.0.1:8090";
+/// How long a guest waits for the host before it is turned away.
+pub const DEFAULT_APPROVAL_TIMEOUT: Duration = Duration::from_secs(120);
+/// How long the server waits to hear from a client, pings included, before dropping it.
+pub const DEFAULT_CLIENT_TIMEOUT: Duration = Duration::from_secs(15);
+/// Most entities a room keeps; past it, the oldest are erased for everyone.
+pub const DEFAULT_MAX_ENTITIES: usize = 200_000;
+/// Shortest time between the points a held button adds to a stroke.
+pub const DEFAULT_DRAW_INTERVAL: Duration = Duration::from_millis(5);
+
+pub const USAGE: &str = "\
+Usage: tcp-drawing [OPTIONS]
+
+Modes (pick one):
+  --listen <addr>    Run as the server on <addr>; repeat to listen on several,
+                     e.g. --listen 0.0.0.0:8090 --listen [::]:8090
+  --connect <addr>   Run as a client of the server at <addr>
+  <addr>             Deprecated: serve on <addr> if it can be bound, else connect to it
+  --replay <file>    Play back a --record file instead of joining a session
+  --bots <n>         Load test the server: connect <n> drawing bots to it instead
+                     of opening a window, and print how they fare
+
+Options:
+  --name <name>      Name shown in the server's client list (default: OS username)
+  --wire <format>    Wire format to send, json or bincode (default: json)
+  --save <path>      File written by Ctrl+S (default: canvas.json)
+  --load <path>      File loaded at startup and by Ctrl+O
+  --record <path>    Write every canvas change, with its time, to <path>
+  --persist <path>   Keep the server's canvas in <path>: restored on startup,
+                     saved every few seconds it changes and on shutdown
+  --headless         Run the server without a window
+  --max-clients <n>  Turn away clients beyond the first <n> (server only)
+  --max-entities <n> Keep at most <n> entities in a room, erasing the oldest
+                     for everyone past that (server only; default: 200000)
+  --approve-joins    Hold new clients until the host approves them (server only)
+  --approval-timeout <secs>
+                     Turn away guests not approved in time (default: 120)
+  --client-timeout <secs>
+                     Drop clients silent for this long, pings included (server
+                     only; default: 15)
+  --allow-erase-any  Let clients erase and recolor each other's drawings (server only)
+  --fade-seconds <secs>
+                     Fade everything drawn out over this long, then erase it for
+                     everyone (server only)
+  --ws-port <port>   Also take WebSocket clients on <port> (server only;
+                     needs a build with --features websocket)
+  --cert <path>      Serve over TLS with the PEM certificate chain in <path>
+  --key <path>       PEM private key for --cert
+  --tls              Connect to the server over TLS
+  --ca <path>        Trust the PEM certificates in <path>, such as a self-signed
+                     server certificate, instead of the public roots (with --tls)
+  --room <name>      Room to join, or for a server the room its window draws in
+                     (default: main)
+  --spectate         Connect as a view-only client that never draws
+  --restore-on-reconnect
+                     Send what this client has up again to a server that
+                     restarted without it, instead of dropping it (client only)
+  --taper            Thin each stroke out the longer the button is held
+  --smooth-strokes   Start out drawing strokes as smoothed curves (K toggles)
+  --fake-pressure <pattern>
+                     Make up pen pressure for testing; the only pattern is sine
+  --fill-resolution <px>
+                     Size of the cells the fill tool works in, 1 to 64 (default: 4)
+  --draw-interval-ms <ms>
+                     Add stroke points at most this often, 0 to 1000; 0 means
+                     every frame (default: 5)
+  --pressure-min <share>
+                     Stroke width at no pen pressure, as a share of the brush
+                     size, 0 to 4 (default: 0.2)
+  --pressure-max <share>
+                     Stroke width at full pen pressure, as a share of the brush
+                     size, 0.05 to 4 (default: 1)
+  --bot-rate <n>     Strokes a second each bot draws, over 0 up to 1000
+                     (default: 10)
+  --bot-seed <n>     Seed the bots' random walks, to repeat a run (default: random)
+  --keymap <path>    Read key bindings from <path> instead of keymap.json next
+                     to the binary
+  --dump-keymap      Print the key bindings in effect, as a keymap file to edit
+  --help             Print this help";
+
+/// Made-up pen pressure for exercising pressure handling without a tablet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FakePressure {
+    /// Swells and thins once a second.
+    Sine,
+}
+
+impl FakePressure {
+    /// Pressure, 0 to 1, at `time` seconds.
+    pub fn sample(self, time: f64) -> f32 {
+        match self {
+            FakePressure::Sine => (0.5 + 0.5 * (time * std::f64::consts::TAU).sin()) as f32,
+        }
+    }
+}
+
+impl FromStr for FakePressure {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sine" => Ok(FakePressure::Sine),
+            other => Err(format!("Unknown pressure pattern: {}", other)),
+        }
+    }
+}
+
+/// How this process finds its place in the session.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Role {
+    /// One or more addresses, from repeated `--listen`.
+    Listen(Vec<String>),
+    Connect(String),
+    /// The old behavior: bind if possible, otherwise connect.
+    Auto(String),
+}
+
+impl Role {
+    /// The address, or the first one this server listens on.
+    pub fn addr(&self) -> &str {
+        match self {
+            Role::Listen(addrs) => &addrs[0],
+            Role::Connect(addr) | Role::Auto(addr) => addr,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub role: Role,
+    pub wire_format: WireFormat,
+    pub save_path: PathBuf,
+    pub load_path: Option<PathBuf>,
+    pub name: String,
+    pub headless: bool,
+    pub spectate: bool,
+    pub max_clients: Option<usize>,
+    pub max_entities: usize,
+    pub approve_joins: bool,
+    pub approval_timeout: Duration,
+    pub client_timeout: Duration,
+    /// Set by `--fade-seconds`: entities fade out and are erased this long after they arrive.
+    pub fade: Option<Duration>,
+    /// Clients may change entities someone else drew, not just their own.
+    pub allow_erase_any: bool,
+    /// Port for WebSocket clients, on the address the server listens on.
+    pub ws_port: Option<u16>,
+    /// Set by `--cert` and `--key`: accepted connections must speak TLS.
+    pub server_tls: Option<Arc<rustls::ServerConfig>>,
+    /// Set by `--tls`: connections to the server are wrapped in TLS.
+    pub client_tls: Option<Arc<rustls::ClientConfig>>,
+    pub room: String,
+    /// Set by `--restore-on-reconnect`: after the server restarts, the client
+    /// sends up what it had under new ids rather than dropping it.
+    pub restore_on_reconnect: bool,
+    pub fake_pressure: Option<FakePressure>,
+    pub taper: bool,
+    /// Set by `--smooth-strokes`: the window starts out drawing strokes as
+    /// smoothed curves instead of segments between dots.
+    pub smooth_strokes: bool,
+    /// Cell size the fill tool works out regions in; finer cells hug edges closer.
+    pub fill_resolution: f32,
+    pub draw_interval: Duration,
+    /// Stroke width at no and at full pen pressure, as shares of the brush size.
+    pub pressure_range: (f32, f32),
+    pub record_path: Option<PathBuf>,
+    /// Set by `--persist`: the server keeps its canvas in this file.
+    pub persist_path: Option<PathBuf>,
+    pub replay_path: Option<PathBuf>,
+    /// Set by `--bots`: this many load-testing bots connect instead of a window.
+    pub bots: Option<usize>,
+    /// Strokes a second each bot draws.
+    pub bot_rate: f64,
+    /// Set by `--bot-seed`: the bots walk the same way every run.
+    pub bot_seed: Option<u64>,
+    /// Set by `--keymap`: the window's key bindings come from this file
+    /// rather than the one next to the binary.
+    pub keymap_path: Option<PathBuf>,
+}
+
+/// What the command line asked for.
+pub enum Command {
+    Run(Box<Config>),
+    Help,
+    /// Print the key bindings in effect with the `--keymap` file, if one was given.
+    DumpKeymap(Option<PathBuf>),
+}
+
+/// Parses the arguments after the program name.
+pub fn parse_args(args: &[String]) -> Result<Command, String> {
+    let mut role: Option<Role> = None;
+    let (mut cert, mut key, mut ca) = (None, None, None);
+    let mut use_tls = false;
+    let mut dump_keymap = false;
+    // --bot-rate or --bot-seed, which need --bots
+    let mut bot_options = false;
+    let mut config = Config {
+        role: Role::Auto(DEFAULT_ADDR.to_string()),
+        wire_format: WireFormat::Json,
+        save_path: PathBuf::from(storage::DEFAULT_CANVAS_PATH),
+        load_path: None,
+        name: default_name(),
+        headless: !display_available(),
+        spectate: false,
+        max_clients: None,
+        max_entities: DEFAULT_MAX_ENTITIES,
+        approve_joins: false,
+        approval_timeout: DEFAULT_APPROVAL_TIMEOUT,
+        client_timeout: DEFAULT_CLIENT_TIMEOUT,
+        fade: None,
+        allow_erase_any: false,
+        ws_port: None,
+        server_tls: None,
+        client_tls: None,
+        room: network::DEFAULT_ROOM.to_string(),
+        restore_on_reconnect: false,
+        fake_pressure: None,
+        taper: false,
+        smooth_strokes: false,
+        fill_resolution: fill::DEFAULT_RESOLUTION,
+        draw_interval: DEFAULT_DRAW_INTERVAL,
+        pressure_range: (entity::DEFAULT_THINNEST, 1.0),
+        record_path: None,
+        persist_path: None,
+        replay_path: None,
+        bots: None,
+        bot_rate: bots::DEFAULT_BOT_RATE,
+        bot_seed: None,
+        keymap_path: None,
+    };
+
+    let mut args_iter = args.iter();
+    while let Some(arg) = args_iter.next() {
+        let mut value = |expects: &str| {
+            args_iter
+                .next()
+                .cloned()
+                .ok_or_else(|| format!("{} expects {}", arg, expects))
+        };
+
+        let new_role = match arg.as_str() {
+            "--listen" => {
+                let addr = value("an address")?;
+                match &mut role {
+                    Some(Role::Listen(addrs)) => {
+                        addrs.push(addr);
+                        None
+                    }
+                    _ => Some(Role::Listen(vec![addr])),
+                }
+            }
+            "--connect" => Some(Role::Connect(value("an address")?)),
+            "--wire" => {
+                config.wire_format = value("json or bincode")?.parse()?;
+                None
+            }
+            "--save" => {
+                config.save_path = PathBuf::from(value("a file path")?);
+                None
+            }
+            "--load" => {
+                config.load_path = Some(PathBuf::from(value("a file path")?));
+                None
+            }
+            "--record" => {
+                config.record_path = Some(PathBuf::from(value("a file path")?));
+                None
+            }
+            "--persist" => {
+                config.persist_path = Some(PathBuf::from(value("a file path")?));
+                None
+            }
+            "--replay" => {
+                config.replay_path = Some(PathBuf::from(value("a file path")?));
+                None
+            }
+            "--name" => {
+                config.name = value("a name")?;
+                None
+            }
+            "--headless" => {
+                config.headless = true;
+                None
+            }
+            "--max-clients" => {
+                let max = value("a number")?;
+                let max = max
+                    .parse()
+                    .map_err(|_| format!("--max-clients expects a number, got {}", max))?;
+                config.max_clients = Some(max);
+                None
+            }
+            "--max-entities" => {
+                let max = value("a number")?;
+                let max = max.parse().ok().filter(|&max| max > 0).ok_or_else(|| {
+                    format!("--max-entities expects a positive number, got {}", max)
+                })?;
+                config.max_entities = max;
+                None
+            }
+            "--approve-joins" => {
+                config.approve_joins = true;
+                None
+            }
+            "--allow-erase-any" => {
+                config.allow_erase_any = true;
+                None
+            }
+            "--approval-timeout" => {
+                let secs = value("a number of seconds")?;
+                let secs = secs.parse().map_err(|_| {
+                    format!(
+                        "--approval-timeout expects a number of seconds, got {}",
+                        secs
+                    )
+                })?;
+                config.approval_timeout = Duration::from_secs(secs);
+                None
+            }
+            "--client-timeout" => {
+                let secs = value("a number of seconds")?;
+                let secs = secs.parse().ok().filter(|&secs| secs > 0).ok_or_else(|| {
+                    format!(
+                        "--client-timeout expects a positive number of seconds, got {}",
+                        secs
+                    )
+                })?;
+                config.client_timeout = Duration::from_secs(secs);
+                None
+            }
+            "--fade-seconds" => {
+                let secs = value("a number of seconds")?;
+                let secs = secs.parse().ok().filter(|&secs| secs > 0).ok_or_else(|| {
+                    format!(
+                        "--fade-seconds expects a positive number of seconds, got {}",
+                        secs
+                    )
+                })?;
+                config.fade = Some(Duration::from_secs(secs));
+                None
+            }
+            "--ws-port" => {
+                let port = value("a port number")?;
+                let port = port
+                    .parse()
+                    .map_err(|_| format!("--ws-port expects a port number, got {}", port))?;
+                if !cfg!(feature = "websocket") {
+                    return Err("--ws-port needs a build with --features websocket".into());
+                }
+                config.ws_port = Some(port);
+                None
+            }
+            "--cert" => {
+                cert = Some(PathBuf::from(value("a file path")?));
+                None
+            }
+            "--key" => {
+                key = Some(PathBuf::from(value("a file path")?));
+                None
+            }
+            "--tls" => {
+                use_tls = true;
+                None
+            }
+            "--ca" => {
+                ca = Some(PathBuf::from(value("a file path")?));
+                None
+            }
+            "--room" => {
+                config.room = value("a room name")?;
+                None
+            }
+            "--fake-pressure" => {
+                config.fake_pressure = Some(value("a pattern")?.parse()?);
+                None
+            }
+            "--taper" => {
+                config.taper = true;
+                None
+            }
+            "--smooth-strokes" => {
+                config.smooth_strokes = true;
+                None
+            }
+            "--fill-resolution" => {
+                let px = value("a number of pixels")?;
+                config.fill_resolution = px
+                    .parse()
+                    .ok()
+                    .filter(|px| (1.0..=64.0).contains(px))
+                    .ok_or_else(|| {
+                        format!("--fill-resolution expects 1 to 64 pixels, got {}", px)
+                    })?;
+                None
+            }
+            "--draw-interval-ms" => {
+                let ms = value("a number of milliseconds")?;
+                let ms = ms.parse().ok().filter(|ms| *ms <= 1000).ok_or_else(|| {
+                    format!(
+                        "--draw-interval-ms expects 0 to 1000 milliseconds, got {}",
+                        ms
+                    )
+                })?;
+                config.draw_interval = Duration::from_millis(ms);
+                None
+            }
+            "--pressure-min" => {
+                config.pressure_range.0 = share(&value("a share of the brush size")?, arg, 0.0)?;
+                None
+            }
+            "--pressure-max" => {
+                config.pressure_range.1 = share(&value("a share of the brush size")?, arg, 0.05)?;
+                None
+            }
+            "--spectate" => {
+                config.spectate = true;
+                None
+            }
+            "--restore-on-reconnect" => {
+                config.restore_on_reconnect = true;
+                None
+            }
+            "--bots" => {
+                let count = value("a number of bots")?;
+                let count = count
+                    .parse()
+                    .ok()
+                    .filter(|&count| count > 0)
+                    .ok_or_else(|| {
+                        format!("--bots expects a positive number of bots, got {}", count)
+                    })?;
+                config.bots = Some(count);
+                None
+            }
+            "--bot-rate" => {
+                let rate = value("a number of strokes a second")?;
+                config.bot_rate = rate
+                    .parse()
+                    .ok()
+                    .filter(|rate| *rate > 0.0 && *rate <= 1000.0)
+                    .ok_or_else(|| {
+                        format!(
+                            "--bot-rate expects over 0 up to 1000 strokes a second, got {}",
+                            rate
+                        )
+                    })?;
+                bot_options = true;
+                None
+            }
+            "--bot-seed" => {
+                let seed = value("a number")?;
+                let seed = seed
+                    .parse()
+                    .map_err(|_| format!("--bot-seed expects a number, got {}", seed))?;
+                config.bot_seed = Some(seed);
+                bot_options = true;
+                None
+            }
+            "--keymap" => {
+                config.keymap_path = Some(PathBuf::from(value("a file path")?));
+                None
+            }
+            "--dump-keymap" => {
+                dump_keymap = true;
+                None
+            }
+            "--help" | "-h" => return Ok(Command::Help),
+            flag if flag.starts_with("--") => return Err(format!("Unknown option {}", flag)),
+            addr => Some(Role::Auto(addr.to_string())),
+        };
+
+        if let Some(new_role) = new_role {
+            if role.is_some() {
+                return Err("--listen, --connect and a bare address are mutually exclusive".into());
+            }
+            role = Some(new_role);
+        }
+    }
+
+    if dump_keymap {
+        return Ok(Command::DumpKeymap(config.keymap_path));
+    }
+    if config.replay_path.is_some() {
+        if role.is_some() {
+            return Err("--replay plays back on its own; it cannot --listen or --connect".into());
+        }
+        if config.record_path.is_some() {
+            return Err("--replay cannot be combined with --record".into());
+        }
+        if config.headless {
+            return Err("Replaying (--replay) needs a window".into());
+        }
+    }
+    if config.bots.is_some() {
+        if config.replay_path.is_some() || config.spectate {
+            return Err("--bots runs on its own; it cannot --replay or --spectate".into());
+        }
+        if use_tls {
+            return Err("--bots connect without TLS".into());
+        }
+        match role {
+            Some(Role::Listen(_)) => {
+                return Err("--bots connect to a server; they cannot --listen".into());
+            }
+            // bots never become the server
+            Some(Role::Auto(addr)) => role = Some(Role::Connect(addr)),
+            None => role = Some(Role::Connect(DEFAULT_ADDR.to_string())),
+            Some(Role::Connect(_)) => {}
+        }
+    } else if bot_options {
+        return Err("--bot-rate and --bot-seed only matter with --bots".into());
+    }
+    if let Some(Role::Auto(addr)) = &role {
+        eprintln!(
+            "A bare address is deprecated; use --listen {} or --connect {}",
+            addr, addr
+        );
+    }
+    if let Some(role) = role {
+        config.role = role;
+    }
+    if config.spectate {
+        match config.role {
+            Role::Listen(_) => {
+                return Err("--spectate connects to a server, it cannot --listen".into());
+            }
+            // a spectator never becomes the server
+            Role::Auto(addr) => config.role = Role::Connect(addr),
+            Role::Connect(_) => {}
+        }
+    }
+    match (cert, key) {
+        (Some(cert), Some(key)) => {
+            if matches!(config.role, Role::Connect(_)) {
+                return Err("--cert and --key are for a server; a client uses --tls".into());
+            }
+            if config.ws_port.is_some() {
+                return Err("--ws-port cannot be combined with --cert: WebSocket clients would not be encrypted".into());
+            }
+            config.server_tls = Some(tls::server_config(&cert, &key)?);
+        }
+        (None, None) => {}
+        _ => return Err("--cert and --key go together".into()),
+    }
+    if ca.is_some() && !use_tls {
+        return Err("--ca only matters with --tls".into());
+    }
+    if use_tls {
+        if matches!(config.role, Role::Listen(_)) {
+            return Err("--tls is for a client; a server uses --cert and --key".into());
+        }
+        config.client_tls = Some(tls::client_config(ca.as_deref())?);
+    }
+    if config.ws_port.is_some() && matches!(config.role, Role::Connect(_)) {
+        return Err("--ws-port is for a server; it cannot --connect".into());
+    }
+    if config.fade.is_some() && matches!(config.role, Role::Connect(_)) {
+        return Err("--fade-seconds is for a server; clients fade as it says".into());
+    }
+    if config.persist_path.is_some() && matches!(config.role, Role::Connect(_)) {
+        return Err("--persist is for a server; it cannot --connect".into());
+    }
+    if config.restore_on_reconnect && matches!(config.role, Role::Listen(_)) {
+        return Err("--restore-on-reconnect is for a client; it cannot --listen".into());
+    }
+    // bots need no window either
+    if config.headless && config.bots.is_none() && matches!(config.role, Role::Connect(_)) {
+        return Err("Headless mode (--headless, or no display) only runs a server".into());
+    }
+    if config.pressure_range.0 > config.pressure_range.1 {
+        return Err("--pressure-min cannot be more than --pressure-max".into());
+    }
+
+    Ok(Command::Run(Box::new(config)))
+}
+
+/// A share of the brush size given to `flag`, from `least` to 4.
+fn share(value: &str, flag: &str, least: f32) -> Result<f32, String> {
+    value
+        .parse()
+        .ok()
+        .filter(|share| (least..=4.0).contains(share))
+        .ok_or_else(|| format!("{} expects {} to 4, got {}", flag, least, value))
+}
+
+/// The OS username, shown in the server's client list unless `--name` is given.
+fn default_name() -> String {
+    std::env::var("USER")
+        .or_else(|_| std::env::var("USERNAME"))
+        .unwrap_or_else(|_| "anonymous".to_string())
+}
+
+/// Without X11 or Wayland there is nowhere to open a window.
+fn display_available() -> bool {
+    if cfg!(target_os = "linux") {
+        std::env::var_os("DISPLAY").is_some() || std::env::var_os("WAYLAND_DISPLAY").is_some()
+    } else {
+        true
+    }
+}
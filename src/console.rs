@@ -0,0 +1,131 @@
+use crate::network::{JoinDecision, ServerHandle};
+use crate::transform::Transform;
+use crossbeam_channel::Receiver;
+use std::io::BufRead;
+use std::thread;
+
+pub const HELP: &str = "\
+Commands: approve <name|addr>, deny <name|addr>, pending, kick <name|addr>,
+          translate <dx> <dy>, scale <factor> [<x> <y>], rotate <cw|ccw|180> [<x> <y>]";
+
+/// Reads commands from stdin on a background thread, one line per message.
+pub fn spawn_reader() -> Receiver<String> {
+    let (tx, rx) = crossbeam_channel::unbounded();
+    thread::spawn(move || {
+        for line in std::io::stdin().lock().lines() {
+            let Ok(line) = line else {
+                break;
+            };
+            if tx.send(line).is_err() {
+                break;
+            }
+        }
+    });
+    rx
+}
+
+/// Runs one line typed on the server's console. Canvas transforms are handed
+/// back for the caller to send, so they go through its undo history.
+pub fn execute(line: &str, server: &ServerHandle) -> Option<Transform> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let (decision, who) = match words.as_slice() {
+        [] => return None,
+        ["pending"] => {
+            list_pending(server);
+            return None;
+        }
+        ["approve", who] => (JoinDecision::Approve, *who),
+        ["deny", who] => (JoinDecision::Deny, *who),
+        ["kick", who] => {
+            kick(server, who);
+            return None;
+        }
+        [command @ ("translate" | "scale" | "rotate"), args @ ..] => {
+            return match parse_transform(command, args) {
+                Ok(transform) => Some(transform),
+                Err(e) => {
+                    println!("{}", e);
+                    None
+                }
+            };
+        }
+        _ => {
+            println!("{}", HELP);
+            return None;
+        }
+    };
+
+    let Ok(clients) = server.clients.lock() else {
+        return None;
+    };
+    let guest = clients.iter().find(|client| {
+        client.pending && (client.name.as_deref() == Some(who) || client.addr.to_string() == who)
+    });
+    match guest {
+        Some(guest) => server.decide(guest.addr, decision),
+        None => println!("Nobody called {} is waiting for approval", who),
+    }
+    None
+}
+
+fn parse_transform(command: &str, args: &[&str]) -> Result<Transform, String> {
+    let number = |arg: &str| {
+        arg.parse::<f32>()
+            .ok()
+            .filter(|n| n.is_finite())
+            .ok_or_else(|| format!("{} expects numbers, got {}", command, arg))
+    };
+    // scaling and rotating happen around the top-left corner unless told otherwise
+    let origin = |rest: &[&str]| match rest {
+        [] => Ok((0.0, 0.0)),
+        [x, y] => Ok((number(x)?, number(y)?)),
+        _ => Err(format!("{} takes an optional <x> <y> origin", command)),
+    };
+
+    match (command, args) {
+        ("translate", [dx, dy]) => Ok(Transform::translate(number(dx)?, number(dy)?)),
+        ("scale", [factor, rest @ ..]) => {
+            let factor = number(factor)?;
+            if factor <= 0.0 {
+                return Err("scale expects a factor above 0".to_string());
+            }
+            Ok(Transform::scale_about(factor, origin(rest)?))
+        }
+        ("rotate", [turn, rest @ ..]) => {
+            let quarter_turns = match *turn {
+                "cw" => 1,
+                "180" => 2,
+                "ccw" => 3,
+                other => return Err(format!("rotate expects cw, ccw or 180, got {}", other)),
+            };
+            Ok(Transform::rotate_about(quarter_turns, origin(rest)?))
+        }
+        _ => Err(HELP.to_string()),
+    }
+}
+
+fn kick(server: &ServerHandle, who: &str) {
+    let Ok(clients) = server.clients.lock() else {
+        return;
+    };
+    let client = clients.iter().find(|client| {
+        !client.pending && (client.name.as_deref() == Some(who) || client.addr.to_string() == who)
+    });
+    match client {
+        Some(client) => server.kick(client.addr, "Removed by the host".to_string()),
+        None => println!("Nobody called {} is connected", who),
+    }
+}
+
+fn list_pending(server: &ServerHandle) {
+    let Ok(clients) = server.clients.lock() else {
+        return;
+    };
+    let mut pending = clients.iter().filter(|client| client.pending).peekable();
+    if pending.peek().is_none() {
+        println!("Nobody is waiting for approval");
+    }
+    for client in pending {
+        println!("  {}", client.label());
+    }
+}
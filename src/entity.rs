@@ -1,73 +1,1062 @@
+use crate::transform::Transform;
+use crossbeam_channel::{Receiver, Sender, TrySendError, bounded};
 use dashmap::DashMap;
+use dashmap::iter::Iter;
+use dashmap::mapref::one::Ref;
 use macroquad::math::Vec2;
-use macroquad::prelude::Circle;
-use serde::{Deserialize, Serialize};
+use macroquad::prelude::{Circle, Color, Rect};
+use serde::de::{self, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+static NEXT_SEQ: AtomicU64 = AtomicU64::new(1);
+
+/// Share of the full width a stroke keeps at zero pressure, unless it was
+/// drawn with another `--pressure-min`.
+pub const DEFAULT_THINNEST: f32 = 0.2;
+
+/// Side of a spatial grid cell, in pixels.
+const GRID_CELL_SIZE: f32 = 64.0;
+
+/// Width of a character as a share of the font size, for the box around a
+/// text label. The default font is monospaced, so this holds for any text.
+const TEXT_ADVANCE: f32 = 0.55;
+
+/// Events a canvas subscriber may fall behind by before it misses newer ones.
+pub const SUBSCRIBER_CAPACITY: usize = 4096;
+
+/// Owner of what the host draws, and of anything from before entities had owners.
+pub const HOST_OWNER: u64 = 0;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub enum Shape {
+    /// A single dot at the entity position.
+    #[default]
+    Circle,
+    /// A polyline `radius * 2` wide through `points`, starting at the entity position.
+    Stroke {
+        points: Vec<(f32, f32)>,
+        #[serde(default)]
+        pen: PenSamples,
+        /// Share of `radius` left at zero pressure. It travels with the stroke,
+        /// so every peer draws the widths it was drawn with.
+        #[serde(default = "default_thinnest")]
+        thinnest: f32,
+    },
+    /// A filled `w` by `h` rectangle with its top-left corner at the entity position.
+    Rect { w: f32, h: f32 },
+    /// A straight segment `thickness` wide from the entity position to `(x2, y2)`.
+    Line { x2: f32, y2: f32, thickness: f32 },
+    /// A filled region such as a bucket fill, as `(x, y, w, h)` rectangles in world
+    /// coordinates that do not overlap. The entity position is its top-left corner.
+    Fill { rects: Vec<(f32, f32, f32, f32)> },
+    /// A one-line label `size` pixels high with its top-left corner at the entity position.
+    Text { text: String, size: f32 },
+}
+
+fn default_thinnest() -> f32 {
+    DEFAULT_THINNEST
+}
+
+/// Optional pen data for stroke points. Each list is either empty or has one
+/// entry per point; peers that do not know about it draw constant width.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct PenSamples {
+    /// Pen pressure, 0 to 1.
+    #[serde(default)]
+    pub pressure: Vec<f32>,
+    /// Pen tilt from vertical along x and y, in radians. Reserved: nothing draws with it yet.
+    #[serde(default)]
+    pub tilt: Vec<(f32, f32)>,
+}
+
+impl PenSamples {
+    /// Samples for one point, empty when there is no pressure reading.
+    pub fn single(pressure: Option<f32>) -> Self {
+        Self {
+            pressure: pressure.map(|p| p.clamp(0.0, 1.0)).into_iter().collect(),
+            tilt: Vec::new(),
+        }
+    }
+
+    /// Appends the samples for `added` new points after `had` existing ones.
+    /// A list that would no longer line up with the points is dropped.
+    fn append(&mut self, had: usize, new: &PenSamples, added: usize) {
+        if self.pressure.len() == had && new.pressure.len() == added {
+            self.pressure
+                .extend(new.pressure.iter().map(|p| p.clamp(0.0, 1.0)));
+        } else {
+            self.pressure.clear();
+        }
+        if self.tilt.len() == had && new.tilt.len() == added {
+            self.tilt.extend_from_slice(&new.tilt);
+        } else {
+            self.tilt.clear();
+        }
+    }
+}
+
+/// A color as `0xTTRRGGBB`, with transparency in the top byte so a plain
+/// `0xRRGGBB` is opaque. Text formats such as JSON write it as `"#RRGGBB"`,
+/// or `"#RRGGBBAA"` with the alpha if it is not opaque, and read that or the
+/// integer older builds wrote; bincode keeps the integer.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct EntityColor(u32);
+
+impl EntityColor {
+    pub const WHITE: EntityColor = EntityColor::rgb(0xFFFFFF);
+    pub const BLACK: EntityColor = EntityColor::rgb(0x000000);
+    pub const RED: EntityColor = EntityColor::rgb(0xE62938);
+    pub const GREEN: EntityColor = EntityColor::rgb(0x00E330);
+    pub const BLUE: EntityColor = EntityColor::rgb(0x0078F2);
+    pub const ORANGE: EntityColor = EntityColor::rgb(0xFFA100);
+    pub const PURPLE: EntityColor = EntityColor::rgb(0xC77AFF);
+    pub const YELLOW: EntityColor = EntityColor::rgb(0xFCFA00);
+    pub const BROWN: EntityColor = EntityColor::rgb(0x806B4F);
+    /// The brush colors a window offers: macroquad's colors of the same names.
+    pub const PALETTE: [EntityColor; 8] = [
+        Self::RED,
+        Self::GREEN,
+        Self::BLUE,
+        Self::BLACK,
+        Self::ORANGE,
+        Self::PURPLE,
+        Self::YELLOW,
+        Self::BROWN,
+    ];
+
+    /// The opaque color `0xRRGGBB`; anything above the low 24 bits is ignored.
+    pub const fn rgb(rgb: u32) -> Self {
+        Self(rgb & 0xFFFFFF)
+    }
+
+    /// The color with these `0xTTRRGGBB` bits, as `bits` gives them back.
+    pub const fn from_bits(bits: u32) -> Self {
+        Self(bits)
+    }
+
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+
+    /// The color as older builds wrote it: the bits as a signed integer, or a
+    /// plain `0xRRGGBB`. Anything that fits in neither is refused.
+    pub fn from_integer(value: i64) -> Result<Self, String> {
+        match u32::try_from(value) {
+            Ok(bits) => Ok(Self(bits)),
+            Err(_) => i32::try_from(value)
+                .map(|signed| Self(signed as u32))
+                .map_err(|_| format!("color {} is out of range", value)),
+        }
+    }
+
+    /// `0xRRGGBB`, without the transparency.
+    pub const fn rgb_bits(self) -> u32 {
+        self.0 & 0xFFFFFF
+    }
+
+    /// From 0 for invisible to 1 for opaque.
+    pub fn alpha(self) -> f32 {
+        1.0 - (self.0 >> 24) as f32 / 255.0
+    }
+
+    /// The same color at `alpha`, clamped to 0..=1.
+    pub fn with_alpha(self, alpha: f32) -> Self {
+        let transparency = ((1.0 - alpha.clamp(0.0, 1.0)) * 255.0).round() as u32;
+        Self((transparency << 24) | self.rgb_bits())
+    }
+}
+
+impl From<Color> for EntityColor {
+    fn from(color: Color) -> Self {
+        let channel = |value: f32| (value.clamp(0.0, 1.0) * 255.0).round() as u32;
+        Self::rgb((channel(color.r) << 16) | (channel(color.g) << 8) | channel(color.b))
+            .with_alpha(color.a)
+    }
+}
+
+impl From<EntityColor> for Color {
+    fn from(color: EntityColor) -> Self {
+        let channel = |shift: u32| ((color.0 >> shift) & 0xFF) as f32 / 255.0;
+        Color::new(channel(16), channel(8), channel(0), color.alpha())
+    }
+}
+
+impl fmt::Display for EntityColor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let alpha = 0xFF - (self.0 >> 24);
+        if alpha == 0xFF {
+            write!(f, "#{:06X}", self.rgb_bits())
+        } else {
+            write!(f, "#{:06X}{:02X}", self.rgb_bits(), alpha)
+        }
+    }
+}
+
+impl FromStr for EntityColor {
+    type Err = String;
+
+    /// `#RRGGBB`, or `#RRGGBBAA` with the alpha from 00 for invisible to FF for opaque.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("color {:?} is not #RRGGBB or #RRGGBBAA", s);
+        let digits = s.strip_prefix('#').ok_or_else(invalid)?;
+        if !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(invalid());
+        }
+        let value = u32::from_str_radix(digits, 16).map_err(|_| invalid())?;
+        match digits.len() {
+            6 => Ok(Self::rgb(value)),
+            8 => Ok(Self(((0xFF - (value & 0xFF)) << 24) | (value >> 8))),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl Serialize for EntityColor {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.collect_str(self)
+        } else {
+            serializer.serialize_i32(self.0 as i32)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for EntityColor {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_any(ColorVisitor)
+        } else {
+            deserializer.deserialize_i32(ColorVisitor)
+        }
+    }
+}
+
+struct ColorVisitor;
+
+impl Visitor<'_> for ColorVisitor {
+    type Value = EntityColor;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("a color as \"#RRGGBB\", \"#RRGGBBAA\" or a 32-bit integer")
+    }
+
+    fn visit_str<E: de::Error>(self, s: &str) -> Result<Self::Value, E> {
+        s.parse().map_err(E::custom)
+    }
+
+    fn visit_i64<E: de::Error>(self, value: i64) -> Result<Self::Value, E> {
+        EntityColor::from_integer(value).map_err(E::custom)
+    }
+
+    fn visit_u64<E: de::Error>(self, value: u64) -> Result<Self::Value, E> {
+        let value = i64::try_from(value).map_err(|_| E::custom("color is out of range"))?;
+        self.visit_i64(value)
+    }
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Entity {
-    pub(crate) id: usize,
-    pub(crate) x: f32,
-    pub(crate) y: f32,
-    pub(crate) radius: f32,
-    pub(crate) color: i32,
+    pub id: usize,
+    pub x: f32,
+    pub y: f32,
+    pub radius: f32,
+    pub color: EntityColor,
+    /// Canvas epoch the entity was drawn in, stamped by the network layer.
+    #[serde(default)]
+    pub epoch: u64,
+    /// Canvas transforms applied before the entity was drawn, stamped by the network layer.
+    #[serde(default)]
+    pub generation: u64,
+    #[serde(default)]
+    pub shape: Shape,
+    /// Connection that drew the entity, stamped by the server; HOST_OWNER
+    /// until then, and for the host's own.
+    #[serde(default)]
+    pub owner: u64,
+    /// When it was drawn, in milliseconds since the Unix epoch by the clock of
+    /// the peer that drew it; 0 for anything from before entities had one.
+    /// Peers' clocks disagree, so fading goes by local arrival instead.
+    #[serde(default)]
+    pub created_at: u64,
+    /// Where it stacks: everything draws in ascending `(seq, id)`. Taken from a
+    /// counter each peer keeps ahead of every seq it has seen, so an entity
+    /// drawn after another one arrived goes on top of it on every peer; 0 for
+    /// anything from before entities had one.
+    #[serde(default)]
+    pub seq: u64,
+    /// Layer it was drawn on. Layers stack in ascending order, whatever the
+    /// seqs within them; 0 for anything from before there were layers.
+    #[serde(default)]
+    pub layer: u8,
 }
 
-impl Create for Entity {
-    fn spawn(
-        x: f32,
-        y: f32,
+impl Entity {
+    /// A rectangle with opposite corners at `from` and `to`, whichever way it was dragged.
+    pub fn rect(id: usize, from: (f32, f32), to: (f32, f32), color: EntityColor) -> Self {
+        Self {
+            id,
+            x: from.0.min(to.0),
+            y: from.1.min(to.1),
+            radius: 0.0,
+            color,
+            epoch: 0,
+            generation: 0,
+            owner: HOST_OWNER,
+            created_at: now_millis(),
+            seq: next_seq(),
+            layer: 0,
+            shape: Shape::Rect {
+                w: (to.0 - from.0).abs(),
+                h: (to.1 - from.1).abs(),
+            },
+        }
+    }
+
+    pub fn line(
+        id: usize,
+        from: (f32, f32),
+        to: (f32, f32),
+        thickness: f32,
+        color: EntityColor,
+    ) -> Self {
+        Self {
+            id,
+            x: from.0,
+            y: from.1,
+            radius: 0.0,
+            color,
+            epoch: 0,
+            generation: 0,
+            owner: HOST_OWNER,
+            created_at: now_millis(),
+            seq: next_seq(),
+            layer: 0,
+            shape: Shape::Line {
+                x2: to.0,
+                y2: to.1,
+                thickness,
+            },
+        }
+    }
+
+    /// A region made of `rects`, positioned at their top-left corner.
+    pub fn fill(id: usize, rects: Vec<(f32, f32, f32, f32)>, color: EntityColor) -> Self {
+        let corner = rects.iter().fold((f32::MAX, f32::MAX), |(x, y), rect| {
+            (x.min(rect.0), y.min(rect.1))
+        });
+        Self {
+            id,
+            x: corner.0,
+            y: corner.1,
+            radius: 0.0,
+            color,
+            epoch: 0,
+            generation: 0,
+            owner: HOST_OWNER,
+            created_at: now_millis(),
+            seq: next_seq(),
+            layer: 0,
+            shape: Shape::Fill { rects },
+        }
+    }
+
+    /// A label with its top-left corner at `at`.
+    pub fn text(id: usize, at: (f32, f32), text: String, size: f32, color: EntityColor) -> Self {
+        Self {
+            id,
+            x: at.0,
+            y: at.1,
+            radius: 0.0,
+            color,
+            epoch: 0,
+            generation: 0,
+            owner: HOST_OWNER,
+            created_at: now_millis(),
+            seq: next_seq(),
+            layer: 0,
+            shape: Shape::Text { text, size },
+        }
+    }
+
+    /// Appends points and their pen samples to a stroke, returning false for any other shape.
+    pub fn extend_stroke(&mut self, new_points: &[(f32, f32)], new_pen: &PenSamples) -> bool {
+        match &mut self.shape {
+            Shape::Stroke { points, pen, .. } => {
+                pen.append(points.len(), new_pen, new_points.len());
+                points.extend_from_slice(new_points);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Points from the end of a stroke up to `to`, no more than `spacing` apart,
+    /// so a fast pointer still leaves points all along the way. Pressure runs
+    /// evenly from the last reading on the stroke to `pressure`.
+    pub fn points_toward(
+        &self,
+        to: (f32, f32),
+        pressure: Option<f32>,
+        spacing: f32,
+    ) -> (Vec<(f32, f32)>, PenSamples) {
+        let from = self.last_point();
+        let length = Vec2::new(to.0 - from.0, to.1 - from.1).length();
+        let steps = ((length / spacing.max(1.0)).ceil() as usize).max(1);
+        let previous = match &self.shape {
+            Shape::Stroke { pen, .. } => pen.pressure.last().copied(),
+            _ => None,
+        };
+
+        let mut points = Vec::with_capacity(steps);
+        let mut samples = PenSamples::default();
+        for step in 1..=steps {
+            let t = step as f32 / steps as f32;
+            points.push((from.0 + (to.0 - from.0) * t, from.1 + (to.1 - from.1) * t));
+            if let Some(pressure) = pressure {
+                let start = previous.unwrap_or(pressure);
+                samples.pressure.push(start + (pressure - start) * t);
+            }
+        }
+        (points, samples)
+    }
+
+    /// Replaces the pen samples of a stroke that has just been started.
+    pub fn set_pen(&mut self, samples: PenSamples) {
+        if let Shape::Stroke { points, pen, .. } = &mut self.shape {
+            *pen = PenSamples::default();
+            pen.append(0, &samples, points.len());
+        }
+    }
+
+    /// Makes a stroke `radius` wide at full pressure and `thinnest` of that at
+    /// none, for a pressure range other than the default.
+    pub fn set_pressure_range(&mut self, radius: f32, thinnest: f32) {
+        if let Shape::Stroke {
+            thinnest: share, ..
+        } = &mut self.shape
+        {
+            self.radius = radius;
+            *share = thinnest.clamp(0.0, 1.0);
+        }
+    }
+
+    /// Radius at stroke point `index`, narrowed by the pen pressure there if there is any.
+    pub fn radius_at(&self, index: usize) -> f32 {
+        match &self.shape {
+            Shape::Stroke { pen, thinnest, .. } => match pen.pressure.get(index) {
+                Some(pressure) => self.radius * (thinnest + (1.0 - thinnest) * pressure),
+                None => self.radius,
+            },
+            _ => self.radius,
+        }
+    }
+
+    /// Moves the entity, every point of it included, so its position is `to`.
+    pub fn move_to(&mut self, to: (f32, f32)) {
+        Transform::translate(to.0 - self.x, to.1 - self.y).apply_to(self);
+    }
+
+    pub fn last_point(&self) -> (f32, f32) {
+        match &self.shape {
+            Shape::Stroke { points, .. } => points.last().copied().unwrap_or((self.x, self.y)),
+            Shape::Line { x2, y2, .. } => (*x2, *y2),
+            Shape::Circle | Shape::Rect { .. } | Shape::Fill { .. } | Shape::Text { .. } => {
+                (self.x, self.y)
+            }
+        }
+    }
+
+    /// World area the entity covers, radius or line thickness included.
+    pub fn bounds(&self) -> Rect {
+        let (mut min, mut max) = ((self.x, self.y), (self.x, self.y));
+        let mut pad = self.radius;
+        match &self.shape {
+            Shape::Circle => {}
+            Shape::Stroke { points, .. } => {
+                for &(x, y) in points {
+                    min = (min.0.min(x), min.1.min(y));
+                    max = (max.0.max(x), max.1.max(y));
+                }
+            }
+            Shape::Rect { w, h } => max = (self.x + w, self.y + h),
+            Shape::Line { x2, y2, thickness } => {
+                min = (min.0.min(*x2), min.1.min(*y2));
+                max = (max.0.max(*x2), max.1.max(*y2));
+                pad = thickness / 2.0;
+            }
+            Shape::Fill { rects } => {
+                for &(x, y, w, h) in rects {
+                    min = (min.0.min(x), min.1.min(y));
+                    max = (max.0.max(x + w), max.1.max(y + h));
+                }
+            }
+            Shape::Text { text, size } => {
+                let width = text.chars().count() as f32 * size * TEXT_ADVANCE;
+                max = (self.x + width, self.y + size);
+            }
+        }
+        Rect::new(
+            min.0 - pad,
+            min.1 - pad,
+            max.0 - min.0 + 2.0 * pad,
+            max.1 - min.1 + 2.0 * pad,
+        )
+    }
+
+    /// Whether `area` takes in a point of the entity: its position or a stroke
+    /// point, or any part of a rectangle, line, fill or a label's box.
+    fn touches(&self, area: &Circle) -> bool {
+        let center = Vec2::new(area.x, area.y);
+        match &self.shape {
+            Shape::Circle => area.contains(&Vec2::from((self.x, self.y))),
+            Shape::Stroke { points, .. } => points
+                .iter()
+                .any(|point| area.contains(&Vec2::from(*point))),
+            Shape::Rect { w, h } => {
+                let nearest =
+                    center.clamp(Vec2::new(self.x, self.y), Vec2::new(self.x + w, self.y + h));
+                nearest.distance(center) <= area.r
+            }
+            Shape::Fill { rects } => rects.iter().any(|&(x, y, w, h)| {
+                let nearest = center.clamp(Vec2::new(x, y), Vec2::new(x + w, y + h));
+                nearest.distance(center) <= area.r
+            }),
+            Shape::Text { .. } => {
+                let bounds = self.bounds();
+                let nearest = center.clamp(bounds.point(), bounds.point() + bounds.size());
+                nearest.distance(center) <= area.r
+            }
+            Shape::Line { x2, y2, thickness } => {
+                let (from, to) = (Vec2::new(self.x, self.y), Vec2::new(*x2, *y2));
+                let along = to - from;
+                let t = if along.length_squared() > 0.0 {
+                    ((center - from).dot(along) / along.length_squared()).clamp(0.0, 1.0)
+                } else {
+                    0.0
+                };
+                (from + along * t).distance(center) <= area.r + thickness / 2.0
+            }
+        }
+    }
+
+    /// Grid cells holding the points `touches` looks at, or every cell a
+    /// rectangle, line, fill or label covers, without repeats.
+    fn cells(&self) -> Vec<(i32, i32)> {
+        let mut cells: Vec<(i32, i32)> = match &self.shape {
+            Shape::Circle => vec![cell_of((self.x, self.y))],
+            Shape::Stroke { points, .. } => points.iter().map(|point| cell_of(*point)).collect(),
+            Shape::Rect { .. } | Shape::Line { .. } | Shape::Fill { .. } | Shape::Text { .. } => {
+                let bounds = self.bounds();
+                let (min_x, min_y) = cell_of((bounds.x, bounds.y));
+                let (max_x, max_y) = cell_of((bounds.right(), bounds.bottom()));
+                (min_x..=max_x)
+                    .flat_map(|cx| (min_y..=max_y).map(move |cy| (cx, cy)))
+                    .collect()
+            }
+        };
+        cells.sort_unstable();
+        cells.dedup();
+        cells
+    }
+
+    /// How far past the cells it is filed under the entity can be seen: a dot's
+    /// or stroke's radius, and for a stroke half its longest segment, which may
+    /// cross cells holding none of its points.
+    fn reach(&self) -> f32 {
+        match &self.shape {
+            Shape::Circle => self.radius,
+            Shape::Stroke { points, .. } => {
+                let longest = points
+                    .windows(2)
+                    .map(|segment| Vec2::from(segment[0]).distance(segment[1].into()))
+                    .fold(0.0, f32::max);
+                self.radius + longest / 2.0
+            }
+            Shape::Rect { .. } | Shape::Line { .. } | Shape::Fill { .. } | Shape::Text { .. } => {
+                0.0
+            }
+        }
+    }
+}
+
+fn cell_of((x, y): (f32, f32)) -> (i32, i32) {
+    (
+        (x / GRID_CELL_SIZE).floor() as i32,
+        (y / GRID_CELL_SIZE).floor() as i32,
+    )
+}
+
+/// Uniform grid of entity ids by the cells their points fall in, so hit-testing
+/// looks at the few entities near the cursor instead of the whole canvas.
+#[derive(Debug, Default)]
+struct SpatialGrid {
+    cells: HashMap<(i32, i32), HashSet<usize>>,
+    /// Cells each entity is filed under, to take it out again.
+    filed: HashMap<usize, Vec<(i32, i32)>>,
+    /// The longest `Entity::reach` filed since the grid was last cleared.
+    reach: f32,
+    /// Where each entity stacks, and when it was first added here.
+    places: HashMap<usize, Place>,
+    /// Ids by the order they were first added here in, oldest first.
+    arrivals: BTreeMap<u64, usize>,
+    next_arrival: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Place {
+    /// The entity's `(layer, seq, id)`: greater ones are on top.
+    stack: (u8, u64, usize),
+    added_at: Instant,
+    /// Key into `arrivals`.
+    arrival: u64,
+}
+
+impl SpatialGrid {
+    /// Files `entity` under its current cells, replacing wherever its id was filed before.
+    fn file(&mut self, entity: &Entity) {
+        self.unfile(entity.id);
+        let cells = entity.cells();
+        for cell in cells.iter() {
+            self.cells.entry(*cell).or_default().insert(entity.id);
+        }
+        self.filed.insert(entity.id, cells);
+        self.reach = self.reach.max(entity.reach());
+        let stack = (entity.layer, entity.seq, entity.id);
+        let next_arrival = &mut self.next_arrival;
+        let arrivals = &mut self.arrivals;
+        self.places
+            .entry(entity.id)
+            .and_modify(|place| place.stack = stack)
+            .or_insert_with(|| {
+                let arrival = *next_arrival;
+                *next_arrival += 1;
+                arrivals.insert(arrival, entity.id);
+                Place {
+                    stack,
+                    added_at: Instant::now(),
+                    arrival,
+                }
+            });
+        saw_seq(entity.seq);
+    }
+
+    /// Takes `id` out for good, its place in the stacking order included.
+    fn forget(&mut self, id: usize) {
+        self.unfile(id);
+        if let Some(place) = self.places.remove(&id) {
+            self.arrivals.remove(&place.arrival);
+        }
+    }
+
+    fn place(&self, id: usize) -> (u8, u64, usize) {
+        self.places
+            .get(&id)
+            .map_or((u8::MAX, u64::MAX, usize::MAX), |place| place.stack)
+    }
+
+    fn unfile(&mut self, id: usize) {
+        let Some(cells) = self.filed.remove(&id) else {
+            return;
+        };
+        for cell in cells {
+            if let Some(ids) = self.cells.get_mut(&cell) {
+                ids.remove(&id);
+                if ids.is_empty() {
+                    self.cells.remove(&cell);
+                }
+            }
+        }
+    }
+
+    fn clear(&mut self) {
+        self.cells.clear();
+        self.filed.clear();
+        self.reach = 0.0;
+        self.places.clear();
+        self.arrivals.clear();
+    }
+
+    /// Ids filed in any cell overlapping the square around `area`.
+    fn near(&self, area: &Circle) -> HashSet<usize> {
+        let (min_x, min_y) = cell_of((area.x - area.r, area.y - area.r));
+        let (max_x, max_y) = cell_of((area.x + area.r, area.y + area.r));
+        let mut ids = HashSet::new();
+        for cx in min_x..=max_x {
+            for cy in min_y..=max_y {
+                if let Some(cell) = self.cells.get(&(cx, cy)) {
+                    ids.extend(cell.iter().copied());
+                }
+            }
+        }
+        ids
+    }
+
+    /// Ids that may show in `area`, in order and without repeats: those filed
+    /// in cells overlapping it, once it is widened by the longest reach. A wide
+    /// area looks through the occupied cells rather than every cell it spans.
+    fn near_rect(&self, area: &Rect) -> Vec<usize> {
+        let area = Rect::new(
+            area.x - self.reach,
+            area.y - self.reach,
+            area.w + 2.0 * self.reach,
+            area.h + 2.0 * self.reach,
+        );
+        let (min_x, min_y) = cell_of((area.x, area.y));
+        let (max_x, max_y) = cell_of((area.right(), area.bottom()));
+        let spanned = (max_x as i64 - min_x as i64 + 1) * (max_y as i64 - min_y as i64 + 1);
+        let mut ids = Vec::new();
+        if spanned > self.cells.len() as i64 {
+            for ((cx, cy), cell) in &self.cells {
+                if (min_x..=max_x).contains(cx) && (min_y..=max_y).contains(cy) {
+                    ids.extend(cell.iter().copied());
+                }
+            }
+        } else {
+            for cx in min_x..=max_x {
+                for cy in min_y..=max_y {
+                    if let Some(cell) = self.cells.get(&(cx, cy)) {
+                        ids.extend(cell.iter().copied());
+                    }
+                }
+            }
+        }
+        ids.sort_unstable();
+        ids.dedup();
+        ids
+    }
+}
+
+/// A change to a canvas, as its subscribers see it.
+#[derive(Debug, Clone)]
+pub enum EntityEvent {
+    /// An entity under an id the canvas did not have, as it was added.
+    Added(Entity),
+    Removed(usize),
+    /// An entity replaced or changed in place, as it is now.
+    Updated(Entity),
+    Cleared,
+}
+
+/// The entities of one canvas by id, with a spatial grid kept in step with
+/// every change. Changes go through the grid lock, so the two never disagree,
+/// and subscribers get them in the order they were made.
+#[derive(Debug, Default)]
+pub struct Canvas {
+    entities: DashMap<usize, Entity>,
+    grid: Mutex<SpatialGrid>,
+    /// Bumped by every change.
+    revision: AtomicU64,
+    subscribers: Mutex<Vec<Sender<EntityEvent>>>,
+}
+
+impl Canvas {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn grid(&self) -> MutexGuard<'_, SpatialGrid> {
+        // a panic on another thread must not take hit-testing down with it
+        self.grid
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Counts changes, so a reader can tell whether anything changed since it last looked.
+    pub fn revision(&self) -> u64 {
+        self.revision.load(Ordering::SeqCst)
+    }
+
+    fn changed(&self) {
+        self.revision.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Every change from now on, for a side tool such as a logger to follow
+    /// without scanning the canvas. Nothing ever waits on a subscriber: one
+    /// more than SUBSCRIBER_CAPACITY events behind misses the newer ones until
+    /// it catches up. Dropping the receiver unsubscribes.
+    pub fn subscribe(&self) -> Receiver<EntityEvent> {
+        let (tx, rx) = bounded(SUBSCRIBER_CAPACITY);
+        self.subscribers().push(tx);
+        rx
+    }
+
+    fn subscribers(&self) -> MutexGuard<'_, Vec<Sender<EntityEvent>>> {
+        self.subscribers
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Hands every subscriber the event `event` makes, called with the grid
+    /// lock held so events keep the order of the changes. The event is only
+    /// made if someone is listening.
+    fn publish(&self, event: impl FnOnce() -> EntityEvent) {
+        let mut subscribers = self.subscribers();
+        if subscribers.is_empty() {
+            return;
+        }
+        let event = event();
+        subscribers.retain(|tx| {
+            !matches!(
+                tx.try_send(event.clone()),
+                Err(TrySendError::Disconnected(_))
+            )
+        });
+    }
+
+    /// Adds the entity, or replaces the one with the same id.
+    pub fn insert(&self, entity: Entity) -> Option<Entity> {
+        let mut grid = self.grid();
+        grid.file(&entity);
+        self.changed();
+        // only copied for subscribers, as whole canvases come through here
+        let copy = (!self.subscribers().is_empty()).then(|| entity.clone());
+        let replaced = self.entities.insert(entity.id, entity);
+        if let Some(entity) = copy {
+            self.publish(|| match replaced {
+                Some(_) => EntityEvent::Updated(entity),
+                None => EntityEvent::Added(entity),
+            });
+        }
+        replaced
+    }
+
+    pub fn remove(&self, id: usize) -> Option<Entity> {
+        let mut grid = self.grid();
+        grid.forget(id);
+        let removed = self.entities.remove(&id).map(|(_, entity)| entity);
+        if removed.is_some() {
+            self.changed();
+            self.publish(|| EntityEvent::Removed(id));
+        }
+        removed
+    }
+
+    pub fn clear(&self) {
+        let mut grid = self.grid();
+        grid.clear();
+        self.changed();
+        self.entities.clear();
+        self.publish(|| EntityEvent::Cleared);
+    }
+
+    /// Changes the entity with `id` in place, returning what `change` returned,
+    /// or None if there is no such entity.
+    pub fn update<R>(&self, id: usize, change: impl FnOnce(&mut Entity) -> R) -> Option<R> {
+        let mut grid = self.grid();
+        let mut entity = self.entities.get_mut(&id)?;
+        let result = change(&mut entity);
+        grid.file(&entity);
+        self.changed();
+        self.publish(|| EntityEvent::Updated(entity.clone()));
+        Some(result)
+    }
+
+    /// Changes every entity in place, then files them all afresh.
+    pub fn update_all(&self, mut change: impl FnMut(&mut Entity)) {
+        let mut grid = self.grid();
+        for mut entity in self.entities.iter_mut() {
+            change(&mut entity);
+            grid.file(&entity);
+            self.publish(|| EntityEvent::Updated(entity.clone()));
+        }
+        self.changed();
+    }
+
+    pub fn get(&self, id: usize) -> Option<Ref<'_, usize, Entity>> {
+        self.entities.get(&id)
+    }
+
+    pub fn contains(&self, id: usize) -> bool {
+        self.entities.contains_key(&id)
+    }
+
+    pub fn iter(&self) -> Iter<'_, usize, Entity> {
+        self.entities.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entities.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entities.is_empty()
+    }
+
+    /// Ids of the entities the circle touches, found through the grid.
+    pub fn query_circle(&self, center: (f32, f32), radius: f32) -> Vec<usize> {
+        let area = Circle::new(center.0, center.1, radius);
+        let near = self.grid().near(&area);
+        near.into_iter()
+            .filter(|id| {
+                self.entities
+                    .get(id)
+                    .is_some_and(|entity| entity.touches(&area))
+            })
+            .collect()
+    }
+
+    /// Ids of the entities whose bounds overlap `area`, found through the grid,
+    /// bottom first in stacking order, the same on every peer.
+    pub fn query_rect(&self, area: &Rect) -> Vec<usize> {
+        let grid = self.grid();
+        let mut ids = grid.near_rect(area);
+        ids.retain(|id| {
+            self.entities
+                .get(id)
+                .is_some_and(|entity| entity.bounds().overlaps(area))
+        });
+        ids.sort_unstable_by_key(|id| grid.place(*id));
+        ids
+    }
+
+    /// How long ago the entity with `id` was first added to this canvas.
+    pub fn age(&self, id: usize) -> Option<Duration> {
+        let grid = self.grid();
+        grid.places.get(&id).map(|place| place.added_at.elapsed())
+    }
+
+    /// Ids of the entities added to this canvas more than `age` ago.
+    pub fn older_than(&self, age: Duration) -> Vec<usize> {
+        let grid = self.grid();
+        grid.places
+            .iter()
+            .filter(|(_, place)| place.added_at.elapsed() > age)
+            .map(|(id, _)| *id)
+            .collect()
+    }
+
+    /// Ids of the `count` entities first added to this canvas longest ago,
+    /// oldest first.
+    pub fn oldest(&self, count: usize) -> Vec<usize> {
+        let grid = self.grid();
+        grid.arrivals.values().take(count).copied().collect()
+    }
+
+    /// The entity the circle touches that stacks highest among those `wanted`
+    /// takes, the one drawn on top of them.
+    pub fn topmost(
+        &self,
+        center: (f32, f32),
         radius: f32,
-        color: i32,
-        entities: &DashMap<usize, Entity>,
+        wanted: impl Fn(&Entity) -> bool,
     ) -> Option<usize> {
-        let next_id = entities.len();
+        let mut touched = self.query_circle(center, radius);
+        touched.retain(|id| self.entities.get(id).is_some_and(|entity| wanted(&entity)));
+        let grid = self.grid();
+        touched.into_iter().max_by_key(|id| grid.place(*id))
+    }
+}
+
+impl Create for Entity {
+    fn spawn(x: f32, y: f32, radius: f32, color: EntityColor, entities: &Canvas) -> Option<usize> {
+        let next_id = next_id();
         let new = Entity {
             id: next_id,
             x,
             y,
             radius,
             color,
+            epoch: 0,
+            generation: 0,
+            owner: HOST_OWNER,
+            created_at: now_millis(),
+            seq: next_seq(),
+            layer: 0,
+            shape: Shape::Stroke {
+                points: vec![(x, y)],
+                pen: PenSamples::default(),
+                thinnest: DEFAULT_THINNEST,
+            },
         };
-        entities.insert(next_id, new);
+        entities.insert(new);
         Option::from(next_id)
     }
 }
 
 impl Eraser for Entity {
-    fn erase(
-        &mut self,
-        area: Circle,
-        entities: &DashMap<usize, Entity>,
-    ) -> Option<(usize, Entity)> {
-        let pos = Vec2::from((self.x, self.y));
-
-        if area.contains(&pos) {
-            if entities.contains_key(&self.id) {
-                return entities.remove(&self.id);
-            }
+    fn erase(&mut self, area: Circle, entities: &Canvas) -> Option<Entity> {
+        if self.touches(&area) && entities.contains(self.id) {
+            return entities.remove(self.id);
         }
 
         None
     }
 
-    fn destroy(&mut self, entities: &DashMap<usize, Entity>) -> Option<(usize, Entity)> {
-        entities.remove(&self.id)
+    fn destroy(&mut self, entities: &Canvas) -> Option<Entity> {
+        entities.remove(self.id)
+    }
+}
+
+impl Paint for Entity {
+    fn colorize(&mut self, area: &Circle, color: EntityColor) -> bool {
+        if self.color != color && self.touches(area) {
+            self.color = color;
+            return true;
+        }
+
+        false
     }
 }
 
+/// Milliseconds since the Unix epoch by this machine's clock.
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64)
+}
+
+/// The next stacking position for an entity drawn here: above anything this
+/// process has drawn or been sent so far.
+pub fn next_seq() -> u64 {
+    NEXT_SEQ.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Keeps `next_seq` ahead of an entity that came from anywhere.
+fn saw_seq(seq: u64) {
+    NEXT_SEQ.fetch_max(seq.saturating_add(1), Ordering::Relaxed);
+}
+
+/// Random tag identifying this process among its peers.
+pub fn instance_tag() -> u64 {
+    static INSTANCE_TAG: OnceLock<u64> = OnceLock::new();
+    *INSTANCE_TAG.get_or_init(|| RandomState::new().build_hasher().finish())
+}
+
+/// Ids carry the instance tag in their high half so peers drawing at the same
+/// time, or offline, never hand out the same id.
+pub fn next_id() -> usize {
+    let half = usize::BITS / 2;
+    let tag = (instance_tag() as usize) << half;
+    tag | (NEXT_ID.fetch_add(1, Ordering::Relaxed) & ((1 << half) - 1))
+}
+
+/// Whether the entity with `id` was made by this process, going by the tag in its id.
+pub fn drawn_here(id: usize) -> bool {
+    let half = usize::BITS / 2;
+    id >> half == (instance_tag() as usize) & ((1 << half) - 1)
+}
+
 pub trait Create {
-    fn spawn(
-        x: f32,
-        y: f32,
-        radius: f32,
-        color: i32,
-        entities: &DashMap<usize, Entity>,
-    ) -> Option<usize>;
+    /// Starts a new stroke at `(x, y)` and returns its id.
+    fn spawn(x: f32, y: f32, radius: f32, color: EntityColor, entities: &Canvas) -> Option<usize>;
 }
 
 pub trait Eraser {
-    fn erase(&mut self, area: Circle, entities: &DashMap<usize, Entity>)
-    -> Option<(usize, Entity)>;
+    fn erase(&mut self, area: Circle, entities: &Canvas) -> Option<Entity>;
+
+    fn destroy(&mut self, entities: &Canvas) -> Option<Entity>;
+}
 
-    fn destroy(&mut self, entities: &DashMap<usize, Entity>) -> Option<(usize, Entity)>;
+pub trait Paint {
+    /// Gives the entity `color` if it touches `area`, returning whether it changed.
+    fn colorize(&mut self, area: &Circle, color: EntityColor) -> bool;
 }
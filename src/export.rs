@@ -0,0 +1,64 @@
+use macroquad::texture::Image;
+use std::path::PathBuf;
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// `canvas-YYYYMMDD-HHMMSS.png` in UTC, in the working directory.
+pub fn timestamped_png_path() -> PathBuf {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let (year, month, day) = civil_from_days((secs / 86_400) as i64);
+    let time = secs % 86_400;
+
+    PathBuf::from(format!(
+        "canvas-{:04}{:02}{:02}-{:02}{:02}{:02}.png",
+        year,
+        month,
+        day,
+        time / 3600,
+        time % 3600 / 60,
+        time % 60
+    ))
+}
+
+/// Encodes and writes the image on a background thread so a large canvas
+/// doesn't stall the render loop.
+pub fn write_png_in_background(image: Image, path: PathBuf) {
+    thread::spawn(move || {
+        let (width, height) = (image.width as usize, image.height as usize);
+        let row_len = width * 4;
+
+        // render target pixels come back bottom row first
+        let mut bytes = Vec::with_capacity(image.bytes.len());
+        for row in image.bytes.chunks_exact(row_len).rev() {
+            bytes.extend_from_slice(row);
+        }
+
+        match image::save_buffer(
+            &path,
+            &bytes,
+            width as u32,
+            height as u32,
+            image::ColorType::Rgba8,
+        ) {
+            Ok(()) => println!("Exported canvas to {}", path.display()),
+            Err(e) => eprintln!("Error exporting canvas to {}: {}", path.display(), e),
+        }
+    });
+}
+
+// Howard Hinnant's days-to-civil conversion.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
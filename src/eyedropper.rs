@@ -0,0 +1,71 @@
+use crate::MIN_OPACITY;
+use crate::bindings;
+use crate::layers::Layers;
+use crate::picker::Picker;
+use crate::view::View;
+use macroquad::prelude::*;
+use tcp_drawing::entity::Canvas;
+use tcp_drawing::keymap::Action;
+
+/// How near the pointer, in screen pixels, an entity counts as under it.
+const REACH: f32 = 3.0;
+/// Seconds the "no color here" hint stays up.
+const HINT_SECONDS: f64 = 1.2;
+
+/// Holding I turns a click into picking up the color of whatever is drawn on
+/// top under the pointer. The color goes to the picker, so it is the brush
+/// color until a palette slot is chosen again.
+pub struct Eyedropper {
+    /// When the hint after a click on empty canvas goes away.
+    hint_until: f64,
+}
+
+impl Eyedropper {
+    pub fn new() -> Self {
+        Self {
+            hint_until: f64::NEG_INFINITY,
+        }
+    }
+
+    /// Picks on a click while I is held and `keys_free`, setting `picker` and
+    /// `opacity` from the color of the top entity `layers` shows. Returns
+    /// whether the pointer belongs to the eyedropper this frame, in which case
+    /// it must not draw.
+    pub fn handle_input(
+        &mut self,
+        keys_free: bool,
+        canvas: &Canvas,
+        view: &View,
+        layers: &Layers,
+        picker: &mut Picker,
+        opacity: &mut f32,
+    ) -> bool {
+        if !keys_free || !bindings::down(Action::Eyedropper) {
+            return false;
+        }
+        if is_mouse_button_pressed(MouseButton::Left) {
+            let picked = canvas
+                .topmost(view.mouse(), REACH / view.zoom(), |entity| {
+                    layers.shows(entity)
+                })
+                .and_then(|id| canvas.get(id).map(|entity| entity.color));
+            match picked {
+                Some(hex) => {
+                    let color = Color::from(hex);
+                    picker.set_color(color);
+                    *opacity = color.a.clamp(MIN_OPACITY, 1.0);
+                }
+                None => self.hint_until = get_time() + HINT_SECONDS,
+            }
+        }
+        true
+    }
+
+    /// Draws the hint next to the pointer, in screen space, in `ink`.
+    pub fn render(&self, ink: Color) {
+        if get_time() < self.hint_until {
+            let (x, y) = mouse_position();
+            draw_text("no color here", x + 12.0, y + 24.0, 18.0, ink);
+        }
+    }
+}
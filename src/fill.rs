@@ -0,0 +1,221 @@
+//! Bucket fill: the enclosed area around a point, worked out on a grid of
+//! cells over what is drawn there and returned as rectangles for a `Shape::Fill`.
+
+use crate::entity::{Canvas, Entity, Shape};
+use macroquad::math::{Rect, Vec2};
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::Range;
+
+/// Side of a fill cell in world pixels, unless `--fill-resolution` says otherwise.
+pub const DEFAULT_RESOLUTION: f32 = 4.0;
+/// Cells along each side of the square a fill may spread over, centered on the
+/// click. A region that reaches its edge is taken to be open.
+const SPAN: usize = 512;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FillError {
+    /// The point is on something already drawn.
+    Covered,
+    /// The region runs out of the square a fill may cover, `size` pixels across.
+    Unbounded { size: f32 },
+}
+
+impl fmt::Display for FillError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FillError::Covered => write!(f, "there is a drawing under the pointer"),
+            FillError::Unbounded { size } => write!(
+                f,
+                "the area is not enclosed within {:.0} px around the pointer",
+                size
+            ),
+        }
+    }
+}
+
+/// The rectangles, as `(x, y, w, h)`, covering the region around `start` that
+/// nothing on the canvas closes off, in cells `resolution` pixels wide.
+pub fn flood_fill(
+    canvas: &Canvas,
+    start: (f32, f32),
+    resolution: f32,
+) -> Result<Vec<(f32, f32, f32, f32)>, FillError> {
+    let mut grid = Grid::around(start, resolution);
+    for entry in canvas.iter() {
+        let entity = entry.value();
+        if entity.bounds().overlaps(&grid.area()) {
+            grid.mark(entity);
+        }
+    }
+
+    let first = SPAN / 2 * SPAN + SPAN / 2;
+    if grid.blocked[first] {
+        return Err(FillError::Covered);
+    }
+    let mut filled = vec![false; SPAN * SPAN];
+    filled[first] = true;
+    let mut stack = vec![first];
+    while let Some(cell) = stack.pop() {
+        let (col, row) = (cell % SPAN, cell / SPAN);
+        if col == 0 || row == 0 || col == SPAN - 1 || row == SPAN - 1 {
+            return Err(FillError::Unbounded {
+                size: SPAN as f32 * resolution,
+            });
+        }
+        for next in [cell - 1, cell + 1, cell - SPAN, cell + SPAN] {
+            if !grid.blocked[next] && !filled[next] {
+                filled[next] = true;
+                stack.push(next);
+            }
+        }
+    }
+    Ok(grid.rects(&filled))
+}
+
+/// Which cells of the square around the click something drawn takes up.
+struct Grid {
+    /// World position of the top-left cell's corner.
+    origin: Vec2,
+    resolution: f32,
+    blocked: Vec<bool>,
+}
+
+impl Grid {
+    fn around(start: (f32, f32), resolution: f32) -> Self {
+        let half = SPAN as f32 / 2.0 * resolution;
+        Self {
+            origin: Vec2::new(start.0 - half, start.1 - half),
+            resolution,
+            blocked: vec![false; SPAN * SPAN],
+        }
+    }
+
+    fn area(&self) -> Rect {
+        let size = SPAN as f32 * self.resolution;
+        Rect::new(self.origin.x, self.origin.y, size, size)
+    }
+
+    fn center(&self, col: usize, row: usize) -> Vec2 {
+        self.origin + (Vec2::new(col as f32, row as f32) + 0.5) * self.resolution
+    }
+
+    /// Cells `rect` overlaps, as column and row ranges.
+    fn cells_in(&self, rect: Rect) -> (Range<usize>, Range<usize>) {
+        let range = |from: f32, to: f32, origin: f32| {
+            let first = ((from - origin) / self.resolution).floor().max(0.0) as usize;
+            let end = ((to - origin) / self.resolution).ceil().max(0.0) as usize;
+            first.min(SPAN)..end.min(SPAN)
+        };
+        (
+            range(rect.x, rect.right(), self.origin.x),
+            range(rect.y, rect.bottom(), self.origin.y),
+        )
+    }
+
+    fn mark(&mut self, entity: &Entity) {
+        match &entity.shape {
+            Shape::Circle => {
+                let center = Vec2::new(entity.x, entity.y);
+                self.capsule(center, center, entity.radius);
+            }
+            Shape::Stroke { points, .. } => {
+                if let [point] = points.as_slice() {
+                    let point = Vec2::from(*point);
+                    self.capsule(point, point, entity.radius_at(0));
+                }
+                for (i, segment) in points.windows(2).enumerate() {
+                    let radius = entity.radius_at(i).max(entity.radius_at(i + 1));
+                    self.capsule(segment[0].into(), segment[1].into(), radius);
+                }
+            }
+            Shape::Rect { w, h } => self.rect(Rect::new(entity.x, entity.y, *w, *h)),
+            Shape::Line { x2, y2, thickness } => self.capsule(
+                Vec2::new(entity.x, entity.y),
+                Vec2::new(*x2, *y2),
+                thickness / 2.0,
+            ),
+            Shape::Fill { rects } => {
+                for &(x, y, w, h) in rects {
+                    self.rect(Rect::new(x, y, w, h));
+                }
+            }
+            Shape::Text { .. } => self.rect(entity.bounds()),
+        }
+    }
+
+    /// Blocks cells near the segment from `from` to `to`. Anything thinner than
+    /// a cell still makes a wall the fill cannot slip through diagonally.
+    fn capsule(&mut self, from: Vec2, to: Vec2, radius: f32) {
+        let radius = radius.max(self.resolution * 0.75);
+        let bounds = Rect::new(
+            from.x.min(to.x) - radius,
+            from.y.min(to.y) - radius,
+            (from.x - to.x).abs() + 2.0 * radius,
+            (from.y - to.y).abs() + 2.0 * radius,
+        );
+        let (cols, rows) = self.cells_in(bounds);
+        let along = to - from;
+        for row in rows {
+            for col in cols.clone() {
+                let point = self.center(col, row);
+                let t = if along.length_squared() > 0.0 {
+                    ((point - from).dot(along) / along.length_squared()).clamp(0.0, 1.0)
+                } else {
+                    0.0
+                };
+                if (from + along * t).distance(point) <= radius {
+                    self.blocked[row * SPAN + col] = true;
+                }
+            }
+        }
+    }
+
+    /// Blocks every cell `rect` overlaps at all.
+    fn rect(&mut self, rect: Rect) {
+        let (cols, rows) = self.cells_in(rect);
+        for row in rows {
+            for col in cols.clone() {
+                self.blocked[row * SPAN + col] = true;
+            }
+        }
+    }
+
+    /// Runs of filled cells in each row, stacked into one rectangle while the
+    /// rows below repeat them exactly.
+    fn rects(&self, filled: &[bool]) -> Vec<(f32, f32, f32, f32)> {
+        let mut rects: Vec<(f32, f32, f32, f32)> = Vec::new();
+        // runs in the row above, by first and past-last column, with their rectangle
+        let mut open: HashMap<(usize, usize), usize> = HashMap::new();
+        for row in 0..SPAN {
+            let mut still_open = HashMap::new();
+            let mut col = 0;
+            while col < SPAN {
+                if !filled[row * SPAN + col] {
+                    col += 1;
+                    continue;
+                }
+                let first = col;
+                while col < SPAN && filled[row * SPAN + col] {
+                    col += 1;
+                }
+                let index = match open.get(&(first, col)) {
+                    Some(&index) => {
+                        rects[index].3 += self.resolution;
+                        index
+                    }
+                    None => {
+                        let corner =
+                            self.origin + Vec2::new(first as f32, row as f32) * self.resolution;
+                        let width = (col - first) as f32 * self.resolution;
+                        rects.push((corner.x, corner.y, width, self.resolution));
+                        rects.len() - 1
+                    }
+                };
+                still_open.insert((first, col), index);
+            }
+            open = still_open;
+        }
+        rects
+    }
+}
@@ -0,0 +1,118 @@
+use crate::background;
+use crate::bindings;
+use crate::view::View;
+use macroquad::prelude::*;
+use tcp_drawing::keymap::Action;
+
+/// Spacings, in world pixels, that G cycles through after "off".
+const SPACINGS: [f32; 3] = [16.0, 32.0, 64.0];
+/// Lines closer together than this on screen are left out, so zooming far out
+/// does not draw a solid sheet of them.
+const MIN_SCREEN_SPACING: f32 = 4.0;
+const LINE_ALPHA: f32 = 0.08;
+
+/// A background grid for lining things up. Only this window sees it; what it
+/// changes is where the pointer draws, when snapping is on.
+pub struct Grid {
+    /// Index into SPACINGS, or None while the grid is off.
+    spacing: Option<usize>,
+    snap: bool,
+    /// Line positions for the view they were worked out for.
+    lines: Option<Lines>,
+}
+
+struct Lines {
+    visible: Rect,
+    spacing: f32,
+    xs: Vec<f32>,
+    ys: Vec<f32>,
+}
+
+impl Grid {
+    pub fn new() -> Self {
+        Self {
+            spacing: None,
+            snap: false,
+            lines: None,
+        }
+    }
+
+    fn spacing(&self) -> Option<f32> {
+        self.spacing.map(|index| SPACINGS[index])
+    }
+
+    /// G cycles off / 16 / 32 / 64 px, Shift+G turns snapping on or off.
+    pub fn handle_input(&mut self) {
+        if bindings::pressed(Action::ToggleSnap) {
+            self.snap = !self.snap;
+        }
+        if bindings::pressed(Action::ToggleGrid) {
+            self.spacing = match self.spacing {
+                None => Some(0),
+                Some(index) if index + 1 < SPACINGS.len() => Some(index + 1),
+                Some(_) => None,
+            };
+        }
+    }
+
+    /// `point` moved to the nearest grid intersection while snapping to a visible grid.
+    pub fn snap(&self, point: (f32, f32)) -> (f32, f32) {
+        match self.spacing() {
+            Some(spacing) if self.snap => (
+                (point.0 / spacing).round() * spacing,
+                (point.1 / spacing).round() * spacing,
+            ),
+            _ => point,
+        }
+    }
+
+    /// Draws the lines across the view, in world coordinates, in a faint `ink`.
+    /// Only worked out again when the view or the spacing changes.
+    pub fn render(&mut self, view: &View, ink: Color) {
+        let Some(spacing) = self.spacing() else {
+            return;
+        };
+        // zoomed out, every second or fourth line is enough
+        let mut spacing = spacing;
+        while spacing * view.zoom() < MIN_SCREEN_SPACING {
+            spacing *= 2.0;
+        }
+
+        let visible = view.visible();
+        let stale = self
+            .lines
+            .as_ref()
+            .is_none_or(|lines| lines.visible != visible || lines.spacing != spacing);
+        if stale {
+            self.lines = Some(Lines {
+                visible,
+                spacing,
+                xs: positions(visible.x, visible.right(), spacing),
+                ys: positions(visible.y, visible.bottom(), spacing),
+            });
+        }
+        let Some(lines) = &self.lines else {
+            return;
+        };
+
+        let width = 1.0 / view.zoom();
+        let line_color = background::faint(ink, LINE_ALPHA);
+        for &x in &lines.xs {
+            draw_line(x, visible.y, x, visible.bottom(), width, line_color);
+        }
+        for &y in &lines.ys {
+            draw_line(visible.x, y, visible.right(), y, width, line_color);
+        }
+        if self.snap {
+            let (x, y) = self.snap(view.mouse());
+            draw_circle_lines(x, y, 3.0 / view.zoom(), width, GRAY);
+        }
+    }
+}
+
+/// Multiples of `spacing` from `from` to `to`.
+fn positions(from: f32, to: f32, spacing: f32) -> Vec<f32> {
+    let first = (from / spacing).ceil() as i64;
+    let last = (to / spacing).floor() as i64;
+    (first..=last).map(|i| i as f32 * spacing).collect()
+}
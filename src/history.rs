@@ -0,0 +1,111 @@
+use crate::entity::{Canvas, Entity, Eraser};
+use crate::transform::Transform;
+use std::collections::VecDeque;
+
+const MAX_HISTORY: usize = 100;
+
+/// Something this instance did that undo can take back.
+enum Step {
+    Drew(usize),
+    Transformed(Transform),
+}
+
+/// Something undo took back, kept for redo.
+enum Undone {
+    Entity(Entity),
+    Transform(Transform),
+}
+
+/// What an undo or redo did to the local canvas, for the caller to pass on to peers.
+pub enum Change {
+    Removed(usize),
+    Restored(Entity),
+    /// Not applied yet: the network layer moves the canvas.
+    Transformed(Transform),
+}
+
+/// Undo/redo over the entities this instance drew and the canvas transforms it
+/// made. Only ids handed to `record` are ever undone, so other peers' drawings
+/// are never touched.
+#[derive(Default)]
+pub struct History {
+    done: VecDeque<Step>,
+    undone: Vec<Undone>,
+}
+
+impl History {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn push(&mut self, step: Step) {
+        self.done.push_back(step);
+        if self.done.len() > MAX_HISTORY {
+            self.done.pop_front();
+        }
+    }
+
+    /// Remembers a freshly drawn entity. Drawing something new drops the redo stack.
+    pub fn record(&mut self, id: usize) {
+        self.undone.clear();
+        self.push(Step::Drew(id));
+    }
+
+    /// Remembers a canvas transform. Like drawing, it drops the redo stack.
+    pub fn record_transform(&mut self, transform: Transform) {
+        self.undone.clear();
+        self.push(Step::Transformed(transform));
+    }
+
+    /// Takes back the most recent own entity still on the canvas, or the most
+    /// recent transform by handing back its inverse.
+    pub fn undo(&mut self, entities: &Canvas) -> Option<Change> {
+        while let Some(step) = self.done.pop_back() {
+            let (undone, change) = match step {
+                Step::Drew(id) => {
+                    // skip entities that were already cleared away
+                    let Some(mut entity) = entities.get(id).map(|e| e.value().clone()) else {
+                        continue;
+                    };
+                    if entity.destroy(entities).is_none() {
+                        continue;
+                    }
+                    (Undone::Entity(entity), Change::Removed(id))
+                }
+                Step::Transformed(transform) => {
+                    let Some(inverse) = transform.inverse() else {
+                        continue;
+                    };
+                    (Undone::Transform(transform), Change::Transformed(inverse))
+                }
+            };
+            self.undone.push(undone);
+            if self.undone.len() > MAX_HISTORY {
+                self.undone.remove(0);
+            }
+            return Some(change);
+        }
+        None
+    }
+
+    /// Puts the last undone entity back on the canvas, or hands back the last
+    /// undone transform to make again.
+    pub fn redo(&mut self, entities: &Canvas) -> Option<Change> {
+        match self.undone.pop()? {
+            Undone::Entity(entity) => {
+                entities.insert(entity.clone());
+                self.push(Step::Drew(entity.id));
+                Some(Change::Restored(entity))
+            }
+            Undone::Transform(transform) => {
+                self.push(Step::Transformed(transform));
+                Some(Change::Transformed(transform))
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.done.clear();
+        self.undone.clear();
+    }
+}
@@ -0,0 +1,402 @@
+//! Which keys and buttons call for what in the window: built-in defaults, any
+//! of which a JSON keymap file can rebind by action name.
+
+use macroquad::input::{KeyCode, MouseButton};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Looked for next to the binary when no `--keymap` is given.
+pub const KEYMAP_FILE: &str = "keymap.json";
+
+/// Something a key or button does in the window. Typing into labels and the
+/// chat box, Escape and Enter, and drawing, panning and zooming with the mouse
+/// keep their keys.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Action {
+    ClearCanvas,
+    Undo,
+    Redo,
+    Save,
+    Load,
+    Export,
+    Copy,
+    Paste,
+    DeleteSelection,
+    ToolFreehand,
+    ToolRect,
+    ToolLine,
+    ToolFill,
+    ToolText,
+    ToolSelect,
+    /// A palette slot, from 0.
+    Color(usize),
+    ColorNext,
+    ColorPrevious,
+    OpacityUp,
+    OpacityDown,
+    ToggleGrid,
+    ToggleSnap,
+    Mirror,
+    Smoothing,
+    Picker,
+    /// Held rather than pressed: a click meanwhile picks a color up.
+    Eyedropper,
+    NetStats,
+    FitView,
+    NextRoom,
+    Background,
+    /// Draw on a layer, from 0.
+    Layer(u8),
+    /// Hide or show a layer, from 0.
+    ToggleLayer(u8),
+}
+
+/// Every action with the name a keymap file calls it by and its default bindings.
+const ACTIONS: &[(Action, &str, &[&str])] = &[
+    (Action::ClearCanvas, "clear_canvas", &["c"]),
+    (Action::Undo, "undo", &["ctrl+z"]),
+    (Action::Redo, "redo", &["ctrl+y"]),
+    (Action::Save, "save", &["ctrl+s"]),
+    (Action::Load, "load", &["ctrl+o"]),
+    (Action::Export, "export", &["ctrl+e"]),
+    (Action::Copy, "copy", &["ctrl+c"]),
+    (Action::Paste, "paste", &["ctrl+v"]),
+    (Action::DeleteSelection, "delete_selection", &["delete"]),
+    (Action::ToolFreehand, "tool_freehand", &["q"]),
+    (Action::ToolRect, "tool_rect", &["w"]),
+    (Action::ToolLine, "tool_line", &["e"]),
+    (Action::ToolFill, "tool_fill", &["f"]),
+    (Action::ToolText, "tool_text", &["t"]),
+    (Action::ToolSelect, "tool_select", &["v"]),
+    (Action::Color(0), "color_1", &["1"]),
+    (Action::Color(1), "color_2", &["2"]),
+    (Action::Color(2), "color_3", &["3"]),
+    (Action::Color(3), "color_4", &["4"]),
+    (Action::Color(4), "color_5", &["5"]),
+    (Action::Color(5), "color_6", &["6"]),
+    (Action::Color(6), "color_7", &["7"]),
+    (Action::Color(7), "color_8", &["8"]),
+    (Action::ColorNext, "color_next", &["right_bracket"]),
+    (Action::ColorPrevious, "color_previous", &["left_bracket"]),
+    (
+        Action::OpacityUp,
+        "opacity_up",
+        &["equal", "shift+equal", "kp_add"],
+    ),
+    (
+        Action::OpacityDown,
+        "opacity_down",
+        &["minus", "kp_subtract"],
+    ),
+    (Action::ToggleGrid, "toggle_grid", &["g"]),
+    (Action::ToggleSnap, "toggle_snap", &["shift+g"]),
+    (Action::Mirror, "mirror", &["m"]),
+    (Action::Smoothing, "smoothing", &["k"]),
+    (Action::Picker, "picker", &["p"]),
+    (Action::Eyedropper, "eyedropper", &["i"]),
+    (Action::NetStats, "net_stats", &["f3"]),
+    (Action::FitView, "fit_view", &["home"]),
+    (Action::NextRoom, "next_room", &["tab"]),
+    (Action::Background, "background", &["b"]),
+    (Action::Layer(0), "layer_1", &["ctrl+1"]),
+    (Action::Layer(1), "layer_2", &["ctrl+2"]),
+    (Action::Layer(2), "layer_3", &["ctrl+3"]),
+    (Action::Layer(3), "layer_4", &["ctrl+4"]),
+    (Action::ToggleLayer(0), "toggle_layer_1", &["ctrl+shift+1"]),
+    (Action::ToggleLayer(1), "toggle_layer_2", &["ctrl+shift+2"]),
+    (Action::ToggleLayer(2), "toggle_layer_3", &["ctrl+shift+3"]),
+    (Action::ToggleLayer(3), "toggle_layer_4", &["ctrl+shift+4"]),
+];
+
+/// Names of the keys and buttons a binding can use.
+const INPUTS: &[(&str, Input)] = &[
+    ("a", Input::Key(KeyCode::A)),
+    ("b", Input::Key(KeyCode::B)),
+    ("c", Input::Key(KeyCode::C)),
+    ("d", Input::Key(KeyCode::D)),
+    ("e", Input::Key(KeyCode::E)),
+    ("f", Input::Key(KeyCode::F)),
+    ("g", Input::Key(KeyCode::G)),
+    ("h", Input::Key(KeyCode::H)),
+    ("i", Input::Key(KeyCode::I)),
+    ("j", Input::Key(KeyCode::J)),
+    ("k", Input::Key(KeyCode::K)),
+    ("l", Input::Key(KeyCode::L)),
+    ("m", Input::Key(KeyCode::M)),
+    ("n", Input::Key(KeyCode::N)),
+    ("o", Input::Key(KeyCode::O)),
+    ("p", Input::Key(KeyCode::P)),
+    ("q", Input::Key(KeyCode::Q)),
+    ("r", Input::Key(KeyCode::R)),
+    ("s", Input::Key(KeyCode::S)),
+    ("t", Input::Key(KeyCode::T)),
+    ("u", Input::Key(KeyCode::U)),
+    ("v", Input::Key(KeyCode::V)),
+    ("w", Input::Key(KeyCode::W)),
+    ("x", Input::Key(KeyCode::X)),
+    ("y", Input::Key(KeyCode::Y)),
+    ("z", Input::Key(KeyCode::Z)),
+    ("0", Input::Key(KeyCode::Key0)),
+    ("1", Input::Key(KeyCode::Key1)),
+    ("2", Input::Key(KeyCode::Key2)),
+    ("3", Input::Key(KeyCode::Key3)),
+    ("4", Input::Key(KeyCode::Key4)),
+    ("5", Input::Key(KeyCode::Key5)),
+    ("6", Input::Key(KeyCode::Key6)),
+    ("7", Input::Key(KeyCode::Key7)),
+    ("8", Input::Key(KeyCode::Key8)),
+    ("9", Input::Key(KeyCode::Key9)),
+    ("f1", Input::Key(KeyCode::F1)),
+    ("f2", Input::Key(KeyCode::F2)),
+    ("f3", Input::Key(KeyCode::F3)),
+    ("f4", Input::Key(KeyCode::F4)),
+    ("f5", Input::Key(KeyCode::F5)),
+    ("f6", Input::Key(KeyCode::F6)),
+    ("f7", Input::Key(KeyCode::F7)),
+    ("f8", Input::Key(KeyCode::F8)),
+    ("f9", Input::Key(KeyCode::F9)),
+    ("f10", Input::Key(KeyCode::F10)),
+    ("f11", Input::Key(KeyCode::F11)),
+    ("f12", Input::Key(KeyCode::F12)),
+    ("space", Input::Key(KeyCode::Space)),
+    ("tab", Input::Key(KeyCode::Tab)),
+    ("backspace", Input::Key(KeyCode::Backspace)),
+    ("insert", Input::Key(KeyCode::Insert)),
+    ("delete", Input::Key(KeyCode::Delete)),
+    ("home", Input::Key(KeyCode::Home)),
+    ("end", Input::Key(KeyCode::End)),
+    ("page_up", Input::Key(KeyCode::PageUp)),
+    ("page_down", Input::Key(KeyCode::PageDown)),
+    ("up", Input::Key(KeyCode::Up)),
+    ("down", Input::Key(KeyCode::Down)),
+    ("left", Input::Key(KeyCode::Left)),
+    ("right", Input::Key(KeyCode::Right)),
+    ("apostrophe", Input::Key(KeyCode::Apostrophe)),
+    ("comma", Input::Key(KeyCode::Comma)),
+    ("minus", Input::Key(KeyCode::Minus)),
+    ("period", Input::Key(KeyCode::Period)),
+    ("slash", Input::Key(KeyCode::Slash)),
+    ("semicolon", Input::Key(KeyCode::Semicolon)),
+    ("equal", Input::Key(KeyCode::Equal)),
+    ("left_bracket", Input::Key(KeyCode::LeftBracket)),
+    ("backslash", Input::Key(KeyCode::Backslash)),
+    ("right_bracket", Input::Key(KeyCode::RightBracket)),
+    ("grave_accent", Input::Key(KeyCode::GraveAccent)),
+    ("kp_0", Input::Key(KeyCode::Kp0)),
+    ("kp_1", Input::Key(KeyCode::Kp1)),
+    ("kp_2", Input::Key(KeyCode::Kp2)),
+    ("kp_3", Input::Key(KeyCode::Kp3)),
+    ("kp_4", Input::Key(KeyCode::Kp4)),
+    ("kp_5", Input::Key(KeyCode::Kp5)),
+    ("kp_6", Input::Key(KeyCode::Kp6)),
+    ("kp_7", Input::Key(KeyCode::Kp7)),
+    ("kp_8", Input::Key(KeyCode::Kp8)),
+    ("kp_9", Input::Key(KeyCode::Kp9)),
+    ("kp_decimal", Input::Key(KeyCode::KpDecimal)),
+    ("kp_divide", Input::Key(KeyCode::KpDivide)),
+    ("kp_multiply", Input::Key(KeyCode::KpMultiply)),
+    ("kp_subtract", Input::Key(KeyCode::KpSubtract)),
+    ("kp_add", Input::Key(KeyCode::KpAdd)),
+    ("mouse_left", Input::Mouse(MouseButton::Left)),
+    ("mouse_middle", Input::Mouse(MouseButton::Middle)),
+    ("mouse_right", Input::Mouse(MouseButton::Right)),
+];
+
+impl Action {
+    /// The name a keymap file calls this action by.
+    pub fn name(self) -> &'static str {
+        ACTIONS
+            .iter()
+            .find(|(action, ..)| *action == self)
+            .map_or("unknown", |(_, name, _)| name)
+    }
+}
+
+impl FromStr for Action {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        ACTIONS
+            .iter()
+            .find(|(_, name, _)| *name == s)
+            .map(|(action, ..)| *action)
+            .ok_or_else(|| format!("no such action {:?}", s))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Input {
+    Key(KeyCode),
+    Mouse(MouseButton),
+}
+
+/// A key or button, with exactly the modifiers that must be held along with
+/// it: "g" does not fire while Shift is down, so "shift+g" can mean something else.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Binding {
+    pub input: Input,
+    pub ctrl: bool,
+    pub shift: bool,
+    pub alt: bool,
+}
+
+impl FromStr for Binding {
+    type Err = String;
+
+    /// Reads names such as "z", "ctrl+z" or "ctrl+shift+mouse_right", in any case.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let lower = s.to_ascii_lowercase();
+        let mut parts: Vec<&str> = lower.split('+').map(str::trim).collect();
+        let input = parts.pop().unwrap_or_default();
+        let input = INPUTS
+            .iter()
+            .find(|(name, _)| *name == input)
+            .map(|(_, input)| *input)
+            .ok_or_else(|| format!("unknown key {:?}", input))?;
+        let (mut ctrl, mut shift, mut alt) = (false, false, false);
+        for modifier in parts {
+            match modifier {
+                "ctrl" => ctrl = true,
+                "shift" => shift = true,
+                "alt" => alt = true,
+                other => return Err(format!("unknown modifier {:?}", other)),
+            }
+        }
+        Ok(Self {
+            input,
+            ctrl,
+            shift,
+            alt,
+        })
+    }
+}
+
+impl fmt::Display for Binding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (held, modifier) in [
+            (self.ctrl, "ctrl+"),
+            (self.shift, "shift+"),
+            (self.alt, "alt+"),
+        ] {
+            if held {
+                f.write_str(modifier)?;
+            }
+        }
+        let name = INPUTS
+            .iter()
+            .find(|(_, input)| *input == self.input)
+            .map_or("unknown", |(name, _)| name);
+        f.write_str(name)
+    }
+}
+
+/// The bindings of every action.
+#[derive(Debug, Clone)]
+pub struct Keymap {
+    bindings: HashMap<Action, Vec<Binding>>,
+}
+
+impl Default for Keymap {
+    fn default() -> Self {
+        let bindings = ACTIONS
+            .iter()
+            .map(|(action, _, defaults)| {
+                let defaults = defaults
+                    .iter()
+                    .map(|name| name.parse().expect("default bindings are valid"))
+                    .collect();
+                (*action, defaults)
+            })
+            .collect();
+        Self { bindings }
+    }
+}
+
+impl Keymap {
+    /// What calls for `action`; nothing if a keymap unbound it.
+    pub fn bindings(&self, action: Action) -> &[Binding] {
+        self.bindings.get(&action).map_or(&[], Vec::as_slice)
+    }
+
+    /// The defaults with the actions a keymap file names rebound, along with
+    /// each entry that was ignored and why. An entry is a key name or a list
+    /// of them; an empty list unbinds the action. A file that is not a JSON
+    /// object at all is an error.
+    pub fn from_json(json: &str) -> Result<(Self, Vec<String>), String> {
+        let entries: serde_json::Map<String, serde_json::Value> =
+            serde_json::from_str(json).map_err(|e| format!("not a keymap: {}", e))?;
+        let mut keymap = Self::default();
+        let mut problems = Vec::new();
+        for (name, value) in entries {
+            match rebinding(&name, value) {
+                Ok((action, bindings)) => {
+                    keymap.bindings.insert(action, bindings);
+                }
+                Err(e) => problems.push(format!("{}: {}", name, e)),
+            }
+        }
+        Ok((keymap, problems))
+    }
+
+    /// Reads a keymap file, as `from_json` does.
+    pub fn load(path: &Path) -> Result<(Self, Vec<String>), String> {
+        let json = fs::read_to_string(path)
+            .map_err(|e| format!("Cannot read {}: {}", path.display(), e))?;
+        Self::from_json(&json).map_err(|e| format!("{} is {}", path.display(), e))
+    }
+
+    /// Every action and its bindings, in a file `from_json` reads back.
+    pub fn to_json(&self) -> String {
+        let lines: Vec<String> = ACTIONS
+            .iter()
+            .map(|(action, name, _)| {
+                let names: Vec<String> = self
+                    .bindings(*action)
+                    .iter()
+                    .map(Binding::to_string)
+                    .collect();
+                let value = match names.as_slice() {
+                    [one] => serde_json::json!(one),
+                    _ => serde_json::json!(names),
+                };
+                format!("  {}: {}", serde_json::json!(name), value)
+            })
+            .collect();
+        format!("{{\n{}\n}}", lines.join(",\n"))
+    }
+}
+
+/// `KEYMAP_FILE` in the directory of the running binary.
+pub fn beside_binary() -> Option<PathBuf> {
+    let exe = std::env::current_exe().ok()?;
+    Some(exe.with_file_name(KEYMAP_FILE))
+}
+
+/// One entry of a keymap file read as the action it names and what calls for it.
+fn rebinding(name: &str, value: serde_json::Value) -> Result<(Action, Vec<Binding>), String> {
+    let action = name.parse()?;
+    let names = match value {
+        serde_json::Value::String(name) => vec![name],
+        serde_json::Value::Array(names) => names
+            .into_iter()
+            .map(|name| match name {
+                serde_json::Value::String(name) => Ok(name),
+                other => Err(format!("{} is not a key name", other)),
+            })
+            .collect::<Result<_, _>>()?,
+        other => {
+            return Err(format!(
+                "expects a key name or a list of them, got {}",
+                other
+            ));
+        }
+    };
+    let bindings = names
+        .iter()
+        .map(|name| name.parse())
+        .collect::<Result<_, String>>()?;
+    Ok((action, bindings))
+}
@@ -0,0 +1,134 @@
+use crate::view::View;
+use crossbeam_channel::Sender;
+use macroquad::prelude::*;
+use tcp_drawing::entity::{Canvas, Entity, EntityColor, next_id};
+use tcp_drawing::history::History;
+use tcp_drawing::network::Message;
+
+/// Longest label the text tool takes.
+const MAX_LABEL_LEN: usize = 200;
+
+/// The text tool: a click places a caret, typing builds the label there, and
+/// Enter puts it on the canvas. Nothing is sent before Enter.
+pub struct Labels {
+    typing: Option<Typing>,
+}
+
+struct Typing {
+    at: (f32, f32),
+    size: f32,
+    layer: u8,
+    text: String,
+}
+
+impl Labels {
+    pub fn new() -> Self {
+        Self { typing: None }
+    }
+
+    /// Whether keys go to a label rather than to the canvas and the chat.
+    pub fn is_typing(&self) -> bool {
+        self.typing.is_some()
+    }
+
+    /// Starts a label `size` pixels high at `at`, on `layer`.
+    pub fn place(&mut self, at: (f32, f32), size: f32, layer: u8) {
+        self.typing = Some(Typing {
+            at,
+            size,
+            layer,
+            text: String::new(),
+        });
+    }
+
+    /// Takes this frame's typed characters while a label is being typed.
+    /// Enter commits it, Escape drops it, and a `click` elsewhere commits it and
+    /// starts the next one there. Returns whether the keyboard was taken.
+    pub fn handle_input(
+        &mut self,
+        click: Option<(f32, f32)>,
+        color: EntityColor,
+        entities: &Canvas,
+        tx: &Sender<Message>,
+        history: &mut History,
+    ) -> bool {
+        let Some(typing) = &mut self.typing else {
+            return false;
+        };
+        let typed: Vec<char> = std::iter::from_fn(get_char_pressed).collect();
+        if is_key_pressed(KeyCode::Escape) {
+            self.typing = None;
+            return true;
+        }
+        if is_key_pressed(KeyCode::Backspace) {
+            typing.text.pop();
+        }
+        for c in typed {
+            if !c.is_control() && typing.text.chars().count() < MAX_LABEL_LEN {
+                typing.text.push(c);
+            }
+        }
+
+        if is_key_pressed(KeyCode::Enter) || click.is_some() {
+            let (size, layer) = (typing.size, typing.layer);
+            if let Some(typing) = self.typing.take() {
+                commit(typing, color, entities, tx, history);
+            }
+            if let Some(at) = click {
+                self.place(at, size, layer);
+            }
+        }
+        true
+    }
+
+    /// The label as it would land on the canvas, for drawing while it is typed.
+    pub fn preview(&self, color: EntityColor) -> Option<Entity> {
+        let typing = self.typing.as_ref()?;
+        Some(Entity::text(
+            0,
+            typing.at,
+            typing.text.clone(),
+            typing.size,
+            color,
+        ))
+    }
+
+    /// Draws the caret after the label being typed, in world coordinates, in `ink`.
+    pub fn render_caret(&self, view: &View, ink: Color) {
+        let Some(preview) = self.preview(EntityColor::BLACK) else {
+            return;
+        };
+        let bounds = preview.bounds();
+        let width = 1.5 / view.zoom();
+        draw_line(
+            bounds.right(),
+            bounds.y,
+            bounds.right(),
+            bounds.bottom(),
+            width,
+            ink,
+        );
+    }
+}
+
+/// Puts a typed label on the canvas and sends it, unless it is blank.
+fn commit(
+    typing: Typing,
+    color: EntityColor,
+    entities: &Canvas,
+    tx: &Sender<Message>,
+    history: &mut History,
+) {
+    if typing.text.trim().is_empty() {
+        return;
+    }
+    let label = Entity {
+        layer: typing.layer,
+        ..Entity::text(next_id(), typing.at, typing.text, typing.size, color)
+    };
+    entities.insert(label.clone());
+    history.record(label.id);
+    if let Err(e) = tx.send(Message::NewEntity(label)) {
+        eprintln!("Error sending label to network thread: {}", e);
+    }
+}
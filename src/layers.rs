@@ -0,0 +1,73 @@
+use crate::background;
+use crate::bindings;
+use macroquad::prelude::*;
+use tcp_drawing::entity::Entity;
+use tcp_drawing::keymap::Action;
+
+/// Layers the tabs offer. Anything on a layer past them, from a peer that
+/// has more, still draws, stacked by its layer, and cannot be hidden.
+pub const LAYERS: usize = 4;
+const TAB_WIDTH: f32 = 28.0;
+const TAB_HEIGHT: f32 = 22.0;
+const MARGIN: f32 = 16.0;
+
+/// Which layer new drawings go on, and which layers this window shows.
+/// Visibility is this window's alone; it is never sent to peers.
+pub struct Layers {
+    active: u8,
+    hidden: [bool; LAYERS],
+}
+
+impl Layers {
+    pub fn new() -> Self {
+        Self {
+            active: 0,
+            hidden: [false; LAYERS],
+        }
+    }
+
+    /// The layer new drawings go on.
+    pub fn active(&self) -> u8 {
+        self.active
+    }
+
+    /// Ctrl+1-4 draws on that layer, Ctrl+Shift+1-4 hides or shows it.
+    pub fn handle_input(&mut self) {
+        for layer in 0..LAYERS {
+            if bindings::pressed(Action::ToggleLayer(layer as u8)) {
+                self.hidden[layer] = !self.hidden[layer];
+            }
+            if bindings::pressed(Action::Layer(layer as u8)) {
+                self.active = layer as u8;
+            }
+        }
+    }
+
+    /// Whether `entity` is on a layer this window shows.
+    pub fn shows(&self, entity: &Entity) -> bool {
+        self.hidden
+            .get(entity.layer as usize)
+            .is_none_or(|hidden| !hidden)
+    }
+
+    /// Draws the tabs in the bottom-right corner, in screen space: the active
+    /// one outlined, hidden ones grayed out, in `ink`.
+    pub fn render(&self, ink: Color) {
+        let y = screen_height() - MARGIN - TAB_HEIGHT;
+        for layer in 0..LAYERS {
+            let x = screen_width() - MARGIN - (LAYERS - layer) as f32 * TAB_WIDTH;
+            let color = if self.hidden[layer] {
+                background::faint(ink, 0.3)
+            } else {
+                ink
+            };
+            if layer == self.active as usize {
+                draw_rectangle(x, y, TAB_WIDTH, TAB_HEIGHT, background::faint(ink, 0.08));
+                draw_rectangle_lines(x, y, TAB_WIDTH, TAB_HEIGHT, 2.0, ink);
+            } else {
+                draw_rectangle_lines(x, y, TAB_WIDTH, TAB_HEIGHT, 1.0, GRAY);
+            }
+            draw_text(&(layer + 1).to_string(), x + 10.0, y + 16.0, 18.0, color);
+        }
+    }
+}
@@ -0,0 +1,19 @@
+//! Everything but the window: the canvas model, the wire protocol and the
+//! server and client threads, so they can be driven and tested without macroquad
+//! opening a window.
+
+pub mod chat;
+pub mod clipboard;
+pub mod config;
+pub mod console;
+pub mod entity;
+pub mod export;
+pub mod fill;
+pub mod history;
+pub mod keymap;
+pub mod network;
+pub mod notice;
+pub mod recording;
+pub mod smooth;
+pub mod storage;
+pub mod transform;
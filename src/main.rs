@@ -1,149 +1,1883 @@
-mod entity;
-mod network;
+mod background;
+mod batch;
+mod bindings;
+mod eyedropper;
+mod grid;
+mod label;
+mod layers;
+mod mirror;
+mod picker;
+mod selection;
+mod touch;
+mod view;
 
-use crossbeam_channel::{Sender, unbounded};
+use batch::Batch;
+use crossbeam_channel::{Sender, bounded, never, select, tick, unbounded};
 use dashmap::DashMap;
-use entity::*;
+use eyedropper::Eyedropper;
+use grid::Grid;
+use label::Labels;
+use layers::Layers;
 use macroquad::prelude::*;
+use mirror::{MAX_REFLECTIONS, Mirror};
+use picker::Picker;
+use selection::Selection;
+use std::collections::HashMap;
 use std::env::args;
-use std::net::TcpListener;
+use std::net::{SocketAddr, TcpListener};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::Duration;
+use tcp_drawing::chat::{self, ChatLog};
+use tcp_drawing::config::{self, Command, Config, Role};
+use tcp_drawing::entity::*;
+use tcp_drawing::fill::{self, FillError};
+use tcp_drawing::history::{Change, History};
+use tcp_drawing::keymap::Action;
+use tcp_drawing::network::{self, CursorMap, Message, NetTotals, Permissions, RateMeter};
+use tcp_drawing::notice::Notices;
+use tcp_drawing::{console, export, recording, storage, transform};
+use touch::Touches;
+use view::View;
 
-#[macroquad::main("TCP-Drawing")]
-async fn main() {
-    let args: Vec<String> = args().collect();
-    let default_addr = "127.0.0.1:8090".to_string();
-    let addr = args.get(1).cloned().unwrap_or(default_addr);
+// minimum distance between recorded stroke points
+const STROKE_POINT_SPACING: f32 = 2.0;
 
-    let entities: Arc<DashMap<usize, Entity>> = Arc::new(DashMap::new());
-    let mut client_press_cooldown: f32 = 0f32;
-    let mut shape_size = 24f32;
+// baseline of a label below its top edge, as a share of the font size
+const TEXT_BASELINE: f32 = 0.75;
+
+// seconds between cursor updates while the mouse moves, and while it rests
+const CURSOR_SEND_INTERVAL: f32 = 0.05;
+const CURSOR_KEEPALIVE_INTERVAL: f32 = 1.0;
+
+/// How this window takes part in the session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Server,
+    Client,
+    Spectator,
+}
+
+/// What a left-button drag draws.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Tool {
+    Freehand,
+    Rect,
+    Line,
+    /// Not a drag: a click fills the enclosed area under the pointer.
+    Fill,
+    /// Not a drag: a click places a caret to type a label at.
+    Text,
+    /// Picks entities with a dragged rectangle and moves them.
+    Select,
+}
+
+/// What the pointer draws with this frame.
+#[derive(Debug, Clone, Copy)]
+struct Pen {
+    /// Pointer position in world coordinates.
+    position: (f32, f32),
+    size: f32,
+    /// None without a pressure reading, as from a mouse when nothing makes one up.
+    pressure: Option<f32>,
+    /// Stroke width at no and at full pressure, as shares of `size`.
+    pressure_range: (f32, f32),
+    tool: Tool,
+    /// Brush color from the palette or the picker, before `opacity`.
+    color: Color,
+    /// Alpha given to what the pen draws.
+    opacity: f32,
+    /// Which entities recoloring may touch; the server drops anything else.
+    permissions: Permissions,
+    /// Seconds to wait after adding a stroke point before adding the next.
+    draw_interval: f32,
+    /// Reflections drawn along with everything new.
+    mirror: Mirror,
+    /// Layer new drawings go on, and the only one recoloring touches.
+    layer: u8,
+}
+
+/// Ids of a stroke's reflections, with the transforms that make them.
+type Reflections = [Option<(usize, transform::Transform)>; MAX_REFLECTIONS];
+
+/// What the left button is in the middle of drawing.
+#[derive(Debug, Clone, Copy)]
+enum Drawing {
+    /// A stroke, on the canvas and sent to peers as it grows, with the ids of
+    /// its reflections and the transforms that make them.
+    Stroke { id: usize, reflections: Reflections },
+    /// A rectangle or line, only a preview until the button comes up.
+    Shape {
+        tool: Tool,
+        id: usize,
+        from: (f32, f32),
+    },
+}
 
-    let (mut tx, rx) = unbounded::<Entity>();
+// seconds between status lines in headless mode
+const HEADLESS_STATUS_INTERVAL: u64 = 5;
 
-    let (is_server, client_list) = match TcpListener::bind(&addr) {
-        Ok(listener) => {
-            println!("Running as server on {}", &addr);
-            let clients = network::start_server(listener, entities.clone(), rx);
-            (true, Some(clients))
+// brush radius range for the mouse wheel, and the factor one notch changes it by
+const MIN_BRUSH_SIZE: f32 = 2.0;
+const MAX_BRUSH_SIZE: f32 = 128.0;
+const BRUSH_SIZE_STEP: f32 = 1.1;
+
+// brush opacity range for the +/- keys, and how much one press changes it
+const MIN_OPACITY: f32 = 0.1;
+const OPACITY_STEP: f32 = 0.1;
+
+// seconds a tapered stroke takes to thin out to its narrowest
+const TAPER_SECONDS: f64 = 2.0;
+
+// seconds the arrow keys skip in a replay
+const REPLAY_SKIP: f64 = 5.0;
+
+fn main() {
+    let args: Vec<String> = args().skip(1).collect();
+    let config = match config::parse_args(&args) {
+        Ok(Command::Run(config)) => *config,
+        Ok(Command::Help) => {
+            println!("{}", config::USAGE);
+            return;
+        }
+        Ok(Command::DumpKeymap(path)) => {
+            println!("{}", bindings::load(path.as_deref()).to_json());
+            return;
+        }
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, config::USAGE);
+            std::process::exit(2);
         }
-        Err(_) => {
-            println!("Running as client, connecting to {}", &addr);
-            let (client_tx, _client_rx) = unbounded::<Entity>();
-            network::start_client(entities.clone(), client_tx.clone(), addr.clone());
-            tx = client_tx;
-            (false, None)
+    };
+
+    if let Some(path) = config.replay_path {
+        macroquad::Window::new("TCP-Drawing replay", run_replay(path));
+    } else if config.bots.is_some() {
+        run_bots(&config);
+    } else if config.headless {
+        run_headless(config);
+    } else {
+        bindings::init(bindings::load(config.keymap_path.as_deref()));
+        macroquad::Window::new("TCP-Drawing", run_windowed(config));
+    }
+}
+
+/// Relay server with no window: serves and relays exactly like the windowed
+/// server and prints a status line now and then until Ctrl+C.
+fn run_headless(config: Config) {
+    let addrs = match &config.role {
+        Role::Listen(addrs) => addrs.clone(),
+        other => vec![other.addr().to_string()],
+    };
+    let listeners = match network::bind_all(&addrs) {
+        Ok(listeners) => listeners,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
         }
     };
+    println!("Running as headless server on {}", listening_on(&listeners));
+
+    let entities = Arc::new(Canvas::new());
+    let cursors: CursorMap = Arc::new(DashMap::new());
+    let (tx, rx) = unbounded::<Message>();
+    let chat_log: ChatLog = Default::default();
+    let server = network::start_server(
+        listeners,
+        entities.clone(),
+        rx,
+        cursors.clone(),
+        chat_log,
+        &config,
+    );
+
+    if let Some(path) = &config.load_path {
+        load_into_canvas(path, &entities, &tx);
+    }
+
+    let (stop_tx, stop_rx) = bounded::<()>(1);
+    if let Err(e) = ctrlc::set_handler(move || {
+        let _ = stop_tx.try_send(());
+    }) {
+        eprintln!("Error installing Ctrl+C handler: {}", e);
+    }
 
+    println!("{}", console::HELP);
+    let mut commands = console::spawn_reader();
+
+    let status_tick = tick(Duration::from_secs(HEADLESS_STATUS_INTERVAL));
     loop {
-        handle_input(
-            &entities,
-            &tx,
-            is_server,
-            &mut client_press_cooldown,
-            &mut shape_size,
+        select! {
+            recv(stop_rx) -> _ => break,
+            recv(commands) -> line => match line {
+                Ok(line) => {
+                    if let Some(transform) = console::execute(&line, &server) {
+                        send_transform(transform, &tx);
+                    }
+                }
+                // stdin closed
+                Err(_) => commands = never(),
+            },
+            recv(status_tick) -> _ => {
+                cursors.retain(|_, cursor| cursor.last_seen.elapsed() < network::CURSOR_TIMEOUT);
+                let (client_count, waiting) = server
+                    .clients
+                    .lock()
+                    .map(|clients| {
+                        let waiting = clients.iter().filter(|client| client.pending).count();
+                        (clients.len() - waiting, waiting)
+                    })
+                    .unwrap_or((0, 0));
+                if waiting > 0 {
+                    println!(
+                        "{} clients ({} waiting for approval), {} entities",
+                        client_count,
+                        waiting,
+                        entities.len()
+                    );
+                } else {
+                    println!("{} clients, {} entities", client_count, entities.len());
+                }
+                let rooms = server.room_summary();
+                if rooms.len() > 1 {
+                    for (room, entity_count, client_count) in rooms {
+                        println!("  #{}: {} clients, {} entities", room, client_count, entity_count);
+                    }
+                }
+            }
+        }
+    }
+
+    // dropping the sender is what stops the server thread
+    drop(tx);
+    server.join();
+}
+
+/// Load-testing bots with no window: they draw until Ctrl+C, printing how
+/// they fare now and then and once more when they stop.
+fn run_bots(config: &Config) {
+    println!("Connecting bots to {}", config.role.addr());
+    let bots = network::bots::start_bots(config);
+
+    let (stop_tx, stop_rx) = bounded::<()>(1);
+    if let Err(e) = ctrlc::set_handler(move || {
+        let _ = stop_tx.try_send(());
+    }) {
+        eprintln!("Error installing Ctrl+C handler: {}", e);
+    }
+
+    let status_tick = tick(Duration::from_secs(HEADLESS_STATUS_INTERVAL));
+    loop {
+        select! {
+            recv(stop_rx) -> _ => break,
+            recv(status_tick) -> _ => println!("{}", bots.report()),
+        }
+    }
+    println!("Stopped: {}", bots.stop());
+}
+
+/// Plays a recording back in a window of its own, without any network.
+async fn run_replay(path: PathBuf) {
+    let mut replay = match recording::Replay::load(&path) {
+        Ok(replay) => replay,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    println!("Replaying {} ({:.1}s)", path.display(), replay.duration());
+    let canvas = Canvas::new();
+    let mut batch = Batch::new(false);
+    let mut view = View::new();
+
+    while !is_key_pressed(KeyCode::Escape) {
+        if is_key_pressed(KeyCode::Space) {
+            replay.paused = !replay.paused;
+        }
+        if is_key_pressed(KeyCode::Right) {
+            replay.seek(replay.position() + REPLAY_SKIP, &canvas);
+        }
+        if is_key_pressed(KeyCode::Left) {
+            replay.seek(replay.position() - REPLAY_SKIP, &canvas);
+        }
+        if is_key_pressed(KeyCode::Up) {
+            replay.change_speed(2.0);
+        }
+        if is_key_pressed(KeyCode::Down) {
+            replay.change_speed(0.5);
+        }
+        if is_key_pressed(KeyCode::K) {
+            batch.toggle_smoothing();
+        }
+        replay.advance(get_frame_time() as f64, &canvas);
+
+        clear_background(WHITE);
+        view.fit_to_window();
+        set_camera(&view.camera());
+        view.render_margins(BLACK);
+        render_entities(&canvas, &view.visible(), None, &Layers::new(), &mut batch);
+        set_default_camera();
+        draw_text("REPLAY", 32f32, 32f32, 22f32, BLACK);
+        let status = format!(
+            "{:.1}s / {:.1}s at {}x{}",
+            replay.position(),
+            replay.duration(),
+            replay.speed,
+            if replay.paused { ", paused" } else { "" }
         );
+        draw_text(&status, 32f32, 54f32, 16f32, BLACK);
+        draw_text(
+            "Space: pause, Left/Right: skip 5s, Up/Down: speed",
+            32f32,
+            74f32,
+            16f32,
+            GRAY,
+        );
+        next_frame().await;
+    }
+}
+
+async fn run_windowed(config: Config) {
+    if config.draw_interval != config::DEFAULT_DRAW_INTERVAL {
+        println!(
+            "Adding stroke points at most every {} ms",
+            config.draw_interval.as_millis()
+        );
+    }
+    let entities = Arc::new(Canvas::new());
+    let mut client_press_cooldown: f32 = 0f32;
+    let mut shape_size = 24f32;
+    let mut opacity = 1f32;
+    let mut tool = Tool::Freehand;
+    let mut drawing: Option<Drawing> = None;
+    let mut stroke_started_at = 0f64;
+    let mut history = History::new();
+    let cursors: CursorMap = Arc::new(DashMap::new());
+    let mut since_cursor_sent: f32 = 0f32;
+    let mut last_cursor_sent: (f32, f32) = (0f32, 0f32);
+    let chat_log: ChatLog = Default::default();
+    let mut chat_input: Option<String> = None;
+    // another room the server window looks at instead of its own
+    let mut viewed_room: Option<String> = None;
+    // traffic rates, toggled with F3
+    let mut net_overlay: Option<NetOverlay> = None;
+    let fills = Fills::new();
+    let mut labels = Labels::new();
+    let mut selection = Selection::new();
+    let mut picker = Picker::new();
+    let mut eyedropper = Eyedropper::new();
+    let mut mirror = Mirror::new();
+    let mut layers = Layers::new();
+    let mut batch = Batch::new(config.smooth_strokes);
+    let mut touches = Touches::new();
+
+    let (tx, rx) = unbounded::<Message>();
+
+    let listeners = match &config.role {
+        Role::Listen(addrs) => match network::bind_all(addrs) {
+            Ok(listeners) => Some(listeners),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        },
+        Role::Connect(_) => None,
+        Role::Auto(addr) => network::bind_all(std::slice::from_ref(addr)).ok(),
+    };
+
+    let (server, client) = match listeners {
+        Some(listeners) => {
+            println!("Running as server on {}", listening_on(&listeners));
+            let server = network::start_server(
+                listeners,
+                entities.clone(),
+                rx,
+                cursors.clone(),
+                chat_log.clone(),
+                &config,
+            );
+            (Some(server), None)
+        }
+        None => {
+            println!("Running as client, connecting to {}", config.role.addr());
+            let client = network::start_client(
+                entities.clone(),
+                rx,
+                cursors.clone(),
+                chat_log.clone(),
+                &config,
+            );
+            (None, Some(client))
+        }
+    };
+    let mode = match (&server, config.spectate) {
+        (Some(_), _) => Mode::Server,
+        (None, false) => Mode::Client,
+        (None, true) => Mode::Spectator,
+    };
+    let is_server = mode == Mode::Server;
+
+    let commands = if is_server {
+        println!("{}", console::HELP);
+        console::spawn_reader()
+    } else {
+        never()
+    };
+
+    // red for the server; clients start green and take the color the server assigns
+    let mut color_index: usize = if is_server { 0 } else { 1 };
+    // until the user picks a color by hand
+    let mut follow_assigned_color = !is_server;
+
+    if let Some(path) = &config.load_path {
+        if mode == Mode::Spectator {
+            eprintln!("Spectators cannot load a canvas; ignoring --load");
+        } else {
+            load_into_canvas(path, &entities, &tx);
+        }
+    }
+    let save_path = config.save_path.as_path();
+    let load_path = config.load_path.as_deref();
+
+    let no_cursors = DashMap::new();
+    let mut view = View::new();
+    let mut grid = Grid::new();
+
+    // closing the window sets is_quit_requested instead of ending the process
+    prevent_quit();
+
+    // Escape cancels typing, a shape being dragged out or a paste rather than quitting
+    while !is_quit_requested()
+        && (chat_input.is_some()
+            || labels.is_typing()
+            || matches!(drawing, Some(Drawing::Shape { .. }))
+            || selection.pasting().is_some()
+            || !is_key_pressed(KeyCode::Escape))
+    {
+        // nothing but the chat box or the label being typed sees the keys meanwhile
+        let keys_free = chat_input.is_none() && !labels.is_typing();
+        if let Some(server) = &server {
+            for line in commands.try_iter() {
+                if let Some(transform) = console::execute(&line, server) {
+                    history.record_transform(transform);
+                    send_transform(transform, &tx);
+                }
+            }
+        }
+
+        // a click on a client list button is not the start of a stroke
+        let clicked_client_button = server.as_ref().is_some_and(handle_client_buttons);
+
+        if let Some(server) = &server
+            && keys_free
+            && bindings::pressed(Action::NextRoom)
+        {
+            viewed_room = next_room(server, viewed_room.as_deref());
+        }
+        let viewed_canvas = match (&server, &viewed_room) {
+            (Some(server), Some(room)) => server.rooms.get(room).map(|c| c.value().clone()),
+            _ => None,
+        };
+        if keys_free && bindings::pressed(Action::NetStats) {
+            net_overlay = match net_overlay {
+                Some(_) => None,
+                None => Some(NetOverlay::default()),
+            };
+        }
+        if keys_free {
+            grid.handle_input();
+            layers.handle_input();
+        }
+        if viewed_canvas.is_none() {
+            // back home when the viewed room closes
+            viewed_room = None;
+        }
+        let canvas: &Canvas = viewed_canvas.as_deref().unwrap_or(&entities);
+        // the other rooms the host looks into keep the default
+        let background = match (&server, &client) {
+            (Some(server), _) if viewed_canvas.is_none() => {
+                EntityColor::from_bits(server.background.load(Ordering::Relaxed))
+            }
+            (_, Some(client)) => EntityColor::from_bits(client.background.load(Ordering::Relaxed)),
+            _ => network::DEFAULT_BACKGROUND,
+        };
+        let ink = background::ink(background);
+        let panning = keys_free && view.handle_input();
+        let panning = touches.track(&mut view) || panning;
+        if keys_free && bindings::pressed(Action::Smoothing) {
+            batch.toggle_smoothing();
+        }
+
+        if follow_assigned_color
+            && let Some(index) = client.as_ref().and_then(assigned_palette_index)
+        {
+            color_index = index;
+        }
+        // a press on the color picker, and the drag after it, never reach the
+        // canvas; nor does a click while I is held, which picks a color up instead
+        let picker_took_mouse = mode != Mode::Spectator
+            && (picker.handle_input(keys_free)
+                || eyedropper.handle_input(
+                    keys_free,
+                    canvas,
+                    &view,
+                    &layers,
+                    &mut picker,
+                    &mut opacity,
+                ));
+        if picker.color().is_some() {
+            follow_assigned_color = false;
+        }
+
+        if mode == Mode::Spectator || viewed_canvas.is_some() {
+            // view only: saving and exporting are fine, anything that changes the canvas is not
+            if handle_file_shortcuts(canvas, background, &tx, save_path, None) {
+                export_canvas(canvas, &view, &layers, background, &mut batch);
+            }
+        } else {
+            let label_color =
+                EntityColor::from(with_opacity(brush_color(&picker, color_index), opacity));
+            let click = (is_mouse_button_pressed(MouseButton::Left)
+                && !clicked_client_button
+                && !picker_took_mouse)
+                .then(|| grid.snap(view.mouse()));
+            // typed keys belong to the label or the chat box, not the canvas
+            if !labels.handle_input(click, label_color, &entities, &tx, &mut history)
+                && !handle_chat_input(&mut chat_input, &chat_log, &tx, &config.name)
+                && !clicked_client_button
+                && !picker_took_mouse
+            {
+                if select_palette_color(&mut color_index) {
+                    picker.release();
+                    follow_assigned_color = false;
+                }
+                // Ctrl and the wheel zoom instead
+                if !ctrl_down() {
+                    adjust_brush_size(&mut shape_size);
+                }
+                select_tool(&mut tool);
+                if tool != Tool::Select {
+                    selection.clear();
+                }
+                adjust_opacity(&mut opacity);
+                mirror.handle_input(&view);
+                // the background is the host's to change
+                if is_server && bindings::pressed(Action::Background) {
+                    send_background(background::next(background), &tx);
+                }
+                if is_mouse_button_pressed(MouseButton::Left) {
+                    stroke_started_at = get_time();
+                }
+                let pen = Pen {
+                    position: grid.snap(view.mouse()),
+                    size: shape_size,
+                    pressure: pen_pressure(&config, get_time() - stroke_started_at),
+                    pressure_range: config.pressure_range,
+                    tool,
+                    color: brush_color(&picker, color_index),
+                    opacity,
+                    permissions: client.as_ref().map_or(Permissions::HOST, permissions),
+                    draw_interval: config.draw_interval.as_secs_f32(),
+                    mirror,
+                    layer: layers.active(),
+                };
+                if tool == Tool::Fill
+                    && is_mouse_button_pressed(MouseButton::Left)
+                    && !alt_down()
+                    && !panning
+                {
+                    let color = with_opacity(pen.color, opacity);
+                    fills.start(
+                        &entities,
+                        pen.position,
+                        config.fill_resolution,
+                        EntityColor::from(color),
+                        pen.layer,
+                    );
+                }
+                if tool == Tool::Text
+                    && is_mouse_button_pressed(MouseButton::Left)
+                    && !alt_down()
+                    && !panning
+                {
+                    labels.place(pen.position, pen.size, pen.layer);
+                }
+                if tool == Tool::Select && !panning {
+                    selection.handle_input(
+                        pen.position,
+                        &entities,
+                        &view,
+                        &layers,
+                        pen.permissions,
+                        &tx,
+                    );
+                }
+                // a pan drag is not a stroke, though a stroke already going carries on
+                if !panning || drawing.is_some() {
+                    handle_input(
+                        &entities,
+                        &tx,
+                        &mut client_press_cooldown,
+                        pen,
+                        &mut drawing,
+                        &mut history,
+                    );
+                }
+                touches.draw(
+                    &entities,
+                    &tx,
+                    &mut history,
+                    pen,
+                    |at| grid.snap(view.to_world(at).into()),
+                    |held| pen_pressure(&config, held),
+                );
+                let load_path = Some(load_path.unwrap_or(save_path));
+                if handle_file_shortcuts(&entities, background, &tx, save_path, load_path) {
+                    export_canvas(&entities, &view, &layers, background, &mut batch);
+                }
+            }
+            fills.collect(&entities, &tx, &mut history);
+            share_cursor(
+                macroquad::time::get_frame_time(),
+                view.to_world(touches.pointer()).into(),
+                &mut since_cursor_sent,
+                &mut last_cursor_sent,
+                brush_color(&picker, color_index),
+                &tx,
+            );
+        }
         process(
             macroquad::time::get_frame_time(),
             &mut client_press_cooldown,
             &entities,
+            &cursors,
         );
-        render(&entities, is_server, shape_size, client_list.as_ref()).await;
+        clear_background(Color::from(background));
+        set_camera(&view.camera());
+        view.render_margins(ink);
+        grid.render(&view, ink);
+        let fade = match &client {
+            Some(client) => client.fade.lock().ok().and_then(|fade| *fade),
+            None => config.fade,
+        };
+        render_entities(canvas, &view.visible(), fade, &layers, &mut batch);
+        if let Some(preview) = drawing.and_then(|drawing| {
+            let color = with_opacity(brush_color(&picker, color_index), opacity);
+            dragged_shape(drawing, view.mouse(), shape_size, color)
+        }) {
+            render_entity(&preview, 1.0, &mut batch);
+            for image in mirror.images(&preview) {
+                render_entity(&image, 1.0, &mut batch);
+            }
+        }
+        let label_color =
+            EntityColor::from(with_opacity(brush_color(&picker, color_index), opacity));
+        if let Some(preview) = labels.preview(label_color) {
+            render_entity(&preview, 1.0, &mut batch);
+        }
+        batch.flush();
+        labels.render_caret(&view, ink);
+        if tool == Tool::Select {
+            let at = grid.snap(view.mouse());
+            if let Some(clipboard) = selection.pasting() {
+                render_ghost(clipboard.entities(), at, &view, &mut batch);
+            }
+            selection.render(at, &entities, &view);
+        }
+        if mode != Mode::Spectator && viewed_canvas.is_none() {
+            mirror.render(&view, ink);
+        }
+        set_default_camera();
+        render_cursors(
+            // cursors belong to the host's own room
+            if viewed_canvas.is_some() {
+                &no_cursors
+            } else {
+                &cursors
+            },
+            &view,
+        );
+        // a client that gave up shares nothing, so it shows no brush either
+        let gave_up = client
+            .as_ref()
+            .is_some_and(|client| client.status.lock().is_ok_and(|status| status.gave_up()));
+        if mode != Mode::Spectator && !gave_up {
+            render_brush(
+                touches.pointer(),
+                shape_size,
+                shape_size * view.zoom(),
+                tool,
+                with_opacity(brush_color(&picker, color_index), opacity),
+                background,
+            );
+        }
+        render(
+            mode,
+            server.as_ref().map(|server| &server.clients),
+            client.as_ref(),
+            ink,
+        );
+        picker.render();
+        layers.render(ink);
+        eyedropper.render(ink);
+        if let Some(server) = &server {
+            render_room_status(server, viewed_room.as_deref().unwrap_or(&server.room), ink);
+        }
+        if let Some(overlay) = &mut net_overlay {
+            render_net_stats(
+                overlay,
+                server.as_ref().map(|server| &server.clients),
+                client.as_ref(),
+            );
+        }
+        render_chat(&chat_log, chat_input.as_deref(), ink);
+        if let Some(client) = &client
+            && let Ok(status) = client.status.lock()
+        {
+            render_connection_banner(&status, config.role.addr());
+        }
+        if let Some(notices) = server
+            .as_ref()
+            .map(|server| &server.notices)
+            .or(client.as_ref().map(|client| &client.notices))
+        {
+            render_notices(notices);
+        }
+        next_frame().await;
+    }
+
+    // Say goodbye, then drop the sender so the network threads wind down.
+    if mode == Mode::Client
+        && let Err(e) = tx.send(Message::Disconnect)
+    {
+        eprintln!("Error sending disconnect to network thread: {}", e);
+    }
+    drop(tx);
+    if let Some(server) = server {
+        server.join();
+    }
+    if let Some(client) = client {
+        client.join();
     }
 }
 
-fn process(delta: f32, cooldown_press: &mut f32, _entities: &DashMap<usize, Entity>) {
+fn process(
+    delta: f32,
+    cooldown_press: &mut f32,
+    _entities: &Canvas,
+    cursors: &DashMap<u64, network::RemoteCursor>,
+) {
     // cooldown
     *cooldown_press -= delta;
     if *cooldown_press < 0.0 {
         *cooldown_press = 0.0;
     }
+
+    // peers that left or went quiet
+    cursors.retain(|_, cursor| cursor.last_seen.elapsed() < network::CURSOR_TIMEOUT);
+}
+
+/// Sends our pointer position when it moved, throttled, and otherwise as a
+/// keepalive so peers do not expire it.
+fn share_cursor(
+    delta: f32,
+    (x, y): (f32, f32),
+    since_sent: &mut f32,
+    last_sent: &mut (f32, f32),
+    brush_color: Color,
+    tx: &Sender<Message>,
+) {
+    *since_sent += delta;
+    let moved = (x, y) != *last_sent;
+
+    if (moved && *since_sent >= CURSOR_SEND_INTERVAL) || *since_sent >= CURSOR_KEEPALIVE_INTERVAL {
+        *since_sent = 0.0;
+        *last_sent = (x, y);
+        let message = Message::Cursor {
+            peer_id: instance_tag(),
+            x,
+            y,
+            color: EntityColor::from(brush_color),
+        };
+        if let Err(e) = tx.send(message) {
+            eprintln!("Error sending cursor to network thread: {}", e);
+        }
+    }
 }
 
 fn handle_input(
-    entities: &DashMap<usize, Entity>,
-    tx: &Sender<Entity>,
-    is_server: bool,
+    entities: &Canvas,
+    tx: &Sender<Message>,
     client_press_cooldown: &mut f32,
-    shape_size: &mut f32,
+    pen: Pen,
+    drawing: &mut Option<Drawing>,
+    history: &mut History,
 ) {
-    if is_mouse_button_released(MouseButton::Left) {
-        *shape_size = 32f32;
+    if bindings::pressed(Action::ClearCanvas) {
+        entities.clear();
+        history.clear();
+        if let Err(e) = tx.send(Message::ClearCanvas { epoch: 0 }) {
+            eprintln!("Error sending clear to network thread: {}", e);
+        }
     }
 
-    if is_mouse_button_down(MouseButton::Left) && *client_press_cooldown <= 0.0 {
-        *client_press_cooldown = 0.005f32;
-        let (x, y) = mouse_position();
-        let id = Entity::spawn(
-            x,
-            y,
-            shape_size.clone(),
-            color_to_hex(if is_server { RED } else { GREEN }),
-            entities,
-        );
-        *shape_size -= 0.5f32;
-        if *shape_size < 4f32 {
-            *shape_size = 4f32;
+    if bindings::pressed(Action::Undo)
+        && let Some(change) = history.undo(entities)
+    {
+        send_change(change, tx);
+    }
+
+    if bindings::pressed(Action::Redo)
+        && let Some(change) = history.redo(entities)
+    {
+        send_change(change, tx);
+    }
+
+    // nothing of a shape has gone out before the button comes up
+    if is_key_pressed(KeyCode::Escape) && matches!(drawing, Some(Drawing::Shape { .. })) {
+        *drawing = None;
+    }
+
+    let pressed = is_mouse_button_pressed(MouseButton::Left) && !alt_down();
+    // Shift makes a freehand drag a straight line
+    let shape_tool = match pen.tool {
+        Tool::Freehand if shift_down() => Some(Tool::Line),
+        Tool::Rect | Tool::Line => Some(pen.tool),
+        Tool::Freehand | Tool::Fill | Tool::Text | Tool::Select => None,
+    };
+    if pressed && let Some(tool) = shape_tool {
+        *drawing = Some(Drawing::Shape {
+            tool,
+            id: next_id(),
+            from: pen.position,
+        });
+    } else if pressed && pen.tool == Tool::Freehand {
+        if let Some(stroke) = start_stroke(entities, tx, &pen, history) {
+            *drawing = Some(stroke);
+        }
+    } else if is_mouse_button_down(MouseButton::Left)
+        && *client_press_cooldown <= 0.0
+        && let Some(Drawing::Stroke { id, reflections }) = *drawing
+    {
+        *client_press_cooldown = pen.draw_interval;
+        if !extend_stroke(entities, tx, &pen, id, reflections) {
+            // the canvas was cleared mid-stroke
+            *drawing = None;
         }
+    } else if is_mouse_button_down(MouseButton::Left) && alt_down() && drawing.is_none() {
+        let color = with_opacity(pen.color, pen.opacity);
+        recolor_under_cursor(entities, tx, pen, EntityColor::from(color));
+    }
 
-        if let Some(id) = id {
-            if let Some(entity) = entities.get(&id) {
-                let entity_clone = entity.value().clone();
-                if let Err(e) = tx.send(entity_clone) {
-                    eprintln!("Error sending entity to network thread: {}", e);
-                }
+    if !is_mouse_button_released(MouseButton::Left) {
+        return;
+    }
+    match drawing.take() {
+        Some(Drawing::Stroke { id, reflections }) => finish_stroke(entities, tx, id, reflections),
+        Some(shape) => {
+            let color = with_opacity(pen.color, pen.opacity);
+            let shape = dragged_shape(shape, pen.position, pen.size, color).map(|shape| Entity {
+                layer: pen.layer,
+                ..shape
+            });
+            let copies = shape.iter().flat_map(|shape| {
+                let reflections = pen.mirror.reflections().into_iter();
+                reflections.map(|reflection| mirror::reflected_copy(shape, reflection))
+            });
+            let finished: Vec<Entity> = copies.chain(shape.clone()).collect();
+            for entity in finished {
+                entities.insert(entity.clone());
+                history.record(entity.id);
+                send_entity(entity, tx);
+            }
+        }
+        None => {}
+    }
+}
+
+/// Starts a stroke, and its reflections, where the pen is, announcing it
+/// right away so peers see it grow. None if the canvas had no room for it.
+fn start_stroke(
+    entities: &Canvas,
+    tx: &Sender<Message>,
+    pen: &Pen,
+    history: &mut History,
+) -> Option<Drawing> {
+    let (x, y) = pen.position;
+    let color = EntityColor::from(with_opacity(pen.color, pen.opacity));
+    let id = Entity::spawn(x, y, pen.size, color, entities)?;
+    let entity_clone = entities.update(id, |entity| {
+        entity.set_pen(PenSamples::single(pen.pressure));
+        entity.layer = pen.layer;
+        // the widths go out with the stroke, so peers draw them the same
+        if pen.pressure.is_some() {
+            let (thinnest, widest) = pen.pressure_range;
+            entity.set_pressure_range(pen.size * widest, thinnest / widest);
+        }
+        entity.clone()
+    })?;
+    history.record(id);
+    let mut reflections = [None; MAX_REFLECTIONS];
+    for (slot, reflection) in reflections.iter_mut().zip(pen.mirror.reflections()) {
+        let copy = mirror::reflected_copy(&entity_clone, reflection);
+        *slot = Some((copy.id, reflection));
+        entities.insert(copy.clone());
+        history.record(copy.id);
+        send_entity(copy, tx);
+    }
+    send_entity(entity_clone, tx);
+    Some(Drawing::Stroke { id, reflections })
+}
+
+/// Extends stroke `id`, and its reflections, to where the pen is, unless it
+/// has hardly moved. False once the stroke is gone, as after a clear.
+fn extend_stroke(
+    entities: &Canvas,
+    tx: &Sender<Message>,
+    pen: &Pen,
+    id: usize,
+    reflections: Reflections,
+) -> bool {
+    let (x, y) = pen.position;
+    let Some(appended) = entities.update(id, |entity| {
+        let (last_x, last_y) = entity.last_point();
+        if Vec2::new(x - last_x, y - last_y).length() < STROKE_POINT_SPACING {
+            return None;
+        }
+        // filled in along the way, so erasing and recoloring find no gaps
+        let spacing = (entity.radius * 0.5).max(STROKE_POINT_SPACING);
+        let (points, samples) = entity.points_toward((x, y), pen.pressure, spacing);
+        entity
+            .extend_stroke(&points, &samples)
+            .then_some((points, samples))
+    }) else {
+        return false;
+    };
+
+    if let Some((points, samples)) = appended {
+        // the reflections grow by the same points, mirrored
+        for (mirror_id, reflection) in reflections.into_iter().flatten() {
+            let mut mirrored = points.clone();
+            reflection.apply_all(&mut mirrored);
+            if entities.update(mirror_id, |entity| {
+                entity.extend_stroke(&mirrored, &samples)
+            }) == Some(true)
+            {
+                send_stroke_points(mirror_id, mirrored, samples.clone(), tx);
+            }
+        }
+        send_stroke_points(id, points, samples, tx);
+    }
+    true
+}
+
+/// Sends the finished stroke and its reflections out whole, so every peer
+/// ends up with the same points.
+fn finish_stroke(entities: &Canvas, tx: &Sender<Message>, id: usize, reflections: Reflections) {
+    let ids = std::iter::once(id).chain(reflections.into_iter().flatten().map(|(id, _)| id));
+    for id in ids {
+        if let Some(entity) = entities.get(id).map(|entity| entity.value().clone()) {
+            send_entity(entity, tx);
+        }
+    }
+}
+
+fn send_entity(entity: Entity, tx: &Sender<Message>) {
+    if let Err(e) = tx.send(Message::NewEntity(entity)) {
+        eprintln!("Error sending entity to network thread: {}", e);
+    }
+}
+
+fn send_stroke_points(id: usize, points: Vec<(f32, f32)>, pen: PenSamples, tx: &Sender<Message>) {
+    let message = Message::StrokeAppend {
+        id,
+        points,
+        pen,
+        generation: 0,
+    };
+    if let Err(e) = tx.send(message) {
+        eprintln!("Error sending stroke points to network thread: {}", e);
+    }
+}
+
+/// The rectangle or line being dragged out, as it stands with the pointer at
+/// `to`. None for a stroke, or a shape not dragged anywhere yet.
+fn dragged_shape(drawing: Drawing, to: (f32, f32), size: f32, color: Color) -> Option<Entity> {
+    let Drawing::Shape { tool, id, from } = drawing else {
+        return None;
+    };
+    if from == to {
+        return None;
+    }
+    let color = EntityColor::from(color);
+    match tool {
+        Tool::Rect => Some(Entity::rect(id, from, to, color)),
+        // as wide as a stroke with the same brush
+        Tool::Line => Some(Entity::line(
+            id,
+            from,
+            line_end(from, to),
+            size * 2.0,
+            color,
+        )),
+        Tool::Freehand | Tool::Fill | Tool::Text | Tool::Select => None,
+    }
+}
+
+/// Where a line dragged from `from` to `to` ends: at `to`, or with Ctrl down
+/// as far out along the nearest multiple of 45°.
+fn line_end(from: (f32, f32), to: (f32, f32)) -> (f32, f32) {
+    if !ctrl_down() {
+        return to;
+    }
+    let (dx, dy) = (to.0 - from.0, to.1 - from.1);
+    let step = std::f32::consts::FRAC_PI_4;
+    let angle = (dy.atan2(dx) / step).round() * step;
+    let length = dx.hypot(dy);
+    (from.0 + length * angle.cos(), from.1 + length * angle.sin())
+}
+
+/// Passes an undo or redo on to the network thread.
+fn send_change(change: Change, tx: &Sender<Message>) {
+    let message = match change {
+        Change::Removed(id) => Message::RemoveEntity { id },
+        Change::Restored(entity) => Message::NewEntity(entity),
+        Change::Transformed(transform) => {
+            send_transform(transform, tx);
+            return;
+        }
+    };
+    if let Err(e) = tx.send(message) {
+        eprintln!("Error sending undo or redo to network thread: {}", e);
+    }
+}
+
+/// Hands a new background to the server thread, which keeps it with the
+/// canvas and tells every client in the host's room.
+fn send_background(color: EntityColor, tx: &Sender<Message>) {
+    if let Err(e) = tx.send(Message::SetBackground(color)) {
+        eprintln!("Error sending background to network thread: {}", e);
+    }
+}
+
+/// Hands a canvas transform to the server thread, which applies it to the
+/// canvas and to every client's.
+fn send_transform(transform: transform::Transform, tx: &Sender<Message>) {
+    let message = Message::TransformAll {
+        transform,
+        generation: 0,
+    };
+    if let Err(e) = tx.send(message) {
+        eprintln!("Error sending transform to network thread: {}", e);
+    }
+}
+
+/// Opens the chat box on Enter, edits it while open and sends it on the next
+/// Enter. Returns whether the chat box has the keyboard this frame.
+fn handle_chat_input(
+    chat_input: &mut Option<String>,
+    chat_log: &ChatLog,
+    tx: &Sender<Message>,
+    name: &str,
+) -> bool {
+    // drain the queue every frame so keys typed while drawing don't show up later
+    let typed: Vec<char> = std::iter::from_fn(get_char_pressed).collect();
+
+    let Some(input) = chat_input else {
+        if is_key_pressed(KeyCode::Enter) {
+            *chat_input = Some(String::new());
+            return true;
+        }
+        return false;
+    };
+
+    if is_key_pressed(KeyCode::Escape) {
+        *chat_input = None;
+        return true;
+    }
+    if is_key_pressed(KeyCode::Backspace) {
+        input.pop();
+    }
+    for c in typed {
+        if !c.is_control() && input.chars().count() < chat::MAX_CHAT_LEN {
+            input.push(c);
+        }
+    }
+
+    if is_key_pressed(KeyCode::Enter) {
+        let text = chat::clean(input);
+        *chat_input = None;
+        if !text.is_empty() {
+            chat::push(chat_log, name, &text);
+            let message = Message::Chat {
+                from: name.to_string(),
+                text,
+            };
+            if let Err(e) = tx.send(message) {
+                eprintln!("Error sending chat to network thread: {}", e);
             }
         }
     }
+
+    true
+}
+
+/// The room after `viewed` in name order, skipping the host's own, or None to
+/// go back to it after the last.
+fn next_room(server: &network::ServerHandle, viewed: Option<&str>) -> Option<String> {
+    server
+        .room_summary()
+        .into_iter()
+        .map(|(room, _, _)| room)
+        .filter(|room| *room != server.room)
+        .find(|room| viewed.is_none_or(|viewed| room.as_str() > viewed))
 }
 
-fn render_entities(entities: &DashMap<usize, Entity>) {
-    for entry in entities.iter() {
-        let e = entry.value();
-        draw_circle(e.x, e.y, e.radius, hex_to_color(e.color));
+/// Room shown in the server window, next to the SERVER label.
+fn render_room_status(server: &network::ServerHandle, room: &str, ink: Color) {
+    let rooms = server.room_summary();
+    let (entity_count, client_count) = rooms
+        .iter()
+        .find(|(name, _, _)| name == room)
+        .map_or((0, 0), |(_, entity_count, client_count)| {
+            (*entity_count, *client_count)
+        });
+
+    let mut text = format!(
+        "#{}: {} / {} entities, {} clients",
+        room, entity_count, server.max_entities, client_count
+    );
+    if rooms.len() > 1 {
+        text.push_str(&format!(" ({} rooms, Tab to switch)", rooms.len()));
+    }
+    if room != server.room {
+        text.push_str(", view only");
     }
+    draw_text(&text, 120f32, 32f32, 16f32, ink);
+}
+
+/// Row of the client list, below the SERVER label, that `index` is drawn at.
+fn client_row_y(index: usize) -> f32 {
+    54f32 + 20f32 * index as f32
+}
+
+/// Approve and deny buttons after a waiting guest's label in the client list.
+fn join_buttons(label: &str, y: f32) -> (Rect, Rect) {
+    let x = 32f32 + measure_text(label, None, 16, 1.0).width + 12f32;
+    let approve = Rect::new(x, y - 14f32, 60f32, 18f32);
+    let deny = Rect::new(x + 68f32, y - 14f32, 44f32, 18f32);
+    (approve, deny)
+}
+
+/// Kick button after an admitted client's label in the client list.
+fn kick_button(label: &str, y: f32) -> Rect {
+    let x = 32f32 + measure_text(label, None, 16, 1.0).width + 12f32;
+    Rect::new(x, y - 14f32, 36f32, 18f32)
 }
 
-async fn render(entities: &DashMap<usize, Entity>, is_server: bool, shape_size: f32, client_list: Option<&network::ClientList>) {
-    clear_background(WHITE);
+/// Passes clicks on the client list's approve, deny and kick buttons to the
+/// server. Returns whether the click landed on one.
+fn handle_client_buttons(server: &network::ServerHandle) -> bool {
+    if !is_mouse_button_pressed(MouseButton::Left) {
+        return false;
+    }
+    let mouse = Vec2::from(mouse_position());
+    let Ok(clients) = server.clients.lock() else {
+        return false;
+    };
+
+    for (i, client) in clients.iter().enumerate() {
+        if !client.pending {
+            if kick_button(&client.label(), client_row_y(i)).contains(mouse) {
+                server.kick(client.addr, "Removed by the host".to_string());
+                return true;
+            }
+            continue;
+        }
+        let (approve, deny) = join_buttons(&client.label(), client_row_y(i));
+        let decision = if approve.contains(mouse) {
+            network::JoinDecision::Approve
+        } else if deny.contains(mouse) {
+            network::JoinDecision::Deny
+        } else {
+            continue;
+        };
+        server.decide(client.addr, decision);
+        return true;
+    }
+    false
+}
 
-    render_entities(entities);
-    let (mousex, mousey) = mouse_position();
-    draw_circle_lines(mousex, mousey, shape_size, 1.0, BLACK);
+/// Picks a palette slot with 1-8, or the next or previous one with ] and [.
+/// Returns whether a slot was picked.
+fn select_palette_color(color_index: &mut usize) -> bool {
+    let before = *color_index;
+    let mut picked = false;
+    for i in 0..EntityColor::PALETTE.len() {
+        if bindings::pressed(Action::Color(i)) {
+            *color_index = i;
+            picked = true;
+        }
+    }
+    if bindings::pressed(Action::ColorNext) {
+        *color_index = (before + 1) % EntityColor::PALETTE.len();
+        picked = true;
+    }
+    if bindings::pressed(Action::ColorPrevious) {
+        *color_index = (before + EntityColor::PALETTE.len() - 1) % EntityColor::PALETTE.len();
+        picked = true;
+    }
+    picked
+}
 
-    if is_server {
-        draw_text("SERVER", 32f32, 32f32, 22f32, BLACK);
+/// What the brush draws in: the picker's color while it is in use, else the palette slot.
+fn brush_color(picker: &Picker, color_index: usize) -> Color {
+    picker
+        .color()
+        .unwrap_or(EntityColor::PALETTE[color_index].into())
+}
+
+/// Palette slot of the color the server assigned us, once it has.
+fn assigned_palette_index(client: &network::ClientHandle) -> Option<usize> {
+    let assigned = (*client.color.lock().ok()?)?;
+    EntityColor::PALETTE
+        .iter()
+        .position(|&color| color == assigned)
+}
+
+/// What the server's Welcome allows us to change, or nothing before it arrives.
+fn permissions(client: &network::ClientHandle) -> Permissions {
+    client
+        .permissions
+        .lock()
+        .map_or(Permissions::UNKNOWN, |permissions| *permissions)
+}
+
+/// Grows or shrinks the brush with the mouse wheel, within the allowed range.
+fn adjust_brush_size(size: &mut f32) {
+    let (_, wheel) = mouse_wheel();
+    if wheel != 0.0 {
+        *size =
+            (*size * BRUSH_SIZE_STEP.powf(wheel.signum())).clamp(MIN_BRUSH_SIZE, MAX_BRUSH_SIZE);
+    }
+}
+
+/// Makes the brush more opaque with + and more see-through with -.
+fn adjust_opacity(opacity: &mut f32) {
+    let mut step = 0.0;
+    if bindings::pressed(Action::OpacityUp) {
+        step += OPACITY_STEP;
+    }
+    if bindings::pressed(Action::OpacityDown) {
+        step -= OPACITY_STEP;
+    }
+    // rounded to whole steps so repeated presses land back on exactly 1
+    *opacity = ((*opacity + step) / OPACITY_STEP).round() * OPACITY_STEP;
+    *opacity = opacity.clamp(MIN_OPACITY, 1.0);
+}
+
+fn with_opacity(color: Color, opacity: f32) -> Color {
+    Color {
+        a: opacity,
+        ..color
+    }
+}
+
+/// Picks the tool for the next drag: Q draws freehand, W rectangles and E
+/// lines. F picks the fill tool and T the text tool, which work on a click instead.
+fn select_tool(tool: &mut Tool) {
+    for (action, picked) in [
+        (Action::ToolFreehand, Tool::Freehand),
+        (Action::ToolRect, Tool::Rect),
+        (Action::ToolLine, Tool::Line),
+        (Action::ToolFill, Tool::Fill),
+        (Action::ToolText, Tool::Text),
+        (Action::ToolSelect, Tool::Select),
+    ] {
+        if bindings::pressed(action) {
+            *tool = picked;
+        }
+    }
+}
+
+/// Pressure for the point drawn `held` seconds into a stroke, thinned out over
+/// time with `--taper`. A finger or stylus on a touch screen presses fully, as
+/// no backend reports force yet; otherwise it is whatever `--fake-pressure`
+/// makes up.
+fn pen_pressure(config: &Config, held: f64) -> Option<f32> {
+    let reading = if touches().is_empty() {
+        config
+            .fake_pressure
+            .map(|pattern| pattern.sample(get_time()))
+    } else {
+        Some(1.0)
+    };
+    let taper = config
+        .taper
+        .then(|| (1.0 - held / TAPER_SECONDS).max(0.0) as f32);
+    match (reading, taper) {
+        (Some(reading), Some(taper)) => Some(reading * taper),
+        (reading, taper) => reading.or(taper),
+    }
+}
+
+fn ctrl_down() -> bool {
+    is_key_down(KeyCode::LeftControl) || is_key_down(KeyCode::RightControl)
+}
+
+fn alt_down() -> bool {
+    is_key_down(KeyCode::LeftAlt) || is_key_down(KeyCode::RightAlt)
+}
+
+fn shift_down() -> bool {
+    is_key_down(KeyCode::LeftShift) || is_key_down(KeyCode::RightShift)
+}
+
+/// Gives everything under the brush circle that we may change the brush color.
+fn recolor_under_cursor(entities: &Canvas, tx: &Sender<Message>, pen: Pen, color: EntityColor) {
+    let (x, y) = pen.position;
+    let area = Circle::new(x, y, pen.size);
+
+    for id in entities.query_circle((x, y), pen.size) {
+        let recolored = entities.update(id, |entity| {
+            entity.layer == pen.layer
+                && pen.permissions.may_change(entity)
+                && entity.colorize(&area, color)
+        });
+        if recolored == Some(true)
+            && let Err(e) = tx.send(Message::UpdateEntity { id, color })
+        {
+            eprintln!("Error sending recolor to network thread: {}", e);
+        }
+    }
+}
+
+/// Ctrl+S saves and Ctrl+O loads from `load_path` unless it is None. Returns
+/// whether Ctrl+E asked for an export, which the caller draws.
+fn handle_file_shortcuts(
+    entities: &Canvas,
+    background: EntityColor,
+    tx: &Sender<Message>,
+    save_path: &Path,
+    load_path: Option<&Path>,
+) -> bool {
+    if bindings::pressed(Action::Save) {
+        match storage::save_canvas(save_path, entities, background) {
+            Ok(count) => println!("Saved {} entities to {}", count, save_path.display()),
+            Err(e) => eprintln!("Error saving canvas to {}: {}", save_path.display(), e),
+        }
+    }
+
+    if let Some(load_path) = load_path
+        && bindings::pressed(Action::Load)
+    {
+        load_into_canvas(load_path, entities, tx);
+    }
+
+    bindings::pressed(Action::Export)
+}
+
+/// Renders just the entities in view, without any HUD, into an offscreen target
+/// and hands the pixels to a background thread for PNG encoding.
+fn export_canvas(
+    entities: &Canvas,
+    view: &View,
+    layers: &Layers,
+    background: EntityColor,
+    batch: &mut Batch,
+) {
+    let (width, height) = (screen_width(), screen_height());
+    let target = render_target(width as u32, height as u32);
+
+    let mut camera = view.camera();
+    camera.render_target = Some(target.clone());
+    set_camera(&camera);
+    clear_background(Color::from(background));
+    // at full strength: the export keeps the drawing, not the moment
+    render_entities(entities, &view.visible(), None, layers, batch);
+    set_default_camera();
+
+    export::write_png_in_background(
+        target.texture.get_texture_data(),
+        export::timestamped_png_path(),
+    );
+}
+
+/// The addresses the server ended up bound to, for the startup line.
+fn listening_on(listeners: &[TcpListener]) -> String {
+    listeners
+        .iter()
+        .filter_map(|listener| listener.local_addr().ok())
+        .map(|addr| addr.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Adds a saved canvas to the local map and hands it to the network thread,
+/// which broadcasts it (server) or pushes it up to the server (client). Its
+/// background goes along, though the server only takes the host's.
+fn load_into_canvas(path: &Path, entities: &Canvas, tx: &Sender<Message>) {
+    match storage::load_canvas(path, entities) {
+        Ok(loaded) => {
+            println!(
+                "Loaded {} entities from {}",
+                loaded.entities.len(),
+                path.display()
+            );
+            for entity in loaded.entities.iter() {
+                entities.insert(entity.clone());
+            }
+            if let Err(e) = tx.send(Message::AllEntities(loaded.entities)) {
+                eprintln!("Error sending loaded canvas to network thread: {}", e);
+            }
+            if let Some(color) = loaded.background {
+                send_background(color, tx);
+            }
+        }
+        Err(e) => eprintln!("Error loading canvas: {}", e),
+    }
+}
+
+/// Draws the entities overlapping `visible`, a world area, under the current camera.
+/// Draws the entities overlapping `visible`, found through the canvas grid.
+/// When they `fade`, each is fainter the longer it has been on this canvas.
+fn render_entities(
+    entities: &Canvas,
+    visible: &Rect,
+    fade: Option<Duration>,
+    layers: &Layers,
+    batch: &mut Batch,
+) {
+    for id in entities.query_rect(visible) {
+        let strength = match (fade, entities.age(id)) {
+            (Some(fade), Some(age)) => 1.0 - age.as_secs_f32() / fade.as_secs_f32(),
+            _ => 1.0,
+        };
+        // gone, or about to be erased by the server
+        if strength <= 0.0 {
+            continue;
+        }
+        if let Some(e) = entities.get(id)
+            && layers.shows(&e)
+        {
+            render_entity(&e, strength, batch);
+        }
+    }
+    batch.flush();
+}
+
+/// Adds `e` to `batch` at `strength` times its own alpha; the caller flushes it.
+fn render_entity(e: &Entity, strength: f32, batch: &mut Batch) {
+    let mut color = Color::from(e.color);
+    color.a *= strength;
+
+    match &e.shape {
+        Shape::Circle => batch.circle(e.x, e.y, e.radius, color),
+        Shape::Stroke { points, .. } => batch.stroke(points, |i| e.radius_at(i), color),
+        Shape::Rect { w, h } => batch.rect(e.x, e.y, *w, *h, color),
+        Shape::Line { x2, y2, thickness } => batch.line((e.x, e.y), (*x2, *y2), *thickness, color),
+        Shape::Fill { rects } => {
+            for &(x, y, w, h) in rects {
+                batch.rect(x, y, w, h, color);
+            }
+        }
+        Shape::Text { text, size } => {
+            // text has its own texture, so whatever came before draws first
+            batch.flush();
+            draw_text(text, e.x, e.y + size * TEXT_BASELINE, *size, color);
+        }
+    }
+}
+
+/// Draws `entities`, placed around the origin, faintly with the origin at
+/// `at`, then goes back to the view's camera.
+fn render_ghost(entities: &[Entity], at: (f32, f32), view: &View, batch: &mut Batch) {
+    let visible = view.visible();
+    set_camera(&Camera2D::from_display_rect(Rect {
+        x: visible.x - at.0,
+        y: visible.y - at.1,
+        ..visible
+    }));
+    for entity in entities {
+        render_entity(entity, selection::GHOST_STRENGTH, batch);
+    }
+    batch.flush();
+    set_camera(&view.camera());
+}
+
+/// Draws peers' pointers at their world positions, at a constant size on screen.
+fn render_cursors(cursors: &DashMap<u64, network::RemoteCursor>, view: &View) {
+    for entry in cursors.iter() {
+        let cursor = entry.value();
+        let color = Color::from(cursor.color);
+        let label = format!("{:04x}", entry.key() & 0xFFFF);
+        let Vec2 { x, y } = view.to_screen(Vec2::new(cursor.x, cursor.y));
+
+        draw_circle_lines(x, y, 6.0, 2.0, color);
+        draw_text(&label, x + 9.0, y - 9.0, 16f32, color);
+    }
+}
+
+/// Draws the brush outline at `pointer`, the mouse or the primary finger, and
+/// the active color swatch, in screen space. `brush_radius` is `brush_size` as it shows at the current zoom.
+fn render_brush(
+    pointer: Vec2,
+    brush_size: f32,
+    brush_radius: f32,
+    tool: Tool,
+    brush_color: Color,
+    background: EntityColor,
+) {
+    let (x, y) = pointer.into();
+    // the outline stays solid so a faint brush can still be found, and takes the
+    // UI color where the brush would vanish into the background
+    let outline = background::against(with_opacity(brush_color, 1.0), background);
+    draw_circle_lines(x, y, brush_radius, 1.0, outline);
+    let tool_name = match tool {
+        Tool::Freehand => "",
+        Tool::Rect => " rect",
+        Tool::Line => " line",
+        Tool::Fill => " fill",
+        Tool::Text => " text",
+        Tool::Select => " select",
+    };
+    let mut label = format!("{:.0}{}", brush_size, tool_name);
+    if brush_color.a < 1.0 {
+        label.push_str(&format!(" {:.0}%", brush_color.a * 100.0));
+    }
+    draw_text(&label, x + brush_radius + 4f32, y + 4f32, 16f32, outline);
+
+    // active color swatch, see-through like the brush
+    let swatch_x = screen_width() - 48f32;
+    draw_rectangle(swatch_x, 16f32, 32f32, 32f32, brush_color);
+    draw_rectangle_lines(
+        swatch_x,
+        16f32,
+        32f32,
+        32f32,
+        2.0,
+        background::ink(background),
+    );
+}
+
+/// Draws the peer this window is, and the client list or connection status
+/// under it, in screen space, in `ink`.
+fn render(
+    mode: Mode,
+    client_list: Option<&network::ClientList>,
+    client: Option<&network::ClientHandle>,
+    ink: Color,
+) {
+    if mode == Mode::Server {
+        draw_text("SERVER", 32f32, 32f32, 22f32, ink);
 
         // Display client IPs under the SERVER text
-        if let Some(clients) = client_list {
-            if let Ok(clients) = clients.lock() {
-                let mut y_offset = 54f32; // Start below the SERVER text
-                for client in clients.iter() {
-                    let client_text = format!("Client: {}", client.addr);
-                    draw_text(&client_text, 32f32, y_offset, 16f32, BLACK);
-                    y_offset += 20f32; // Move down for the next client
+        if let Some(clients) = client_list
+            && let Ok(clients) = clients.lock()
+        {
+            for (i, client) in clients.iter().enumerate() {
+                let client_text = client.label();
+                let y = client_row_y(i);
+                // tinted like the client's strokes, so the list doubles as a legend
+                draw_text(&client_text, 32f32, y, 16f32, Color::from(client.color));
+
+                if client.pending {
+                    let (approve, deny) = join_buttons(&client_text, y);
+                    for (rect, text, color) in
+                        [(approve, "approve", DARKGREEN), (deny, "deny", RED)]
+                    {
+                        draw_rectangle_lines(rect.x, rect.y, rect.w, rect.h, 1.0, color);
+                        draw_text(text, rect.x + 4f32, y, 16f32, color);
+                    }
+                } else {
+                    let rect = kick_button(&client_text, y);
+                    draw_rectangle_lines(rect.x, rect.y, rect.w, rect.h, 1.0, GRAY);
+                    draw_text("kick", rect.x + 4f32, y, 16f32, GRAY);
                 }
             }
         }
     } else {
-        draw_text("CLIENT", 32f32, 32f32, 22f32, BLACK);
+        let label = if mode == Mode::Spectator {
+            "SPECTATOR"
+        } else {
+            "CLIENT"
+        };
+        draw_text(label, 32f32, 32f32, 22f32, ink);
+
+        // anything but a working connection is on the banner instead
+        if let Some(client) = client
+            && let Ok(status) = client.status.lock()
+            && *status == network::ClientStatus::Connected
+        {
+            let ping = match client.rtt.load(Ordering::Relaxed) {
+                network::NO_RTT => "ping: ...".to_string(),
+                ms => format!("ping: {} ms", ms),
+            };
+            draw_text(&ping, 32f32, 54f32, 16f32, ink);
+
+            // the rest of the room, below where F3 puts the traffic
+            if let Ok(roster) = client.roster.lock() {
+                for (i, (_, name)) in roster.iter().enumerate() {
+                    draw_text(name, 32f32, client_row_y(i + 2), 16f32, ink);
+                }
+            }
+        }
+    }
+}
+
+/// Says across the top of the window what keeps the client from drawing with
+/// everyone at `addr`, if anything: amber while it may still come right, red
+/// once the client has given up.
+fn render_connection_banner(status: &network::ClientStatus, addr: &str) {
+    let text = match status {
+        network::ClientStatus::Connected => return,
+        network::ClientStatus::Connecting => format!("Connecting to {}...", addr),
+        network::ClientStatus::AwaitingApproval => {
+            format!("Waiting for the host at {} to let you in", addr)
+        }
+        network::ClientStatus::Reconnecting {
+            attempt,
+            server_closed,
+        } => {
+            let what = if *server_closed {
+                "The server closed"
+            } else {
+                "No connection"
+            };
+            format!(
+                "{}; reconnecting to {} (attempt {}). What you draw goes out once it is back",
+                what, addr, attempt
+            )
+        }
+        network::ClientStatus::Rejected { reason } => {
+            format!(
+                "Turned away by {}: {}. Nothing you draw is shared",
+                addr, reason
+            )
+        }
+        network::ClientStatus::Incompatible { reason } => {
+            format!(
+                "Cannot talk to {}: {}. Nothing you draw is shared",
+                addr, reason
+            )
+        }
+        network::ClientStatus::Kicked { reason } => {
+            format!(
+                "Removed by the host: {}. Nothing you draw is shared",
+                reason
+            )
+        }
+    };
+    let (fill, ink) = if status.gave_up() {
+        (Color::new(0.8, 0.1, 0.1, 0.95), WHITE)
+    } else {
+        (Color::new(1.0, 0.78, 0.25, 0.95), BLACK)
+    };
+    let font_size = 18f32;
+    let width = measure_text(&text, None, font_size as u16, 1.0).width + 24f32;
+    let x = ((screen_width() - width) / 2.0).max(0.0);
+    draw_rectangle(x, 8f32, width, 28f32, fill);
+    draw_text(&text, x + 12f32, 27f32, font_size, ink);
+}
+
+/// Recent warnings from the network threads, under the banner, fading out.
+fn render_notices(notices: &Notices) {
+    let Ok(notices) = notices.lock() else {
+        return;
+    };
+    let font_size = 16f32;
+    let mut y = 44f32;
+    for notice in notices.iter() {
+        let opacity = notice.opacity();
+        if opacity <= 0.0 {
+            continue;
+        }
+        let width = measure_text(&notice.text, None, font_size as u16, 1.0).width + 16f32;
+        let x = ((screen_width() - width) / 2.0).max(0.0);
+        draw_rectangle(
+            x,
+            y,
+            width,
+            24f32,
+            Color::new(0.15, 0.15, 0.15, 0.85 * opacity),
+        );
+        draw_text(
+            &notice.text,
+            x + 8f32,
+            y + 17f32,
+            font_size,
+            Color::new(1.0, 1.0, 1.0, opacity),
+        );
+        y += 28f32;
     }
+}
 
-    next_frame().await;
+/// Bucket fills being worked out on their own threads, so a big region does
+/// not hold up drawing the frame.
+struct Fills {
+    done_tx: Sender<Result<Entity, FillError>>,
+    done: crossbeam_channel::Receiver<Result<Entity, FillError>>,
 }
 
-fn hex_to_color(hex: i32) -> Color {
-    let r = ((hex >> 16) & 0xFF) as f32 / 255.0;
-    let g = ((hex >> 8) & 0xFF) as f32 / 255.0;
-    let b = (hex & 0xFF) as f32 / 255.0;
+impl Fills {
+    fn new() -> Self {
+        let (done_tx, done) = unbounded();
+        Self { done_tx, done }
+    }
+
+    fn start(
+        &self,
+        entities: &Arc<Canvas>,
+        at: (f32, f32),
+        resolution: f32,
+        color: EntityColor,
+        layer: u8,
+    ) {
+        let (entities, done_tx) = (entities.clone(), self.done_tx.clone());
+        thread::spawn(move || {
+            let fill = fill::flood_fill(&entities, at, resolution).map(|rects| Entity {
+                layer,
+                ..Entity::fill(next_id(), rects, color)
+            });
+            let _ = done_tx.send(fill);
+        });
+    }
 
-    Color::new(r, g, b, 1.0)
+    /// Puts finished fills on the canvas and sends them to peers like any other drawing.
+    fn collect(&self, entities: &Canvas, tx: &Sender<Message>, history: &mut History) {
+        for fill in self.done.try_iter() {
+            match fill {
+                Ok(entity) => {
+                    entities.insert(entity.clone());
+                    history.record(entity.id);
+                    if let Err(e) = tx.send(Message::NewEntity(entity)) {
+                        eprintln!("Error sending fill to network thread: {}", e);
+                    }
+                }
+                Err(e) => eprintln!("Not filling: {}", e),
+            }
+        }
+    }
 }
-fn color_to_hex(color: Color) -> i32 {
-    let r = (color.r * 255.0).round() as i32;
-    let g = (color.g * 255.0).round() as i32;
-    let b = (color.b * 255.0).round() as i32;
 
-    (r << 16) | (g << 8) | b
+/// Per-second traffic for the F3 overlay, with a meter per connection.
+#[derive(Default)]
+struct NetOverlay {
+    clients: HashMap<SocketAddr, RateMeter>,
+    /// The client window's own connection.
+    server: Option<RateMeter>,
+}
+
+/// Traffic after each client in the server's list, or under CLIENT in a client window.
+fn render_net_stats(
+    overlay: &mut NetOverlay,
+    client_list: Option<&network::ClientList>,
+    client: Option<&network::ClientHandle>,
+) {
+    if let Some(clients) = client_list
+        && let Ok(clients) = clients.lock()
+    {
+        overlay
+            .clients
+            .retain(|addr, _| clients.iter().any(|client| client.addr == *addr));
+        for (i, client) in clients.iter().enumerate() {
+            let totals = client.stats.totals();
+            let rates = overlay
+                .clients
+                .entry(client.addr)
+                .or_insert_with(|| RateMeter::new(totals))
+                .update(totals);
+            let label = client.label();
+            let mut x = 32f32 + measure_text(&label, None, 16, 1.0).width + 12f32;
+            // past the approve and deny buttons, or the kick button
+            x += if client.pending { 124f32 } else { 44f32 };
+            draw_text(&stats_text(rates), x, client_row_y(i), 16f32, GRAY);
+        }
+    }
+    if let Some(client) = client {
+        let totals = client.stats.totals();
+        let rates = overlay
+            .server
+            .get_or_insert_with(|| RateMeter::new(totals))
+            .update(totals);
+        draw_text(&stats_text(rates), 32f32, 72f32, 16f32, GRAY);
+    }
+}
+
+/// "out 12/s 3.4 KB/s, in 5/s 1.1 KB/s, canvas 120 KB, queued 2.0 KB"
+fn stats_text(rates: NetTotals) -> String {
+    let mut text = format!(
+        "out {}/s {}/s, in {}/s {}/s",
+        rates.messages_sent,
+        format_bytes(rates.bytes_sent),
+        rates.messages_received,
+        format_bytes(rates.bytes_received)
+    );
+    if rates.last_snapshot > 0 {
+        text.push_str(&format!(", canvas {}", format_bytes(rates.last_snapshot)));
+    }
+    if rates.queued > 0 {
+        text.push_str(&format!(", queued {}", format_bytes(rates.queued)));
+    }
+    text
+}
+
+fn format_bytes(bytes: u64) -> String {
+    match bytes {
+        0..1024 => format!("{} B", bytes),
+        1024..1_048_576 => format!("{:.1} KB", bytes as f64 / 1024.0),
+        _ => format!("{:.1} MB", bytes as f64 / 1_048_576.0),
+    }
+}
+
+/// Recent chat lines, fading out, above the input box when it is open.
+fn render_chat(chat_log: &ChatLog, chat_input: Option<&str>, ink: Color) {
+    let font_size = 18f32;
+    let line_height = 22f32;
+    let max_width = (screen_width() / 2.0).max(200.0);
+    let width = |text: &str| measure_text(text, None, font_size as u16, 1.0).width;
+    let mut y = screen_height() - 16f32;
+
+    if let Some(input) = chat_input {
+        let prompt = format!("> {}_", input);
+        let lines = chat::wrap(&prompt, max_width, width);
+        y -= line_height * lines.len().saturating_sub(1) as f32;
+        let top = y - line_height * 0.75;
+        draw_rectangle(
+            24f32,
+            top,
+            max_width + 16f32,
+            line_height * lines.len() as f32 + 8f32,
+            background::faint(ink, 0.08),
+        );
+        for (i, line) in lines.iter().enumerate() {
+            draw_text(line, 32f32, y + line_height * i as f32, font_size, ink);
+        }
+        y = top - 8f32;
+    }
+
+    let Ok(log) = chat_log.lock() else {
+        return;
+    };
+    for line in log.iter().rev() {
+        let opacity = line.opacity();
+        if opacity <= 0.0 {
+            continue;
+        }
+        let text = format!("{}: {}", line.from, line.text);
+        let wrapped = chat::wrap(&text, max_width, width);
+        y -= line_height * wrapped.len() as f32;
+        let color = background::faint(ink, opacity);
+        for (i, row) in wrapped.iter().enumerate() {
+            draw_text(
+                row,
+                32f32,
+                y + line_height * (i as f32 + 1.0),
+                font_size,
+                color,
+            );
+        }
+    }
 }
@@ -0,0 +1,123 @@
+use crate::background;
+use crate::bindings;
+use crate::view::View;
+use macroquad::prelude::*;
+use tcp_drawing::entity::{Entity, next_id, next_seq};
+use tcp_drawing::keymap::Action;
+use tcp_drawing::transform::Transform;
+
+/// Length of a dash and of the gap after it, in screen pixels.
+const DASH: f32 = 8.0;
+const AXIS_ALPHA: f32 = 0.2;
+/// Most reflections one drawing gets, in four-way mode.
+pub const MAX_REFLECTIONS: usize = 3;
+
+/// Which copies of every new drawing the pen adds, cycled with M.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Symmetry {
+    Off,
+    /// Mirrored left to right across a vertical axis.
+    Vertical,
+    /// Mirrored top to bottom across a horizontal axis.
+    Horizontal,
+    /// Both, and the two together: four of everything.
+    FourWay,
+}
+
+/// Mirror drawing: strokes, rectangles and lines also go down reflected
+/// across axes through a fixed world point. The reflections are entities of
+/// their own, so peers see them without being in the mode.
+#[derive(Debug, Clone, Copy)]
+pub struct Mirror {
+    symmetry: Symmetry,
+    /// Where the axes cross, in world coordinates: the middle of the view when
+    /// the mode was turned on, so panning and zooming leave the axes put.
+    center: (f32, f32),
+}
+
+impl Mirror {
+    pub fn new() -> Self {
+        Self {
+            symmetry: Symmetry::Off,
+            center: (0.0, 0.0),
+        }
+    }
+
+    /// M cycles off / vertical / horizontal / four-way.
+    pub fn handle_input(&mut self, view: &View) {
+        if !bindings::pressed(Action::Mirror) {
+            return;
+        }
+        self.symmetry = match self.symmetry {
+            Symmetry::Off => {
+                self.center = view.visible().center().into();
+                Symmetry::Vertical
+            }
+            Symmetry::Vertical => Symmetry::Horizontal,
+            Symmetry::Horizontal => Symmetry::FourWay,
+            Symmetry::FourWay => Symmetry::Off,
+        };
+    }
+
+    /// The transforms taking a drawing to each of its reflections; none while off.
+    pub fn reflections(&self) -> Vec<Transform> {
+        let flips: &[(bool, bool)] = match self.symmetry {
+            Symmetry::Off => &[],
+            Symmetry::Vertical => &[(true, false)],
+            Symmetry::Horizontal => &[(false, true)],
+            Symmetry::FourWay => &[(true, false), (false, true), (true, true)],
+        };
+        flips
+            .iter()
+            .map(|&(flip_x, flip_y)| Transform::mirror_about(flip_x, flip_y, self.center))
+            .collect()
+    }
+
+    /// The reflections of `entity`, keeping its id, for previews.
+    pub fn images(&self, entity: &Entity) -> Vec<Entity> {
+        self.reflections()
+            .into_iter()
+            .map(|reflection| reflected(entity, reflection))
+            .collect()
+    }
+
+    /// Draws the axes across the view as dashed lines, in world coordinates,
+    /// in a faint `ink`.
+    pub fn render(&self, view: &View, ink: Color) {
+        let (x, y) = self.center;
+        let color = background::faint(ink, AXIS_ALPHA);
+        let visible = view.visible();
+        let (dash, width) = (DASH / view.zoom(), 1.0 / view.zoom());
+        if matches!(self.symmetry, Symmetry::Vertical | Symmetry::FourWay) {
+            // dashes start from the center, so they stay put while the view pans
+            let mut from = y - ((y - visible.y) / (2.0 * dash)).ceil() * 2.0 * dash;
+            while from < visible.bottom() {
+                draw_line(x, from, x, from + dash, width, color);
+                from += 2.0 * dash;
+            }
+        }
+        if matches!(self.symmetry, Symmetry::Horizontal | Symmetry::FourWay) {
+            let mut from = x - ((x - visible.x) / (2.0 * dash)).ceil() * 2.0 * dash;
+            while from < visible.right() {
+                draw_line(from, y, from + dash, y, width, color);
+                from += 2.0 * dash;
+            }
+        }
+    }
+}
+
+/// `entity` moved by `reflection`, still under its own id.
+fn reflected(entity: &Entity, reflection: Transform) -> Entity {
+    let mut image = entity.clone();
+    reflection.apply_to(&mut image);
+    image
+}
+
+/// A new entity: `entity` moved by `reflection`, with an id and a place in
+/// the stacking order of its own.
+pub fn reflected_copy(entity: &Entity, reflection: Transform) -> Entity {
+    let mut copy = reflected(entity, reflection);
+    copy.id = next_id();
+    copy.seq = next_seq();
+    copy
+}
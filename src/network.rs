@@ -1,401 +1,3947 @@
-use crate::entity::Entity;
-use crossbeam_channel::{Receiver, Sender};
+use crate::chat::{self, ChatLog};
+use crate::config::Config;
+use crate::entity::{self, Canvas, Entity, EntityColor, HOST_OWNER, PenSamples};
+use crate::notice::{self, Notices};
+use crate::recording::Recorder;
+use crate::storage::Persister;
+use crate::transform::Transform;
+use compact::CompactBatch;
+use connection::{Connection, Event, Reports};
+use crossbeam_channel::{Receiver, RecvTimeoutError, TryRecvError};
 use dashmap::DashMap;
+use flate2::Compression;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use mio::{Events, Interest, Poll, Token, Waker};
 use serde::{Deserialize, Serialize};
-use serde_json;
+use std::borrow::Cow;
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
 use std::io::{self, Read, Write};
-use std::net::{TcpListener, TcpStream};
-use std::sync::{Arc, Mutex};
-use std::thread;
-use std::time::Duration;
-use std::net::SocketAddr;
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+pub mod bots;
+pub mod compact;
+mod connection;
+pub mod tls;
+#[cfg(feature = "websocket")]
+mod websocket;
 
 const BUFFER_CAPACITY: usize = 16384;
-const MAX_BUFFER_SIZE: usize = 100_000;
-const SLEEP_DURATION: u64 = 20;
+/// Opens every connection in both directions, ahead of any frame and outside
+/// `Message`, so builds of any protocol version can read each other's.
+const HANDSHAKE_MAGIC: &[u8; 6] = b"TCPDRW";
+pub const HANDSHAKE_LEN: usize = HANDSHAKE_MAGIC.len() + 2;
+/// First byte of a TLS client's opening record.
+const TLS_HANDSHAKE_RECORD: u8 = 0x16;
+/// Bumped whenever builds could no longer understand each other's messages.
+const PROTOCOL_VERSION: u16 = 4;
+/// What builds from before the handshake speak; they open with a frame instead.
+const UNVERSIONED_PROTOCOL: u16 = 1;
+/// Largest frame accepted off the wire, compressed or not.
+const MAX_BUFFER_SIZE: usize = 8 * 1024 * 1024;
+/// Largest message a compressed frame may inflate to, so a tiny frame cannot exhaust memory.
+const MAX_DECOMPRESSED_SIZE: usize = 64 * 1024 * 1024;
+/// Encoded messages shorter than this go out as they are; deflating them costs more than it saves.
+const COMPRESSION_THRESHOLD: usize = 1024;
+/// First byte of a deflated payload. JSON starts with `{` or `"` and bincode
+/// with a small variant index, so neither is ever mistaken for it.
+const COMPRESSED_TAG: u8 = 0xFF;
+/// Keeps batched frames well below MAX_BUFFER_SIZE.
+const MAX_BATCH_LEN: usize = 256;
+/// A client with more than this many bytes waiting to be written, once no
+/// drawing traffic is left to drop, is dropped.
+pub const MAX_OUTBOUND_BYTES: usize = 8 * 1024 * 1024;
+/// Bytes a client's queue holds before the oldest drawing traffic waiting in
+/// it is dropped, to be made up for by the canvas once the client catches up.
+pub const OUTBOUND_BUDGET: usize = 1024 * 1024;
+const SEND_RETRY_INTERVAL: u64 = 100;
+const MAX_RECONNECT_DELAY_SECS: u64 = 30;
+/// Remote cursors not refreshed within this long are dropped.
+pub const CURSOR_TIMEOUT: Duration = Duration::from_secs(2);
+/// Longest the server waits for socket events before checking timeouts.
+const HOUSEKEEPING_INTERVAL: Duration = Duration::from_secs(1);
+const WAKER: Token = Token(1);
+/// Reports from connection threads waiting for the server's loop, past which
+/// the threads wait too.
+const MAX_REPORTS: usize = 64;
+#[cfg(feature = "websocket")]
+const WS_LISTENER: Token = Token(2);
+/// Listeners take the first tokens from here on, clients the rest.
+const FIRST_PEER_TOKEN: usize = 3;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often the client pings the server.
+const PING_INTERVAL: Duration = Duration::from_secs(2);
+/// A server silent for this long (not even a pong) is considered dead. The
+/// server gives clients `--client-timeout` instead.
+const PEER_TIMEOUT: Duration = Duration::from_secs(15);
+/// Weight of the newest sample in the smoothed round-trip time.
+const RTT_SMOOTHING: f64 = 0.25;
+/// Value of a `SharedRtt` before the first pong arrives.
+pub const NO_RTT: u64 = u64::MAX;
+/// A client that has not sent its Hello this long after connecting is turned away.
+const HELLO_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_NAME_LEN: usize = 32;
+/// Guests allowed to wait for approval at once; more are turned away.
+const MAX_PENDING: usize = 8;
+/// Room of clients that do not ask for one.
+pub const DEFAULT_ROOM: &str = "main";
+/// Colors handed to clients, least used first: the UI palette minus the server's red.
+const PEER_COLORS: [EntityColor; 7] = [
+    EntityColor::GREEN,
+    EntityColor::BLUE,
+    EntityColor::ORANGE,
+    EntityColor::PURPLE,
+    EntityColor::BROWN,
+    EntityColor::BLACK,
+    EntityColor::YELLOW,
+];
+
+#[derive(Debug, Clone)]
+pub struct ClientInfo {
+    pub addr: SocketAddr,
+    pub name: Option<String>,
+    /// View-only client; nothing it sends touches the canvas.
+    pub spectator: bool,
+    /// Waiting for the host to let it in; it is sent nothing but pongs meanwhile.
+    pub pending: bool,
+    /// Room picked in the hello; DEFAULT_ROOM until then.
+    pub room: String,
+    /// Drawing color the server assigned, sent in the Welcome.
+    pub color: EntityColor,
+    pub connected_at: Instant,
+    /// Traffic with this client since it connected.
+    pub stats: SharedStats,
+    /// Stamped on what the client draws; the same again when it reconnects.
+    pub owner: u64,
+}
+
+impl ClientInfo {
+    /// Said hello to `room` and was let in.
+    fn in_room(&self, room: &str) -> bool {
+        self.name.is_some() && !self.pending && self.room == room
+    }
+
+    /// "alice (192.168.1.5:53012)", or just the address until the client says hello.
+    pub fn label(&self) -> String {
+        let mut label = match &self.name {
+            Some(name) => format!("{} ({})", name, self.addr),
+            None => self.addr.to_string(),
+        };
+        if self.room != DEFAULT_ROOM {
+            label = format!("{} #{}", label, self.room);
+        }
+        if self.spectator {
+            format!("{} [spectator]", label)
+        } else if self.pending {
+            format!("{} [waiting]", label)
+        } else {
+            label
+        }
+    }
+}
+
+/// What the host asks of the server thread about one client.
+enum HostCommand {
+    Decide(JoinDecision),
+    Kick(String),
+}
+
+/// The host's answer to a guest waiting for approval.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JoinDecision {
+    Approve,
+    Deny,
+}
+
+/// Trims and shortens `requested`, then appends a numeric suffix if another
+/// client already goes by that name.
+fn unique_name(requested: &str, taken: &[&str]) -> String {
+    let base: String = requested.trim().chars().take(MAX_NAME_LEN).collect();
+    let base = if base.is_empty() {
+        "anonymous".to_string()
+    } else {
+        base
+    };
+
+    if !taken.contains(&base.as_str()) {
+        return base;
+    }
+    (2..)
+        .map(|n| format!("{}-{}", base, n))
+        .find(|candidate| !taken.contains(&candidate.as_str()))
+        .expect("suffixes are unbounded")
+}
+
+/// Trims and shortens a requested room name; an empty one means DEFAULT_ROOM.
+fn room_name(requested: &str) -> String {
+    let room: String = requested.trim().chars().take(MAX_NAME_LEN).collect();
+    if room.is_empty() {
+        DEFAULT_ROOM.to_string()
+    } else {
+        room
+    }
+}
+
+pub type ClientList = Arc<Mutex<Vec<ClientInfo>>>;
+
+/// Canvas of every room the server hosts, by room name.
+pub type Rooms = Arc<DashMap<String, Arc<Canvas>>>;
+
+/// Per-room counters that entities and appends are checked against.
+#[derive(Debug, Default)]
+struct RoomLog {
+    epoch: u64,
+    /// Every canvas transform so far; the generation is its length.
+    transforms: Vec<Transform>,
+}
+
+impl RoomLog {
+    fn generation(&self) -> u64 {
+        self.transforms.len() as u64
+    }
+
+    /// The transforms made after `generation`, combined, or None if there were none.
+    fn since(&self, generation: u64) -> Option<Transform> {
+        let missed = self.transforms.get(generation as usize..)?;
+        missed
+            .iter()
+            .copied()
+            .reduce(|combined, next| combined.then(next))
+    }
+
+    /// Moves an entity drawn before the latest transforms to where they put everything else.
+    fn bring_up_to_date(&self, entity: &mut Entity) {
+        if let Some(missed) = self.since(entity.generation) {
+            missed.apply_to(entity);
+        }
+        entity.generation = self.generation();
+    }
+}
+
+/// Canvases, epochs and transforms of the server's rooms. The host's own room
+/// always exists; any other room lives as long as someone is in it.
+struct RoomState {
+    canvases: Rooms,
+    logs: HashMap<String, RoomLog>,
+    host_room: String,
+    /// Background of the host's room; the others keep DEFAULT_BACKGROUND.
+    background: SharedBackground,
+    /// `--max-entities`: most entities a room keeps before the oldest go.
+    max_entities: usize,
+}
+
+impl RoomState {
+    fn new(
+        host_room: String,
+        host_canvas: Arc<Canvas>,
+        background: SharedBackground,
+        max_entities: usize,
+    ) -> Self {
+        let canvases: Rooms = Arc::new(DashMap::new());
+        canvases.insert(host_room.clone(), host_canvas);
+        Self {
+            canvases,
+            logs: HashMap::new(),
+            host_room,
+            background,
+            max_entities,
+        }
+    }
+
+    fn background(&self, room: &str) -> EntityColor {
+        if room == self.host_room {
+            EntityColor::from_bits(self.background.load(Ordering::Relaxed))
+        } else {
+            DEFAULT_BACKGROUND
+        }
+    }
+
+    /// Canvas of `room`, created empty for the first client to join it.
+    fn canvas(&self, room: &str) -> Arc<Canvas> {
+        if let Some(canvas) = self.canvases.get(room) {
+            return canvas.clone();
+        }
+        println!("Room {} opened", room);
+        self.canvases
+            .entry(room.to_string())
+            .or_default()
+            .value()
+            .clone()
+    }
+
+    fn log_mut(&mut self, room: &str) -> &mut RoomLog {
+        self.logs.entry(room.to_string()).or_default()
+    }
+
+    /// Drops the rooms nobody is in anymore, except the host's.
+    fn close_empty(&mut self, infos: &[ClientInfo]) {
+        let occupied =
+            |room: &String| *room == self.host_room || infos.iter().any(|info| info.room == *room);
+        self.canvases.retain(|room, _| {
+            let keep = occupied(room);
+            if !keep {
+                println!("Room {} closed", room);
+            }
+            keep
+        });
+        self.logs.retain(|room, _| occupied(room));
+    }
+}
+
+/// Last known pointer position of another peer.
+#[derive(Debug, Clone)]
+pub struct RemoteCursor {
+    pub x: f32,
+    pub y: f32,
+    pub color: EntityColor,
+    pub last_seen: Instant,
+}
+
+impl RemoteCursor {
+    fn new(x: f32, y: f32, color: EntityColor) -> Self {
+        Self {
+            x,
+            y,
+            color,
+            last_seen: Instant::now(),
+        }
+    }
+}
+
+pub type CursorMap = Arc<DashMap<u64, RemoteCursor>>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClientStatus {
+    Connecting,
+    Connected,
+    /// Connected, but the host has not let us in yet.
+    AwaitingApproval,
+    /// `server_closed` is set when the server said goodbye rather than vanishing.
+    Reconnecting {
+        attempt: u32,
+        server_closed: bool,
+    },
+    /// Turned away by the server; the client does not retry.
+    Rejected {
+        reason: String,
+    },
+    /// The server speaks another protocol version, or disagrees about TLS;
+    /// the client does not retry.
+    Incompatible {
+        reason: String,
+    },
+    /// Removed by the host; the client does not retry.
+    Kicked {
+        reason: String,
+    },
+}
+
+impl ClientStatus {
+    /// Whether the client has given up on the server and will not retry, so
+    /// nothing drawn from now on reaches anyone.
+    pub fn gave_up(&self) -> bool {
+        matches!(
+            self,
+            ClientStatus::Rejected { .. }
+                | ClientStatus::Incompatible { .. }
+                | ClientStatus::Kicked { .. }
+        )
+    }
+}
+
+pub type SharedClientStatus = Arc<Mutex<ClientStatus>>;
+
+/// Smoothed round-trip time to the server in milliseconds, or `NO_RTT`.
+pub type SharedRtt = Arc<AtomicU64>;
+
+/// Color the server assigned in its Welcome, once it has.
+pub type SharedColor = Arc<Mutex<Option<EntityColor>>>;
+
+/// What the server's Welcome said this client may change.
+pub type SharedPermissions = Arc<Mutex<Permissions>>;
+
+/// How long the server's Welcome said entities take to fade out, if they do.
+pub type SharedFade = Arc<Mutex<Option<Duration>>>;
+
+/// The other clients in this client's room as `(id, name)`, in the order
+/// they joined, as the server last told it.
+pub type SharedRoster = Arc<Mutex<Vec<(u64, String)>>>;
+
+/// Canvas background of the host's room, as the bits of an EntityColor: set
+/// by the host, and on a client by the server's SetBackground.
+pub type SharedBackground = Arc<AtomicU32>;
+
+/// Background of a canvas nobody has changed it on.
+pub const DEFAULT_BACKGROUND: EntityColor = EntityColor::WHITE;
+
+/// What this peer may change on the canvas.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Permissions {
+    /// Owner the server stamps on this peer's entities.
+    pub owner: u64,
+    /// Set by a server run with `--allow-erase-any`.
+    pub change_any: bool,
+}
+
+impl Permissions {
+    /// The host may change anything.
+    pub const HOST: Permissions = Permissions {
+        owner: HOST_OWNER,
+        change_any: true,
+    };
+    /// A client before its first Welcome.
+    pub const UNKNOWN: Permissions = Permissions {
+        owner: u64::MAX,
+        change_any: false,
+    };
+
+    /// Whether erasing, recoloring or undoing `entity` is allowed. What this
+    /// process drew counts as its own even before the server has stamped it.
+    pub fn may_change(&self, entity: &Entity) -> bool {
+        self.change_any
+            || entity.owner == self.owner
+            || (entity.owner == HOST_OWNER && entity::drawn_here(entity.id))
+    }
+}
+
+/// Traffic on one connection, or on the client's connection to the server,
+/// counted as it happens and read by the UI.
+#[derive(Debug, Default)]
+pub struct NetStats {
+    messages_sent: AtomicU64,
+    bytes_sent: AtomicU64,
+    messages_received: AtomicU64,
+    bytes_received: AtomicU64,
+    last_snapshot: AtomicU64,
+    queued: AtomicU64,
+}
+
+impl NetStats {
+    fn sent(&self, bytes: usize) {
+        self.messages_sent.fetch_add(1, Ordering::Relaxed);
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    fn read(&self, bytes: usize) {
+        self.bytes_received
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    fn received(&self, messages: usize) {
+        self.messages_received
+            .fetch_add(messages as u64, Ordering::Relaxed);
+    }
+
+    /// Size on the wire of the whole canvas, sent or received.
+    fn snapshot(&self, bytes: usize) {
+        self.last_snapshot.store(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Bytes waiting to be written to the connection.
+    fn queued(&self, bytes: usize) {
+        self.queued.store(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Starts over, for a new connection.
+    fn reset(&self) {
+        for counter in [
+            &self.messages_sent,
+            &self.bytes_sent,
+            &self.messages_received,
+            &self.bytes_received,
+            &self.last_snapshot,
+            &self.queued,
+        ] {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+
+    pub fn totals(&self) -> NetTotals {
+        NetTotals {
+            messages_sent: self.messages_sent.load(Ordering::Relaxed),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            messages_received: self.messages_received.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            last_snapshot: self.last_snapshot.load(Ordering::Relaxed),
+            queued: self.queued.load(Ordering::Relaxed),
+        }
+    }
+}
+
+pub type SharedStats = Arc<NetStats>;
+
+/// What a `NetStats` has counted so far.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NetTotals {
+    pub messages_sent: u64,
+    pub bytes_sent: u64,
+    pub messages_received: u64,
+    pub bytes_received: u64,
+    pub last_snapshot: u64,
+    /// Bytes the server has yet to write to a client.
+    pub queued: u64,
+}
+
+/// Turns totals sampled every frame into per-second rates, recomputed once a second.
+#[derive(Debug, Clone, Copy)]
+pub struct RateMeter {
+    since: Instant,
+    at_since: NetTotals,
+    rates: NetTotals,
+}
+
+impl RateMeter {
+    pub fn new(totals: NetTotals) -> Self {
+        Self {
+            since: Instant::now(),
+            at_since: totals,
+            rates: NetTotals::default(),
+        }
+    }
+
+    /// Rates over the last whole second, with `last_snapshot` and `queued`
+    /// passed through as they are.
+    pub fn update(&mut self, totals: NetTotals) -> NetTotals {
+        // the counters start over with every connection
+        let then = self.at_since;
+        if totals.messages_sent < then.messages_sent
+            || totals.bytes_sent < then.bytes_sent
+            || totals.messages_received < then.messages_received
+            || totals.bytes_received < then.bytes_received
+        {
+            *self = Self::new(totals);
+        }
+        let elapsed = self.since.elapsed().as_secs_f64();
+        if elapsed >= 1.0 {
+            let rate = |now: u64, then: u64| ((now - then) as f64 / elapsed).round() as u64;
+            self.rates = NetTotals {
+                messages_sent: rate(totals.messages_sent, self.at_since.messages_sent),
+                bytes_sent: rate(totals.bytes_sent, self.at_since.bytes_sent),
+                messages_received: rate(totals.messages_received, self.at_since.messages_received),
+                bytes_received: rate(totals.bytes_received, self.at_since.bytes_received),
+                last_snapshot: 0,
+                queued: 0,
+            };
+            self.since = Instant::now();
+            self.at_since = totals;
+        }
+        NetTotals {
+            last_snapshot: totals.last_snapshot,
+            queued: totals.queued,
+            ..self.rates
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Message {
+    NewEntity(Entity),
+    AllEntities(Vec<Entity>),
+    /// Several new entities produced in the same tick, framed once.
+    EntityBatch(Vec<Entity>),
+    RequestAllEntities,
+    /// Wipes every entity. `epoch` is the canvas generation after the clear;
+    /// for clears requested by the UI the network thread fills it in.
+    ClearCanvas {
+        epoch: u64,
+    },
+    /// Deletes one entity, e.g. when its author undoes it.
+    RemoveEntity {
+        id: usize,
+    },
+    /// Points added to an in-progress stroke since the last append.
+    StrokeAppend {
+        id: usize,
+        points: Vec<(f32, f32)>,
+        #[serde(default)]
+        pen: PenSamples,
+        /// Canvas transforms the sender had applied when it drew the points.
+        #[serde(default)]
+        generation: u64,
+    },
+    /// Moves every entity at once; only the host may ask for it. `generation` is
+    /// the number of transforms made so far, this one included; for transforms
+    /// requested by the UI the server fills it in.
+    TransformAll {
+        transform: Transform,
+        generation: u64,
+    },
+    /// Sent by the client every PING_INTERVAL; the payload comes back in the Pong.
+    Ping(u64),
+    Pong(u64),
+    /// An existing entity was recolored; ids the receiver does not have are ignored.
+    UpdateEntity {
+        id: usize,
+        color: EntityColor,
+    },
+    /// A chat line; the server replaces `from` with the name the client said hello with.
+    Chat {
+        from: String,
+        text: String,
+    },
+    /// Sent instead of anything else to a client the server will not take, right
+    /// before it closes the connection.
+    Rejected {
+        reason: String,
+    },
+    /// Answers the Hello: the color this client draws in by default, the owner
+    /// its entities are stamped with and whether it may change everyone's.
+    Welcome {
+        color: EntityColor,
+        #[serde(default)]
+        owner: u64,
+        #[serde(default)]
+        change_any: bool,
+        /// Set by a server run with `--fade-seconds`: how long entities take
+        /// to fade out before the server erases them.
+        #[serde(default)]
+        fade_ms: Option<u64>,
+        /// Answers the Hello's `compact`: set if both ends read CompactEntities.
+        #[serde(default)]
+        compact: bool,
+        /// New each time the server starts, unless it restores a `--persist`
+        /// canvas, so a client that sees it change knows the server lost
+        /// what it had; 0 from servers that have none.
+        #[serde(default)]
+        session: u64,
+    },
+    /// Sent on connect by a server that approves joins; the canvas follows an Approved.
+    AwaitingApproval,
+    Approved,
+    /// Sent by a peer that is shutting down, so the other side can drop it right away.
+    Disconnect,
+    /// First message a client sends, naming itself for the server's client list;
+    /// only a KnownEntities may come before it.
+    Hello {
+        name: String,
+        #[serde(default)]
+        spectator: bool,
+        /// Room to join; empty for DEFAULT_ROOM.
+        #[serde(default)]
+        room: String,
+        /// Random for each client process and never shown to other peers, so the
+        /// server knows it again after a reconnect; 0 for none.
+        #[serde(default)]
+        secret: u64,
+        /// Reads CompactEntities, and sends them if the Welcome says the same.
+        #[serde(default)]
+        compact: bool,
+    },
+    /// Pointer position of a peer, sent a few times per second. The server
+    /// replaces a client's `peer_id` with the id PeerJoined gave it.
+    Cursor {
+        peer_id: u64,
+        x: f32,
+        y: f32,
+        color: EntityColor,
+    },
+    /// Sent to a client the host removes, right before the server closes the connection.
+    Kicked {
+        reason: String,
+    },
+    /// Sent by a reconnecting client before its Hello: the entities it still has,
+    /// and the session, epoch and transform generation it last saw. If the
+    /// server's canvas can be trusted to match, only what is missing goes out,
+    /// in an EntityBatch.
+    KnownEntities {
+        epoch: u64,
+        generation: u64,
+        ids: Vec<usize>,
+        #[serde(default)]
+        session: u64,
+    },
+    /// The canvas background; only the host may change it. A
+    /// client joining the host's room gets it before the canvas, unless it is
+    /// still DEFAULT_BACKGROUND.
+    SetBackground(EntityColor),
+    /// Existing entities moved, as `(id, x, y)`: each one's position after the
+    /// move. Ids the receiver does not have are ignored.
+    MoveEntities {
+        moves: Vec<(usize, f32, f32)>,
+        /// Canvas transforms the sender had applied when it moved them.
+        #[serde(default)]
+        generation: u64,
+    },
+    /// A NewEntity or EntityBatch with its positions counted in steps, sent
+    /// only to a peer that agreed to read it. It never gets further than the
+    /// frame it came in: decoding turns it back into the message it stands for.
+    CompactEntities(CompactBatch),
+    /// Told to the rest of a room when a client is let in; `id` tells it apart
+    /// from another connection under the same name.
+    PeerJoined {
+        id: u64,
+        name: String,
+    },
+    /// Told to the rest of the room when a client that joined it goes, however
+    /// it went: goodbye, kick, timeout or a connection that failed.
+    PeerLeft {
+        id: u64,
+        name: String,
+    },
+    /// The clients already in the room, as `(id, name)` in the order they
+    /// joined: sent to a client when it is let in, before the canvas.
+    Roster(Vec<(u64, String)>),
+}
+
+impl Message {
+    /// Whether the message changes the canvas or shows the sender's pointer.
+    fn is_drawing(&self) -> bool {
+        self.changes_canvas() || matches!(self, Message::Cursor { .. })
+    }
+
+    /// Whether a client that missed the message is set right by the canvas it
+    /// is sent later: true of new and changed entities and of cursors, but not
+    /// of removals and clears, which a canvas merged into the client's cannot undo.
+    fn is_repairable(&self) -> bool {
+        matches!(
+            self,
+            Message::NewEntity(_)
+                | Message::EntityBatch(_)
+                | Message::StrokeAppend { .. }
+                | Message::UpdateEntity { .. }
+                | Message::MoveEntities { .. }
+                | Message::Cursor { .. }
+        )
+    }
+
+    /// Whether applying the message adds, removes or alters entities.
+    pub fn changes_canvas(&self) -> bool {
+        matches!(
+            self,
+            Message::NewEntity(_)
+                | Message::AllEntities(_)
+                | Message::EntityBatch(_)
+                | Message::ClearCanvas { .. }
+                | Message::RemoveEntity { .. }
+                | Message::StrokeAppend { .. }
+                | Message::UpdateEntity { .. }
+                | Message::TransformAll { .. }
+                | Message::MoveEntities { .. }
+        )
+    }
+
+    /// New entities in the compact encoding, or None for any other message and
+    /// for entities that do not fit in it.
+    fn compacted(&self) -> Option<Message> {
+        let entities = match self {
+            Message::NewEntity(entity) => std::slice::from_ref(entity),
+            Message::EntityBatch(batch) => batch,
+            _ => return None,
+        };
+        CompactBatch::try_from(entities)
+            .ok()
+            .map(Message::CompactEntities)
+    }
+
+    /// The message a CompactEntities stands for, a single entity coming back
+    /// as a NewEntity; any other message as it is.
+    fn expanded(self) -> Result<Message, String> {
+        let Message::CompactEntities(batch) = self else {
+            return Ok(self);
+        };
+        if !batch.is_readable() {
+            return Err(format!("compact entities at a scale of {}", batch.scale));
+        }
+        let mut entities: Vec<Entity> = batch.into();
+        Ok(match entities.len() {
+            1 => Message::NewEntity(entities.remove(0)),
+            _ => Message::EntityBatch(entities),
+        })
+    }
+}
+
+/// Payload encoding used inside the length-prefixed frames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WireFormat {
+    Json,
+    Bincode,
+}
+
+impl WireFormat {
+    fn encode(self, message: &Message) -> io::Result<Vec<u8>> {
+        match self {
+            WireFormat::Json => serde_json::to_vec(message)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            WireFormat::Bincode => {
+                bincode::serde::encode_to_vec(message, bincode::config::standard())
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+            }
+        }
+    }
+
+    fn decode(self, data: &[u8]) -> Result<Message, String> {
+        match self {
+            WireFormat::Json => serde_json::from_slice::<Message>(data).map_err(|e| e.to_string()),
+            WireFormat::Bincode => {
+                let (message, used) = bincode::serde::decode_from_slice::<Message, _>(
+                    data,
+                    bincode::config::standard(),
+                )
+                .map_err(|e| e.to_string())?;
+                // like JSON, a frame holds one message and nothing after it; a
+                // resync landing inside a frame could otherwise read a prefix of it
+                if used != data.len() {
+                    return Err(format!("{} bytes after the message", data.len() - used));
+                }
+                Ok(message)
+            }
+        }
+    }
+
+    // A JSON message is always an object or a bare string (unit variant),
+    // while bincode starts with the small variant index.
+    fn detect(data: &[u8]) -> Self {
+        match data.first() {
+            Some(b'{') | Some(b'"') => WireFormat::Json,
+            _ => WireFormat::Bincode,
+        }
+    }
+}
+
+impl FromStr for WireFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(WireFormat::Json),
+            "bincode" => Ok(WireFormat::Bincode),
+            other => Err(format!("Unknown wire format: {}", other)),
+        }
+    }
+}
+
+/// Why a frame was dropped. None of these end the connection: the handler
+/// scans on for the next frame that decodes, without reporting whatever it
+/// skips on the way.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FrameError {
+    /// A length prefix beyond MAX_BUFFER_SIZE, most likely garbage.
+    TooLarge(usize),
+    /// A compressed payload that does not inflate.
+    BadCompression(String),
+    /// A compressed payload that would inflate beyond MAX_DECOMPRESSED_SIZE.
+    InflatesTooLarge,
+    /// A payload that is no message this build knows.
+    Undecodable { format: WireFormat, reason: String },
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::TooLarge(len) => write!(
+                f,
+                "Received suspiciously large message size: {}, resynchronizing",
+                len
+            ),
+            FrameError::BadCompression(e) => write!(f, "Error inflating message: {}", e),
+            FrameError::InflatesTooLarge => write!(
+                f,
+                "Compressed message inflates beyond {} bytes, dropping it",
+                MAX_DECOMPRESSED_SIZE
+            ),
+            FrameError::Undecodable { format, reason } => {
+                write!(f, "Error decoding {:?} message: {}", format, reason)
+            }
+        }
+    }
+}
+
+/// What the front of a `MessageHandler` buffer holds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ReadState {
+    /// The peer's handshake, still to be checked.
+    Handshake,
+    /// A length prefix and the payload it announces.
+    Frame,
+    /// Bytes after a frame that could not be read, or from an oversized
+    /// length prefix on. Every offset is tried as the start of a frame, and the
+    /// first that decodes puts the stream back in step.
+    Resync,
+}
+
+/// Reassembles messages from the bytes one peer sends, whatever pieces they arrive in.
+pub struct MessageHandler {
+    buffer: Vec<u8>,
+    state: ReadState,
+    peer_format: Option<WireFormat>,
+    /// Size on the wire of the frame the last message came in.
+    last_frame_len: usize,
+    /// While resynchronizing: offsets below this have been looked at already.
+    scanned: usize,
+    /// While resynchronizing: offsets below `scanned`, in order, whose frame
+    /// has not all arrived yet, so may still decode. Every other offset
+    /// below `scanned` never will.
+    waiting: Vec<usize>,
+}
+
+impl Default for MessageHandler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MessageHandler {
+    pub fn new() -> Self {
+        Self {
+            buffer: Vec::with_capacity(BUFFER_CAPACITY),
+            state: ReadState::Frame,
+            peer_format: None,
+            last_frame_len: 0,
+            scanned: 0,
+            waiting: Vec::new(),
+        }
+    }
+
+    /// A handler that reads the peer's handshake before any frame.
+    pub fn expecting_handshake() -> Self {
+        Self {
+            state: ReadState::Handshake,
+            ..Self::new()
+        }
+    }
+
+    /// Takes the peer's handshake off the buffer once all of it has arrived.
+    /// Ok right away for a handler that expects none.
+    pub fn take_handshake(&mut self) -> Option<Result<(), String>> {
+        if self.state != ReadState::Handshake {
+            return Some(Ok(()));
+        }
+        let bytes: [u8; HANDSHAKE_LEN] = self.buffer.get(..HANDSHAKE_LEN)?.try_into().unwrap();
+        self.buffer.drain(..HANDSHAKE_LEN);
+        self.state = ReadState::Frame;
+        Some(check_handshake(&bytes))
+    }
+
+    /// Size on the wire of the frame `next_message` last returned a message from.
+    pub fn last_frame_len(&self) -> usize {
+        self.last_frame_len
+    }
+
+    /// Format the peer was last seen speaking, falling back to ours until it has sent anything.
+    pub fn reply_format(&self, fallback: WireFormat) -> WireFormat {
+        self.peer_format.unwrap_or(fallback)
+    }
+
+    pub fn extend_buffer(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+
+    /// The next whole message in the buffer, or an error for a frame that had
+    /// to be dropped; None until more bytes arrive.
+    pub fn next_message(&mut self) -> Option<Result<Message, FrameError>> {
+        match self.state {
+            ReadState::Handshake => None,
+            ReadState::Frame => self.next_frame(),
+            ReadState::Resync => self.resync(),
+        }
+    }
+
+    fn next_frame(&mut self) -> Option<Result<Message, FrameError>> {
+        let msg_len = frame_len(&self.buffer, 0)?;
+        if msg_len > MAX_BUFFER_SIZE {
+            self.state = ReadState::Resync;
+            return Some(Err(FrameError::TooLarge(msg_len)));
+        }
+        let payload = self.buffer.get(4..4 + msg_len)?;
+        let result = decode_frame(payload);
+        self.buffer.drain(..4 + msg_len);
+        match result {
+            Ok((format, message)) => {
+                self.last_frame_len = 4 + msg_len;
+                Some(Ok(self.heard(format, message)))
+            }
+            Err(e) => {
+                // the prefix may have been garbage too, so the next one is not trusted either
+                self.state = ReadState::Resync;
+                Some(Err(e))
+            }
+        }
+    }
+
+    /// Looks for the first offset holding a frame that decodes, and carries on
+    /// from there. Until one turns up, bytes are only dropped once no frame
+    /// could start at them, so the buffer stays within one frame of the last
+    /// offset that might. Each call picks up where the last one stopped, so
+    /// the bytes in the buffer are looked at once rather than on every read.
+    fn resync(&mut self) -> Option<Result<Message, FrameError>> {
+        let end = self.buffer.len().saturating_sub(3);
+        let mut found = None;
+        let mut waiting = Vec::with_capacity(self.waiting.len());
+        for &offset in &self.waiting {
+            match found.is_none().then(|| self.candidate(offset)) {
+                Some(Candidate::Decodes(frame)) => found = Some((offset, frame)),
+                Some(Candidate::Garbage) => {}
+                Some(Candidate::Arriving) | None => waiting.push(offset),
+            }
+        }
+        self.waiting = waiting;
+        while found.is_none() && self.scanned < end {
+            let offset = self.scanned;
+            self.scanned += 1;
+            match self.candidate(offset) {
+                Candidate::Decodes(frame) => found = Some((offset, frame)),
+                Candidate::Garbage => {}
+                Candidate::Arriving => self.waiting.push(offset),
+            }
+        }
+
+        if let Some((offset, (msg_len, format, message))) = found {
+            self.buffer.drain(..offset + 4 + msg_len);
+            self.last_frame_len = 4 + msg_len;
+            self.state = ReadState::Frame;
+            self.scanned = 0;
+            self.waiting.clear();
+            return Some(Ok(self.heard(format, message)));
+        }
+        let unusable = self.waiting.first().copied().unwrap_or(self.scanned);
+        self.buffer.drain(..unusable);
+        self.scanned -= unusable;
+        for offset in &mut self.waiting {
+            *offset -= unusable;
+        }
+        None
+    }
+
+    /// What a frame starting at `offset` would be.
+    fn candidate(&self, offset: usize) -> Candidate {
+        let Some(msg_len) = frame_len(&self.buffer, offset).filter(|&n| n <= MAX_BUFFER_SIZE)
+        else {
+            return Candidate::Garbage;
+        };
+        let Some(payload) = self.buffer.get(offset + 4..offset + 4 + msg_len) else {
+            return Candidate::Arriving;
+        };
+        match decode_frame(payload) {
+            Ok((format, message)) => Candidate::Decodes((msg_len, format, message)),
+            Err(_) => Candidate::Garbage,
+        }
+    }
+
+    fn heard(&mut self, format: WireFormat, message: Message) -> Message {
+        if self.peer_format.is_some_and(|known| known != format) {
+            println!("Peer switched wire format to {:?}", format);
+        }
+        self.peer_format = Some(format);
+        message
+    }
+}
+
+/// A place in a garbled buffer a frame might start.
+enum Candidate {
+    /// A frame that decodes, with its length and what it holds.
+    Decodes((usize, WireFormat, Message)),
+    /// No frame could start here, however many bytes follow.
+    Garbage,
+    /// A frame whose payload has not all arrived yet.
+    Arriving,
+}
+
+/// The length prefix at `offset`, once all four bytes of it are there.
+fn frame_len(buffer: &[u8], offset: usize) -> Option<usize> {
+    let len_bytes: [u8; 4] = buffer.get(offset..offset + 4)?.try_into().unwrap();
+    Some(u32::from_le_bytes(len_bytes) as usize)
+}
+
+fn decode_frame(payload: &[u8]) -> Result<(WireFormat, Message), FrameError> {
+    let data = decompress(payload)?;
+    let format = WireFormat::detect(&data);
+    format
+        .decode(&data)
+        .and_then(Message::expanded)
+        .map(|message| (format, message))
+        .map_err(|reason| FrameError::Undecodable { format, reason })
+}
+
+/// Queues `message`, folding it into a NewEntity or EntityBatch at the back of
+/// the queue so a run of new entities goes out as one frame. Anything else
+/// keeps its place in the order.
+fn push_batched(queue: &mut VecDeque<Message>, message: Message) {
+    let Message::NewEntity(entity) = message else {
+        queue.push_back(message);
+        return;
+    };
+
+    if let Some(Message::EntityBatch(batch)) = queue.back_mut()
+        && batch.len() < MAX_BATCH_LEN
+    {
+        batch.push(entity);
+        return;
+    }
+
+    match queue.pop_back() {
+        Some(Message::NewEntity(previous)) => {
+            queue.push_back(Message::EntityBatch(vec![previous, entity]));
+        }
+        back => {
+            queue.extend(back);
+            queue.push_back(Message::NewEntity(entity));
+        }
+    }
+}
+
+/// This process's Hello secret.
+fn client_secret() -> u64 {
+    static SECRET: OnceLock<u64> = OnceLock::new();
+    *SECRET.get_or_init(|| RandomState::new().build_hasher().finish() | 1)
+}
+
+/// A new server session: random, and never 0, which stands for none.
+pub(crate) fn new_session() -> u64 {
+    RandomState::new().build_hasher().finish() | 1
+}
+
+/// What this build opens every connection with.
+pub fn handshake() -> [u8; HANDSHAKE_LEN] {
+    let mut bytes = [0; HANDSHAKE_LEN];
+    bytes[..HANDSHAKE_MAGIC.len()].copy_from_slice(HANDSHAKE_MAGIC);
+    bytes[HANDSHAKE_MAGIC.len()..].copy_from_slice(&PROTOCOL_VERSION.to_le_bytes());
+    bytes
+}
+
+/// Checks the peer's handshake, explaining a version mismatch.
+fn check_handshake(bytes: &[u8; HANDSHAKE_LEN]) -> Result<(), String> {
+    if bytes[0] == TLS_HANDSHAKE_RECORD {
+        return Err("peer speaks TLS, but this end was started without it".into());
+    }
+    let (magic, version) = bytes.split_at(HANDSHAKE_MAGIC.len());
+    let version = if magic == HANDSHAKE_MAGIC {
+        u16::from_le_bytes([version[0], version[1]])
+    } else {
+        UNVERSIONED_PROTOCOL
+    };
+    if version == PROTOCOL_VERSION {
+        Ok(())
+    } else {
+        Err(version_mismatch(version))
+    }
+}
+
+fn version_mismatch(version: u16) -> String {
+    format!(
+        "peer speaks protocol v{}, this build requires v{}",
+        version, PROTOCOL_VERSION
+    )
+}
+
+/// Deflates large payloads behind `COMPRESSED_TAG`, keeping whichever is smaller.
+fn compress(data: Vec<u8>) -> Vec<u8> {
+    if data.len() < COMPRESSION_THRESHOLD {
+        return data;
+    }
+    let mut encoder = ZlibEncoder::new(vec![COMPRESSED_TAG], Compression::fast());
+    if encoder.write_all(&data).is_err() {
+        return data;
+    }
+    match encoder.finish() {
+        Ok(compressed) if compressed.len() < data.len() => compressed,
+        _ => data,
+    }
+}
+
+/// Inflates a payload sent by `compress`, passing any other payload through.
+fn decompress(data: &[u8]) -> Result<Cow<'_, [u8]>, FrameError> {
+    let Some((&COMPRESSED_TAG, compressed)) = data.split_first() else {
+        return Ok(Cow::Borrowed(data));
+    };
+    let mut inflated = Vec::new();
+    ZlibDecoder::new(compressed)
+        .take(MAX_DECOMPRESSED_SIZE as u64 + 1)
+        .read_to_end(&mut inflated)
+        .map_err(|e| FrameError::BadCompression(e.to_string()))?;
+    if inflated.len() > MAX_DECOMPRESSED_SIZE {
+        return Err(FrameError::InflatesTooLarge);
+    }
+    Ok(Cow::Owned(inflated))
+}
+
+pub fn frame_message(message: &Message, format: WireFormat) -> io::Result<Vec<u8>> {
+    let data = compress(format.encode(message)?);
+
+    let msg_len = data.len() as u32;
+    let mut framed_data = Vec::with_capacity(4 + data.len());
+    framed_data.extend_from_slice(&msg_len.to_le_bytes());
+    framed_data.extend_from_slice(&data);
+
+    Ok(framed_data)
+}
+
+pub fn send_message(
+    stream: &mut impl Write,
+    message: &Message,
+    format: WireFormat,
+) -> io::Result<()> {
+    let framed_data = frame_message(message, format)?;
+    stream.write_all(&framed_data)?;
+    stream.flush()?;
+    Ok(())
+}
+
+/// Frames waiting to be written to one client. Broadcast frames are shared
+/// between every recipient's queue rather than copied.
+#[derive(Debug, Default)]
+pub struct Outbound {
+    frames: VecDeque<Queued>,
+    queued: usize,
+    /// Of `queued`, the bytes in drawing frames, which alone count against
+    /// OUTBOUND_BUDGET.
+    drawing_queued: usize,
+    /// Canvas frames not yet written in full.
+    canvases: usize,
+    /// Drawing frames dropped since the client last caught up.
+    skipped: usize,
+}
+
+#[derive(Debug)]
+struct Queued {
+    frame: Arc<[u8]>,
+    /// How much of the frame the socket has taken.
+    written: usize,
+    traffic: Traffic,
+}
+
+/// What a queued frame carries, which decides whether it may be dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Traffic {
+    /// Has to arrive, like a clear, a kick or a chat line.
+    Control,
+    /// May be dropped for the canvas to make up for.
+    Drawing,
+    /// The canvas, on joining or making up for dropped drawing; has to arrive.
+    Canvas,
+}
+
+impl Outbound {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Bytes still to be written.
+    pub fn queued(&self) -> usize {
+        self.queued
+    }
+
+    /// Drawing frames dropped since the client last caught up.
+    pub fn skipped(&self) -> usize {
+        self.skipped
+    }
+
+    /// Queues a frame that must arrive, such as a clear or a kick. Fails if
+    /// that would put more than MAX_OUTBOUND_BYTES in the queue even with
+    /// every drawing frame not yet begun dropped; the caller then drops the
+    /// client.
+    pub fn push(&mut self, frame: Arc<[u8]>) -> io::Result<()> {
+        self.push_as(frame, Traffic::Control)
+    }
+
+    /// `push` for a canvas. Until it has been written a client is not caught up.
+    pub fn push_canvas(&mut self, frame: Arc<[u8]>) -> io::Result<()> {
+        self.push_as(frame, Traffic::Canvas)
+    }
+
+    /// Queues a drawing frame, which is dropped, oldest first among those not
+    /// yet begun, whenever the drawing queued is over OUTBOUND_BUDGET; this
+    /// one too, if nothing older is left to drop.
+    pub fn push_drawing(&mut self, frame: Arc<[u8]>) {
+        self.queue(frame, Traffic::Drawing);
+        self.shed(|_, drawing| drawing <= OUTBOUND_BUDGET);
+    }
+
+    /// `push`, `push_canvas` or `push_drawing`, going by `traffic`.
+    pub fn push_as(&mut self, frame: Arc<[u8]>, traffic: Traffic) -> io::Result<()> {
+        if traffic == Traffic::Drawing {
+            self.push_drawing(frame);
+            return Ok(());
+        }
+        let len = frame.len();
+        self.shed(|queued, _| queued + len <= MAX_OUTBOUND_BYTES);
+        if self.queued + len > MAX_OUTBOUND_BYTES {
+            return Err(io::Error::other("client is not keeping up"));
+        }
+        self.queue(frame, traffic);
+        Ok(())
+    }
+
+    /// Whether the client had drawing frames dropped and has since drained its
+    /// queue, any canvas in it included, to a quarter of the budget, so a
+    /// canvas sent now would soon arrive. Starts the count of skipped frames
+    /// over.
+    pub fn caught_up(&mut self) -> bool {
+        if self.skipped == 0 || self.canvases > 0 || self.queued > OUTBOUND_BUDGET / 4 {
+            return false;
+        }
+        self.skipped = 0;
+        true
+    }
+
+    fn queue(&mut self, frame: Arc<[u8]>, traffic: Traffic) {
+        self.queued += frame.len();
+        match traffic {
+            Traffic::Drawing => self.drawing_queued += frame.len(),
+            Traffic::Canvas => self.canvases += 1,
+            Traffic::Control => {}
+        }
+        self.frames.push_back(Queued {
+            frame,
+            written: 0,
+            traffic,
+        });
+    }
+
+    /// Drops drawing frames, oldest first, until `fits` the bytes queued and
+    /// the drawing bytes among them.
+    fn shed(&mut self, fits: impl Fn(usize, usize) -> bool) {
+        let Self {
+            frames,
+            queued,
+            drawing_queued,
+            skipped,
+            ..
+        } = self;
+        if fits(*queued, *drawing_queued) {
+            return;
+        }
+        frames.retain(|queued_frame| {
+            // a frame cut off halfway would garble everything after it
+            if fits(*queued, *drawing_queued)
+                || queued_frame.traffic != Traffic::Drawing
+                || queued_frame.written > 0
+            {
+                return true;
+            }
+            *queued -= queued_frame.frame.len();
+            *drawing_queued -= queued_frame.frame.len();
+            *skipped += 1;
+            false
+        });
+    }
+
+    /// Writes as much as the socket takes, keeping the offset into a partly
+    /// written frame for the next call. WouldBlock only ends the call; any
+    /// other error is the connection's end.
+    pub fn flush(&mut self, stream: &mut impl Write) -> io::Result<()> {
+        while let Some(front) = self.frames.front_mut() {
+            match stream.write(&front.frame[front.written..]) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    front.written += n;
+                    self.queued -= n;
+                    if front.traffic == Traffic::Drawing {
+                        self.drawing_queued -= n;
+                    }
+                    if front.written == front.frame.len() {
+                        if front.traffic == Traffic::Canvas {
+                            self.canvases -= 1;
+                        }
+                        self.frames.pop_front();
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        stream.flush()
+    }
+}
+
+/// How a message is put on the wire for one peer, the compact encoding aside.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Encoding {
+    /// Length-prefixed, and deflated when large, in the peer's wire format.
+    Framed(WireFormat),
+    /// A bare JSON payload, for transports that delimit messages themselves.
+    #[cfg(feature = "websocket")]
+    Bare,
+}
+
+impl Encoding {
+    fn encode(self, message: &Message) -> io::Result<Vec<u8>> {
+        match self {
+            Encoding::Framed(format) => frame_message(message, format),
+            #[cfg(feature = "websocket")]
+            Encoding::Bare => WireFormat::Json.encode(message),
+        }
+    }
+
+    /// `encode`, with new entities in the compact encoding for a peer that reads it.
+    fn encode_for(self, compact: bool, message: &Message) -> io::Result<Vec<u8>> {
+        match compact.then(|| message.compacted()).flatten() {
+            Some(compacted) => self.encode(&compacted),
+            None => self.encode(message),
+        }
+    }
+}
+
+/// Why a peer's connection is over.
+enum Closed {
+    /// The peer hung up.
+    Gone,
+    /// The peer is not speaking our protocol, for the given reason.
+    Refused(String),
+    Failed(io::Error),
+}
+
+/// What one peer's socket had to read this time round.
+#[derive(Default)]
+struct Incoming {
+    messages: Vec<Message>,
+    /// Whether anything at all arrived, keepalives included.
+    heard: bool,
+    /// Bytes read off the socket.
+    bytes: usize,
+    closed: Option<Closed>,
+}
+
+/// One client connection, whichever listener it came in on.
+enum Peer {
+    Tcp {
+        stream: mio::net::TcpStream,
+        handler: MessageHandler,
+        outbound: Outbound,
+    },
+    Tls(Box<tls::TlsPeer>),
+    #[cfg(feature = "websocket")]
+    WebSocket(Box<websocket::WsPeer>),
+}
+
+impl Peer {
+    fn tcp(stream: mio::net::TcpStream) -> Self {
+        let mut outbound = Outbound::new();
+        // ours goes out first; the client's is checked as it arrives
+        let _ = outbound.push(handshake().as_slice().into());
+        Peer::Tcp {
+            stream,
+            handler: MessageHandler::expecting_handshake(),
+            outbound,
+        }
+    }
+
+    fn encoding(&self, fallback: WireFormat) -> Encoding {
+        match self {
+            Peer::Tcp { handler, .. } => Encoding::Framed(handler.reply_format(fallback)),
+            Peer::Tls(peer) => Encoding::Framed(peer.reply_format(fallback)),
+            #[cfg(feature = "websocket")]
+            Peer::WebSocket(_) => Encoding::Bare,
+        }
+    }
+
+    /// Queues an encoded message and writes as much as the socket takes.
+    /// Drawing may be dropped for a client that is behind; a WebSocket client
+    /// is held to tungstenite's write buffer instead.
+    fn send(&mut self, encoded: Arc<[u8]>, traffic: Traffic) -> io::Result<()> {
+        match self {
+            Peer::Tcp {
+                stream, outbound, ..
+            } => {
+                outbound.push_as(encoded, traffic)?;
+                outbound.flush(stream)
+            }
+            Peer::Tls(peer) => peer.send(encoded, traffic),
+            #[cfg(feature = "websocket")]
+            Peer::WebSocket(peer) => peer.send(encoded),
+        }
+    }
+
+    /// The queue a TCP or TLS client's messages wait in.
+    fn outbound(&mut self) -> Option<&mut Outbound> {
+        match self {
+            Peer::Tcp { outbound, .. } => Some(outbound),
+            Peer::Tls(peer) => Some(peer.outbound()),
+            #[cfg(feature = "websocket")]
+            Peer::WebSocket(_) => None,
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Peer::Tcp {
+                stream, outbound, ..
+            } => outbound.flush(stream),
+            Peer::Tls(peer) => peer.flush(),
+            #[cfg(feature = "websocket")]
+            Peer::WebSocket(peer) => peer.flush(),
+        }
+    }
+
+    /// Reads until the socket would block; readiness is edge-triggered.
+    fn receive(&mut self) -> Incoming {
+        match self {
+            Peer::Tcp {
+                stream, handler, ..
+            } => receive_framed(stream, handler),
+            Peer::Tls(peer) => peer.receive(),
+            #[cfg(feature = "websocket")]
+            Peer::WebSocket(peer) => peer.receive(),
+        }
+    }
+}
+
+fn receive_framed(stream: &mut impl Read, handler: &mut MessageHandler) -> Incoming {
+    let mut incoming = Incoming::default();
+    let mut buffer = [0; 4096];
+    while incoming.closed.is_none() {
+        match stream.read(&mut buffer) {
+            Ok(0) => incoming.closed = Some(Closed::Gone),
+            Ok(n) => {
+                incoming.heard = true;
+                incoming.bytes += n;
+                take_messages(handler, &buffer[..n], &mut incoming);
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => incoming.closed = Some(Closed::Failed(e)),
+        }
+    }
+    incoming
+}
+
+/// Hands bytes off the connection to `handler`, collecting every whole message.
+fn take_messages(handler: &mut MessageHandler, bytes: &[u8], incoming: &mut Incoming) {
+    handler.extend_buffer(bytes);
+    match handler.take_handshake() {
+        Some(Ok(())) => {}
+        None => return,
+        Some(Err(e)) => {
+            incoming.closed = Some(Closed::Refused(e));
+            return;
+        }
+    }
+    while let Some(message_result) = handler.next_message() {
+        match message_result {
+            Ok(message) => incoming.messages.push(message),
+            Err(e) => eprintln!("{}", e),
+        }
+    }
+}
+
+/// Connections and info of every connected client, kept index-aligned.
+struct Connections {
+    peers: Vec<Connection>,
+    tokens: Vec<Token>,
+    infos: Vec<ClientInfo>,
+    format: WireFormat,
+    failed: Vec<usize>,
+    last_heard: Vec<Instant>,
+    /// What each client said it already has, until its canvas goes out.
+    known: Vec<Option<KnownCanvas>>,
+    /// Whether each client said in its Hello that it reads CompactEntities.
+    compact: Vec<bool>,
+    /// Sent in every Welcome; see there.
+    session: u64,
+    /// Owner of each Hello secret seen so far, and the next owner to hand out.
+    owners: HashMap<u64, u64>,
+    next_owner: u64,
+    /// `--allow-erase-any`: clients may change each other's entities.
+    change_any: bool,
+    /// `--fade-seconds`, told to every client in its Welcome.
+    fade: Option<Duration>,
+    client_list: ClientList,
+    /// Records every change to this room's canvas as it goes out to the room.
+    recording: Option<(String, Recorder)>,
+    /// Clients dropped for falling behind or going silent, for the host's window.
+    notices: Notices,
+}
+
+impl Connections {
+    fn new(
+        format: WireFormat,
+        change_any: bool,
+        fade: Option<Duration>,
+        client_list: ClientList,
+        recording: Option<(String, Recorder)>,
+        notices: Notices,
+        session: u64,
+    ) -> Self {
+        Self {
+            peers: Vec::new(),
+            tokens: Vec::new(),
+            infos: Vec::new(),
+            format,
+            failed: Vec::new(),
+            last_heard: Vec::new(),
+            known: Vec::new(),
+            compact: Vec::new(),
+            session,
+            owners: HashMap::new(),
+            next_owner: HOST_OWNER + 1,
+            change_any,
+            fade,
+            client_list,
+            recording,
+            notices,
+        }
+    }
+
+    /// Mirrors `infos` into the list the UI renders.
+    fn publish(&self) {
+        if let Ok(mut client_list) = self.client_list.lock() {
+            client_list.clone_from(&self.infos);
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    fn index_of(&self, token: Token) -> Option<usize> {
+        self.tokens.iter().position(|&t| t == token)
+    }
+
+    fn push(&mut self, peer: Connection, token: Token, info: ClientInfo) {
+        self.peers.push(peer);
+        self.tokens.push(token);
+        self.infos.push(info);
+        self.last_heard.push(Instant::now());
+        self.known.push(None);
+        self.compact.push(false);
+        self.publish();
+    }
+
+    /// Why a new client cannot come in right now, for the log and for the client.
+    fn refusal(&self, max_clients: Option<usize>, approve_joins: bool) -> Option<(String, String)> {
+        if let Some(max) = max_clients
+            && self.len() - self.pending_count() >= max
+        {
+            let reason = format!("The server is full (limit {})", max);
+            return Some(("server is full".to_string(), reason));
+        }
+        if approve_joins && self.pending_count() >= MAX_PENDING {
+            let reason = "Too many guests are waiting for approval".to_string();
+            return Some(("too many guests waiting".to_string(), reason));
+        }
+        None
+    }
+
+    /// Takes in a peer whose socket is registered with `poll`, starts its
+    /// thread and greets it.
+    fn admit(
+        &mut self,
+        peer: Peer,
+        poll: Poll,
+        token: Token,
+        addr: SocketAddr,
+        approve_joins: bool,
+        reports: &Reports,
+    ) {
+        let stats = SharedStats::default();
+        let reports = reports.clone();
+        let peer = match Connection::spawn(peer, poll, token, self.format, stats.clone(), reports) {
+            Ok(peer) => peer,
+            Err(e) => {
+                eprintln!("Error starting a thread for client {}: {}", addr, e);
+                return;
+            }
+        };
+        println!("New client connected: {}", addr);
+        let color = self.free_color();
+        let client_info = ClientInfo {
+            addr,
+            name: None,
+            spectator: false,
+            pending: approve_joins,
+            room: DEFAULT_ROOM.to_string(),
+            color,
+            connected_at: Instant::now(),
+            stats,
+            owner: HOST_OWNER,
+        };
+        self.push(peer, token, client_info);
+        let idx = self.len() - 1;
+
+        // the welcome and canvas go out once the hello says who it is and which room to join
+        if approve_joins {
+            println!("Client {} is waiting for approval", addr);
+            self.send_to(idx, &Message::AwaitingApproval);
+        }
+    }
+
+    /// How PeerJoined and PeerLeft name the connection: its token, which no
+    /// other connection to this server ever has.
+    fn peer_id(&self, idx: usize) -> u64 {
+        self.tokens[idx].0 as u64
+    }
+
+    fn remove(&mut self, idx: usize) -> ClientInfo {
+        self.peers.remove(idx);
+        self.tokens.remove(idx);
+        self.last_heard.remove(idx);
+        self.known.remove(idx);
+        self.compact.remove(idx);
+        let info = self.infos.remove(idx);
+        self.publish();
+        info
+    }
+
+    /// Sends in the format the client speaks; a failed client is queued for removal.
+    fn send_to(&mut self, idx: usize, message: &Message) {
+        self.send_as(idx, message, Traffic::Control);
+    }
+
+    /// `send_to` for a canvas, or the part of one the client is missing,
+    /// counted as the client's last snapshot.
+    fn send_snapshot(&mut self, idx: usize, message: &Message) {
+        let len = self.send_as(idx, message, Traffic::Canvas);
+        self.infos[idx].stats.snapshot(len);
+    }
+
+    /// Returns the size of the frame, or 0 if it could not be encoded.
+    fn send_as(&mut self, idx: usize, message: &Message, traffic: Traffic) -> usize {
+        let encoding = self.peers[idx].encoding();
+        match encoding.encode_for(self.compact[idx], message) {
+            Ok(frame) => {
+                let len = frame.len();
+                self.enqueue(idx, frame.into(), traffic);
+                len
+            }
+            Err(e) => {
+                eprintln!("Error encoding message: {}", e);
+                0
+            }
+        }
+    }
+
+    /// Sends to every client except `skip`, encoding once per encoding in use.
+    /// With a `room`, only admitted clients that joined that room get it.
+    fn broadcast(&mut self, message: &Message, room: Option<&str>, skip: Option<usize>) {
+        if let Some((recorded_room, recorder)) = &self.recording
+            && room == Some(recorded_room.as_str())
+        {
+            recorder.record(message);
+        }
+        // keyed by the encoding and whether it is compact
+        let mut frames: Vec<(Encoding, bool, Arc<[u8]>)> = Vec::new();
+        for idx in 0..self.len() {
+            if Some(idx) == skip {
+                continue;
+            }
+            if let Some(room) = room
+                && !self.infos[idx].in_room(room)
+            {
+                continue;
+            }
+            let (encoding, compact) = (self.peers[idx].encoding(), self.compact[idx]);
+            let frame = match frames
+                .iter()
+                .find(|(e, c, _)| (*e, *c) == (encoding, compact))
+            {
+                Some((.., frame)) => frame.clone(),
+                None => match encoding.encode_for(compact, message) {
+                    Ok(frame) => {
+                        let frame: Arc<[u8]> = frame.into();
+                        frames.push((encoding, compact, frame.clone()));
+                        frame
+                    }
+                    Err(e) => {
+                        eprintln!("Error encoding message: {}", e);
+                        return;
+                    }
+                },
+            };
+            let traffic = if message.is_repairable() {
+                Traffic::Drawing
+            } else {
+                Traffic::Control
+            };
+            self.enqueue(idx, frame, traffic);
+        }
+    }
+
+    fn enqueue(&mut self, idx: usize, frame: Arc<[u8]>, traffic: Traffic) {
+        self.infos[idx].stats.sent(frame.len());
+        self.peers[idx].send(frame, traffic);
+    }
+
+    /// Takes in what the thread of the client with `token` reports, adding
+    /// the client to `caught_up` if it is ready for `repair_caught_up`.
+    fn report(
+        &mut self,
+        token: Token,
+        event: Event,
+        rooms: &mut RoomState,
+        cursors: &DashMap<u64, RemoteCursor>,
+        chat_log: &ChatLog,
+        caught_up: &mut Vec<usize>,
+    ) {
+        // from a client removed since
+        let Some(idx) = self.index_of(token) else {
+            return;
+        };
+        match event {
+            Event::Read(incoming, encoding) => {
+                self.peers[idx].set_encoding(encoding);
+                self.take_in(idx, incoming, rooms, cursors, chat_log);
+            }
+            Event::FellBehind => {
+                let text = format!(
+                    "{} is falling behind; holding back drawing until it catches up",
+                    self.infos[idx].label()
+                );
+                notice::warn(&self.notices, text);
+            }
+            Event::CaughtUp => caught_up.push(idx),
+            Event::Failed(e) => self.drop_failing(idx, e),
+        }
+    }
+
+    /// Handles what client `idx` sent, removing it at the end of this tick
+    /// if that was the last of it.
+    fn take_in(
+        &mut self,
+        idx: usize,
+        incoming: Incoming,
+        rooms: &mut RoomState,
+        cursors: &DashMap<u64, RemoteCursor>,
+        chat_log: &ChatLog,
+    ) {
+        if incoming.heard {
+            self.last_heard[idx] = Instant::now();
+        }
+        let stats = &self.infos[idx].stats;
+        stats.read(incoming.bytes);
+        stats.received(incoming.messages.len());
+        for message in incoming.messages {
+            handle_client_message(message, idx, self, rooms, cursors, chat_log);
+        }
+        match incoming.closed {
+            None => return,
+            Some(Closed::Gone) => println!("Client disconnected"),
+            Some(Closed::Refused(e)) => {
+                let text = format!("Closing {}: {}", self.infos[idx].label(), e);
+                notice::warn(&self.notices, text);
+            }
+            Some(Closed::Failed(e)) => {
+                let text = format!("Lost {}: {}", self.infos[idx].label(), e);
+                notice::warn(&self.notices, text);
+            }
+        }
+        self.failed.push(idx);
+    }
+
+    /// Ends every connection thread, each once it has written what its socket takes.
+    fn close_all(&mut self) {
+        for peer in self.peers.drain(..) {
+            peer.close();
+        }
+    }
+
+    /// Removes a client that cannot be sent to, at the end of this tick.
+    fn drop_failing(&mut self, idx: usize, e: io::Error) {
+        let text = format!("Dropped {}: {}", self.infos[idx].label(), e);
+        notice::warn(&self.notices, text);
+        self.failed.push(idx);
+    }
+
+    /// Tells the client why it has to go and removes it at the end of this tick.
+    fn turn_away(&mut self, idx: usize, reason: String) {
+        self.send_to(idx, &Message::Rejected { reason });
+        self.failed.push(idx);
+    }
+
+    /// The owner a Hello with `secret` draws as: the one it had before, if any.
+    fn owner_for(&mut self, secret: u64) -> u64 {
+        if let Some(&owner) = self.owners.get(&secret) {
+            return owner;
+        }
+        let owner = self.next_owner;
+        self.next_owner += 1;
+        if secret != 0 {
+            self.owners.insert(secret, owner);
+        }
+        owner
+    }
+
+    /// Whether client `idx` may erase, recolor or extend `entity`.
+    fn may_change(&self, idx: usize, entity: &Entity) -> bool {
+        self.change_any || entity.owner == self.infos[idx].owner
+    }
+
+    /// Stamps an entity from client `idx` with its owner: the client's own for a
+    /// new one, or the existing owner if the client may replace that entity. A
+    /// different drawing under an id someone else's already has, say from a
+    /// file saved elsewhere, is kept under a new id rather than dropped.
+    fn claim(&mut self, idx: usize, entities: &Canvas, entity: &mut Entity) -> Claim {
+        let existing = entities
+            .get(entity.id)
+            .map(|existing| existing.value().clone());
+        match existing {
+            Some(existing) if !self.may_change(idx, &existing) => {
+                // versions of one drawing keep when and where in the stack it was drawn
+                if (existing.created_at, existing.seq) == (entity.created_at, entity.seq) {
+                    self.refuse(idx, &existing);
+                    return Claim::Refused;
+                }
+                println!(
+                    "Entity {:x} from {} is another drawing than ours; giving it a new id",
+                    entity.id,
+                    self.infos[idx].label()
+                );
+                // the client has its own under the old id; ours is the one everyone else sees
+                self.send_to(idx, &Message::NewEntity(existing));
+                entity.id = entity::next_id();
+                entity.owner = self.infos[idx].owner;
+                Claim::Rekeyed
+            }
+            Some(existing) => {
+                entity.owner = existing.owner;
+                Claim::Granted
+            }
+            None => {
+                entity.owner = self.infos[idx].owner;
+                Claim::Granted
+            }
+        }
+    }
+
+    /// Whether client `idx` may change the entity with `id`, saying so if not.
+    fn may_change_id(&mut self, idx: usize, entities: &Canvas, id: usize) -> bool {
+        let refused = entities
+            .get(id)
+            .map(|entity| entity.value().clone())
+            .filter(|entity| !self.may_change(idx, entity));
+        match refused {
+            Some(entity) => {
+                self.refuse(idx, &entity);
+                false
+            }
+            None => true,
+        }
+    }
+
+    /// Drops a change to `entity` by client `idx`, and sends the client the
+    /// entity as it stands so its copy no longer differs from everyone's.
+    fn refuse(&mut self, idx: usize, entity: &Entity) {
+        println!(
+            "Dropping a change by {} to entity {:x}, which it does not own",
+            self.infos[idx].label(),
+            entity.id
+        );
+        self.send_to(idx, &Message::NewEntity(entity.clone()));
+    }
+
+    /// Removes a client the host wants gone at the end of this tick, telling it why.
+    fn kick(&mut self, idx: usize, reason: String) {
+        println!("Kicking {}", self.infos[idx].label());
+        self.send_to(idx, &Message::Kicked { reason });
+        self.failed.push(idx);
+    }
+
+    /// The first peer color nobody draws in, or the least used one once all are taken.
+    /// A departed client's color is free again as soon as it is removed.
+    fn free_color(&self) -> EntityColor {
+        let users = |color: EntityColor| {
+            self.infos
+                .iter()
+                .filter(|info| !info.spectator && info.color == color)
+                .count()
+        };
+        PEER_COLORS
+            .into_iter()
+            .min_by_key(|&color| users(color))
+            .expect("PEER_COLORS is not empty")
+    }
+
+    fn pending_count(&self) -> usize {
+        self.infos.iter().filter(|info| info.pending).count()
+    }
+
+    /// Guests that have waited for approval longer than `wait`.
+    fn overdue(&self, wait: Duration) -> Vec<usize> {
+        (0..self.len())
+            .filter(|&idx| self.infos[idx].pending && self.infos[idx].connected_at.elapsed() > wait)
+            .inspect(|&idx| println!("Client {} was not approved in time", self.infos[idx].addr))
+            .collect()
+    }
+
+    /// Clients that have not said who they are within `wait`, and so never
+    /// joined a room.
+    fn nameless(&self, wait: Duration) -> Vec<usize> {
+        (0..self.len())
+            .filter(|&idx| {
+                self.infos[idx].name.is_none() && self.infos[idx].connected_at.elapsed() > wait
+            })
+            .inspect(|&idx| println!("Client {} sent no hello in time", self.infos[idx].addr))
+            .collect()
+    }
+
+    /// Removes the client at the end of this tick after it said goodbye.
+    fn close(&mut self, idx: usize) {
+        println!("Client {} disconnected", self.infos[idx].addr);
+        self.failed.push(idx);
+    }
+
+    fn take_failed(&mut self) -> Vec<usize> {
+        std::mem::take(&mut self.failed)
+    }
+
+    /// Clients that have not sent anything, pings included, within `wait`. Each
+    /// is sent a goodbye in case it is only slow and still listening.
+    fn silent(&mut self, wait: Duration) -> Vec<usize> {
+        let silent: Vec<usize> = (0..self.len())
+            .filter(|&idx| self.last_heard[idx].elapsed() > wait)
+            .collect();
+        for &idx in &silent {
+            let text = format!(
+                "Dropped {}: nothing heard for {}s",
+                self.infos[idx].label(),
+                wait.as_secs()
+            );
+            notice::warn(&self.notices, text);
+            self.send_to(idx, &Message::Disconnect);
+        }
+        silent
+    }
+}
+
+/// What became of an entity a client sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Claim {
+    /// Someone else's to change; the client was sent it back as it is.
+    Refused,
+    Granted,
+    /// A different drawing that came under an id already taken, now under one
+    /// of its own.
+    Rekeyed,
+}
+
+/// A reconnecting client's KnownEntities, kept until its canvas goes out.
+struct KnownCanvas {
+    session: u64,
+    epoch: u64,
+    generation: u64,
+    ids: HashSet<usize>,
+}
+
+fn get_all_entities(entities: &Canvas) -> Vec<Entity> {
+    entities.iter().map(|e| e.value().clone()).collect()
+}
+
+/// Erases, for the host and every room, the entities that arrived more than
+/// `fade` ago and so have faded out by now.
+fn erase_faded(connections: &mut Connections, rooms: &RoomState, fade: Duration) {
+    let canvases: Vec<(String, Arc<Canvas>)> = rooms
+        .canvases
+        .iter()
+        .map(|room| (room.key().clone(), room.value().clone()))
+        .collect();
+    for (room, canvas) in canvases {
+        for id in canvas.older_than(fade) {
+            if canvas.remove(id).is_some() {
+                connections.broadcast(&Message::RemoveEntity { id }, Some(&room), None);
+            }
+        }
+    }
+}
+
+/// Erases the entities first added to `room` longest ago, for everyone in it,
+/// until no more than `--max-entities` are left. Whatever just arrived is newer
+/// than all of them, so it stays.
+fn evict_oldest(connections: &mut Connections, rooms: &RoomState, room: &str) {
+    let canvas = rooms.canvas(room);
+    let over = canvas.len().saturating_sub(rooms.max_entities);
+    for id in canvas.oldest(over) {
+        if canvas.remove(id).is_some() {
+            connections.broadcast(&Message::RemoveEntity { id }, Some(room), None);
+        }
+    }
+}
+
+/// Adds what client `idx` sent in a batch or a loaded canvas to `entities`,
+/// claimed and brought up to date, and returns it. Anything drawn before the
+/// last clear is left out; whatever had to be re-keyed also goes back to the
+/// client, which only has it under its old id.
+fn take_entities(
+    connections: &mut Connections,
+    idx: usize,
+    entities: &Canvas,
+    log: &RoomLog,
+    incoming: Vec<Entity>,
+) -> Vec<Entity> {
+    let mut taken = Vec::new();
+    let mut rekeyed = Vec::new();
+    for mut entity in incoming {
+        if entity.epoch < log.epoch {
+            continue;
+        }
+        let claim = connections.claim(idx, entities, &mut entity);
+        if claim == Claim::Refused {
+            continue;
+        }
+        log.bring_up_to_date(&mut entity);
+        entities.insert(entity.clone());
+        if claim == Claim::Rekeyed {
+            rekeyed.push(entity.clone());
+        }
+        taken.push(entity);
+    }
+    if !rekeyed.is_empty() {
+        connections.send_to(idx, &Message::EntityBatch(rekeyed));
+    }
+    taken
+}
+
+/// Lets a client that said hello into its room: it is told who else is there,
+/// they are told it joined, and it gets the canvas.
+fn admit(connections: &mut Connections, idx: usize, rooms: &mut RoomState) {
+    let room = connections.infos[idx].room.clone();
+    let roster = (0..connections.len())
+        .filter(|&other| other != idx && connections.infos[other].in_room(&room))
+        .filter_map(|other| {
+            Some((
+                connections.peer_id(other),
+                connections.infos[other].name.clone()?,
+            ))
+        })
+        .collect();
+    connections.send_to(idx, &Message::Roster(roster));
+    if let Some(name) = connections.infos[idx].name.clone() {
+        let joined = Message::PeerJoined {
+            id: connections.peer_id(idx),
+            name,
+        };
+        connections.broadcast(&joined, Some(&room), Some(idx));
+    }
+    send_canvas(connections, idx, rooms);
+}
+
+/// Brings a newly admitted client up to date with the epoch, transform
+/// generation and canvas of its room.
+fn send_canvas(connections: &mut Connections, idx: usize, rooms: &mut RoomState) {
+    let room = connections.infos[idx].room.clone();
+    // first, so the entities show up on the right background
+    let background = rooms.background(&room);
+    if background != DEFAULT_BACKGROUND {
+        connections.send_to(idx, &Message::SetBackground(background));
+    }
+    let entities = rooms.canvas(&room);
+    let log = rooms.log_mut(&room);
+
+    // From an earlier run or some other server, or after a clear the client
+    // missed, its ids mean nothing here.
+    let session = connections.session;
+    let known = connections.known[idx].take().filter(|known| {
+        known.session == session && known.epoch == log.epoch && known.generation <= log.generation()
+    });
+    if let Some(known) = known {
+        if let Some(transform) = log.since(known.generation) {
+            let message = Message::TransformAll {
+                transform,
+                generation: log.generation(),
+            };
+            connections.send_to(idx, &message);
+        }
+        let missing: Vec<Entity> = entities
+            .iter()
+            .filter(|entity| !known.ids.contains(entity.key()))
+            .map(|entity| entity.value().clone())
+            .collect();
+        if !missing.is_empty() {
+            connections.send_snapshot(idx, &Message::EntityBatch(missing));
+        }
+        return;
+    }
+
+    if log.epoch > 0 {
+        connections.send_to(idx, &Message::ClearCanvas { epoch: log.epoch });
+    }
+    // nothing to move; it only tells the client which generation it is drawing in
+    if log.generation() > 0 {
+        let message = Message::TransformAll {
+            transform: Transform::IDENTITY,
+            generation: log.generation(),
+        };
+        connections.send_to(idx, &message);
+    }
+
+    if !entities.is_empty() {
+        let all_entities = get_all_entities(&entities);
+        connections.send_snapshot(idx, &Message::AllEntities(all_entities));
+    }
+}
+
+/// Sends each client in `caught_up` the canvas of its room, which makes up
+/// for the drawing it was not sent while it was behind.
+fn repair_caught_up(connections: &mut Connections, rooms: &RoomState, caught_up: Vec<usize>) {
+    for idx in caught_up {
+        let text = format!(
+            "{} caught up; sending it the canvas for the drawing it missed",
+            connections.infos[idx].label()
+        );
+        notice::tell(&connections.notices, text);
+        let entities = rooms.canvas(&connections.infos[idx].room);
+        let message = Message::AllEntities(get_all_entities(&entities));
+        connections.send_snapshot(idx, &message);
+    }
+}
+
+fn handle_client_message(
+    message: Message,
+    client_idx: usize,
+    connections: &mut Connections,
+    rooms: &mut RoomState,
+    cursors: &DashMap<u64, RemoteCursor>,
+    chat_log: &ChatLog,
+) {
+    if connections.infos[client_idx].spectator && message.is_drawing() {
+        return;
+    }
+    // a guest that has not joined a room yet may only introduce itself, stay alive or leave
+    let info = &connections.infos[client_idx];
+    if (info.pending || info.name.is_none())
+        && !matches!(
+            message,
+            Message::Hello { .. }
+                | Message::KnownEntities { .. }
+                | Message::Ping(_)
+                | Message::Disconnect
+        )
+    {
+        return;
+    }
+
+    let room = connections.infos[client_idx].room.clone();
+    let canvas = rooms.canvas(&room);
+    let entities = &*canvas;
+    // the host sees chat and cursors of its own room only
+    let in_host_room = room == rooms.host_room;
+    let log = rooms.log_mut(&room);
+
+    match message {
+        Message::Chat { from, text } => {
+            let text = chat::clean(&text);
+            if text.is_empty() {
+                return;
+            }
+            let from = match &connections.infos[client_idx].name {
+                Some(name) => name.clone(),
+                None => chat::clean(&from),
+            };
+            if in_host_room {
+                chat::push(chat_log, &from, &text);
+            }
+            connections.broadcast(&Message::Chat { from, text }, Some(&room), Some(client_idx));
+        }
+        Message::Ping(payload) => connections.send_to(client_idx, &Message::Pong(payload)),
+        // only ever sent by the server
+        Message::Pong(_)
+        | Message::Rejected { .. }
+        | Message::Welcome { .. }
+        | Message::AwaitingApproval
+        | Message::Approved
+        | Message::Kicked { .. }
+        | Message::PeerJoined { .. }
+        | Message::PeerLeft { .. }
+        | Message::Roster(_) => {}
+        // expanded as it arrived
+        Message::CompactEntities(_) => {}
+        // canvas transforms and the background are the host's call
+        Message::TransformAll { .. } | Message::SetBackground(_) => {}
+        Message::Disconnect => connections.close(client_idx),
+        Message::Hello {
+            name,
+            spectator,
+            room,
+            secret,
+            compact,
+        } => {
+            let info = &connections.infos[client_idx];
+            if info.name.is_some() {
+                return;
+            }
+            let taken: Vec<&str> = connections
+                .infos
+                .iter()
+                .filter_map(|info| info.name.as_deref())
+                .collect();
+            let name = unique_name(&name, &taken);
+            let room = room_name(&room);
+            println!("Client {} is {} in room {}", info.addr, name, room);
+            let owner = connections.owner_for(secret);
+            let info = &mut connections.infos[client_idx];
+            info.name = Some(name);
+            info.spectator = spectator;
+            info.room = room.clone();
+            info.owner = owner;
+            connections.compact[client_idx] = compact;
+            let welcome = Message::Welcome {
+                color: info.color,
+                owner,
+                change_any: connections.change_any,
+                fade_ms: connections.fade.map(|fade| fade.as_millis() as u64),
+                compact,
+                session: connections.session,
+            };
+            connections.publish();
+            connections.send_to(client_idx, &welcome);
+
+            // the canvas follows approval instead while the host decides
+            if !connections.infos[client_idx].pending {
+                admit(connections, client_idx, rooms);
+            }
+        }
+        Message::NewEntity(mut entity) => {
+            if entity.epoch < log.epoch {
+                // Drawn before a clear the sender had not seen yet.
+                return;
+            }
+            let claim = connections.claim(client_idx, entities, &mut entity);
+            if claim == Claim::Refused {
+                return;
+            }
+            log.bring_up_to_date(&mut entity);
+            entities.insert(entity.clone());
+            if claim == Claim::Rekeyed {
+                connections.send_to(client_idx, &Message::NewEntity(entity.clone()));
+            }
+            connections.broadcast(&Message::NewEntity(entity), Some(&room), Some(client_idx));
+            evict_oldest(connections, rooms, &room);
+        }
+        Message::EntityBatch(batch) => {
+            let batch = take_entities(connections, client_idx, entities, log, batch);
+            if batch.is_empty() {
+                return;
+            }
+            connections.broadcast(&Message::EntityBatch(batch), Some(&room), Some(client_idx));
+            evict_oldest(connections, rooms, &room);
+        }
+        Message::RequestAllEntities => {
+            let all_entities = get_all_entities(entities);
+            connections.send_to(client_idx, &Message::AllEntities(all_entities));
+        }
+        Message::KnownEntities {
+            epoch,
+            generation,
+            ids,
+            session,
+        } => {
+            connections.known[client_idx] = Some(KnownCanvas {
+                session,
+                epoch,
+                generation,
+                ids: ids.into_iter().collect(),
+            });
+            // otherwise it waits for the hello or the host's approval
+            if connections.infos[client_idx].in_room(&room) {
+                send_canvas(connections, client_idx, rooms);
+            }
+        }
+        Message::ClearCanvas { epoch: requested } => {
+            // A clear based on an epoch we have already moved past raced another clear.
+            if requested > log.epoch {
+                log.epoch = requested;
+                entities.clear();
+                connections.broadcast(
+                    &Message::ClearCanvas { epoch: requested },
+                    Some(&room),
+                    Some(client_idx),
+                );
+            }
+        }
+        Message::RemoveEntity { id } => {
+            if connections.may_change_id(client_idx, entities, id) && entities.remove(id).is_some()
+            {
+                connections.broadcast(&Message::RemoveEntity { id }, Some(&room), Some(client_idx));
+            }
+        }
+        Message::UpdateEntity { id, color } => {
+            if !connections.may_change_id(client_idx, entities, id) {
+                return;
+            }
+            let updated = entities.update(id, |entity| entity.color = color).is_some();
+
+            if updated {
+                connections.broadcast(
+                    &Message::UpdateEntity { id, color },
+                    Some(&room),
+                    Some(client_idx),
+                );
+            }
+        }
+        Message::MoveEntities { moves, generation } => {
+            // moved before a transform the sender had not seen yet: the move is
+            // made where the entity was then, and the transform applied after it
+            let missed = log
+                .since(generation)
+                .and_then(|missed| Some((missed, missed.inverse()?)));
+            let moved: Vec<(usize, f32, f32)> = moves
+                .into_iter()
+                .filter(|&(id, ..)| connections.may_change_id(client_idx, entities, id))
+                .filter_map(|(id, x, y)| {
+                    entities.update(id, |entity| {
+                        match missed {
+                            Some((missed, undo)) => {
+                                undo.apply_to(entity);
+                                entity.move_to((x, y));
+                                missed.apply_to(entity);
+                            }
+                            None => entity.move_to((x, y)),
+                        }
+                        (id, entity.x, entity.y)
+                    })
+                })
+                .collect();
+            if !moved.is_empty() {
+                let message = Message::MoveEntities {
+                    moves: moved,
+                    generation: log.generation(),
+                };
+                connections.broadcast(&message, Some(&room), Some(client_idx));
+            }
+        }
+        Message::StrokeAppend {
+            id,
+            mut points,
+            pen,
+            generation,
+        } => {
+            if !connections.may_change_id(client_idx, entities, id) {
+                return;
+            }
+            // points drawn before a transform the sender had not seen yet
+            if let Some(missed) = log.since(generation) {
+                missed.apply_all(&mut points);
+            }
+            let generation = log.generation();
+            let extended = entities
+                .update(id, |entity| entity.extend_stroke(&points, &pen))
+                .unwrap_or(false);
+
+            if extended {
+                let message = Message::StrokeAppend {
+                    id,
+                    points,
+                    pen,
+                    generation,
+                };
+                connections.broadcast(&message, Some(&room), Some(client_idx));
+            }
+        }
+        Message::AllEntities(all_entities) => {
+            // a client loaded a canvas file
+            let all_entities = take_entities(connections, client_idx, entities, log, all_entities);
+            connections.broadcast(
+                &Message::AllEntities(all_entities),
+                Some(&room),
+                Some(client_idx),
+            );
+            evict_oldest(connections, rooms, &room);
+        }
+        Message::Cursor { x, y, color, .. } => {
+            // not whatever the client claims, which could be another peer's
+            let peer_id = connections.peer_id(client_idx);
+            if in_host_room {
+                cursors.insert(peer_id, RemoteCursor::new(x, y, color));
+            }
+            let message = Message::Cursor {
+                peer_id,
+                x,
+                y,
+                color,
+            };
+            connections.broadcast(&message, Some(&room), Some(client_idx));
+        }
+    }
+}
+
+/// Tells a client why it is turned away. The socket is fresh, so the handshake and
+/// small frame fit its send buffer; dropping the stream afterwards closes the connection.
+fn reject(stream: &mut mio::net::TcpStream, reason: String, format: WireFormat) {
+    let result = frame_message(&Message::Rejected { reason }, format)
+        .and_then(|frame| stream.write_all(&[handshake().as_slice(), &frame].concat()))
+        .and_then(|()| stream.flush());
+    if let Err(e) = result {
+        eprintln!("Error sending rejection: {}", e);
+    }
+}
+
+/// Running server: the client list the UI shows, plus the thread to wait for.
+pub struct ServerHandle {
+    pub clients: ClientList,
+    /// Every room's canvas, the host's own included.
+    pub rooms: Rooms,
+    /// Room the host draws in.
+    pub room: String,
+    /// Recent warnings, such as clients dropped for falling behind.
+    pub notices: Notices,
+    /// `--max-entities`: most entities a room keeps before the oldest go.
+    pub max_entities: usize,
+    /// Background of the host's room, changed by sending the server a SetBackground.
+    pub background: SharedBackground,
+    commands: crossbeam_channel::Sender<(SocketAddr, HostCommand)>,
+    waker: Arc<Waker>,
+    thread: JoinHandle<()>,
+    persister: Option<Persister>,
+}
+
+impl ServerHandle {
+    /// Lets the guest at `addr` in or turns it away; ignored unless it is still waiting.
+    pub fn decide(&self, addr: SocketAddr, decision: JoinDecision) {
+        self.command(addr, HostCommand::Decide(decision));
+    }
+
+    /// Removes the client at `addr`, telling it `reason`; it does not come back on its own.
+    pub fn kick(&self, addr: SocketAddr, reason: String) {
+        self.command(addr, HostCommand::Kick(reason));
+    }
+
+    fn command(&self, addr: SocketAddr, command: HostCommand) {
+        if self.commands.send((addr, command)).is_ok() {
+            let _ = self.waker.wake();
+        }
+    }
+
+    /// Name, entity count and admitted client count of every room, by name.
+    pub fn room_summary(&self) -> Vec<(String, usize, usize)> {
+        let clients = self.clients.lock().map(|c| c.clone()).unwrap_or_default();
+        let mut summary: Vec<(String, usize, usize)> = self
+            .rooms
+            .iter()
+            .map(|room| {
+                let name = room.key().clone();
+                let client_count = clients.iter().filter(|c| c.in_room(&name)).count();
+                (name, room.value().len(), client_count)
+            })
+            .collect();
+        summary.sort();
+        summary
+    }
+
+    /// Waits for the server to stop, which it does once the UI drops its sender,
+    /// and for the `--persist` file to take its last changes.
+    pub fn join(self) {
+        if self.thread.join().is_err() {
+            eprintln!("Server thread panicked");
+        }
+        if let Some(persister) = self.persister {
+            persister.stop();
+        }
+    }
+}
+
+/// Binds every address each of `addrs` resolves to. A wildcard IPv6 address
+/// is bound first: where it takes IPv4 connections too, the IPv4 wildcard on
+/// the same port is already served and is skipped instead of failing.
+pub fn bind_all(addrs: &[String]) -> Result<Vec<TcpListener>, String> {
+    let mut resolved = Vec::new();
+    for addr in addrs {
+        let socket_addrs = addr
+            .to_socket_addrs()
+            .map_err(|e| format!("Cannot listen on {}: {}", addr, e))?;
+        resolved.extend(socket_addrs.map(|socket_addr| (addr, socket_addr)));
+    }
+    resolved.sort_by_key(|(_, socket_addr)| {
+        !(socket_addr.is_ipv6() && socket_addr.ip().is_unspecified())
+    });
+    resolved.dedup_by_key(|(_, socket_addr)| *socket_addr);
+
+    let mut listeners: Vec<TcpListener> = Vec::new();
+    for (addr, socket_addr) in resolved {
+        match TcpListener::bind(socket_addr) {
+            Ok(listener) => listeners.push(listener),
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse && covered(&listeners, socket_addr) => {
+                println!(
+                    "{} is already served through [::]:{}",
+                    socket_addr,
+                    socket_addr.port()
+                );
+            }
+            Err(e) => return Err(format!("Cannot listen on {}: {}", addr, e)),
+        }
+    }
+    Ok(listeners)
+}
+
+/// Whether a dual-stack IPv6 wildcard listener already takes `addr`.
+fn covered(listeners: &[TcpListener], addr: SocketAddr) -> bool {
+    addr.is_ipv4()
+        && addr.ip().is_unspecified()
+        && listeners.iter().any(|listener| {
+            listener.local_addr().is_ok_and(|bound| {
+                bound.is_ipv6() && bound.ip().is_unspecified() && bound.port() == addr.port()
+            })
+        })
+}
+
+/// A poll of its own for a new client's connection thread, with the socket
+/// registered in it.
+fn register(stream: &mut mio::net::TcpStream, addr: SocketAddr) -> Option<Poll> {
+    let result = Poll::new().and_then(|poll| {
+        let interest = Interest::READABLE | Interest::WRITABLE;
+        poll.registry()
+            .register(stream, connection::SOCKET, interest)
+            .map(|()| poll)
+    });
+    match result {
+        Ok(poll) => Some(poll),
+        Err(e) => {
+            eprintln!("Error registering client {}: {}", addr, e);
+            None
+        }
+    }
+}
+
+/// Runs the server on `listeners`, all feeding the same client list.
+pub fn start_server(
+    listeners: Vec<TcpListener>,
+    entities: Arc<Canvas>,
+    rx: Receiver<Message>,
+    cursors: CursorMap,
+    chat_log: ChatLog,
+    config: &Config,
+) -> ServerHandle {
+    let format = config.wire_format;
+    let max_clients = config.max_clients;
+    let approve_joins = config.approve_joins;
+    let change_any = config.allow_erase_any;
+    let approval_timeout = config.approval_timeout;
+    let client_timeout = config.client_timeout;
+    let fade = config.fade;
+    let tls = config.server_tls.clone();
+    let recorder = Recorder::from_config(config);
+    let background = Arc::new(AtomicU32::new(DEFAULT_BACKGROUND.bits()));
+    // restored before the first client can ask for the canvas
+    let persister = Persister::from_config(config, &entities, &background);
+    let session = persister
+        .as_ref()
+        .map_or_else(new_session, Persister::session);
+    let client_list = Arc::new(Mutex::new(Vec::new()));
+    let client_list_clone = client_list.clone();
+    let notices = Notices::default();
+    let notices_clone = notices.clone();
+    let mut rooms = RoomState::new(
+        room_name(&config.room),
+        entities.clone(),
+        background.clone(),
+        config.max_entities,
+    );
+    let canvases = rooms.canvases.clone();
+    let host_room = rooms.host_room.clone();
+
+    let mut poll = Poll::new().expect("Failed to create poll");
+    let waker = Arc::new(Waker::new(poll.registry(), WAKER).expect("Failed to create waker"));
+    let (commands, commands_rx) = crossbeam_channel::unbounded();
+    // bounded, so a client sending faster than the server relays is held
+    // back by its own socket rather than queueing up here
+    let (events, events_rx) = crossbeam_channel::bounded(MAX_REPORTS);
+    let reports = Reports {
+        events,
+        waker: waker.clone(),
+    };
+
+    // WebSocket clients are taken on the first address only
+    #[cfg(feature = "websocket")]
+    let ws_listener = config
+        .ws_port
+        .zip(listeners.first())
+        .and_then(|(port, listener)| {
+            websocket::listen(listener, port, poll.registry(), WS_LISTENER)
+        });
+    let mut next_token = FIRST_PEER_TOKEN;
+    let listeners: Vec<(Token, mio::net::TcpListener)> = listeners
+        .into_iter()
+        .map(|listener| {
+            listener
+                .set_nonblocking(true)
+                .expect("Failed to set non-blocking mode");
+            let mut listener = mio::net::TcpListener::from_std(listener);
+            let token = Token(next_token);
+            next_token += 1;
+            poll.registry()
+                .register(&mut listener, token, Interest::READABLE)
+                .expect("Failed to register listener");
+            (token, listener)
+        })
+        .collect();
+
+    // The poll cannot wait on a channel, so a forwarder wakes it for every
+    // message from the UI, and once more when the UI side goes away.
+    let (inbox_tx, inbox) = crossbeam_channel::unbounded();
+    let forwarder_waker = waker.clone();
+    thread::spawn(move || {
+        let waker = forwarder_waker;
+        for message in rx.iter() {
+            if inbox_tx.send(message).is_err() {
+                break;
+            }
+            let _ = waker.wake();
+        }
+        drop(inbox_tx);
+        let _ = waker.wake();
+    });
+
+    // The server stops, dropping every connection, once the UI side of `rx` goes away.
+    let handle = thread::spawn(move || {
+        let host_room = rooms.host_room.clone();
+        let recording = recorder.map(|recorder| (host_room.clone(), recorder));
+        let mut connections = Connections::new(
+            format,
+            change_any,
+            fade,
+            client_list_clone,
+            recording,
+            notices_clone,
+            session,
+        );
+        // a loaded canvas may hold entities stamped by the clients of an earlier run
+        let loaded = rooms.canvas(&host_room);
+        if let Some(highest) = loaded.iter().map(|entity| entity.owner).max() {
+            connections.next_owner = connections.next_owner.max(highest.saturating_add(1));
+        }
+        let mut events = Events::with_capacity(128);
+        let mut last_faded = Instant::now();
+
+        loop {
+            if let Err(e) = poll.poll(&mut events, Some(HOUSEKEEPING_INTERVAL)) {
+                if e.kind() != io::ErrorKind::Interrupted {
+                    eprintln!("Error polling sockets: {}", e);
+                }
+                continue;
+            }
+
+            let mut accepting = Vec::new();
+            #[cfg(feature = "websocket")]
+            let mut ws_accept = false;
+            for event in events.iter() {
+                match event.token() {
+                    token if listeners.iter().any(|(listener, _)| *listener == token) => {
+                        accepting.push(token)
+                    }
+                    #[cfg(feature = "websocket")]
+                    WS_LISTENER => ws_accept = true,
+                    // the inbox and the connection threads' reports are
+                    // drained below on every wakeup
+                    _ => {}
+                }
+            }
+
+            let ready = listeners
+                .iter()
+                .filter(|(token, _)| accepting.contains(token));
+            for (_, listener) in ready {
+                let mut accept = true;
+                while accept {
+                    match listener.accept() {
+                        Ok((mut stream, addr)) => {
+                            if let Some((why, reason)) =
+                                connections.refusal(max_clients, approve_joins)
+                            {
+                                println!("Rejecting {}: {}", addr, why);
+                                // a TLS client could not read a plain rejection, so it just retries
+                                if tls.is_none() {
+                                    reject(&mut stream, reason, format);
+                                }
+                                continue;
+                            }
+                            let token = Token(next_token);
+                            next_token += 1;
+                            let Some(peer_poll) = register(&mut stream, addr) else {
+                                continue;
+                            };
+                            let peer = match &tls {
+                                Some(config) => match tls::TlsPeer::new(stream, config.clone()) {
+                                    Ok(peer) => Peer::Tls(Box::new(peer)),
+                                    Err(e) => {
+                                        eprintln!("Error setting up TLS for {}: {}", addr, e);
+                                        continue;
+                                    }
+                                },
+                                None => Peer::tcp(stream),
+                            };
+                            connections.admit(
+                                peer,
+                                peer_poll,
+                                token,
+                                addr,
+                                approve_joins,
+                                &reports,
+                            );
+                        }
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => accept = false,
+                        Err(e) => {
+                            eprintln!("Error accepting connection: {}", e);
+                            accept = false;
+                        }
+                    }
+                }
+            }
+
+            #[cfg(feature = "websocket")]
+            while ws_accept && let Some(listener) = ws_listener.as_ref() {
+                match listener.accept() {
+                    Ok((mut stream, addr)) => {
+                        // there is no frame to explain in before the upgrade, so just hang up
+                        if let Some((why, _)) = connections.refusal(max_clients, approve_joins) {
+                            println!("Rejecting {}: {}", addr, why);
+                            continue;
+                        }
+                        let token = Token(next_token);
+                        next_token += 1;
+                        let Some(peer_poll) = register(&mut stream, addr) else {
+                            continue;
+                        };
+                        match websocket::WsPeer::accept(stream) {
+                            Ok(peer) => {
+                                let peer = Peer::WebSocket(Box::new(peer));
+                                connections.admit(
+                                    peer,
+                                    peer_poll,
+                                    token,
+                                    addr,
+                                    approve_joins,
+                                    &reports,
+                                );
+                            }
+                            Err(e) => eprintln!("Closing {}: {}", addr, e),
+                        }
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => ws_accept = false,
+                    Err(e) => {
+                        eprintln!("Error accepting WebSocket connection: {}", e);
+                        ws_accept = false;
+                    }
+                }
+            }
+
+            for (addr, command) in commands_rx.try_iter() {
+                let Some(idx) = connections.infos.iter().position(|info| info.addr == addr) else {
+                    continue;
+                };
+                let decision = match command {
+                    HostCommand::Kick(reason) => {
+                        connections.kick(idx, reason);
+                        continue;
+                    }
+                    HostCommand::Decide(decision) if connections.infos[idx].pending => decision,
+                    HostCommand::Decide(_) => continue,
+                };
+                let admitted = connections.len() - connections.pending_count();
+                match decision {
+                    JoinDecision::Approve => match max_clients {
+                        Some(max) if admitted >= max => {
+                            let reason = format!("The server is full (limit {})", max);
+                            connections.turn_away(idx, reason);
+                        }
+                        _ => {
+                            println!("Client {} approved", addr);
+                            connections.infos[idx].pending = false;
+                            connections.publish();
+                            connections.send_to(idx, &Message::Approved);
+                            // without a hello yet, the canvas follows the hello
+                            if connections.infos[idx].name.is_some() {
+                                admit(&mut connections, idx, &mut rooms);
+                            }
+                        }
+                    },
+                    JoinDecision::Deny => {
+                        println!("Client {} denied", addr);
+                        connections.turn_away(idx, "The host declined the join".to_string());
+                    }
+                }
+            }
+
+            let mut outgoing = VecDeque::new();
+            loop {
+                let message = match inbox.try_recv() {
+                    Ok(message) => message,
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => {
+                        println!("Server shutting down");
+                        // best effort: whatever the sockets take now, then FIN on drop
+                        connections.broadcast(&Message::Disconnect, None, None);
+                        // no thread is left waiting to report
+                        drop(events_rx);
+                        connections.close_all();
+                        return;
+                    }
+                };
+                let log = rooms.log_mut(&host_room);
+                let message = match message {
+                    Message::NewEntity(mut entity) => {
+                        entity.epoch = log.epoch;
+                        entity.generation = log.generation();
+                        entities.insert(entity.clone());
+                        Message::NewEntity(entity)
+                    }
+                    Message::ClearCanvas { .. } => {
+                        log.epoch += 1;
+                        entities.clear();
+                        Message::ClearCanvas { epoch: log.epoch }
+                    }
+                    Message::AllEntities(mut all_entities) => {
+                        for entity in all_entities.iter_mut() {
+                            entity.epoch = log.epoch;
+                            entity.generation = log.generation();
+                            entities.insert(entity.clone());
+                        }
+                        Message::AllEntities(all_entities)
+                    }
+                    Message::StrokeAppend {
+                        id, points, pen, ..
+                    } => Message::StrokeAppend {
+                        id,
+                        points,
+                        pen,
+                        generation: log.generation(),
+                    },
+                    Message::MoveEntities { moves, .. } => Message::MoveEntities {
+                        moves,
+                        generation: log.generation(),
+                    },
+                    Message::SetBackground(color) => {
+                        rooms.background.store(color.bits(), Ordering::Relaxed);
+                        Message::SetBackground(color)
+                    }
+                    Message::TransformAll { transform, .. } => {
+                        log.transforms.push(transform);
+                        let generation = log.generation();
+                        entities.update_all(|entity| {
+                            transform.apply_to(entity);
+                            entity.generation = generation;
+                        });
+                        Message::TransformAll {
+                            transform,
+                            generation,
+                        }
+                    }
+                    // RemoveEntity and UpdateEntity were already applied to the shared
+                    // map by the UI, and so were StrokeAppend's points and the moves.
+                    other => other,
+                };
 
-#[derive(Debug, Clone)]
-pub struct ClientInfo {
-    pub addr: SocketAddr,
-}
+                push_batched(&mut outgoing, message);
+            }
+            for message in outgoing {
+                connections.broadcast(&message, Some(&host_room), None);
+            }
+            evict_oldest(&mut connections, &rooms, &host_room);
 
-pub type ClientList = Arc<Mutex<Vec<ClientInfo>>>;
+            // only what has come in so far, however fast a client keeps sending
+            let mut caught_up = Vec::new();
+            for (token, event) in events_rx.try_iter().take(events_rx.len()) {
+                connections.report(
+                    token,
+                    event,
+                    &mut rooms,
+                    &cursors,
+                    &chat_log,
+                    &mut caught_up,
+                );
+            }
+            repair_caught_up(&mut connections, &rooms, caught_up);
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-#[serde(untagged)]
-pub enum Message {
-    NewEntity(Entity),
-    AllEntities(Vec<Entity>),
-    RequestAllEntities,
+            let mut to_remove = connections.take_failed();
+            for idx in connections.overdue(approval_timeout) {
+                let reason = "Nobody approved the join in time".to_string();
+                connections.turn_away(idx, reason);
+            }
+            for idx in connections.nameless(HELLO_TIMEOUT) {
+                let reason = "No hello arrived in time".to_string();
+                connections.turn_away(idx, reason);
+            }
+
+            if let Some(fade) = fade
+                && last_faded.elapsed() >= HOUSEKEEPING_INTERVAL
+            {
+                last_faded = Instant::now();
+                erase_faded(&mut connections, &rooms, fade);
+            }
+
+            to_remove.extend(connections.take_failed());
+            to_remove.extend(connections.silent(client_timeout));
+            to_remove.sort_unstable();
+            to_remove.dedup();
+            // told only once every index is removed, since a send that fails queues one
+            let mut left = Vec::new();
+            for i in to_remove.iter().rev() {
+                let id = connections.peer_id(*i);
+                let info = connections.remove(*i);
+                if let Some(name) = info.name.clone()
+                    && info.in_room(&info.room)
+                {
+                    left.push((info.room, Message::PeerLeft { id, name }));
+                }
+            }
+            for (room, message) in left.iter().rev() {
+                connections.broadcast(message, Some(room), None);
+            }
+            if !to_remove.is_empty() {
+                rooms.close_empty(&connections.infos);
+            }
+        }
+    });
+
+    ServerHandle {
+        clients: client_list,
+        rooms: canvases,
+        room: host_room,
+        notices,
+        max_entities: config.max_entities,
+        background,
+        commands,
+        waker,
+        thread: handle,
+        persister,
+    }
 }
 
-struct MessageHandler {
-    buffer: Vec<u8>,
-    current_msg_len: Option<usize>,
+/// The client's connection to the server, plain or inside TLS.
+enum Link {
+    Plain(TcpStream),
+    Tls(tls::ClientLink),
 }
 
-impl MessageHandler {
-    fn new() -> Self {
-        Self {
-            buffer: Vec::with_capacity(BUFFER_CAPACITY),
-            current_msg_len: None,
+impl Link {
+    /// Another handle on the same connection, for the sender.
+    fn try_clone(&self) -> io::Result<Self> {
+        match self {
+            Link::Plain(stream) => stream.try_clone().map(Link::Plain),
+            Link::Tls(link) => link.try_clone().map(Link::Tls),
         }
     }
 
-    fn extend_buffer(&mut self, data: &[u8]) {
-        self.buffer.extend_from_slice(data);
+    /// The socket underneath, for its timeouts.
+    fn socket(&self) -> &TcpStream {
+        match self {
+            Link::Plain(stream) => stream,
+            Link::Tls(link) => link.socket(),
+        }
     }
 
-    fn check_buffer_size(&mut self) -> bool {
-        if self.buffer.len() > MAX_BUFFER_SIZE {
-            eprintln!("Message buffer too large ({}), clearing", self.buffer.len());
-            self.buffer.clear();
-            self.current_msg_len = None;
-            return true;
+    fn shutdown(&self) -> io::Result<()> {
+        match self {
+            Link::Plain(stream) => stream.shutdown(Shutdown::Both),
+            Link::Tls(link) => link.shutdown(),
         }
-        false
     }
+}
 
-    fn next_message(&mut self) -> Option<Result<Message, String>> {
-        if self.current_msg_len.is_none() {
-            if self.buffer.len() < 4 {
-                return None;
-            }
-
-            let len_bytes: [u8; 4] = self.buffer[0..4].try_into().unwrap();
-            let msg_len = u32::from_le_bytes(len_bytes) as usize;
-
-            if msg_len > MAX_BUFFER_SIZE {
-                eprintln!(
-                    "Received suspiciously large message size: {}, resetting buffer",
-                    msg_len
-                );
-                self.buffer.clear();
-                self.current_msg_len = None;
-                return None;
-            }
-
-            self.current_msg_len = Some(msg_len);
-            self.buffer.drain(0..4);
+impl Read for Link {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Link::Plain(stream) => stream.read(buf),
+            Link::Tls(link) => link.read(buf),
         }
+    }
+}
 
-        if let Some(msg_len) = self.current_msg_len {
-            if self.buffer.len() < msg_len {
-                return None; // Not enough data yet
-            }
-
-            let message_data = self.buffer.drain(0..msg_len).collect::<Vec<u8>>();
-            self.current_msg_len = None;
+impl Write for Link {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Link::Plain(stream) => stream.write(buf),
+            Link::Tls(link) => link.write(buf),
+        }
+    }
 
-            match serde_json::from_slice::<Message>(&message_data) {
-                Ok(message) => Some(Ok(message)),
-                Err(e) => Some(Err(format!("Error decoding message: {}", e))),
-            }
-        } else {
-            None
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Link::Plain(stream) => stream.flush(),
+            Link::Tls(link) => link.flush(),
         }
     }
 }
 
-fn frame_message(message: &Message) -> io::Result<Vec<u8>> {
-    let data =
-        serde_json::to_vec(message).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
-
-    let msg_len = data.len() as u32;
-    let mut framed_data = Vec::with_capacity(4 + data.len());
-    framed_data.extend_from_slice(&msg_len.to_le_bytes());
-    framed_data.extend_from_slice(&data);
-
-    Ok(framed_data)
+/// Sends through the current connection, reporting `NotConnected` while offline.
+fn write_to_server(
+    writer: &Mutex<Option<Link>>,
+    stats: &NetStats,
+    message: &Message,
+    format: WireFormat,
+) -> io::Result<()> {
+    match writer.lock().as_deref_mut() {
+        Ok(Some(stream)) => send_counted(stream, stats, message, format),
+        _ => Err(io::Error::from(io::ErrorKind::NotConnected)),
+    }
 }
 
-fn send_message(stream: &mut TcpStream, message: &Message) -> io::Result<()> {
-    let framed_data = frame_message(message)?;
+/// `send_message`, counting what went out.
+fn send_counted(
+    stream: &mut impl Write,
+    stats: &NetStats,
+    message: &Message,
+    format: WireFormat,
+) -> io::Result<()> {
+    let framed_data = frame_message(message, format)?;
     stream.write_all(&framed_data)?;
     stream.flush()?;
+    stats.sent(framed_data.len());
     Ok(())
 }
 
-fn send_to_clients(clients: &mut Vec<TcpStream>, message: &Message) -> usize {
-    let mut successful_sends = 0;
+fn reconnect_delay(attempt: u32) -> Duration {
+    let secs = 1u64 << attempt.saturating_sub(1).min(5);
+    Duration::from_secs(secs.min(MAX_RECONNECT_DELAY_SECS))
+}
 
-    clients.retain_mut(|client| match send_message(client, message) {
-        Ok(_) => {
-            successful_sends += 1;
-            true
-        }
-        Err(e) => {
-            eprintln!("Error sending to client: {}", e);
-            false
-        }
-    });
+pub fn start_client(
+    entities: Arc<Canvas>,
+    rx: Receiver<Message>,
+    cursors: CursorMap,
+    chat_log: ChatLog,
+    config: &Config,
+) -> ClientHandle {
+    let format = config.wire_format;
+    let addr = config.role.addr().to_string();
+    let status = Arc::new(Mutex::new(ClientStatus::Connecting));
+    let recorder = Recorder::from_config(config);
+    let shared = ClientShared {
+        entities,
+        cursors,
+        chat_log,
+        writer: Arc::new(Mutex::new(None)),
+        epoch: Arc::new(AtomicU64::new(0)),
+        generation: Arc::new(AtomicU64::new(0)),
+        rtt: Arc::new(AtomicU64::new(NO_RTT)),
+        status: status.clone(),
+        color: Arc::new(Mutex::new(None)),
+        permissions: Arc::new(Mutex::new(Permissions::UNKNOWN)),
+        fade: Arc::new(Mutex::new(None)),
+        roster: SharedRoster::default(),
+        background: Arc::new(AtomicU32::new(DEFAULT_BACKGROUND.bits())),
+        notices: Notices::default(),
+        stats: SharedStats::default(),
+        format,
+        compact: Arc::new(AtomicBool::new(false)),
+        session: AtomicU64::new(0),
+        discard_queued: Arc::new(AtomicBool::new(false)),
+        restore: config.restore_on_reconnect,
+        addr: addr.clone(),
+        tls: config.client_tls.clone(),
+        name: config.name.clone(),
+        spectator: config.spectate,
+        room: config.room.clone(),
+        recorder: recorder.clone(),
+    };
+    let rtt = shared.rtt.clone();
+    let color = shared.color.clone();
+    let permissions = shared.permissions.clone();
+    let fade = shared.fade.clone();
+    let roster = shared.roster.clone();
+    let background = shared.background.clone();
+    let notices = shared.notices.clone();
+    let stats = shared.stats.clone();
 
-    successful_sends
-}
+    let send = SenderShared {
+        writer: shared.writer.clone(),
+        epoch: shared.epoch.clone(),
+        generation: shared.generation.clone(),
+        compact: shared.compact.clone(),
+        discard_queued: shared.discard_queued.clone(),
+    };
+    // dropped when the UI goes away, which stops the connect loop
+    let (stop_tx, stop_rx) = crossbeam_channel::bounded::<()>(0);
+    let stopper = Stopper {
+        stop_tx,
+        writer: shared.writer.clone(),
+        stats: shared.stats.clone(),
+        format,
+    };
 
-fn get_all_entities(entities: &DashMap<usize, Entity>) -> Vec<Entity> {
-    entities.iter().map(|e| e.value().clone()).collect()
-}
+    // Spectators never send anything but the handshake, pings and the goodbye.
+    let mut threads = Vec::new();
+    let spectator_stopper = if config.spectate {
+        Some(stopper)
+    } else {
+        threads.push(spawn_sender(rx, send, stopper, recorder));
+        None
+    };
 
-fn handle_client_message(
-    message: Message,
-    client_idx: usize,
-    clients: &mut [TcpStream],
-    entities: &DashMap<usize, Entity>,
-) -> io::Result<()> {
-    match message {
-        Message::NewEntity(entity) => {
-            let id = entity.id;
-            entities.insert(id, entity.clone());
-
-            let message = Message::NewEntity(entity);
-            for (j, client) in clients.iter_mut().enumerate() {
-                if j != client_idx {
-                    if let Err(e) = send_message(client, &message) {
-                        eprintln!("Error forwarding entity to client: {}", e);
+    threads.push(thread::spawn(move || {
+        let status_clone = shared.status.clone();
+        let mut attempt = 0u32;
+
+        loop {
+            let mut server_closed = false;
+            match connect(&addr) {
+                Ok(stream) => {
+                    match stream.peer_addr() {
+                        Ok(peer) => println!("Connected to server at {}", peer),
+                        Err(_) => println!("Connected to server"),
+                    }
+                    attempt = 0;
+                    if let Ok(mut status) = status_clone.lock() {
+                        *status = ClientStatus::Connected;
+                    }
+
+                    let end = run_client_session(stream, &shared);
+
+                    if let Ok(mut writer) = shared.writer.lock() {
+                        *writer = None;
+                    }
+                    shared.rtt.store(NO_RTT, Ordering::Relaxed);
+
+                    match end {
+                        SessionEnd::Lost => {}
+                        SessionEnd::ServerClosed => server_closed = true,
+                        SessionEnd::Rejected(reason) => {
+                            println!("Rejected by server: {}", reason);
+                            if let Ok(mut status) = status_clone.lock() {
+                                *status = ClientStatus::Rejected { reason };
+                            }
+                            // no retries; idle until the UI shuts down
+                            let _ = stop_rx.recv();
+                            return;
+                        }
+                        SessionEnd::Kicked(reason) => {
+                            println!("Removed by the host: {}", reason);
+                            if let Ok(mut status) = status_clone.lock() {
+                                *status = ClientStatus::Kicked { reason };
+                            }
+                            let _ = stop_rx.recv();
+                            return;
+                        }
+                        SessionEnd::Incompatible(reason) => {
+                            eprintln!("Cannot talk to the server: {}", reason);
+                            if let Ok(mut status) = status_clone.lock() {
+                                *status = ClientStatus::Incompatible { reason };
+                            }
+                            let _ = stop_rx.recv();
+                            return;
+                        }
                     }
                 }
+                Err(e) => {
+                    eprintln!("Failed to connect to server: {}", e);
+                }
+            }
+
+            attempt += 1;
+            if let Ok(mut status) = status_clone.lock() {
+                *status = ClientStatus::Reconnecting {
+                    attempt,
+                    server_closed,
+                };
+            }
+            let delay = reconnect_delay(attempt);
+            println!("Reconnecting in {}s (attempt {})", delay.as_secs(), attempt);
+            if let Err(RecvTimeoutError::Disconnected) = stop_rx.recv_timeout(delay) {
+                return;
             }
         }
-        Message::RequestAllEntities => {
-            let all_entities = get_all_entities(entities);
-            let message = Message::AllEntities(all_entities);
-            send_message(&mut clients[client_idx], &message)?;
-        }
-        Message::AllEntities(_) => {}
+    }));
+
+    ClientHandle {
+        status,
+        rtt,
+        color,
+        permissions,
+        fade,
+        roster,
+        background,
+        notices,
+        stats,
+        threads,
+        spectator_stopper,
     }
-    Ok(())
 }
 
-pub fn start_server(
-    listener: TcpListener,
-    entities: Arc<DashMap<usize, Entity>>,
-    rx: Receiver<Entity>,
-) -> ClientList {
-    let client_list = Arc::new(Mutex::new(Vec::new()));
-    let client_list_clone = client_list.clone();
-
-    listener
-        .set_nonblocking(true)
-        .expect("Failed to set non-blocking mode");
+/// What the sender shares with the sessions it sends through.
+struct SenderShared {
+    writer: Arc<Mutex<Option<Link>>>,
+    /// Stamped on what goes out.
+    epoch: Arc<AtomicU64>,
+    generation: Arc<AtomicU64>,
+    /// Set by a Welcome that agreed to CompactEntities.
+    compact: Arc<AtomicBool>,
+    /// Set when the server turns out to have restarted: what was queued for
+    /// the one before is dropped.
+    discard_queued: Arc<AtomicBool>,
+}
 
+/// One sender for the lifetime of the client. Local drawings and clears go out
+/// in the order the UI produced them, stamped with the newest epoch this client
+/// knows about, and queue up while the connection is down.
+fn spawn_sender(
+    rx: Receiver<Message>,
+    send: SenderShared,
+    stopper: Stopper,
+    recorder: Option<Recorder>,
+) -> JoinHandle<()> {
     thread::spawn(move || {
-        let mut clients = Vec::new();
-        let mut client_handlers = Vec::new();
-        let mut client_addresses = Vec::new();
+        let format = stopper.format;
 
-        loop {
-            match listener.accept() {
-                Ok((stream, addr)) => {
-                    println!("New client connected: {}", addr);
-                    stream
-                        .set_nonblocking(true)
-                        .expect("Failed to set client to non-blocking mode");
-
-                    let client_info = ClientInfo { addr };
-                    if let Ok(mut client_list) = client_list_clone.lock() {
-                        client_list.push(client_info.clone());
-                    }
-                    client_addresses.push(client_info);
-
-                    if !entities.is_empty() {
-                        let all_entities = get_all_entities(&entities);
-                        let message = Message::AllEntities(all_entities);
-                        if let Err(e) = send_message(
-                            &mut stream.try_clone().expect("Failed to clone stream"),
-                            &message,
-                        ) {
-                            eprintln!("Error sending initial entities to new client: {}", e);
-                        }
-                    }
+        let mut pending: VecDeque<Message> = VecDeque::new();
 
-                    clients.push(stream);
-                    client_handlers.push(MessageHandler::new());
-                }
-                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
-                Err(e) => {
-                    eprintln!("Error accepting connection: {}", e);
+        let stamp = |message| match message {
+            Message::NewEntity(mut entity) => {
+                entity.epoch = send.epoch.load(Ordering::SeqCst);
+                entity.generation = send.generation.load(Ordering::SeqCst);
+                Message::NewEntity(entity)
+            }
+            Message::ClearCanvas { .. } => Message::ClearCanvas {
+                epoch: send.epoch.fetch_add(1, Ordering::SeqCst) + 1,
+            },
+            Message::AllEntities(mut all_entities) => {
+                let current = send.epoch.load(Ordering::SeqCst);
+                let generation = send.generation.load(Ordering::SeqCst);
+                for entity in all_entities.iter_mut() {
+                    entity.epoch = current;
+                    entity.generation = generation;
                 }
+                Message::AllEntities(all_entities)
             }
+            Message::StrokeAppend {
+                id, points, pen, ..
+            } => Message::StrokeAppend {
+                id,
+                points,
+                pen,
+                generation: send.generation.load(Ordering::SeqCst),
+            },
+            Message::MoveEntities { moves, .. } => Message::MoveEntities {
+                moves,
+                generation: send.generation.load(Ordering::SeqCst),
+            },
+            other => other,
+        };
 
-            while let Ok(entity) = rx.try_recv() {
-                let id = entity.id;
-                entities.insert(id, entity.clone());
-
-                let message = Message::NewEntity(entity);
-                send_to_clients(&mut clients, &message);
+        loop {
+            let first = match rx.recv_timeout(Duration::from_millis(SEND_RETRY_INTERVAL)) {
+                Ok(message) => Some(message),
+                Err(RecvTimeoutError::Timeout) => None,
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+            // everything else the UI produced this tick rides along
+            for message in first.into_iter().chain(rx.try_iter()) {
+                let message = stamp(message);
+                if let Some(recorder) = &recorder {
+                    recorder.record(&message);
+                }
+                push_batched(&mut pending, message);
             }
 
-            let mut to_remove = Vec::new();
-            for i in 0..clients.len() {
-                let mut buffer = [0; 4096];
-
-                match clients[i].read(&mut buffer) {
-                    Ok(0) => {
-                        println!("Client disconnected");
-                        to_remove.push(i);
-                    }
-                    Ok(n) => {
-                        client_handlers[i].extend_buffer(&buffer[..n]);
-
-                        while let Some(message_result) = client_handlers[i].next_message() {
-                            match message_result {
-                                Ok(message) => {
-                                    if let Err(e) =
-                                        handle_client_message(message, i, &mut clients, &entities)
-                                    {
-                                        eprintln!("Error handling client message: {}", e);
-                                    }
-                                }
-                                Err(e) => {
-                                    eprintln!("{}", e);
-                                }
-                            }
-                        }
-
-                        client_handlers[i].check_buffer_size();
+            // held throughout, so a session publishing the writer has already
+            // said whether what is queued is still wanted
+            let Ok(mut writer) = send.writer.lock() else {
+                continue;
+            };
+            let Some(stream) = writer.as_mut() else {
+                continue;
+            };
+            if send.discard_queued.swap(false, Ordering::SeqCst) {
+                pending.clear();
+            }
+            while let Some(message) = pending.front() {
+                let compacted = send
+                    .compact
+                    .load(Ordering::SeqCst)
+                    .then(|| message.compacted())
+                    .flatten();
+                let message = compacted.as_ref().unwrap_or(message);
+                match send_counted(stream, &stopper.stats, message, format) {
+                    Ok(()) => {
+                        pending.pop_front();
                     }
-                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
                     Err(e) => {
-                        eprintln!("Error reading from client: {}", e);
-                        to_remove.push(i);
+                        eprintln!("Error sending to server: {}", e);
+                        // part of the frame may have gone out, so nothing more can
+                        // follow it: the session reconnects and repairs what was lost
+                        if let Some(stream) = writer.take() {
+                            let _ = stream.shutdown();
+                        }
+                        break;
                     }
                 }
             }
+        }
 
-            for i in to_remove.iter().rev() {
-                if let Ok(mut client_list) = client_list_clone.lock() {
-                    if *i < client_addresses.len() {
-                        let addr = client_addresses[*i].addr;
-                        client_list.retain(|client| client.addr != addr);
-                    }
-                }
+        stopper.stop();
+    })
+}
+
+/// Ends the client: stops the connect loop and closes the current session.
+struct Stopper {
+    stop_tx: crossbeam_channel::Sender<()>,
+    writer: Arc<Mutex<Option<Link>>>,
+    stats: SharedStats,
+    format: WireFormat,
+}
+
+impl Stopper {
+    fn stop(self) {
+        drop(self.stop_tx);
+        if let Ok(mut writer) = self.writer.lock()
+            && let Some(stream) = writer.take()
+        {
+            let _ = stream.shutdown();
+        }
+    }
+}
+
+/// Running client: what the UI shows, plus the threads to wait for on shutdown.
+pub struct ClientHandle {
+    pub status: SharedClientStatus,
+    pub rtt: SharedRtt,
+    pub color: SharedColor,
+    pub permissions: SharedPermissions,
+    pub fade: SharedFade,
+    pub roster: SharedRoster,
+    pub background: SharedBackground,
+    /// Recent warnings, such as messages from the server that did not decode.
+    pub notices: Notices,
+    /// Traffic over the current connection; starts over on every reconnect.
+    pub stats: SharedStats,
+    threads: Vec<JoinHandle<()>>,
+    /// Spectators have no sender thread, so joining stops them directly.
+    spectator_stopper: Option<Stopper>,
+}
+
+impl ClientHandle {
+    /// Waits for the client to stop, which it does once the UI drops its sender.
+    pub fn join(self) {
+        if let Some(stopper) = self.spectator_stopper {
+            let _ = write_to_server(
+                &stopper.writer,
+                &stopper.stats,
+                &Message::Disconnect,
+                stopper.format,
+            );
+            stopper.stop();
+        }
+        for thread in self.threads {
+            if thread.join().is_err() {
+                eprintln!("Client thread panicked");
+            }
+        }
+    }
+}
 
-                clients.remove(*i);
-                client_handlers.remove(*i);
-                if *i < client_addresses.len() {
-                    client_addresses.remove(*i);
+/// Like `TcpStream::connect`, but gives up on an unreachable address after
+/// CONNECT_TIMEOUT so shutdown is never stuck behind the OS timeout. A name
+/// resolving to several addresses is tried IPv6 first, alternating with IPv4.
+fn connect(addr: &str) -> io::Result<TcpStream> {
+    let mut last_error = io::Error::new(io::ErrorKind::InvalidInput, "address resolved to nothing");
+    let candidates = connect_order(addr.to_socket_addrs()?.collect());
+    for (i, socket_addr) in candidates.iter().enumerate() {
+        match TcpStream::connect_timeout(socket_addr, CONNECT_TIMEOUT) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => {
+                if i + 1 < candidates.len() {
+                    eprintln!(
+                        "Cannot connect to {}: {}; trying the next address",
+                        socket_addr, e
+                    );
                 }
+                last_error = e;
             }
+        }
+    }
+    Err(last_error)
+}
 
-            //cpu tick
-            thread::sleep(Duration::from_millis(SLEEP_DURATION));
+/// `addrs` as v6, v4, v6, ... each family in the resolver's order.
+fn connect_order(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let (v6, v4): (Vec<_>, Vec<_>) = addrs.into_iter().partition(SocketAddr::is_ipv6);
+    let mut ordered = Vec::with_capacity(v6.len() + v4.len());
+    let (mut v6, mut v4) = (v6.into_iter(), v4.into_iter());
+    loop {
+        let (a, b) = (v6.next(), v4.next());
+        if a.is_none() && b.is_none() {
+            return ordered;
         }
-    });
+        ordered.extend(a.into_iter().chain(b));
+    }
+}
 
-    client_list
+/// State that outlives any single connection of the client.
+struct ClientShared {
+    entities: Arc<Canvas>,
+    cursors: CursorMap,
+    chat_log: ChatLog,
+    writer: Arc<Mutex<Option<Link>>>,
+    epoch: Arc<AtomicU64>,
+    /// Canvas transforms applied so far, stamped on everything the sender draws.
+    generation: Arc<AtomicU64>,
+    rtt: SharedRtt,
+    status: SharedClientStatus,
+    color: SharedColor,
+    permissions: SharedPermissions,
+    fade: SharedFade,
+    roster: SharedRoster,
+    background: SharedBackground,
+    notices: Notices,
+    stats: SharedStats,
+    format: WireFormat,
+    /// Set by a Welcome that agreed to CompactEntities; the sender then sends them.
+    compact: Arc<AtomicBool>,
+    /// Session of the server last connected to; 0 before the first Welcome.
+    session: AtomicU64,
+    /// See `SenderShared`.
+    discard_queued: Arc<AtomicBool>,
+    /// Set by `--restore-on-reconnect`.
+    restore: bool,
+    addr: String,
+    tls: Option<Arc<rustls::ClientConfig>>,
+    name: String,
+    spectator: bool,
+    room: String,
+    /// Gets what arrives from the server; the sender records local changes.
+    recorder: Option<Recorder>,
 }
 
-pub fn start_client(entities: Arc<DashMap<usize, Entity>>, _tx: Sender<Entity>, addr: String) {
-    thread::spawn(move || match TcpStream::connect(&addr) {
-        Ok(mut stream) => {
-            println!("Connected to server");
-            stream
-                .set_nonblocking(true)
-                .expect("Failed to set non-blocking mode");
+/// Why a client session ended.
+enum SessionEnd {
+    /// The connection broke or went silent.
+    Lost,
+    /// The server said goodbye with a Disconnect.
+    ServerClosed,
+    Rejected(String),
+    Kicked(String),
+    /// The server speaks another protocol version.
+    Incompatible(String),
+}
 
-            let send_stream = stream.try_clone().expect("Failed to clone stream");
-            let entities_clone = entities.clone();
+/// Sends our handshake and checks the server's before any frame goes either way.
+fn exchange_handshake(stream: &mut Link) -> Result<(), SessionEnd> {
+    let mut theirs = [0; HANDSHAKE_LEN];
+    let result = stream
+        .write_all(&handshake())
+        .and_then(|()| stream.socket().set_read_timeout(Some(CONNECT_TIMEOUT)))
+        .and_then(|()| stream.read_exact(&mut theirs));
+    match result {
+        Ok(()) => check_handshake(&theirs).map_err(SessionEnd::Incompatible),
+        // servers from before the handshake wait for a frame and say nothing
+        Err(e)
+            if matches!(
+                e.kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+            ) =>
+        {
+            Err(SessionEnd::Incompatible(format!(
+                "no handshake from the server, so {}",
+                version_mismatch(UNVERSIONED_PROTOCOL)
+            )))
+        }
+        Err(e) => {
+            eprintln!("Error exchanging handshake: {}", e);
+            Err(SessionEnd::Lost)
+        }
+    }
+}
 
-            let (server_tx, server_rx) = crossbeam_channel::unbounded::<Entity>();
+/// Runs one connection until the server goes away or falls silent.
+/// Hands the sender a clone of `stream`, sending `unsynced` up through it
+/// first. False if the stream would not clone.
+fn publish_writer(
+    writer: &Mutex<Option<Link>>,
+    stream: &Link,
+    stats: &NetStats,
+    format: WireFormat,
+    unsynced: Vec<Entity>,
+) -> bool {
+    let mut send_stream = match stream.try_clone() {
+        Ok(send_stream) => send_stream,
+        Err(e) => {
+            eprintln!("Failed to clone stream: {}", e);
+            return false;
+        }
+    };
+    if !unsynced.is_empty() {
+        let message = Message::AllEntities(unsynced);
+        if let Err(e) = send_counted(&mut send_stream, stats, &message, format) {
+            eprintln!("Error sending up the canvas from before: {}", e);
+        }
+    }
+    if let Ok(mut writer) = writer.lock() {
+        *writer = Some(send_stream);
+    }
+    true
+}
 
-            thread::spawn(move || {
-                let mut send_stream = send_stream;
-                let mut sent_entities = std::collections::HashSet::new();
+/// Takes everything off the canvas of a client whose server restarted
+/// without it, and drops what the sender queued for the server before. With
+/// `--restore-on-reconnect` the entities go back on under new ids, which are
+/// returned to be sent up; otherwise they are gone.
+fn start_over(shared: &ClientShared) -> Vec<Entity> {
+    let mut kept = get_all_entities(&shared.entities);
+    shared.entities.clear();
+    shared.epoch.store(0, Ordering::SeqCst);
+    shared.generation.store(0, Ordering::SeqCst);
+    shared.discard_queued.store(true, Ordering::SeqCst);
+    if kept.is_empty() {
+        return kept;
+    }
+    if !shared.restore {
+        let text = format!(
+            "The server restarted; dropped the {} entities from before \
+             (--restore-on-reconnect sends them up instead)",
+            kept.len()
+        );
+        notice::warn(&shared.notices, text);
+        return Vec::new();
+    }
+    for entity in kept.iter_mut() {
+        // the server has a new counter, and possibly ids from it
+        entity.id = entity::next_id();
+        entity.epoch = 0;
+        entity.generation = 0;
+        shared.entities.insert(entity.clone());
+    }
+    let text = format!(
+        "The server restarted; sending the {} entities from before up again",
+        kept.len()
+    );
+    notice::warn(&shared.notices, text);
+    kept
+}
 
-                loop {
-                    for entry in entities_clone.iter() {
-                        let entity = entry.value().clone();
-                        let id = entity.id;
+fn run_client_session(stream: TcpStream, shared: &ClientShared) -> SessionEnd {
+    let ClientShared {
+        entities,
+        cursors,
+        chat_log,
+        writer,
+        epoch,
+        generation,
+        rtt,
+        status,
+        color,
+        permissions,
+        fade,
+        roster,
+        background,
+        notices,
+        stats,
+        name,
+        recorder,
+        compact,
+        session,
+        ..
+    } = shared;
+    let format = shared.format;
 
-                        if !sent_entities.contains(&id) {
-                            let message = Message::NewEntity(entity.clone());
-                            if let Err(e) = send_message(&mut send_stream, &message) {
-                                eprintln!("Error sending entity to server: {}", e);
-                                break;
-                            }
+    let mut stream = match &shared.tls {
+        None => Link::Plain(stream),
+        Some(config) => match tls::connect(stream, config.clone(), &shared.addr, CONNECT_TIMEOUT) {
+            Ok((socket, session)) => Link::Tls(tls::ClientLink::new(socket, session)),
+            Err(tls::ConnectError::Io(e)) => {
+                eprintln!("Error setting up TLS: {}", e);
+                return SessionEnd::Lost;
+            }
+            Err(tls::ConnectError::Refused(reason)) => return SessionEnd::Incompatible(reason),
+        },
+    };
 
-                            sent_entities.insert(id);
+    if let Err(end) = exchange_handshake(&mut stream) {
+        return end;
+    }
 
-                            if let Err(e) = server_tx.send(entity) {
-                                eprintln!("Error forwarding entity: {}", e);
-                            }
-                        }
-                    }
+    // The writer is published once the Welcome is in, so nothing queued can beat
+    // the Hello, and nothing goes out before the client knows whether the
+    // server is the one it had.
+    let hello = Message::Hello {
+        name: name.clone(),
+        spectator: shared.spectator,
+        room: shared.room.clone(),
+        secret: client_secret(),
+        compact: true,
+    };
+    // plain until this server's Welcome agrees
+    compact.store(false, Ordering::SeqCst);
+    // the Roster follows the Welcome
+    if let Ok(mut roster) = roster.lock() {
+        roster.clear();
+    }
+    stats.reset();
+    if !entities.is_empty() {
+        let known = Message::KnownEntities {
+            epoch: epoch.load(Ordering::SeqCst),
+            generation: generation.load(Ordering::SeqCst),
+            ids: entities.iter().map(|entity| *entity.key()).collect(),
+            session: session.load(Ordering::SeqCst),
+        };
+        if let Err(e) = send_counted(&mut stream, stats, &known, format) {
+            eprintln!("Error sending known entities: {}", e);
+            return SessionEnd::Lost;
+        }
+    }
+    if let Err(e) = send_counted(&mut stream, stats, &hello, format) {
+        eprintln!("Error sending hello: {}", e);
+        return SessionEnd::Lost;
+    }
 
-                    sent_entities.retain(|id| entities_clone.contains_key(id));
+    // A stalled server must not hold the writer lock forever.
+    if let Err(e) = stream.socket().set_write_timeout(Some(PEER_TIMEOUT)) {
+        eprintln!("Failed to set write timeout: {}", e);
+    }
 
-                    thread::sleep(Duration::from_millis(10));
-                }
-            });
+    // the canvas, or what is missing from ours, follows the hello without asking
 
-            let mut request_initial = true;
-            let mut message_handler = MessageHandler::new();
-            let mut buffer = [0; 4096];
+    let mut message_handler = MessageHandler::new();
+    let mut buffer = [0; 4096];
+    let started = Instant::now();
+    let mut last_ping: Option<Instant> = None;
+    let mut last_heard = Instant::now();
+    let mut smoothed_rtt: Option<f64> = None;
+    // Until the Welcome is in, and while the host decides, the writer stays
+    // unpublished so local drawings queue up in the sender, and pings go out
+    // on the session's own stream.
+    let mut welcomed = false;
+    let mut awaiting = false;
+    // kept through a server restart, to go up as soon as the writer is published
+    let mut unsynced = Vec::new();
 
-            loop {
-                while let Ok(entity) = server_rx.try_recv() {
-                    entities.insert(entity.id, entity);
-                }
+    loop {
+        if last_ping.is_none_or(|sent| sent.elapsed() >= PING_INTERVAL) {
+            last_ping = Some(Instant::now());
+            let ping = Message::Ping(started.elapsed().as_millis() as u64);
+            let result = if welcomed && !awaiting {
+                write_to_server(writer, stats, &ping, format)
+            } else {
+                send_counted(&mut stream, stats, &ping, format)
+            };
+            if let Err(e) = result {
+                eprintln!("Error sending ping: {}", e);
+            }
+        }
+        if last_heard.elapsed() > PEER_TIMEOUT {
+            println!("Server timed out");
+            return SessionEnd::Lost;
+        }
 
-                match stream.read(&mut buffer) {
-                    Ok(0) => {
-                        println!("Server disconnected");
-                        break;
+        // block until data arrives or the next ping is due
+        let until_ping = last_ping.map_or(PING_INTERVAL, |sent| {
+            PING_INTERVAL.saturating_sub(sent.elapsed())
+        });
+        if let Err(e) = stream
+            .socket()
+            .set_read_timeout(Some(until_ping.max(Duration::from_millis(1))))
+        {
+            eprintln!("Failed to set read timeout: {}", e);
+            return SessionEnd::Lost;
+        }
+
+        match stream.read(&mut buffer) {
+            Ok(0) => {
+                println!("Server disconnected");
+                return SessionEnd::Lost;
+            }
+            Ok(n) => {
+                last_heard = Instant::now();
+                stats.read(n);
+                message_handler.extend_buffer(&buffer[..n]);
+
+                while let Some(message_result) = message_handler.next_message() {
+                    if let Ok(message) = &message_result {
+                        stats.received(1);
+                        if matches!(message, Message::AllEntities(_)) {
+                            stats.snapshot(message_handler.last_frame_len());
+                        }
                     }
-                    Ok(n) => {
-                        request_initial = false;
-                        message_handler.extend_buffer(&buffer[..n]);
-
-                        while let Some(message_result) = message_handler.next_message() {
-                            match message_result {
-                                Ok(message) => match message {
-                                    Message::NewEntity(entity) => {
-                                        entities.insert(entity.id, entity);
-                                    }
-                                    Message::AllEntities(all_entities) => {
-                                        entities.clear();
-                                        for entity in all_entities {
-                                            entities.insert(entity.id, entity);
-                                        }
-                                    }
-                                    Message::RequestAllEntities => {
-                                        let all_entities = get_all_entities(&entities);
-                                        let message = Message::AllEntities(all_entities);
-                                        if let Err(e) = send_message(&mut stream, &message) {
-                                            eprintln!("Error sending all entities: {}", e);
-                                        }
+                    if let (Some(recorder), Ok(message)) = (recorder, &message_result) {
+                        recorder.record(message);
+                    }
+                    match message_result {
+                        Ok(message) => match message {
+                            Message::NewEntity(entity) => {
+                                if entity.epoch >= epoch.load(Ordering::SeqCst) {
+                                    entities.insert(entity);
+                                }
+                            }
+                            Message::EntityBatch(batch) => {
+                                let current = epoch.load(Ordering::SeqCst);
+                                for entity in batch {
+                                    if entity.epoch >= current {
+                                        entities.insert(entity);
                                     }
-                                },
-                                Err(e) => {
-                                    eprintln!("{}", e);
                                 }
                             }
-                        }
-
-                        message_handler.check_buffer_size();
-                    }
-                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
-                        if request_initial {
-                            let message = Message::RequestAllEntities;
-                            if let Err(e) = send_message(&mut stream, &message) {
-                                eprintln!("Error requesting initial entities: {}", e);
-                            } else {
-                                request_initial = false;
+                            Message::AllEntities(all_entities) => {
+                                // Merge rather than replace so anything drawn while
+                                // offline survives and is pushed up by the sender.
+                                for entity in all_entities {
+                                    entities.insert(entity);
+                                }
+                            }
+                            Message::RequestAllEntities => {
+                                let all_entities = get_all_entities(entities);
+                                let message = Message::AllEntities(all_entities);
+                                if let Err(e) = write_to_server(writer, stats, &message, format) {
+                                    eprintln!("Error sending all entities: {}", e);
+                                }
+                            }
+                            Message::ClearCanvas { epoch: cleared } => {
+                                if cleared > epoch.load(Ordering::SeqCst) {
+                                    epoch.store(cleared, Ordering::SeqCst);
+                                    entities.clear();
+                                }
+                            }
+                            // the server brings appends up to date before relaying them
+                            Message::StrokeAppend {
+                                id, points, pen, ..
+                            } => {
+                                entities.update(id, |entity| entity.extend_stroke(&points, &pen));
+                            }
+                            Message::TransformAll {
+                                transform,
+                                generation: current,
+                            } => {
+                                entities.update_all(|entity| {
+                                    transform.apply_to(entity);
+                                    entity.generation = current;
+                                });
+                                generation.store(current, Ordering::SeqCst);
+                            }
+                            Message::RemoveEntity { id } => {
+                                entities.remove(id);
+                            }
+                            Message::UpdateEntity { id, color } => {
+                                entities.update(id, |entity| entity.color = color);
+                            }
+                            Message::MoveEntities { moves, .. } => {
+                                for (id, x, y) in moves {
+                                    entities.update(id, |entity| entity.move_to((x, y)));
+                                }
+                            }
+                            // only ever sent to the server
+                            Message::Hello { .. } | Message::KnownEntities { .. } => {}
+                            // expanded as it arrived
+                            Message::CompactEntities(_) => {}
+                            Message::Disconnect => {
+                                println!("Server closed");
+                                return SessionEnd::ServerClosed;
+                            }
+                            Message::Rejected { reason } => {
+                                return SessionEnd::Rejected(reason);
+                            }
+                            Message::Kicked { reason } => {
+                                return SessionEnd::Kicked(reason);
+                            }
+                            Message::Welcome {
+                                color: assigned,
+                                owner,
+                                change_any,
+                                fade_ms,
+                                compact: agreed,
+                                session: current,
+                            } => {
+                                compact.store(agreed, Ordering::SeqCst);
+                                let previous = session.swap(current, Ordering::SeqCst);
+                                if previous != 0 && previous != current {
+                                    unsynced = start_over(shared);
+                                }
+                                if let Ok(mut color) = color.lock() {
+                                    *color = Some(assigned);
+                                }
+                                if let Ok(mut permissions) = permissions.lock() {
+                                    *permissions = Permissions { owner, change_any };
+                                }
+                                if let Ok(mut fade) = fade.lock() {
+                                    *fade = fade_ms.map(Duration::from_millis);
+                                }
+                                // a changed background follows; this server may not have one
+                                background.store(DEFAULT_BACKGROUND.bits(), Ordering::Relaxed);
+                                welcomed = true;
+                                let unsynced = std::mem::take(&mut unsynced);
+                                if !awaiting
+                                    && !publish_writer(writer, &stream, stats, format, unsynced)
+                                {
+                                    return SessionEnd::Lost;
+                                }
+                            }
+                            Message::SetBackground(color) => {
+                                background.store(color.bits(), Ordering::Relaxed);
+                            }
+                            Message::AwaitingApproval => {
+                                println!("Waiting for the host to approve the join");
+                                awaiting = true;
+                                if let Ok(mut writer) = writer.lock() {
+                                    *writer = None;
+                                }
+                                if let Ok(mut status) = status.lock() {
+                                    *status = ClientStatus::AwaitingApproval;
+                                }
+                            }
+                            Message::Approved => {
+                                println!("Join approved");
+                                awaiting = false;
+                                let unsynced = std::mem::take(&mut unsynced);
+                                if welcomed
+                                    && !publish_writer(writer, &stream, stats, format, unsynced)
+                                {
+                                    return SessionEnd::Lost;
+                                }
+                                if let Ok(mut status) = status.lock() {
+                                    *status = ClientStatus::Connected;
+                                }
+                            }
+                            Message::Chat { from, text } => {
+                                chat::push(chat_log, &chat::clean(&from), &chat::clean(&text));
+                            }
+                            Message::Roster(peers) => {
+                                if let Ok(mut roster) = roster.lock() {
+                                    *roster = peers;
+                                }
+                            }
+                            Message::PeerJoined { id, name } => {
+                                notice::tell(notices, format!("{} joined", name));
+                                if let Ok(mut roster) = roster.lock() {
+                                    roster.push((id, name));
+                                }
                             }
+                            Message::PeerLeft { id, name } => {
+                                notice::tell(notices, format!("{} left", name));
+                                if let Ok(mut roster) = roster.lock() {
+                                    roster.retain(|&(peer, _)| peer != id);
+                                }
+                            }
+                            Message::Ping(payload) => {
+                                let pong = Message::Pong(payload);
+                                let result = if welcomed && !awaiting {
+                                    write_to_server(writer, stats, &pong, format)
+                                } else {
+                                    send_counted(&mut stream, stats, &pong, format)
+                                };
+                                if let Err(e) = result {
+                                    eprintln!("Error answering ping: {}", e);
+                                }
+                            }
+                            Message::Pong(sent) => {
+                                let now = started.elapsed().as_millis() as u64;
+                                let sample = now.saturating_sub(sent) as f64;
+                                let smoothed = match smoothed_rtt {
+                                    Some(previous) => {
+                                        previous + RTT_SMOOTHING * (sample - previous)
+                                    }
+                                    None => sample,
+                                };
+                                smoothed_rtt = Some(smoothed);
+                                rtt.store(smoothed.round() as u64, Ordering::Relaxed);
+                            }
+                            Message::Cursor {
+                                peer_id,
+                                x,
+                                y,
+                                color,
+                            } => {
+                                cursors.insert(peer_id, RemoteCursor::new(x, y, color));
+                            }
+                        },
+                        Err(e) => {
+                            let text = format!("Skipped a message from the server: {}", e);
+                            notice::warn(notices, text);
                         }
                     }
-                    Err(e) => {
-                        eprintln!("Error reading from server: {}", e);
-                        break;
-                    }
                 }
-
-                thread::sleep(Duration::from_millis(SLEEP_DURATION));
+            }
+            // read timeout: time to ping
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock
+                        | io::ErrorKind::TimedOut
+                        | io::ErrorKind::Interrupted
+                ) => {}
+            Err(e) => {
+                eprintln!("Error reading from server: {}", e);
+                return SessionEnd::Lost;
             }
         }
-        Err(e) => {
-            eprintln!("Failed to connect to server: {}", e);
-        }
-    });
+    }
 }
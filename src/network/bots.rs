@@ -0,0 +1,476 @@
+//! Synthetic clients for load testing a server. Each one speaks the wire
+//! protocol as a window would, draws random-walk strokes at a steady rate and
+//! times how long every stroke takes to reach the other bots.
+
+use super::{
+    Message, MessageHandler, PING_INTERVAL, WireFormat, connect, handshake, new_session,
+    send_message,
+};
+use crate::config::Config;
+use crate::entity::{
+    DEFAULT_THINNEST, Entity, EntityColor, HOST_OWNER, PenSamples, Shape, next_id, next_seq,
+    now_millis,
+};
+use dashmap::DashMap;
+use std::f32::consts::{PI, TAU};
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Strokes a second each bot draws unless `--bot-rate` says otherwise.
+pub const DEFAULT_BOT_RATE: f64 = 10.0;
+/// Points in each stroke: the first goes out in its NewEntity, the rest in one append.
+pub const STROKE_POINTS: usize = 16;
+/// Length of one step of a walk, in world pixels.
+const STEP: f32 = 6.0;
+/// Most a walk turns in one step, either way, in radians.
+const MAX_TURN: f32 = 0.75;
+/// World area the walks stay in: the canvas every window fits itself to.
+const AREA: (f32, f32) = (1920.0, 1080.0);
+const BRUSH_RADIUS: f32 = 3.0;
+/// How long a stroke is looked out for in what the other bots receive.
+const SENT_KEPT: Duration = Duration::from_secs(30);
+
+/// The strokes one bot draws: a random walk over the canvas, each stroke
+/// starting where the last one ended. The same seed and bot always walk the
+/// same way.
+pub struct Walk {
+    state: u64,
+    position: (f32, f32),
+    heading: f32,
+}
+
+impl Walk {
+    pub fn new(seed: u64, bot: usize) -> Self {
+        let mut walk = Self {
+            state: seed ^ (bot as u64).wrapping_mul(0xD1B5_4A32_D192_ED03),
+            position: (0.0, 0.0),
+            heading: 0.0,
+        };
+        walk.position = (walk.unit() * AREA.0, walk.unit() * AREA.1);
+        walk.heading = walk.unit() * TAU;
+        walk
+    }
+
+    /// The next STROKE_POINTS points of the walk.
+    pub fn next_stroke(&mut self) -> Vec<(f32, f32)> {
+        let mut points = Vec::with_capacity(STROKE_POINTS);
+        points.push(self.position);
+        for _ in 1..STROKE_POINTS {
+            self.heading += (self.unit() * 2.0 - 1.0) * MAX_TURN;
+            let (x, y) = self.position;
+            let next = (x + STEP * self.heading.cos(), y + STEP * self.heading.sin());
+            let inside = (next.0.clamp(0.0, AREA.0), next.1.clamp(0.0, AREA.1));
+            if inside != next {
+                // turned back at the edge
+                self.heading += PI;
+            }
+            self.position = inside;
+            points.push(inside);
+        }
+        points
+    }
+
+    /// SplitMix64, which is all a walk needs and keeps the dependencies as they are.
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// From 0 up to 1.
+    fn unit(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+}
+
+/// How long strokes took to reach the other bots, from the bot that drew one
+/// sending it to another one reading it back out of the server's relay.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Latency {
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+    /// Arrivals timed: every stroke counts once for each bot it reached.
+    pub samples: usize,
+}
+
+impl Latency {
+    /// None without any samples.
+    fn of(mut samples: Vec<Duration>) -> Option<Self> {
+        samples.sort_unstable();
+        let max = *samples.last()?;
+        let percentile = |share: f64| {
+            let last = samples.len() - 1;
+            samples[(last as f64 * share).round() as usize]
+        };
+        Some(Self {
+            p50: percentile(0.5),
+            p90: percentile(0.9),
+            p99: percentile(0.99),
+            max,
+            samples: samples.len(),
+        })
+    }
+}
+
+/// Where a bot run stands.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BotReport {
+    pub bots: usize,
+    /// Bots the server let in and that are still there, or were when the bots
+    /// stopped. One turned away, or whose connection went, no longer counts.
+    pub joined: usize,
+    /// Strokes drawn since the bots started.
+    pub sent: u64,
+    /// Strokes relayed to a bot from the others since the bots started, each
+    /// one counted for every bot it reached.
+    pub received: u64,
+    /// Over the arrivals since the report before; None if there were none.
+    pub latency: Option<Latency>,
+}
+
+impl fmt::Display for BotReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}/{} bots joined, {} strokes sent, {} received from other bots",
+            self.joined, self.bots, self.sent, self.received
+        )?;
+        let ms = |duration: Duration| duration.as_secs_f64() * 1000.0;
+        match self.latency {
+            Some(latency) => write!(
+                f,
+                "; latency p50 {:.1} ms, p90 {:.1} ms, p99 {:.1} ms, max {:.1} ms over {} arrivals",
+                ms(latency.p50),
+                ms(latency.p90),
+                ms(latency.p99),
+                ms(latency.max),
+                latency.samples
+            ),
+            None => write!(f, "; no arrivals to time"),
+        }
+    }
+}
+
+/// What the bots of one run share.
+struct Shared {
+    stop: AtomicBool,
+    /// Strokes still looked out for: who drew each one, and when it went out.
+    sent: DashMap<usize, (usize, Instant)>,
+    joined: AtomicUsize,
+    sent_count: AtomicU64,
+    received: AtomicU64,
+    latencies: Mutex<Vec<Duration>>,
+}
+
+/// Running bots, until `stop`.
+pub struct Bots {
+    count: usize,
+    shared: Arc<Shared>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl Bots {
+    /// Totals so far, and the latencies since the last report.
+    pub fn report(&self) -> BotReport {
+        let latencies = self
+            .shared
+            .latencies
+            .lock()
+            .map(|mut latencies| std::mem::take(&mut *latencies))
+            .unwrap_or_default();
+        // a stroke that reached nobody by now never will
+        self.shared
+            .sent
+            .retain(|_, (_, sent_at)| sent_at.elapsed() < SENT_KEPT);
+        BotReport {
+            bots: self.count,
+            joined: self.shared.joined.load(Ordering::Relaxed),
+            sent: self.shared.sent_count.load(Ordering::Relaxed),
+            received: self.shared.received.load(Ordering::Relaxed),
+            latency: Latency::of(latencies),
+        }
+    }
+
+    /// Says goodbye for every bot and waits for them, returning the last report.
+    pub fn stop(mut self) -> BotReport {
+        self.shared.stop.store(true, Ordering::Relaxed);
+        for thread in self.threads.drain(..) {
+            if thread.join().is_err() {
+                eprintln!("Bot thread panicked");
+            }
+        }
+        self.report()
+    }
+}
+
+/// Starts the `--bots` given in `config`, each on a connection of its own to
+/// the server it connects to, drawing `--bot-rate` strokes a second in
+/// `--room`. The walks follow `--bot-seed`, or a random seed that is printed
+/// so the run can be repeated.
+pub fn start_bots(config: &Config) -> Bots {
+    let count = config.bots.unwrap_or(0);
+    let seed = config.bot_seed.unwrap_or_else(new_session);
+    println!(
+        "Starting {} bots at {} strokes a second each, seed {}",
+        count, config.bot_rate, seed
+    );
+    let shared = Arc::new(Shared {
+        stop: AtomicBool::new(false),
+        sent: DashMap::new(),
+        joined: AtomicUsize::new(0),
+        sent_count: AtomicU64::new(0),
+        received: AtomicU64::new(0),
+        latencies: Mutex::default(),
+    });
+    let interval = Duration::from_secs_f64(1.0 / config.bot_rate);
+    let threads = (0..count)
+        .map(|index| {
+            let bot = Bot {
+                index,
+                name: format!("bot-{}", index + 1),
+                addr: config.role.addr().to_string(),
+                room: config.room.clone(),
+                format: config.wire_format,
+                walk: Walk::new(seed, index),
+                interval,
+                // spread out over the first interval rather than all drawing at once
+                first_stroke: interval.mul_f64(index as f64 / count as f64),
+                epoch: 0,
+                generation: 0,
+                welcomed: false,
+                awaiting: false,
+                joined: false,
+            };
+            let shared = shared.clone();
+            thread::spawn(move || bot.run(&shared))
+        })
+        .collect();
+    Bots {
+        count,
+        shared,
+        threads,
+    }
+}
+
+struct Bot {
+    index: usize,
+    name: String,
+    addr: String,
+    room: String,
+    format: WireFormat,
+    walk: Walk,
+    interval: Duration,
+    first_stroke: Duration,
+    /// Newest canvas epoch and transform generation heard of, stamped on
+    /// strokes so a clear or transform does not get them dropped.
+    epoch: u64,
+    generation: u64,
+    welcomed: bool,
+    awaiting: bool,
+    joined: bool,
+}
+
+impl Bot {
+    fn run(mut self, shared: &Shared) {
+        let mut stream = match connect(&self.addr) {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("{} cannot connect: {}", self.name, e);
+                return;
+            }
+        };
+        let hello = Message::Hello {
+            name: self.name.clone(),
+            spectator: false,
+            room: self.room.clone(),
+            secret: 0,
+            compact: false,
+        };
+        let greeting = stream
+            .write_all(&handshake())
+            .and_then(|()| send_message(&mut stream, &hello, self.format));
+        if let Err(e) = greeting {
+            eprintln!("{} cannot say hello: {}", self.name, e);
+            return;
+        }
+        match self.converse(&mut stream, shared) {
+            Ok(()) => {
+                let _ = send_message(&mut stream, &Message::Disconnect, self.format);
+            }
+            Err(reason) => {
+                eprintln!("{} stopped: {}", self.name, reason);
+                if self.joined {
+                    shared.joined.fetch_sub(1, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+
+    /// Draws and listens until the bots stop, or the server ends it.
+    fn converse(&mut self, stream: &mut TcpStream, shared: &Shared) -> Result<(), String> {
+        let mut handler = MessageHandler::expecting_handshake();
+        let mut buffer = [0; 16384];
+        let mut next_stroke = Instant::now() + self.first_stroke;
+        let mut last_ping = Instant::now();
+        while !shared.stop.load(Ordering::Relaxed) {
+            let now = Instant::now();
+            if self.joined && now >= next_stroke {
+                self.draw(stream, shared).map_err(|e| e.to_string())?;
+                // one that fell behind carries on from now rather than catching up in a burst
+                next_stroke = (next_stroke + self.interval).max(now);
+            }
+            if last_ping.elapsed() >= PING_INTERVAL {
+                last_ping = now;
+                send_message(stream, &Message::Ping(0), self.format).map_err(|e| e.to_string())?;
+            }
+
+            let until_stroke = if self.joined {
+                next_stroke.saturating_duration_since(now)
+            } else {
+                PING_INTERVAL
+            };
+            let until_ping = PING_INTERVAL.saturating_sub(last_ping.elapsed());
+            let wait = until_stroke.min(until_ping).max(Duration::from_millis(1));
+            stream
+                .set_read_timeout(Some(wait))
+                .map_err(|e| e.to_string())?;
+            match stream.read(&mut buffer) {
+                Ok(0) => return Err("the server went away".into()),
+                Ok(n) => {
+                    handler.extend_buffer(&buffer[..n]);
+                    if let Some(Err(e)) = handler.take_handshake() {
+                        return Err(e);
+                    }
+                    while let Some(message) = handler.next_message() {
+                        match message {
+                            Ok(message) => self.hear(message, stream, shared)?,
+                            Err(e) => eprintln!("{} skipped a message: {}", self.name, e),
+                        }
+                    }
+                }
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock
+                            | io::ErrorKind::TimedOut
+                            | io::ErrorKind::Interrupted
+                    ) => {}
+                Err(e) => return Err(e.to_string()),
+            }
+        }
+        Ok(())
+    }
+
+    fn hear(
+        &mut self,
+        message: Message,
+        stream: &mut TcpStream,
+        shared: &Shared,
+    ) -> Result<(), String> {
+        match message {
+            Message::NewEntity(entity) => self.arrived(std::slice::from_ref(&entity), shared),
+            Message::EntityBatch(batch) => self.arrived(&batch, shared),
+            Message::ClearCanvas { epoch } => self.epoch = self.epoch.max(epoch),
+            Message::TransformAll { generation, .. } => self.generation = generation,
+            Message::Welcome { .. } => {
+                self.welcomed = true;
+                self.join_if_let_in(stream, shared)?;
+            }
+            Message::AwaitingApproval => self.awaiting = true,
+            Message::Approved => {
+                self.awaiting = false;
+                self.join_if_let_in(stream, shared)?;
+            }
+            Message::Ping(payload) => {
+                send_message(stream, &Message::Pong(payload), self.format)
+                    .map_err(|e| e.to_string())?;
+            }
+            Message::Rejected { reason } => return Err(format!("rejected: {}", reason)),
+            Message::Kicked { reason } => return Err(format!("kicked: {}", reason)),
+            Message::Disconnect => return Err("the server closed".into()),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Once the server has welcomed the bot and let it in, asks for the
+    /// canvas as a client with nothing of its own does, and starts drawing.
+    fn join_if_let_in(&mut self, stream: &mut TcpStream, shared: &Shared) -> Result<(), String> {
+        if !self.welcomed || self.awaiting || self.joined {
+            return Ok(());
+        }
+        send_message(stream, &Message::RequestAllEntities, self.format)
+            .map_err(|e| e.to_string())?;
+        self.joined = true;
+        shared.joined.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Times the strokes of other bots among `entities`.
+    fn arrived(&self, entities: &[Entity], shared: &Shared) {
+        let mut latencies = Vec::new();
+        for entity in entities {
+            if let Some(sent) = shared.sent.get(&entity.id) {
+                let (drawn_by, sent_at) = *sent;
+                if drawn_by != self.index {
+                    latencies.push(sent_at.elapsed());
+                }
+            }
+        }
+        if latencies.is_empty() {
+            return;
+        }
+        shared
+            .received
+            .fetch_add(latencies.len() as u64, Ordering::Relaxed);
+        if let Ok(mut all) = shared.latencies.lock() {
+            all.extend(latencies);
+        }
+    }
+
+    /// Sends the next stroke of the walk: its first point as a new entity,
+    /// the rest as an append, as a window sends a stroke being drawn.
+    fn draw(&mut self, stream: &mut TcpStream, shared: &Shared) -> io::Result<()> {
+        let points = self.walk.next_stroke();
+        let (x, y) = points[0];
+        let entity = Entity {
+            id: next_id(),
+            x,
+            y,
+            radius: BRUSH_RADIUS,
+            color: EntityColor::PALETTE[self.index % EntityColor::PALETTE.len()],
+            epoch: self.epoch,
+            generation: self.generation,
+            shape: Shape::Stroke {
+                points: vec![(x, y)],
+                pen: PenSamples::default(),
+                thinnest: DEFAULT_THINNEST,
+            },
+            owner: HOST_OWNER,
+            created_at: now_millis(),
+            seq: next_seq(),
+            layer: 0,
+        };
+        let id = entity.id;
+        shared.sent.insert(id, (self.index, Instant::now()));
+        send_message(stream, &Message::NewEntity(entity), self.format)?;
+        let append = Message::StrokeAppend {
+            id,
+            points: points[1..].to_vec(),
+            pen: PenSamples::default(),
+            generation: self.generation,
+        };
+        send_message(stream, &append, self.format)?;
+        shared.sent_count.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+}
@@ -0,0 +1,198 @@
+//! New entities as they go to peers that said they read them this way: every
+//! position counted in fixed steps from an origin the batch carries, instead
+//! of as floats at a precision nobody draws with.
+
+use crate::entity::{Entity, EntityColor, PenSamples, Shape};
+use serde::{Deserialize, Serialize};
+
+/// Steps per pixel positions are counted in, so each is off by an eighth of a
+/// pixel at most.
+pub const STEPS_PER_PIXEL: f32 = 4.0;
+/// Steps per pixel a radius is counted in; a byte of them reaches 127.5.
+const RADIUS_STEPS_PER_PIXEL: f32 = 2.0;
+
+/// Entities drawn anywhere on the canvas, at any pan or zoom: the origin is
+/// the first entity's position, so the steps stay small however far from
+/// the world's origin the drawing is.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CompactBatch {
+    /// World position every step count in the batch is measured from.
+    pub origin: (f32, f32),
+    /// Steps per pixel, STEPS_PER_PIXEL from this build.
+    pub scale: f32,
+    pub entities: Vec<CompactEntity>,
+}
+
+/// An `Entity` with its position in steps from the batch origin and its
+/// radius in half pixels; everything else as it is.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CompactEntity {
+    id: usize,
+    x: i32,
+    y: i32,
+    radius: u8,
+    color: EntityColor,
+    epoch: u64,
+    generation: u64,
+    shape: CompactShape,
+    owner: u64,
+    created_at: u64,
+    seq: u64,
+    layer: u8,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+enum CompactShape {
+    Circle,
+    /// Each point in steps from the one before, the entity position first.
+    Stroke {
+        points: Vec<(i32, i32)>,
+        pen: PenSamples,
+        thinnest: f32,
+    },
+    /// Shapes that keep their full precision.
+    Other(Shape),
+}
+
+impl CompactBatch {
+    /// Whether the batch can be read back: a scale no peer of ours would send
+    /// puts every entity at infinity.
+    pub fn is_readable(&self) -> bool {
+        self.scale.is_finite() && self.scale > 0.0
+    }
+}
+
+impl TryFrom<&[Entity]> for CompactBatch {
+    type Error = String;
+
+    /// Fails for an empty batch, and for one with a position too far out or a
+    /// radius too wide to count in steps; it then goes out as it is.
+    fn try_from(entities: &[Entity]) -> Result<Self, Self::Error> {
+        let first = entities.first().ok_or("nothing to compact")?;
+        let origin = (first.x, first.y);
+        let entities = entities
+            .iter()
+            .map(|entity| {
+                compact(entity, origin)
+                    .ok_or_else(|| format!("entity {} does not fit in steps", entity.id))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            origin,
+            scale: STEPS_PER_PIXEL,
+            entities,
+        })
+    }
+}
+
+impl From<CompactBatch> for Vec<Entity> {
+    fn from(batch: CompactBatch) -> Self {
+        let CompactBatch {
+            origin,
+            scale,
+            entities,
+        } = batch;
+        let at = |x: i64, y: i64| (origin.0 + x as f32 / scale, origin.1 + y as f32 / scale);
+        entities
+            .into_iter()
+            .map(|entity| {
+                let (x, y) = at(entity.x.into(), entity.y.into());
+                let shape = match entity.shape {
+                    CompactShape::Circle => Shape::Circle,
+                    CompactShape::Stroke {
+                        points,
+                        pen,
+                        thinnest,
+                    } => {
+                        let (mut step_x, mut step_y) = (i64::from(entity.x), i64::from(entity.y));
+                        let points = points
+                            .into_iter()
+                            .map(|(dx, dy)| {
+                                step_x += i64::from(dx);
+                                step_y += i64::from(dy);
+                                at(step_x, step_y)
+                            })
+                            .collect();
+                        Shape::Stroke {
+                            points,
+                            pen,
+                            thinnest,
+                        }
+                    }
+                    CompactShape::Other(shape) => shape,
+                };
+                Entity {
+                    id: entity.id,
+                    x,
+                    y,
+                    radius: f32::from(entity.radius) / RADIUS_STEPS_PER_PIXEL,
+                    color: entity.color,
+                    epoch: entity.epoch,
+                    generation: entity.generation,
+                    shape,
+                    owner: entity.owner,
+                    created_at: entity.created_at,
+                    seq: entity.seq,
+                    layer: entity.layer,
+                }
+            })
+            .collect()
+    }
+}
+
+fn compact(entity: &Entity, origin: (f32, f32)) -> Option<CompactEntity> {
+    let (x, y) = (steps(entity.x, origin.0)?, steps(entity.y, origin.1)?);
+    let radius = (entity.radius * RADIUS_STEPS_PER_PIXEL).round();
+    if !(0.0..=f32::from(u8::MAX)).contains(&radius) {
+        return None;
+    }
+    let shape = match &entity.shape {
+        Shape::Circle => CompactShape::Circle,
+        Shape::Stroke {
+            points,
+            pen,
+            thinnest,
+        } => {
+            let mut previous = (x, y);
+            let points = points
+                .iter()
+                .map(|&(px, py)| {
+                    let point = (steps(px, origin.0)?, steps(py, origin.1)?);
+                    let delta = (
+                        point.0.checked_sub(previous.0)?,
+                        point.1.checked_sub(previous.1)?,
+                    );
+                    previous = point;
+                    Some(delta)
+                })
+                .collect::<Option<_>>()?;
+            CompactShape::Stroke {
+                points,
+                pen: pen.clone(),
+                thinnest: *thinnest,
+            }
+        }
+        other => CompactShape::Other(other.clone()),
+    };
+    Some(CompactEntity {
+        id: entity.id,
+        x,
+        y,
+        radius: radius as u8,
+        color: entity.color,
+        epoch: entity.epoch,
+        generation: entity.generation,
+        shape,
+        owner: entity.owner,
+        created_at: entity.created_at,
+        seq: entity.seq,
+        layer: entity.layer,
+    })
+}
+
+/// `value` in steps from `origin`, unless that is beyond an i32.
+fn steps(value: f32, origin: f32) -> Option<i32> {
+    let steps = ((value - origin) * STEPS_PER_PIXEL).round();
+    // i32::MAX as f32 rounds up to 2^31, which no longer fits
+    (steps >= i32::MIN as f32 && steps < i32::MAX as f32).then_some(steps as i32)
+}
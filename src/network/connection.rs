@@ -0,0 +1,215 @@
+//! A thread for every client connection, owning its socket, the framing state
+//! and the queue of what is still to be written to it. The server's loop hands
+//! it frames to send and hears back what the client sent, so a client that is
+//! slow to read or floods the server with bytes only holds up its own thread.
+
+use super::{Encoding, HOUSEKEEPING_INTERVAL, Incoming, Peer, SharedStats, Traffic, WireFormat};
+use crossbeam_channel::{Receiver, Sender, TryRecvError};
+use mio::{Events, Poll, Token, Waker};
+use std::io;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+/// The client's socket in its connection thread's poll.
+pub(super) const SOCKET: Token = Token(0);
+const WAKER: Token = Token(1);
+
+/// What a connection thread tells the server's loop.
+pub(super) enum Event {
+    /// What the socket had to read, and how the client is answered from now on.
+    Read(Incoming, Encoding),
+    /// Drawing was dropped for the client, which is behind.
+    FellBehind,
+    /// See `Outbound::caught_up`.
+    CaughtUp,
+    /// Writing to the client failed, which ends its thread.
+    Failed(io::Error),
+}
+
+/// Where connection threads report to: a channel the server's loop drains
+/// whenever its poll is woken.
+#[derive(Clone)]
+pub(super) struct Reports {
+    pub(super) events: Sender<(Token, Event)>,
+    pub(super) waker: Arc<Waker>,
+}
+
+impl Reports {
+    fn send(&self, token: Token, event: Event) {
+        if self.events.send((token, event)).is_ok() {
+            let _ = self.waker.wake();
+        }
+    }
+}
+
+/// The server loop's end of a connection thread. Dropping it ends the thread,
+/// once it has written what the socket takes of what it was handed.
+pub(super) struct Connection {
+    frames: Option<Sender<(Arc<[u8]>, Traffic)>>,
+    waker: Arc<Waker>,
+    /// As the thread last reported it.
+    encoding: Encoding,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Connection {
+    /// Starts the thread for `peer`, whose socket is registered with `poll`
+    /// as SOCKET. Events go out under `token`.
+    pub(super) fn spawn(
+        peer: Peer,
+        poll: Poll,
+        token: Token,
+        fallback: WireFormat,
+        stats: SharedStats,
+        reports: Reports,
+    ) -> io::Result<Self> {
+        let waker = Arc::new(Waker::new(poll.registry(), WAKER)?);
+        let (frames, frames_rx) = crossbeam_channel::unbounded();
+        let encoding = peer.encoding(fallback);
+        let thread = thread::Builder::new()
+            .name(format!("client {}", token.0))
+            .spawn(move || {
+                let mut thread = Thread {
+                    peer,
+                    poll,
+                    frames: frames_rx,
+                    token,
+                    fallback,
+                    stats,
+                    reports,
+                };
+                thread.run();
+            })?;
+        Ok(Self {
+            frames: Some(frames),
+            waker,
+            encoding,
+            thread: Some(thread),
+        })
+    }
+
+    pub(super) fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+
+    pub(super) fn set_encoding(&mut self, encoding: Encoding) {
+        self.encoding = encoding;
+    }
+
+    /// Hands the thread an encoded message to queue and write. If the thread
+    /// has ended, it has already reported why.
+    pub(super) fn send(&self, frame: Arc<[u8]>, traffic: Traffic) {
+        if let Some(frames) = &self.frames
+            && frames.send((frame, traffic)).is_ok()
+        {
+            let _ = self.waker.wake();
+        }
+    }
+
+    /// Ends the thread and waits for it to finish writing what it can.
+    pub(super) fn close(mut self) {
+        self.hang_up();
+        if let Some(thread) = self.thread.take()
+            && thread.join().is_err()
+        {
+            eprintln!("Connection thread panicked");
+        }
+    }
+
+    fn hang_up(&mut self) {
+        // the thread sees the channel closed once it is woken
+        self.frames = None;
+        let _ = self.waker.wake();
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.hang_up();
+    }
+}
+
+struct Thread {
+    peer: Peer,
+    poll: Poll,
+    frames: Receiver<(Arc<[u8]>, Traffic)>,
+    token: Token,
+    fallback: WireFormat,
+    stats: SharedStats,
+    reports: Reports,
+}
+
+impl Thread {
+    fn run(&mut self) {
+        let mut events = Events::with_capacity(16);
+        loop {
+            let open = match self.take_frames() {
+                Ok(open) => open,
+                Err(e) => return self.reports.send(self.token, Event::Failed(e)),
+            };
+            // best effort once hung up: whatever the socket takes now, then FIN on drop
+            if let Err(e) = self.peer.flush() {
+                if open {
+                    self.reports.send(self.token, Event::Failed(e));
+                }
+                return;
+            }
+            if !open {
+                return;
+            }
+            if let Some(outbound) = self.peer.outbound() {
+                self.stats.queued(outbound.queued());
+                if outbound.caught_up() {
+                    self.reports.send(self.token, Event::CaughtUp);
+                }
+            }
+
+            if let Err(e) = self.poll.poll(&mut events, Some(HOUSEKEEPING_INTERVAL))
+                && e.kind() != io::ErrorKind::Interrupted
+            {
+                eprintln!("Error polling client socket: {}", e);
+            }
+            let readable = events.iter().any(|event| {
+                event.token() == SOCKET && (event.is_readable() || event.is_read_closed())
+            });
+            // writable: the flush above picks up where the socket left off
+            if readable {
+                let incoming = self.peer.receive();
+                let closed = incoming.closed.is_some();
+                if incoming.heard || closed {
+                    let encoding = self.peer.encoding(self.fallback);
+                    self.reports
+                        .send(self.token, Event::Read(incoming, encoding));
+                }
+                // the server's loop removes the client, which is all that is left
+                if closed {
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Queues every frame handed over so far. Returns whether the server's
+    /// loop still holds its end.
+    fn take_frames(&mut self) -> io::Result<bool> {
+        loop {
+            let (frame, traffic) = match self.frames.try_recv() {
+                Ok(frame) => frame,
+                Err(TryRecvError::Empty) => return Ok(true),
+                Err(TryRecvError::Disconnected) => return Ok(false),
+            };
+            let behind = self.skipped() > 0;
+            self.peer.send(frame, traffic)?;
+            if !behind && self.skipped() > 0 {
+                self.reports.send(self.token, Event::FellBehind);
+            }
+        }
+    }
+
+    /// Drawing frames dropped since the client last caught up.
+    fn skipped(&mut self) -> usize {
+        self.peer
+            .outbound()
+            .map_or(0, |outbound| outbound.skipped())
+    }
+}
@@ -0,0 +1,388 @@
+//! TLS on both ends, for sessions over networks nobody trusts. The handshake,
+//! frames and everything after them are the same as over plain TCP, only
+//! inside a TLS session.
+
+use super::{
+    Closed, HANDSHAKE_MAGIC, Incoming, MessageHandler, Outbound, Traffic, WireFormat, handshake,
+    reject, take_messages,
+};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::{ClientConfig, ClientConnection, RootCertStore, ServerConfig, ServerConnection};
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+/// What a server answers a plain client with, so it knows to retry with TLS.
+const TLS_REQUIRED: &str = "This server requires TLS; connect with --tls";
+
+/// Loads the server's certificate chain and private key, both PEM.
+pub fn server_config(cert: &Path, key: &Path) -> Result<Arc<ServerConfig>, String> {
+    let certs = CertificateDer::pem_file_iter(cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("Cannot read certificates from {}: {}", cert.display(), e))?;
+    if certs.is_empty() {
+        return Err(format!("No certificates in {}", cert.display()));
+    }
+    let key = PrivateKeyDer::from_pem_file(key)
+        .map_err(|e| format!("Cannot read a private key from {}: {}", key.display(), e))?;
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| format!("Cannot use the certificate and key: {}", e))?;
+    Ok(Arc::new(config))
+}
+
+/// Trusts the certificates in `ca`, for a server with a self-signed one, or
+/// else the usual public roots.
+pub fn client_config(ca: Option<&Path>) -> Result<Arc<ClientConfig>, String> {
+    let mut roots = RootCertStore::empty();
+    match ca {
+        Some(ca) => {
+            let certs = CertificateDer::pem_file_iter(ca)
+                .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+                .map_err(|e| format!("Cannot read certificates from {}: {}", ca.display(), e))?;
+            let (added, _) = roots.add_parsable_certificates(certs);
+            if added == 0 {
+                return Err(format!("No usable certificates in {}", ca.display()));
+            }
+        }
+        None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+    }
+    let config = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(Arc::new(config))
+}
+
+/// The host part of `addr`, which the server's certificate has to name.
+fn server_name(addr: &str) -> Result<ServerName<'static>, String> {
+    let host = addr.rsplit_once(':').map_or(addr, |(host, _)| host);
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    ServerName::try_from(host.to_string()).map_err(|_| {
+        format!(
+            "{} is not a name a certificate can be checked against",
+            host
+        )
+    })
+}
+
+/// Why a TLS session to the server could not be set up.
+pub(super) enum ConnectError {
+    /// Worth retrying, like any dropped connection.
+    Io(io::Error),
+    /// Retrying will not help: the certificate or the server is wrong.
+    Refused(String),
+}
+
+/// Runs the TLS handshake with the server at `addr` over `socket`.
+pub(super) fn connect(
+    mut socket: TcpStream,
+    config: Arc<ClientConfig>,
+    addr: &str,
+    timeout: Duration,
+) -> Result<(TcpStream, ClientConnection), ConnectError> {
+    let name = server_name(addr).map_err(ConnectError::Refused)?;
+    let mut session = ClientConnection::new(config, name)
+        .map_err(|e| ConnectError::Refused(format!("TLS setup failed: {}", e)))?;
+    socket
+        .set_read_timeout(Some(timeout))
+        .map_err(ConnectError::Io)?;
+    let mut sniffing = Sniffing {
+        socket: &mut socket,
+        first: Vec::new(),
+    };
+    while session.is_handshaking() {
+        if let Err(e) = session.complete_io(&mut sniffing) {
+            // a plain server opens with our handshake, unasked
+            if sniffing.first.starts_with(HANDSHAKE_MAGIC) {
+                return Err(ConnectError::Refused(
+                    "the server does not use TLS; connect without --tls".into(),
+                ));
+            }
+            return Err(match e.kind() {
+                io::ErrorKind::InvalidData => {
+                    ConnectError::Refused(format!("TLS handshake failed: {}", e))
+                }
+                _ => ConnectError::Io(e),
+            });
+        }
+    }
+    Ok((socket, session))
+}
+
+/// Keeps the first bytes the server sends, to tell what it was speaking if
+/// the handshake fails.
+struct Sniffing<'a> {
+    socket: &'a mut TcpStream,
+    first: Vec<u8>,
+}
+
+impl Read for Sniffing<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.socket.read(buf)?;
+        let wanted = HANDSHAKE_MAGIC.len().saturating_sub(self.first.len());
+        self.first.extend_from_slice(&buf[..n.min(wanted)]);
+        Ok(n)
+    }
+}
+
+impl Write for Sniffing<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.socket.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.socket.flush()
+    }
+}
+
+/// The client end of a TLS session. Each clone has its own handle on the socket
+/// and shares the session, so one thread can wait for data while another writes.
+pub(super) struct ClientLink {
+    socket: TcpStream,
+    session: Arc<Mutex<ClientConnection>>,
+}
+
+impl ClientLink {
+    pub(super) fn new(socket: TcpStream, session: ClientConnection) -> Self {
+        Self {
+            socket,
+            session: Arc::new(Mutex::new(session)),
+        }
+    }
+
+    pub(super) fn try_clone(&self) -> io::Result<Self> {
+        Ok(Self {
+            socket: self.socket.try_clone()?,
+            session: self.session.clone(),
+        })
+    }
+
+    pub(super) fn socket(&self) -> &TcpStream {
+        &self.socket
+    }
+
+    pub(super) fn shutdown(&self) -> io::Result<()> {
+        if let Ok(mut session) = self.session.lock() {
+            session.send_close_notify();
+            let _ = session.write_tls(&mut &self.socket);
+        }
+        self.socket.shutdown(Shutdown::Both)
+    }
+
+    fn session(&self) -> io::Result<MutexGuard<'_, ClientConnection>> {
+        self.session
+            .lock()
+            .map_err(|_| io::Error::other("TLS session lock poisoned"))
+    }
+}
+
+impl Read for ClientLink {
+    /// Blocks on the socket, not the session, so writers are never held up by a read.
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut raw = [0; 4096];
+        loop {
+            match self.session()?.reader().read(buf) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                result => return result,
+            }
+            let n = (&self.socket).read(&mut raw)?;
+            if n == 0 {
+                return Ok(0);
+            }
+            let mut session = self.session()?;
+            let mut rest = &raw[..n];
+            while !rest.is_empty() {
+                session.read_tls(&mut rest)?;
+                session
+                    .process_new_packets()
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            }
+            while session.wants_write() {
+                session.write_tls(&mut &self.socket)?;
+            }
+        }
+    }
+}
+
+impl Write for ClientLink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut session = self.session()?;
+        let n = session.writer().write(buf)?;
+        while session.wants_write() {
+            session.write_tls(&mut &self.socket)?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut session = self.session()?;
+        session.writer().flush()?;
+        while session.wants_write() {
+            session.write_tls(&mut &self.socket)?;
+        }
+        Ok(())
+    }
+}
+
+/// A client on a server started with `--cert`.
+pub(super) struct TlsPeer {
+    stream: mio::net::TcpStream,
+    session: ServerConnection,
+    handler: MessageHandler,
+    outbound: Outbound,
+    /// Whether the first bytes have been checked for a client speaking plain TCP.
+    sniffed: bool,
+}
+
+impl TlsPeer {
+    pub(super) fn new(
+        stream: mio::net::TcpStream,
+        config: Arc<ServerConfig>,
+    ) -> Result<Self, String> {
+        let session = ServerConnection::new(config).map_err(|e| e.to_string())?;
+        let mut outbound = Outbound::new();
+        // goes out inside the session once it is set up
+        let _ = outbound.push(handshake().as_slice().into());
+        Ok(Self {
+            stream,
+            session,
+            handler: MessageHandler::expecting_handshake(),
+            outbound,
+            sniffed: false,
+        })
+    }
+
+    pub(super) fn reply_format(&self, fallback: WireFormat) -> WireFormat {
+        self.handler.reply_format(fallback)
+    }
+
+    pub(super) fn send(&mut self, encoded: Arc<[u8]>, traffic: Traffic) -> io::Result<()> {
+        self.outbound.push_as(encoded, traffic)?;
+        self.flush()
+    }
+
+    pub(super) fn outbound(&mut self) -> &mut Outbound {
+        &mut self.outbound
+    }
+
+    pub(super) fn flush(&mut self) -> io::Result<()> {
+        self.outbound.flush(&mut SessionWriter {
+            session: &mut self.session,
+            stream: &mut self.stream,
+        })
+    }
+
+    pub(super) fn receive(&mut self) -> Incoming {
+        let mut incoming = Incoming::default();
+        let mut raw = [0; 4096];
+        let mut plain = [0; 4096];
+        'reading: loop {
+            let n = match self.stream.read(&mut raw) {
+                Ok(0) => {
+                    incoming.closed = Some(Closed::Gone);
+                    break;
+                }
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    incoming.closed = Some(Closed::Failed(e));
+                    break;
+                }
+            };
+            incoming.heard = true;
+            if !self.sniffed {
+                self.sniffed = true;
+                // no TLS record starts with the first byte of our handshake
+                if raw[0] == HANDSHAKE_MAGIC[0] {
+                    reject(&mut self.stream, TLS_REQUIRED.into(), WireFormat::Json);
+                    let reason = "client speaks plain TCP, but this server requires TLS";
+                    incoming.closed = Some(Closed::Refused(reason.into()));
+                    break;
+                }
+            }
+
+            let mut rest = &raw[..n];
+            while !rest.is_empty() {
+                let result = self.session.read_tls(&mut rest).and_then(|_| {
+                    self.session
+                        .process_new_packets()
+                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+                });
+                if let Err(e) = result {
+                    incoming.closed = Some(Closed::Refused(format!("TLS error: {}", e)));
+                    break 'reading;
+                }
+                loop {
+                    match self.session.reader().read(&mut plain) {
+                        Ok(0) => {
+                            incoming.closed = Some(Closed::Gone);
+                            break 'reading;
+                        }
+                        Ok(n) => {
+                            // counted as what the peer sent, before encryption
+                            incoming.bytes += n;
+                            take_messages(&mut self.handler, &plain[..n], &mut incoming);
+                        }
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                        Err(e) => {
+                            incoming.closed = Some(Closed::Failed(e));
+                            break 'reading;
+                        }
+                    }
+                    if incoming.closed.is_some() {
+                        break 'reading;
+                    }
+                }
+            }
+        }
+        // handshake replies and anything held back until the session was up
+        if incoming.closed.is_none()
+            && let Err(e) = self.flush()
+        {
+            incoming.closed = Some(Closed::Failed(e));
+        }
+        incoming
+    }
+}
+
+/// Writes plaintext into the session and its records onto the socket, without
+/// letting either pile up: a socket that is full makes this block like one.
+struct SessionWriter<'a> {
+    session: &'a mut ServerConnection,
+    stream: &'a mut mio::net::TcpStream,
+}
+
+impl Write for SessionWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.flush()?;
+        if self.session.wants_write() {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        match self.session.writer().write(buf)? {
+            // only while the handshake is still going and plenty is waiting
+            0 => Err(io::ErrorKind::WouldBlock.into()),
+            n => {
+                self.flush()?;
+                Ok(n)
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        while self.session.wants_write() {
+            match self.session.write_tls(self.stream) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,209 @@
+//! WebSocket clients, for browsers that cannot open a raw TCP connection. They
+//! skip our handshake, send one message per WebSocket message and get JSON text
+//! back; everything after the upgrade is the same as for a TCP client.
+
+use super::{Closed, Incoming, MAX_BUFFER_SIZE, MAX_OUTBOUND_BYTES, PING_INTERVAL, decode_frame};
+use mio::net::{TcpListener, TcpStream};
+use mio::{Interest, Registry, Token};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Instant;
+use tungstenite::handshake::server::{NoCallback, ServerHandshake};
+use tungstenite::handshake::{HandshakeError, MidHandshake};
+use tungstenite::protocol::WebSocketConfig;
+use tungstenite::{Error, Message as WsMessage, WebSocket};
+
+/// Binds the WebSocket listener next to the TCP one, or says why it could not.
+pub(super) fn listen(
+    tcp: &std::net::TcpListener,
+    port: u16,
+    registry: &Registry,
+    token: Token,
+) -> Option<TcpListener> {
+    let ip = match tcp.local_addr() {
+        Ok(addr) => addr.ip(),
+        Err(e) => {
+            eprintln!("Cannot listen for WebSocket clients: {}", e);
+            return None;
+        }
+    };
+    let addr = SocketAddr::new(ip, port);
+    let mut listener = match TcpListener::bind(addr) {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("Cannot listen for WebSocket clients on {}: {}", addr, e);
+            return None;
+        }
+    };
+    if let Err(e) = registry.register(&mut listener, token, Interest::READABLE) {
+        eprintln!("Error registering WebSocket listener: {}", e);
+        return None;
+    }
+    println!("Taking WebSocket clients on {}", addr);
+    Some(listener)
+}
+
+enum State {
+    Upgrading(MidHandshake<ServerHandshake<TcpStream, NoCallback>>),
+    Open(WebSocket<TcpStream>),
+    /// Only while moving between the other two, or once the upgrade has failed.
+    Gone,
+}
+
+/// A client on the WebSocket listener.
+pub(super) struct WsPeer {
+    state: State,
+    /// Messages sent before the upgrade finished, and their total size.
+    early: Vec<Arc<[u8]>>,
+    early_bytes: usize,
+    last_ping: Instant,
+}
+
+impl WsPeer {
+    /// Starts the upgrade, which usually has to wait for the request to arrive.
+    pub(super) fn accept(stream: TcpStream) -> Result<Self, String> {
+        let config = WebSocketConfig::default()
+            .max_write_buffer_size(MAX_OUTBOUND_BYTES)
+            .max_message_size(Some(MAX_BUFFER_SIZE));
+        let state = match tungstenite::accept_with_config(stream, Some(config)) {
+            Ok(socket) => State::Open(socket),
+            Err(HandshakeError::Interrupted(mid)) => State::Upgrading(mid),
+            Err(HandshakeError::Failure(e)) => return Err(format!("upgrade failed: {}", e)),
+        };
+        Ok(Self {
+            state,
+            early: Vec::new(),
+            early_bytes: 0,
+            last_ping: Instant::now(),
+        })
+    }
+
+    /// Queues a bare JSON message, holding it back until the upgrade is done.
+    pub(super) fn send(&mut self, encoded: Arc<[u8]>) -> io::Result<()> {
+        match &mut self.state {
+            State::Open(socket) => {
+                write(socket, &encoded)?;
+                ignore_blocking(socket.flush())
+            }
+            _ => {
+                self.early_bytes += encoded.len();
+                if self.early_bytes > MAX_OUTBOUND_BYTES {
+                    return Err(io::Error::other("client is not finishing its upgrade"));
+                }
+                self.early.push(encoded);
+                Ok(())
+            }
+        }
+    }
+
+    /// Writes what is pending, with a WebSocket ping now and then: browsers answer
+    /// those on their own, but never send our Ping, so this keeps them from timing out.
+    pub(super) fn flush(&mut self) -> io::Result<()> {
+        let State::Open(socket) = &mut self.state else {
+            return Ok(());
+        };
+        if self.last_ping.elapsed() >= PING_INTERVAL {
+            self.last_ping = Instant::now();
+            ignore_blocking(socket.write(WsMessage::Ping(Default::default())))?;
+        }
+        ignore_blocking(socket.flush())
+    }
+
+    pub(super) fn receive(&mut self) -> Incoming {
+        let mut incoming = Incoming::default();
+        if let Err(closed) = self.finish_upgrade() {
+            incoming.closed = Some(closed);
+            return incoming;
+        }
+        let State::Open(socket) = &mut self.state else {
+            // still upgrading
+            incoming.heard = true;
+            return incoming;
+        };
+        loop {
+            match socket.read() {
+                Ok(message) => {
+                    incoming.heard = true;
+                    let payload = match &message {
+                        WsMessage::Text(text) => text.as_bytes(),
+                        WsMessage::Binary(bytes) => bytes,
+                        // pings are answered by the next flush; pongs only count as being heard
+                        _ => continue,
+                    };
+                    incoming.bytes += payload.len();
+                    match decode_frame(payload) {
+                        Ok((_, message)) => incoming.messages.push(message),
+                        Err(e) => eprintln!("{}", e),
+                    }
+                }
+                Err(Error::Io(e)) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(Error::Io(e)) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(Error::ConnectionClosed | Error::AlreadyClosed) => {
+                    incoming.closed = Some(Closed::Gone);
+                    break;
+                }
+                Err(Error::Io(e)) => {
+                    incoming.closed = Some(Closed::Failed(e));
+                    break;
+                }
+                Err(e) => {
+                    incoming.closed = Some(Closed::Refused(e.to_string()));
+                    break;
+                }
+            }
+        }
+        if incoming.closed.is_none()
+            && let Err(e) = ignore_blocking(socket.flush())
+        {
+            incoming.closed = Some(Closed::Failed(e));
+        }
+        incoming
+    }
+
+    /// Moves the upgrade along, sending what was held back once it is done.
+    fn finish_upgrade(&mut self) -> Result<(), Closed> {
+        self.state = match std::mem::replace(&mut self.state, State::Gone) {
+            State::Upgrading(mid) => match mid.handshake() {
+                Ok(socket) => State::Open(socket),
+                Err(HandshakeError::Interrupted(mid)) => State::Upgrading(mid),
+                Err(HandshakeError::Failure(e)) => {
+                    return Err(Closed::Refused(format!("upgrade failed: {}", e)));
+                }
+            },
+            State::Gone => return Err(Closed::Gone),
+            open => open,
+        };
+        if let State::Open(socket) = &mut self.state
+            && !self.early.is_empty()
+        {
+            for encoded in self.early.drain(..) {
+                write(socket, &encoded).map_err(Closed::Failed)?;
+            }
+            self.early_bytes = 0;
+            ignore_blocking(socket.flush()).map_err(Closed::Failed)?;
+        }
+        Ok(())
+    }
+}
+
+fn write(socket: &mut WebSocket<TcpStream>, encoded: &[u8]) -> io::Result<()> {
+    let text = String::from_utf8(encoded.to_vec()).map_err(io::Error::other)?;
+    ignore_blocking(socket.write(WsMessage::text(text)))
+}
+
+fn into_io(result: tungstenite::Result<()>) -> io::Result<()> {
+    match result {
+        Ok(()) => Ok(()),
+        Err(Error::Io(e)) => Err(e),
+        Err(e) => Err(io::Error::other(e)),
+    }
+}
+
+/// A write the socket would not take yet is finished by a later flush.
+fn ignore_blocking(result: tungstenite::Result<()>) -> io::Result<()> {
+    match into_io(result) {
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+        other => other,
+    }
+}
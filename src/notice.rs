@@ -0,0 +1,53 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Notices kept for the window; older ones are dropped.
+pub const NOTICE_HISTORY: usize = 4;
+/// How long a notice stays on screen, the last second of it fading out.
+pub const NOTICE_VISIBLE: Duration = Duration::from_secs(5);
+
+/// Something that went wrong but did not stop the session, such as a frame
+/// that could not be decoded or a client dropped for falling behind, or news
+/// such as a peer joining.
+#[derive(Debug, Clone)]
+pub struct Notice {
+    pub text: String,
+    pub raised: Instant,
+}
+
+impl Notice {
+    /// Opacity for the window: 1 while fresh, fading to 0 at NOTICE_VISIBLE.
+    pub fn opacity(&self) -> f32 {
+        let left = NOTICE_VISIBLE.saturating_sub(self.raised.elapsed());
+        left.as_secs_f32().min(1.0)
+    }
+}
+
+/// Written by the network threads, shown by the window.
+pub type Notices = Arc<Mutex<VecDeque<Notice>>>;
+
+/// Prints `text` to stderr, and shows it in the window for a few seconds,
+/// since nobody sees stderr when the program is started from a shortcut.
+pub fn warn(notices: &Notices, text: String) {
+    eprintln!("{}", text);
+    show(notices, text);
+}
+
+/// Like `warn`, for news that is no problem, which goes to stdout.
+pub fn tell(notices: &Notices, text: String) {
+    println!("{}", text);
+    show(notices, text);
+}
+
+fn show(notices: &Notices, text: String) {
+    if let Ok(mut notices) = notices.lock() {
+        notices.push_back(Notice {
+            text,
+            raised: Instant::now(),
+        });
+        while notices.len() > NOTICE_HISTORY {
+            notices.pop_front();
+        }
+    }
+}
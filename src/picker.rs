@@ -0,0 +1,250 @@
+use crate::bindings;
+use macroquad::prelude::*;
+use tcp_drawing::keymap::Action;
+
+/// Side of the saturation/value square, in screen pixels.
+const SQUARE: f32 = 128.0;
+const STRIP_WIDTH: f32 = 16.0;
+const GAP: f32 = 8.0;
+const PADDING: f32 = 8.0;
+/// Top of the panel, just under the color swatch in the corner.
+const TOP: f32 = 56.0;
+const RIGHT_MARGIN: f32 = 16.0;
+/// Texels along each side of the generated square; filtering smooths the rest.
+const TEXTURE_SIZE: u16 = 64;
+
+/// Which part of the panel a press landed on, held until the button comes up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Part {
+    Square,
+    Strip,
+    /// The panel's padding: the press is the panel's, but picks nothing.
+    Frame,
+}
+
+/// A hue strip and a saturation/value square, opened with P. Whatever it picks
+/// is the brush color until a palette slot is chosen again; it keeps that color
+/// for the next time it opens.
+pub struct Picker {
+    open: bool,
+    hue: f32,
+    saturation: f32,
+    value: f32,
+    /// The brush follows the picker rather than the palette.
+    in_use: bool,
+    dragging: Option<Part>,
+    strip: Option<Texture2D>,
+    /// The square and the hue it was generated for.
+    square: Option<(f32, Texture2D)>,
+}
+
+impl Picker {
+    pub fn new() -> Self {
+        Self {
+            open: false,
+            hue: 0.0,
+            saturation: 1.0,
+            value: 1.0,
+            in_use: false,
+            dragging: None,
+            strip: None,
+            square: None,
+        }
+    }
+
+    /// The picked color, while the brush uses it.
+    pub fn color(&self) -> Option<Color> {
+        self.in_use
+            .then(|| hsv(self.hue, self.saturation, self.value))
+    }
+
+    /// Hands the brush back to the palette.
+    pub fn release(&mut self) {
+        self.in_use = false;
+    }
+
+    /// Makes `color`, whatever its alpha, the brush color, as if picked here.
+    pub fn set_color(&mut self, color: Color) {
+        let (hue, saturation, value) = to_hsv(color);
+        // a gray keeps the hue it had, so the strip does not jump back to red
+        if saturation > 0.0 {
+            self.hue = hue;
+        }
+        self.saturation = saturation;
+        self.value = value;
+        self.in_use = true;
+    }
+
+    /// Where the panel sits on screen this frame.
+    fn panel() -> Rect {
+        let width = PADDING * 2.0 + SQUARE + GAP + STRIP_WIDTH;
+        Rect::new(
+            screen_width() - RIGHT_MARGIN - width,
+            TOP,
+            width,
+            SQUARE + PADDING * 2.0,
+        )
+    }
+
+    fn square_rect() -> Rect {
+        let panel = Self::panel();
+        Rect::new(panel.x + PADDING, panel.y + PADDING, SQUARE, SQUARE)
+    }
+
+    fn strip_rect() -> Rect {
+        let square = Self::square_rect();
+        Rect::new(square.right() + GAP, square.y, STRIP_WIDTH, SQUARE)
+    }
+
+    /// P opens and closes the panel when `keys_free`; presses on it, and drags
+    /// that start there, pick the color. Returns whether the pointer belongs to
+    /// the panel this frame, in which case it must not draw.
+    pub fn handle_input(&mut self, keys_free: bool) -> bool {
+        if keys_free && bindings::pressed(Action::Picker) {
+            self.open = !self.open;
+        }
+        if !is_mouse_button_down(MouseButton::Left) {
+            // a drag that outlived the panel ends here too
+            self.dragging = None;
+            return false;
+        }
+        let mouse = Vec2::from(mouse_position());
+        if self.open && is_mouse_button_pressed(MouseButton::Left) {
+            self.dragging = if Self::square_rect().contains(mouse) {
+                Some(Part::Square)
+            } else if Self::strip_rect().contains(mouse) {
+                Some(Part::Strip)
+            } else if Self::panel().contains(mouse) {
+                Some(Part::Frame)
+            } else {
+                None
+            };
+        }
+        match self.dragging {
+            Some(Part::Square) if self.open => {
+                let square = Self::square_rect();
+                self.saturation = ((mouse.x - square.x) / square.w).clamp(0.0, 1.0);
+                self.value = 1.0 - ((mouse.y - square.y) / square.h).clamp(0.0, 1.0);
+                self.in_use = true;
+            }
+            Some(Part::Strip) if self.open => {
+                let strip = Self::strip_rect();
+                self.hue = ((mouse.y - strip.y) / strip.h).clamp(0.0, 1.0);
+                self.in_use = true;
+            }
+            _ => {}
+        }
+        self.dragging.is_some()
+    }
+
+    /// Draws the open panel in screen space.
+    pub fn render(&mut self) {
+        if !self.open {
+            return;
+        }
+        let panel = Self::panel();
+        draw_rectangle(panel.x, panel.y, panel.w, panel.h, WHITE);
+        draw_rectangle_lines(panel.x, panel.y, panel.w, panel.h, 2.0, BLACK);
+
+        let hue = self.hue;
+        let square = match &self.square {
+            Some((made_for, texture)) if *made_for == hue => texture.clone(),
+            _ => {
+                let texture = texture_from(TEXTURE_SIZE, TEXTURE_SIZE, |x, y| hsv(hue, x, 1.0 - y));
+                self.square = Some((hue, texture.clone()));
+                texture
+            }
+        };
+        let strip = self
+            .strip
+            .get_or_insert_with(|| texture_from(1, TEXTURE_SIZE, |_, y| hsv(y, 1.0, 1.0)))
+            .clone();
+
+        let (square_rect, strip_rect) = (Self::square_rect(), Self::strip_rect());
+        for (texture, rect) in [(square, square_rect), (strip, strip_rect)] {
+            draw_texture_ex(
+                &texture,
+                rect.x,
+                rect.y,
+                WHITE,
+                DrawTextureParams {
+                    dest_size: Some(rect.size()),
+                    ..Default::default()
+                },
+            );
+        }
+
+        // where the current color sits on each
+        let marker = if self.value > 0.5 { BLACK } else { WHITE };
+        draw_circle_lines(
+            square_rect.x + self.saturation * square_rect.w,
+            square_rect.y + (1.0 - self.value) * square_rect.h,
+            4.0,
+            1.5,
+            marker,
+        );
+        let y = strip_rect.y + self.hue * strip_rect.h;
+        draw_line(
+            strip_rect.x - 2.0,
+            y,
+            strip_rect.right() + 2.0,
+            y,
+            2.0,
+            BLACK,
+        );
+    }
+}
+
+/// A `width` by `height` texture with `color` run on the texel centers, as
+/// fractions of the way across and down.
+fn texture_from(width: u16, height: u16, color: impl Fn(f32, f32) -> Color) -> Texture2D {
+    let mut image = Image::gen_image_color(width, height, WHITE);
+    for y in 0..height {
+        for x in 0..width {
+            let across = (x as f32 + 0.5) / width as f32;
+            let down = (y as f32 + 0.5) / height as f32;
+            image.set_pixel(x as u32, y as u32, color(across, down));
+        }
+    }
+    let texture = Texture2D::from_image(&image);
+    texture.set_filter(FilterMode::Linear);
+    texture
+}
+
+/// `color` as hue, saturation and value from 0 to 1, the way `hsv` takes them.
+fn to_hsv(color: Color) -> (f32, f32, f32) {
+    let max = color.r.max(color.g).max(color.b);
+    let min = color.r.min(color.g).min(color.b);
+    let spread = max - min;
+    if spread <= 0.0 {
+        return (0.0, 0.0, max);
+    }
+    let sector = if max == color.r {
+        ((color.g - color.b) / spread).rem_euclid(6.0)
+    } else if max == color.g {
+        (color.b - color.r) / spread + 2.0
+    } else {
+        (color.r - color.g) / spread + 4.0
+    };
+    (sector / 6.0, spread / max, max)
+}
+
+/// `hue`, `saturation` and `value` from 0 to 1 as an opaque color.
+fn hsv(hue: f32, saturation: f32, value: f32) -> Color {
+    let sector = (hue.fract() * 6.0).min(5.999);
+    let f = sector.fract();
+    let (p, q, t) = (
+        value * (1.0 - saturation),
+        value * (1.0 - saturation * f),
+        value * (1.0 - saturation * (1.0 - f)),
+    );
+    let (r, g, b) = match sector as u32 {
+        0 => (value, t, p),
+        1 => (q, value, p),
+        2 => (p, value, t),
+        3 => (p, q, value),
+        4 => (t, p, value),
+        _ => (value, p, q),
+    };
+    Color::new(r, g, b, 1.0)
+}
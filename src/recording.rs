@@ -0,0 +1,228 @@
+use crate::config::Config;
+use crate::entity::Canvas;
+use crate::network::Message;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// Longest a replay may be sped up or slowed down by.
+const MAX_SPEED: f64 = 16.0;
+
+/// One line of a recording: a canvas change and when it was applied, in
+/// milliseconds since the recording started.
+#[derive(Serialize, Deserialize, Debug)]
+struct Entry<M> {
+    at_ms: u64,
+    message: M,
+}
+
+/// Messages as older builds recorded them, where they no longer read as a Message.
+#[derive(Deserialize)]
+enum Legacy {
+    /// From before moves carried a generation.
+    MoveEntities(Vec<(usize, f32, f32)>),
+}
+
+impl From<Legacy> for Message {
+    fn from(legacy: Legacy) -> Self {
+        match legacy {
+            Legacy::MoveEntities(moves) => Message::MoveEntities {
+                moves,
+                generation: 0,
+            },
+        }
+    }
+}
+
+/// Writes every canvas change this peer applies as a line of JSON. Clones share the file.
+#[derive(Clone)]
+pub struct Recorder {
+    out: Arc<Mutex<BufWriter<File>>>,
+    started: Instant,
+}
+
+impl Recorder {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        Ok(Self {
+            out: Arc::new(Mutex::new(BufWriter::new(File::create(path)?))),
+            started: Instant::now(),
+        })
+    }
+
+    /// Opens the `--record` file, if one was asked for. A file that cannot be
+    /// created is reported and the session goes on unrecorded.
+    pub fn from_config(config: &Config) -> Option<Self> {
+        let path = config.record_path.as_deref()?;
+        match Self::create(path) {
+            Ok(recorder) => {
+                println!("Recording canvas changes to {}", path.display());
+                Some(recorder)
+            }
+            Err(e) => {
+                eprintln!("Cannot record to {}: {}", path.display(), e);
+                None
+            }
+        }
+    }
+
+    /// Appends `message` if it changes the canvas; anything else is left out.
+    pub fn record(&self, message: &Message) {
+        if !message.changes_canvas() {
+            return;
+        }
+        let Ok(mut out) = self.out.lock() else {
+            return;
+        };
+        // stamped under the lock so lines from different threads stay in order
+        let entry = Entry {
+            at_ms: self.started.elapsed().as_millis() as u64,
+            message,
+        };
+        let result = serde_json::to_writer(&mut *out, &entry)
+            .map_err(io::Error::from)
+            .and_then(|()| out.write_all(b"\n"))
+            .and_then(|()| out.flush());
+        if let Err(e) = result {
+            eprintln!("Error writing recording: {}", e);
+        }
+    }
+}
+
+/// A recording played back onto a canvas, which can be paused, sped up and
+/// scrubbed in both directions.
+pub struct Replay {
+    entries: Vec<Entry<Message>>,
+    /// Entries applied to the canvas so far.
+    applied: usize,
+    /// Epoch of the last clear applied, so entities it wiped stay wiped.
+    epoch: u64,
+    /// Playback position in recording milliseconds.
+    position: f64,
+    pub speed: f64,
+    pub paused: bool,
+}
+
+impl Replay {
+    /// Reads a recording, skipping lines this build cannot make sense of.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let file =
+            File::open(path).map_err(|e| format!("Cannot open {}: {}", path.display(), e))?;
+        let mut entries: Vec<Entry<Message>> = Vec::new();
+        let mut skipped = 0;
+        for line in BufReader::new(file).lines() {
+            let line = line.map_err(|e| format!("Error reading {}: {}", path.display(), e))?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str(&line) {
+                Ok(entry) => entries.push(entry),
+                Err(_) => match serde_json::from_str::<Entry<Legacy>>(&line) {
+                    Ok(Entry { at_ms, message }) => entries.push(Entry {
+                        at_ms,
+                        message: message.into(),
+                    }),
+                    Err(_) => skipped += 1,
+                },
+            }
+        }
+        if skipped > 0 {
+            eprintln!("Skipped {} unreadable lines in {}", skipped, path.display());
+        }
+        // a recording is written in order, but a hand-edited one might not be
+        entries.sort_by_key(|entry| entry.at_ms);
+
+        Ok(Self {
+            entries,
+            applied: 0,
+            epoch: 0,
+            position: 0.0,
+            speed: 1.0,
+            paused: false,
+        })
+    }
+
+    /// Time of the last change, in seconds.
+    pub fn duration(&self) -> f64 {
+        self.entries.last().map_or(0.0, |entry| entry.at_ms as f64) / 1000.0
+    }
+
+    /// Playback position, in seconds.
+    pub fn position(&self) -> f64 {
+        self.position / 1000.0
+    }
+
+    /// Moves playback on by `seconds` of real time, unless paused.
+    pub fn advance(&mut self, seconds: f64, canvas: &Canvas) {
+        if !self.paused {
+            self.seek(self.position() + seconds * self.speed, canvas);
+        }
+    }
+
+    /// Jumps to `seconds` into the recording. Going back replays from the start.
+    pub fn seek(&mut self, seconds: f64, canvas: &Canvas) {
+        let target = (seconds * 1000.0).clamp(0.0, self.duration() * 1000.0);
+        if target < self.position {
+            canvas.clear();
+            self.applied = 0;
+            self.epoch = 0;
+        }
+        self.position = target;
+        while let Some(entry) = self.entries.get(self.applied)
+            && entry.at_ms as f64 <= self.position
+        {
+            apply(&entry.message, &mut self.epoch, canvas);
+            self.applied += 1;
+        }
+    }
+
+    pub fn change_speed(&mut self, factor: f64) {
+        self.speed = (self.speed * factor).clamp(1.0 / MAX_SPEED, MAX_SPEED);
+    }
+}
+
+/// Applies a recorded change the way a client applies it coming off the wire.
+fn apply(message: &Message, epoch: &mut u64, canvas: &Canvas) {
+    match message {
+        Message::NewEntity(entity) if entity.epoch >= *epoch => {
+            canvas.insert(entity.clone());
+        }
+        Message::EntityBatch(batch) => {
+            for entity in batch.iter().filter(|entity| entity.epoch >= *epoch) {
+                canvas.insert(entity.clone());
+            }
+        }
+        Message::AllEntities(all_entities) => {
+            for entity in all_entities {
+                canvas.insert(entity.clone());
+            }
+        }
+        Message::ClearCanvas { epoch: cleared } if *cleared > *epoch => {
+            *epoch = *cleared;
+            canvas.clear();
+        }
+        Message::RemoveEntity { id } => {
+            canvas.remove(*id);
+        }
+        Message::UpdateEntity { id, color } => {
+            canvas.update(*id, |entity| entity.color = *color);
+        }
+        Message::MoveEntities { moves, .. } => {
+            for &(id, x, y) in moves {
+                canvas.update(id, |entity| entity.move_to((x, y)));
+            }
+        }
+        Message::StrokeAppend {
+            id, points, pen, ..
+        } => {
+            canvas.update(*id, |entity| entity.extend_stroke(points, pen));
+        }
+        Message::TransformAll { transform, .. } => {
+            canvas.update_all(|entity| transform.apply_to(entity));
+        }
+        // stale entities and clears, and anything that is not a canvas change
+        _ => {}
+    }
+}
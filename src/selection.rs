@@ -0,0 +1,260 @@
+use crate::bindings;
+use crate::layers::Layers;
+use crate::shift_down;
+use crate::view::View;
+use crossbeam_channel::Sender;
+use macroquad::miniquad::window::{clipboard_get, clipboard_set};
+use macroquad::prelude::*;
+use tcp_drawing::clipboard::Clipboard;
+use tcp_drawing::entity::{Canvas, Entity};
+use tcp_drawing::keymap::Action;
+use tcp_drawing::network::{Message, Permissions};
+
+/// How near the pointer, in screen pixels, a selected entity counts as under it.
+const REACH: f32 = 3.0;
+const HIGHLIGHT: Color = Color::new(0.2, 0.5, 1.0, 0.9);
+/// Strength of a paste following the pointer before it is put down.
+pub const GHOST_STRENGTH: f32 = 0.4;
+
+/// The select tool: a drag from anywhere but the selection, or any drag with
+/// Shift, picks what has its center inside the dragged rectangle, and a drag
+/// from the selection moves it. The move shows here as the pointer goes but
+/// is only sent when the button comes up. Only entities this window may
+/// change are picked, but Ctrl+C copies everything shown in the rectangle.
+pub struct Selection {
+    ids: Vec<usize>,
+    /// The rectangle last dragged, moved along with the selection.
+    area: Option<Rect>,
+    drag: Option<Drag>,
+    clipboard: Clipboard,
+    /// Whether the clipboard follows the pointer, to be put down with a click.
+    pasting: bool,
+}
+
+enum Drag {
+    /// A rectangle from where the button went down to the pointer.
+    Marquee { from: (f32, f32) },
+    /// The selection following the pointer from `from`, with where each
+    /// entity was when the drag started.
+    Move {
+        from: (f32, f32),
+        started: Vec<(usize, f32, f32)>,
+    },
+}
+
+impl Selection {
+    pub fn new() -> Self {
+        Self {
+            ids: Vec::new(),
+            area: None,
+            drag: None,
+            clipboard: Clipboard::default(),
+            pasting: false,
+        }
+    }
+
+    /// Drops the selection and any paste that has not been put down, as when
+    /// another tool is picked. A move under way stays where it got to here.
+    pub fn clear(&mut self) {
+        self.ids.clear();
+        self.area = None;
+        self.drag = None;
+        self.pasting = false;
+    }
+
+    /// The clipboard while it follows the pointer, before a click puts it down.
+    pub fn pasting(&self) -> Option<&Clipboard> {
+        self.pasting.then_some(&self.clipboard)
+    }
+
+    /// Selects, moves or, on Delete, removes with the pointer at `position` in
+    /// world coordinates. A click that drags nowhere selects nothing. Ctrl+C
+    /// copies the rectangle and Ctrl+V picks it up to paste, which a click puts
+    /// down and Escape drops.
+    pub fn handle_input(
+        &mut self,
+        position: (f32, f32),
+        entities: &Canvas,
+        view: &View,
+        layers: &Layers,
+        permissions: Permissions,
+        tx: &Sender<Message>,
+    ) {
+        // peers may have erased some of it meanwhile
+        self.ids.retain(|&id| entities.contains(id));
+
+        if self.pasting {
+            if is_key_pressed(KeyCode::Escape) {
+                self.pasting = false;
+            } else if is_mouse_button_pressed(MouseButton::Left) {
+                self.pasting = false;
+                paste(
+                    self.clipboard.pasted_at(position, layers.active()),
+                    entities,
+                    tx,
+                );
+            }
+            return;
+        }
+
+        if bindings::pressed(Action::Copy) && self.drag.is_none() {
+            self.copy(entities, layers);
+        }
+        if bindings::pressed(Action::Paste) {
+            // another instance may have copied something since
+            if let Some(copied) = clipboard_get().and_then(|text| Clipboard::from_json(&text).ok())
+            {
+                self.clipboard = copied;
+            }
+            self.pasting = !self.clipboard.is_empty();
+            self.drag = None;
+            return;
+        }
+
+        if bindings::pressed(Action::DeleteSelection) && self.drag.is_none() {
+            for id in self.ids.drain(..) {
+                if entities.remove(id).is_some()
+                    && let Err(e) = tx.send(Message::RemoveEntity { id })
+                {
+                    eprintln!("Error sending removal to network thread: {}", e);
+                }
+            }
+        }
+
+        if is_mouse_button_pressed(MouseButton::Left) {
+            let grabbed = entities.topmost(position, REACH / view.zoom(), |entity| {
+                self.ids.contains(&entity.id)
+            });
+            self.drag = Some(match grabbed {
+                Some(_) if !shift_down() => Drag::Move {
+                    from: position,
+                    started: self
+                        .ids
+                        .iter()
+                        .filter_map(|&id| entities.get(id).map(|entity| (id, entity.x, entity.y)))
+                        .collect(),
+                },
+                _ => Drag::Marquee { from: position },
+            });
+        }
+
+        if let Some(Drag::Move { from, started }) = &self.drag {
+            let (dx, dy) = (position.0 - from.0, position.1 - from.1);
+            for &(id, x, y) in started {
+                entities.update(id, |entity| entity.move_to((x + dx, y + dy)));
+            }
+        }
+
+        if !is_mouse_button_released(MouseButton::Left) {
+            return;
+        }
+        match self.drag.take() {
+            Some(Drag::Marquee { from }) => {
+                let area = marquee(from, position);
+                self.area = Some(area);
+                self.ids = entities
+                    .query_rect(&area)
+                    .into_iter()
+                    .filter(|&id| {
+                        entities.get(id).is_some_and(|entity| {
+                            area.contains(entity.bounds().center())
+                                && layers.shows(&entity)
+                                && permissions.may_change(&entity)
+                        })
+                    })
+                    .collect();
+            }
+            Some(Drag::Move { from, started }) => {
+                if from == position {
+                    return;
+                }
+                if let Some(area) = &mut self.area {
+                    area.x += position.0 - from.0;
+                    area.y += position.1 - from.1;
+                }
+                let moves: Vec<(usize, f32, f32)> = started
+                    .iter()
+                    .filter_map(|&(id, ..)| entities.get(id).map(|entity| (id, entity.x, entity.y)))
+                    .collect();
+                if let Err(e) = tx.send(Message::MoveEntities {
+                    moves,
+                    generation: 0,
+                }) {
+                    eprintln!("Error sending move to network thread: {}", e);
+                }
+            }
+            None => {}
+        }
+    }
+
+    /// Puts everything shown with its center in the rectangle on the clipboard,
+    /// here and, for other instances, on the system's. Nothing there leaves the
+    /// clipboard as it was.
+    fn copy(&mut self, entities: &Canvas, layers: &Layers) {
+        let Some(area) = self.area else {
+            return;
+        };
+        let copied: Vec<Entity> = entities
+            .query_rect(&area)
+            .into_iter()
+            .filter_map(|id| entities.get(id).map(|entity| entity.value().clone()))
+            .filter(|entity| area.contains(entity.bounds().center()) && layers.shows(entity))
+            .collect();
+        if copied.is_empty() {
+            return;
+        }
+        println!("Copied {} entities", copied.len());
+        self.clipboard = Clipboard::copy(copied, area.center().into());
+        clipboard_set(&self.clipboard.to_json());
+    }
+
+    /// Outlines the selection and the rectangle being dragged, in world
+    /// coordinates, with the pointer at `position`.
+    pub fn render(&self, position: (f32, f32), entities: &Canvas, view: &View) {
+        let width = 1.5 / view.zoom();
+        for &id in &self.ids {
+            if let Some(entity) = entities.get(id) {
+                let bounds = entity.bounds();
+                draw_rectangle_lines(bounds.x, bounds.y, bounds.w, bounds.h, width, HIGHLIGHT);
+            }
+        }
+        if let Some(Drag::Marquee { from }) = &self.drag {
+            let area = marquee(*from, position);
+            draw_rectangle(
+                area.x,
+                area.y,
+                area.w,
+                area.h,
+                Color {
+                    a: 0.1,
+                    ..HIGHLIGHT
+                },
+            );
+            draw_rectangle_lines(area.x, area.y, area.w, area.h, width, HIGHLIGHT);
+        }
+    }
+}
+
+/// Puts `pasted` on the canvas and sends it to peers. The network thread
+/// folds the run of new entities into batches, so even a huge paste goes out
+/// in frames of bounded size.
+fn paste(pasted: Vec<Entity>, entities: &Canvas, tx: &Sender<Message>) {
+    println!("Pasted {} entities", pasted.len());
+    for entity in pasted {
+        entities.insert(entity.clone());
+        if let Err(e) = tx.send(Message::NewEntity(entity)) {
+            eprintln!("Error sending pasted entity to network thread: {}", e);
+            return;
+        }
+    }
+}
+
+/// The rectangle with corners `from` and `to`, whichever way it was dragged.
+fn marquee(from: (f32, f32), to: (f32, f32)) -> Rect {
+    Rect::new(
+        from.0.min(to.0),
+        from.1.min(to.1),
+        (to.0 - from.0).abs(),
+        (to.1 - from.1).abs(),
+    )
+}
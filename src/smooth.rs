@@ -0,0 +1,50 @@
+/// Longest piece, in canvas pixels, a segment of a smoothed stroke is cut into.
+const STEP_LENGTH: f32 = 3.0;
+/// Most pieces one segment is cut into, however long it is.
+const MAX_STEPS: usize = 16;
+
+/// Fills `out` with a uniform Catmull-Rom curve through `points`, as
+/// `(x, y, radius)` samples, the radius going evenly from what `radius_at`
+/// gives one point to what it gives the next. The curve passes through every
+/// point and stops at the ends. It depends on nothing but the points and
+/// their radii, so every peer works out the same curve from the same stroke.
+pub fn catmull_rom(
+    points: &[(f32, f32)],
+    radius_at: impl Fn(usize) -> f32,
+    out: &mut Vec<(f32, f32, f32)>,
+) {
+    out.clear();
+    let Some(&(x, y)) = points.first() else {
+        return;
+    };
+    out.push((x, y, radius_at(0)));
+    let last = points.len() - 1;
+    for i in 0..last {
+        // the ends stand in for the neighbours they do not have
+        let before = points[i.saturating_sub(1)];
+        let (from, to) = (points[i], points[i + 1]);
+        let after = points[(i + 2).min(last)];
+        let (from_radius, to_radius) = (radius_at(i), radius_at(i + 1));
+        let length = (to.0 - from.0).hypot(to.1 - from.1);
+        let steps = ((length / STEP_LENGTH).ceil() as usize).clamp(1, MAX_STEPS);
+        for step in 1..steps {
+            let t = step as f32 / steps as f32;
+            let (x, y) = point_at([before, from, to, after], t);
+            out.push((x, y, from_radius + (to_radius - from_radius) * t));
+        }
+        // exactly on the point, whatever rounding did on the way
+        out.push((to.0, to.1, to_radius));
+    }
+}
+
+/// The point `t` of the way from the second of `controls` to the third.
+fn point_at(controls: [(f32, f32); 4], t: f32) -> (f32, f32) {
+    let [a, b, c, d] = controls;
+    let along = |a: f32, b: f32, c: f32, d: f32| {
+        0.5 * (2.0 * b
+            + (c - a) * t
+            + (2.0 * a - 5.0 * b + 4.0 * c - d) * t * t
+            + (3.0 * b - a - 3.0 * c + d) * t * t * t)
+    };
+    (along(a.0, b.0, c.0, d.0), along(a.1, b.1, c.1, d.1))
+}
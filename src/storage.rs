@@ -0,0 +1,202 @@
+use crate::config::Config;
+use crate::entity::{Canvas, Entity, EntityColor, next_id};
+use crate::network::{self, SharedBackground};
+use crossbeam_channel::{Sender, select, tick};
+use serde::{Deserialize, Serialize};
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+pub const DEFAULT_CANVAS_PATH: &str = "canvas.json";
+/// How often `--persist` looks for changes to write out.
+const PERSIST_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct SavedCanvas {
+    #[serde(default)]
+    pub entities: Vec<Entity>,
+    /// Missing from files saved before backgrounds could change.
+    #[serde(default)]
+    pub background: Option<EntityColor>,
+    /// Session of the server that kept the canvas with `--persist`, so one that
+    /// restores it carries on the same session. None for a canvas saved from a window.
+    #[serde(default)]
+    pub session: Option<u64>,
+}
+
+impl SavedCanvas {
+    fn of(entities: &Canvas, background: EntityColor, session: Option<u64>) -> Self {
+        Self {
+            entities: entities.iter().map(|e| e.value().clone()).collect(),
+            background: Some(background),
+            session,
+        }
+    }
+}
+
+pub fn save_canvas(path: &Path, entities: &Canvas, background: EntityColor) -> io::Result<usize> {
+    let canvas = SavedCanvas::of(entities, background, None);
+
+    let writer = BufWriter::new(File::create(path)?);
+    serde_json::to_writer(writer, &canvas)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok(canvas.entities.len())
+}
+
+/// Like `save_canvas`, but written next to `path` first and renamed over it,
+/// so a crash midway leaves the previous file whole.
+pub fn save_canvas_atomically(
+    path: &Path,
+    entities: &Canvas,
+    background: EntityColor,
+    session: Option<u64>,
+) -> io::Result<usize> {
+    let temporary = with_suffix(path, ".tmp");
+    let canvas = SavedCanvas::of(entities, background, session);
+    let mut writer = BufWriter::new(File::create(&temporary)?);
+    serde_json::to_writer(&mut writer, &canvas)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    writer
+        .into_inner()
+        .map_err(|e| e.into_error())?
+        .sync_all()?;
+    fs::rename(&temporary, path)?;
+    Ok(canvas.entities.len())
+}
+
+/// `path` with `suffix` added to its file name, so `canvas.json` becomes `canvas.json.tmp`.
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().map(OsString::from).unwrap_or_default();
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+/// Reads a saved canvas, giving loaded entities fresh ids where they would
+/// overwrite something already in `entities`.
+pub fn load_canvas(path: &Path, entities: &Canvas) -> Result<SavedCanvas, String> {
+    let file = File::open(path).map_err(|e| format!("Cannot open {}: {}", path.display(), e))?;
+    let mut canvas: SavedCanvas = serde_json::from_reader(BufReader::new(file))
+        .map_err(|e| format!("{} is not a valid canvas file: {}", path.display(), e))?;
+
+    for entity in canvas.entities.iter_mut() {
+        if entities.contains(entity.id) {
+            entity.id = next_id();
+        }
+    }
+    Ok(canvas)
+}
+
+/// The server's canvas kept in the `--persist` file: restored at startup and
+/// written out again every PERSIST_INTERVAL in which it changed, and on shutdown.
+pub struct Persister {
+    /// Restored from the file, or new if there was no canvas to restore.
+    session: u64,
+    stop_tx: Sender<()>,
+    thread: JoinHandle<()>,
+}
+
+impl Persister {
+    /// Restores `entities`, `background` and the server session from the
+    /// `--persist` file, if one was asked for, and starts keeping the file up
+    /// to date. A missing or unreadable file leaves the canvas empty and
+    /// starts a new session.
+    pub fn from_config(
+        config: &Config,
+        entities: &Arc<Canvas>,
+        background: &SharedBackground,
+    ) -> Option<Self> {
+        let path = config.persist_path.clone()?;
+        let session = restore(&path, entities, background).unwrap_or_else(network::new_session);
+
+        let (stop_tx, stop_rx) = crossbeam_channel::bounded::<()>(0);
+        let entities = entities.clone();
+        let state = {
+            let (entities, background) = (entities.clone(), background.clone());
+            move || {
+                let color = EntityColor::from_bits(background.load(Ordering::Relaxed));
+                (entities.revision(), color)
+            }
+        };
+        // read before the thread starts, so nothing drawn meanwhile counts as saved
+        let mut saved = state();
+        let thread = thread::spawn(move || {
+            let ticker = tick(PERSIST_INTERVAL);
+            let save = |saved: &mut (u64, EntityColor)| {
+                // read first, so a change that lands while writing is saved next time
+                let current = state();
+                if current == *saved {
+                    return;
+                }
+                match save_canvas_atomically(&path, &entities, current.1, Some(session)) {
+                    Ok(_) => *saved = current,
+                    Err(e) => eprintln!("Cannot save the canvas to {}: {}", path.display(), e),
+                }
+            };
+            loop {
+                select! {
+                    recv(ticker) -> _ => save(&mut saved),
+                    recv(stop_rx) -> _ => break,
+                }
+            }
+            save(&mut saved);
+        });
+        Some(Self {
+            session,
+            stop_tx,
+            thread,
+        })
+    }
+
+    /// The session the server carries on with.
+    pub fn session(&self) -> u64 {
+        self.session
+    }
+
+    /// Writes any last changes and waits until they are on disk.
+    pub fn stop(self) {
+        drop(self.stop_tx);
+        if self.thread.join().is_err() {
+            eprintln!("Canvas saving thread panicked");
+        }
+    }
+}
+
+/// Fills `entities` and `background` from `path`, returning the session the
+/// canvas was kept under. A file that does not parse is moved aside rather
+/// than left to be overwritten by the next save.
+fn restore(path: &Path, entities: &Canvas, background: &SharedBackground) -> Option<u64> {
+    if !path.exists() {
+        println!("No canvas at {} yet; starting empty", path.display());
+        return None;
+    }
+    match load_canvas(path, entities) {
+        Ok(loaded) => {
+            println!(
+                "Restored {} entities from {}",
+                loaded.entities.len(),
+                path.display()
+            );
+            for entity in loaded.entities {
+                entities.insert(entity);
+            }
+            if let Some(color) = loaded.background {
+                background.store(color.bits(), Ordering::Relaxed);
+            }
+            loaded.session
+        }
+        Err(e) => {
+            let aside = with_suffix(path, ".bad");
+            eprintln!("{}; starting empty", e);
+            match fs::rename(path, &aside) {
+                Ok(()) => eprintln!("Moved it to {}", aside.display()),
+                Err(e) => eprintln!("Cannot move it aside: {}", e),
+            }
+            None
+        }
+    }
+}
@@ -0,0 +1,240 @@
+//! Fingers on a touch screen. Each finger draws a stroke of its own, just as
+//! the mouse would, while two fingers put down together pan and zoom the view.
+
+use crate::view::View;
+use crate::{Drawing, Pen, Reflections, Tool, extend_stroke, finish_stroke, start_stroke};
+use crossbeam_channel::Sender;
+use macroquad::prelude::*;
+use tcp_drawing::entity::Canvas;
+use tcp_drawing::history::History;
+use tcp_drawing::network::Message;
+
+/// Seconds a finger waits before it draws, for a second one to come down and
+/// make the two of them a pinch.
+const PINCH_WINDOW: f64 = 0.15;
+
+struct Finger {
+    id: u64,
+    /// Where it is on screen, or where it came up.
+    at: Vec2,
+    /// When it came down.
+    since: f64,
+    /// Whether it has come up, and goes once its stroke is finished.
+    lifted: bool,
+    state: State,
+}
+
+enum State {
+    /// Not drawing yet, in case it is half of a pinch; where it has been since.
+    Waiting(Vec<Vec2>),
+    Drawing {
+        id: usize,
+        reflections: Reflections,
+        /// Seconds before the stroke takes its next point, as with the mouse.
+        cooldown: f32,
+    },
+    /// Half of a pinch, with where it was last frame.
+    Pinching(Vec2),
+    /// Does nothing until it comes up: left over from a pinch, or put down
+    /// with a tool fingers do not draw with.
+    Idle,
+}
+
+pub struct Touches {
+    /// In the order they came down, so the first is the primary one.
+    fingers: Vec<Finger>,
+    /// Where the last finger was, and where the mouse was then, so the brush
+    /// stays at the finger until the mouse moves.
+    last_touch: Option<(Vec2, Vec2)>,
+}
+
+impl Touches {
+    pub fn new() -> Self {
+        // every finger would otherwise move the one mouse pointer as well
+        simulate_mouse_with_touch(false);
+        Self {
+            fingers: Vec::new(),
+            last_touch: None,
+        }
+    }
+
+    /// Follows the fingers on the screen and pans and zooms `view` with a
+    /// pinch. Returns whether one is going, in which case nothing may draw.
+    pub fn track(&mut self, view: &mut View) -> bool {
+        // strokes are finished by `draw`, which may not have run for a while
+        self.fingers
+            .retain(|finger| !finger.lifted || matches!(finger.state, State::Drawing { .. }));
+        let touches = touches();
+        for finger in &mut self.fingers {
+            match touches.iter().find(|touch| touch.id == finger.id) {
+                Some(touch) => {
+                    finger.at = touch.position;
+                    finger.lifted |= ended(touch.phase);
+                }
+                None => finger.lifted = true,
+            }
+            if let State::Waiting(path) = &mut finger.state
+                && path.last() != Some(&finger.at)
+            {
+                path.push(finger.at);
+            }
+        }
+        let now = get_time();
+        for touch in &touches {
+            if !self.fingers.iter().any(|finger| finger.id == touch.id) {
+                self.fingers.push(Finger {
+                    id: touch.id,
+                    at: touch.position,
+                    since: now,
+                    lifted: ended(touch.phase),
+                    state: State::Waiting(vec![touch.position]),
+                });
+            }
+        }
+
+        // two fingers, come down together onto an otherwise empty screen
+        let down: Vec<&Finger> = self.fingers.iter().filter(|f| !f.lifted).collect();
+        let together = down.iter().all(|finger| {
+            matches!(finger.state, State::Waiting(_)) && now - finger.since < PINCH_WINDOW
+        });
+        if down.len() == 2 && together {
+            for finger in &mut self.fingers {
+                if !finger.lifted {
+                    finger.state = State::Pinching(finger.at);
+                }
+            }
+        }
+        let pinch = self.pinch(view);
+
+        if let Some(primary) = self.fingers.iter().find(|finger| !finger.lifted) {
+            self.last_touch = Some((primary.at, Vec2::from(mouse_position())));
+        }
+        pinch
+    }
+
+    /// Moves `view` along with the two pinching fingers, or breaks the pinch
+    /// up once one of them comes up. Returns whether it goes on.
+    fn pinch(&mut self, view: &mut View) -> bool {
+        let [a, b] = self
+            .fingers
+            .iter()
+            .enumerate()
+            .filter(|(_, finger)| matches!(finger.state, State::Pinching(_)))
+            .map(|(i, _)| i)
+            .collect::<Vec<_>>()[..]
+        else {
+            return false;
+        };
+        if self.fingers[a].lifted || self.fingers[b].lifted {
+            self.fingers[a].state = State::Idle;
+            self.fingers[b].state = State::Idle;
+            return false;
+        }
+        let (State::Pinching(was_a), State::Pinching(was_b)) =
+            (&self.fingers[a].state, &self.fingers[b].state)
+        else {
+            return false;
+        };
+        let (now_a, now_b) = (self.fingers[a].at, self.fingers[b].at);
+        view.pinch((*was_a, *was_b), (now_a, now_b));
+        self.fingers[a].state = State::Pinching(now_a);
+        self.fingers[b].state = State::Pinching(now_b);
+        true
+    }
+
+    /// Draws a stroke with `pen` under every finger that is not pinching,
+    /// through the same calls as the mouse. `place` turns a screen position
+    /// into the pen's, and `pressure` gives the pressure for a finger down
+    /// for so many seconds.
+    pub fn draw(
+        &mut self,
+        entities: &Canvas,
+        tx: &Sender<Message>,
+        history: &mut History,
+        pen: Pen,
+        place: impl Fn(Vec2) -> (f32, f32),
+        pressure: impl Fn(f64) -> Option<f32>,
+    ) {
+        let now = get_time();
+        let delta = get_frame_time();
+        for finger in &mut self.fingers {
+            let mut pen = Pen {
+                position: place(finger.at),
+                pressure: pressure(now - finger.since),
+                ..pen
+            };
+            match &mut finger.state {
+                State::Waiting(path) if finger.lifted || now - finger.since >= PINCH_WINDOW => {
+                    let mut path = std::mem::take(path).into_iter();
+                    finger.state = State::Idle;
+                    if pen.tool != Tool::Freehand {
+                        continue;
+                    }
+                    // from where it came down, along the way it went while waiting
+                    let Some(first) = path.next() else {
+                        continue;
+                    };
+                    let start = Pen {
+                        position: place(first),
+                        pressure: pressure(0.0),
+                        ..pen
+                    };
+                    let Some(Drawing::Stroke { id, reflections }) =
+                        start_stroke(entities, tx, &start, history)
+                    else {
+                        continue;
+                    };
+                    for point in path {
+                        pen.position = place(point);
+                        extend_stroke(entities, tx, &pen, id, reflections);
+                    }
+                    finger.state = State::Drawing {
+                        id,
+                        reflections,
+                        cooldown: pen.draw_interval,
+                    };
+                }
+                State::Drawing {
+                    id,
+                    reflections,
+                    cooldown,
+                } => {
+                    *cooldown -= delta;
+                    // the last point, where it came up, is never held back
+                    if *cooldown <= 0.0 || finger.lifted {
+                        *cooldown = pen.draw_interval;
+                        if !extend_stroke(entities, tx, &pen, *id, *reflections) {
+                            // the canvas was cleared mid-stroke
+                            finger.state = State::Idle;
+                        }
+                    }
+                }
+                _ => {}
+            }
+            if finger.lifted
+                && let State::Drawing {
+                    id, reflections, ..
+                } = finger.state
+            {
+                finish_stroke(entities, tx, id, reflections);
+                finger.state = State::Idle;
+            }
+        }
+        self.fingers
+            .retain(|finger| !finger.lifted || !matches!(finger.state, State::Idle));
+    }
+
+    /// Where the brush goes on screen: at the primary finger, or where the
+    /// last one came up until the mouse moves, or else at the mouse.
+    pub fn pointer(&self) -> Vec2 {
+        let mouse = Vec2::from(mouse_position());
+        match self.last_touch {
+            Some((at, mouse_then)) if mouse_then == mouse => at,
+            _ => mouse,
+        }
+    }
+}
+
+fn ended(phase: TouchPhase) -> bool {
+    matches!(phase, TouchPhase::Ended | TouchPhase::Cancelled)
+}
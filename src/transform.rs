@@ -0,0 +1,166 @@
+use crate::entity::{Entity, Shape};
+use serde::{Deserialize, Serialize};
+
+/// Affine map of canvas coordinates: `x' = a*x + c*y + tx`, `y' = b*x + d*y + ty`.
+/// Only translations, uniform scales, quarter turns and mirror images are
+/// built, so a radius scales by a single factor.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Transform {
+    pub a: f32,
+    pub b: f32,
+    pub c: f32,
+    pub d: f32,
+    pub tx: f32,
+    pub ty: f32,
+}
+
+impl Transform {
+    pub const IDENTITY: Transform = Transform {
+        a: 1.0,
+        b: 0.0,
+        c: 0.0,
+        d: 1.0,
+        tx: 0.0,
+        ty: 0.0,
+    };
+
+    pub fn translate(dx: f32, dy: f32) -> Self {
+        Self {
+            tx: dx,
+            ty: dy,
+            ..Self::IDENTITY
+        }
+    }
+
+    /// Scales by `factor` around `origin`, which stays put.
+    pub fn scale_about(factor: f32, origin: (f32, f32)) -> Self {
+        Self {
+            a: factor,
+            d: factor,
+            ..Self::IDENTITY
+        }
+        .fixing(origin)
+    }
+
+    /// Turns by `quarter_turns` times 90 degrees clockwise on screen around `origin`.
+    pub fn rotate_about(quarter_turns: i32, origin: (f32, f32)) -> Self {
+        // y points down, so clockwise takes +x to +y
+        let (a, b, c, d) = match quarter_turns.rem_euclid(4) {
+            0 => (1.0, 0.0, 0.0, 1.0),
+            1 => (0.0, 1.0, -1.0, 0.0),
+            2 => (-1.0, 0.0, 0.0, -1.0),
+            _ => (0.0, -1.0, 1.0, 0.0),
+        };
+        Self {
+            a,
+            b,
+            c,
+            d,
+            ..Self::IDENTITY
+        }
+        .fixing(origin)
+    }
+
+    /// Mirrors left to right across the vertical line through `origin` if
+    /// `flip_x`, and top to bottom across the horizontal one if `flip_y`.
+    pub fn mirror_about(flip_x: bool, flip_y: bool, origin: (f32, f32)) -> Self {
+        let sign = |flip| if flip { -1.0 } else { 1.0 };
+        Self {
+            a: sign(flip_x),
+            d: sign(flip_y),
+            ..Self::IDENTITY
+        }
+        .fixing(origin)
+    }
+
+    /// The same linear part, translated so that `origin` maps to itself.
+    fn fixing(self, (ox, oy): (f32, f32)) -> Self {
+        let (mx, my) = self.apply((ox, oy));
+        Self {
+            tx: self.tx + ox - mx,
+            ty: self.ty + oy - my,
+            ..self
+        }
+    }
+
+    /// `self` followed by `next`.
+    pub fn then(self, next: Transform) -> Self {
+        Self {
+            a: next.a * self.a + next.c * self.b,
+            b: next.b * self.a + next.d * self.b,
+            c: next.a * self.c + next.c * self.d,
+            d: next.b * self.c + next.d * self.d,
+            tx: next.a * self.tx + next.c * self.ty + next.tx,
+            ty: next.b * self.tx + next.d * self.ty + next.ty,
+        }
+    }
+
+    /// The transform undoing this one, or None if it collapses the canvas.
+    pub fn inverse(self) -> Option<Self> {
+        let det = self.a * self.d - self.b * self.c;
+        if det.abs() < f32::EPSILON {
+            return None;
+        }
+        let (a, b, c, d) = (self.d / det, -self.b / det, -self.c / det, self.a / det);
+        Some(Self {
+            a,
+            b,
+            c,
+            d,
+            tx: -(a * self.tx + c * self.ty),
+            ty: -(b * self.tx + d * self.ty),
+        })
+    }
+
+    pub fn apply(self, (x, y): (f32, f32)) -> (f32, f32) {
+        (
+            self.a * x + self.c * y + self.tx,
+            self.b * x + self.d * y + self.ty,
+        )
+    }
+
+    pub fn apply_all(self, points: &mut [(f32, f32)]) {
+        for point in points.iter_mut() {
+            *point = self.apply(*point);
+        }
+    }
+
+    /// Moves the entity, its stroke points and line end included, and scales
+    /// its radius and size.
+    pub fn apply_to(self, entity: &mut Entity) {
+        let corner = (entity.x, entity.y);
+        (entity.x, entity.y) = self.apply(corner);
+        let factor = (self.a * self.d - self.b * self.c).abs().sqrt();
+        entity.radius *= factor;
+        match &mut entity.shape {
+            Shape::Circle => {}
+            Shape::Stroke { points, .. } => self.apply_all(points),
+            Shape::Rect { w, h } => {
+                // a quarter turn can swap which corner is top-left, so map both
+                let (x1, y1) = self.apply((corner.0 + *w, corner.1 + *h));
+                (entity.x, entity.y, *w, *h) = (
+                    entity.x.min(x1),
+                    entity.y.min(y1),
+                    (x1 - entity.x).abs(),
+                    (y1 - entity.y).abs(),
+                );
+            }
+            Shape::Line { x2, y2, thickness } => {
+                (*x2, *y2) = self.apply((*x2, *y2));
+                *thickness *= factor;
+            }
+            Shape::Fill { rects } => {
+                for (x, y, w, h) in rects.iter_mut() {
+                    let (x0, y0) = self.apply((*x, *y));
+                    let (x1, y1) = self.apply((*x + *w, *y + *h));
+                    (*x, *y, *w, *h) = (x0.min(x1), y0.min(y1), (x1 - x0).abs(), (y1 - y0).abs());
+                }
+                for &(x, y, ..) in rects.iter() {
+                    (entity.x, entity.y) = (entity.x.min(x), entity.y.min(y));
+                }
+            }
+            // labels stay upright; only where they start and how big they are change
+            Shape::Text { size, .. } => *size *= factor,
+        }
+    }
+}
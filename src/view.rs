@@ -0,0 +1,145 @@
+use crate::bindings;
+use macroquad::prelude::*;
+use tcp_drawing::keymap::Action;
+
+const MIN_ZOOM: f32 = 0.05;
+const MAX_ZOOM: f32 = 20.0;
+/// Zoom factor for one notch of the mouse wheel.
+const ZOOM_STEP: f32 = 1.1;
+/// World area every window fits itself around until it is panned or zoomed,
+/// whatever its size, so peers see the same drawing in the same place. What
+/// older builds drew in window pixels lands in it as it was.
+pub const CANVAS_SIZE: Vec2 = Vec2::new(1920.0, 1080.0);
+
+/// The part of the unbounded canvas the window shows. Entities live in world
+/// coordinates, which the window scales to fit CANVAS_SIZE, with margins
+/// where its shape differs, until the view is panned or zoomed.
+pub struct View {
+    /// World position of the window's top-left corner.
+    origin: Vec2,
+    zoom: f32,
+    /// Where the pointer was last frame while dragging the view around.
+    dragged_from: Option<Vec2>,
+    /// Whether the view still fits CANVAS_SIZE, following the window as it is resized.
+    fitted: bool,
+}
+
+impl View {
+    pub fn new() -> Self {
+        let mut view = Self {
+            origin: Vec2::ZERO,
+            zoom: 1.0,
+            dragged_from: None,
+            fitted: true,
+        };
+        view.fit_to_window();
+        view
+    }
+
+    pub fn zoom(&self) -> f32 {
+        self.zoom
+    }
+
+    pub fn to_world(&self, screen: Vec2) -> Vec2 {
+        self.origin + screen / self.zoom
+    }
+
+    pub fn to_screen(&self, world: Vec2) -> Vec2 {
+        (world - self.origin) * self.zoom
+    }
+
+    /// The pointer position in world coordinates.
+    pub fn mouse(&self) -> (f32, f32) {
+        self.to_world(Vec2::from(mouse_position())).into()
+    }
+
+    /// The world area the window shows.
+    pub fn visible(&self) -> Rect {
+        Rect::new(
+            self.origin.x,
+            self.origin.y,
+            screen_width() / self.zoom,
+            screen_height() / self.zoom,
+        )
+    }
+
+    /// Camera drawing world coordinates where this view puts them on screen.
+    pub fn camera(&self) -> Camera2D {
+        Camera2D::from_display_rect(self.visible())
+    }
+
+    /// Scales CANVAS_SIZE to the window as large as it fits, centered.
+    pub fn fit_to_window(&mut self) {
+        let window = Vec2::new(screen_width(), screen_height());
+        let fit = (window / CANVAS_SIZE).min_element();
+        self.zoom = fit.clamp(MIN_ZOOM, MAX_ZOOM);
+        self.origin = (CANVAS_SIZE - window / self.zoom) / 2.0;
+    }
+
+    /// Shades what the window shows outside CANVAS_SIZE, under the current camera.
+    pub fn render_margins(&self, ink: Color) {
+        let visible = self.visible();
+        let color = Color { a: 0.06, ..ink };
+        let (width, height) = CANVAS_SIZE.into();
+        let (left, top) = (visible.x.min(0.0), visible.y.min(0.0));
+        let (right, bottom) = (visible.right().max(width), visible.bottom().max(height));
+        draw_rectangle(left, top, right - left, -top, color);
+        draw_rectangle(left, height, right - left, bottom - height, color);
+        draw_rectangle(left, 0.0, -left, height, color);
+        draw_rectangle(width, 0.0, right - width, height, color);
+    }
+
+    /// Pans while the middle button, or Space and the left button, drag the
+    /// pointer, and zooms with Ctrl and the wheel; Home fits the canvas to the
+    /// window again. Returns whether the pointer is taken up by panning, so
+    /// it does not start drawing as well.
+    pub fn handle_input(&mut self) -> bool {
+        let mouse = Vec2::from(mouse_position());
+        let grab_key = is_key_down(KeyCode::Space);
+        let dragging = is_mouse_button_down(MouseButton::Middle)
+            || (grab_key && is_mouse_button_down(MouseButton::Left));
+        if dragging && let Some(last) = self.dragged_from {
+            self.origin -= (mouse - last) / self.zoom;
+            self.fitted &= mouse == last;
+        }
+        self.dragged_from = dragging.then_some(mouse);
+
+        let (_, wheel) = mouse_wheel();
+        let ctrl = is_key_down(KeyCode::LeftControl) || is_key_down(KeyCode::RightControl);
+        if ctrl && wheel != 0.0 {
+            self.zoom_around(ZOOM_STEP.powf(wheel.signum()), mouse);
+            self.fitted = false;
+        }
+        if bindings::pressed(Action::FitView) {
+            self.fitted = true;
+        }
+        if self.fitted {
+            self.fit_to_window();
+        }
+
+        dragging || grab_key
+    }
+
+    /// Pans and zooms along with two fingers that moved from `was` to `now`,
+    /// in screen coordinates: the world point between them stays between
+    /// them, and their spread scales the zoom.
+    pub fn pinch(&mut self, was: (Vec2, Vec2), now: (Vec2, Vec2)) {
+        if was == now {
+            return;
+        }
+        let fixed = self.to_world((was.0 + was.1) / 2.0);
+        let (was_spread, now_spread) = (was.0.distance(was.1), now.0.distance(now.1));
+        if was_spread > 0.0 && now_spread > 0.0 {
+            self.zoom = (self.zoom * now_spread / was_spread).clamp(MIN_ZOOM, MAX_ZOOM);
+        }
+        self.origin = fixed - (now.0 + now.1) / 2.0 / self.zoom;
+        self.fitted = false;
+    }
+
+    /// Zooms by `factor`, keeping the world point under `anchor` on screen where it is.
+    fn zoom_around(&mut self, factor: f32, anchor: Vec2) {
+        let fixed = self.to_world(anchor);
+        self.zoom = (self.zoom * factor).clamp(MIN_ZOOM, MAX_ZOOM);
+        self.origin = fixed - anchor / self.zoom;
+    }
+}
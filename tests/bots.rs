@@ -0,0 +1,97 @@
+//! Load-testing bots: the walks they draw, and a short run against a real server.
+
+mod common;
+
+use common::{Server, config, wait_until};
+use tcp_drawing::config::{Role, parse_args};
+use tcp_drawing::entity::Shape;
+use tcp_drawing::network::bots::{STROKE_POINTS, Walk, start_bots};
+
+#[test]
+fn the_same_seed_walks_the_same_way() {
+    let strokes = |seed, bot| {
+        let mut walk = Walk::new(seed, bot);
+        (0..20).map(|_| walk.next_stroke()).collect::<Vec<_>>()
+    };
+    assert_eq!(strokes(7, 0), strokes(7, 0));
+    assert_ne!(strokes(7, 0), strokes(7, 1));
+    assert_ne!(strokes(7, 0), strokes(8, 0));
+
+    let walked = strokes(7, 3);
+    for pair in walked.windows(2) {
+        assert_eq!(pair[0].last(), pair[1].first(), "strokes do not join up");
+    }
+    for stroke in &walked {
+        assert_eq!(stroke.len(), STROKE_POINTS);
+        for &(x, y) in stroke {
+            assert!((0.0..=1920.0).contains(&x) && (0.0..=1080.0).contains(&y));
+        }
+        for step in stroke.windows(2) {
+            let (dx, dy) = (step[1].0 - step[0].0, step[1].1 - step[0].1);
+            assert!(dx.hypot(dy) <= 6.01, "a step of {dx} {dy}");
+        }
+    }
+}
+
+#[test]
+fn bots_see_each_others_strokes_through_the_server() {
+    let server = Server::start();
+    let bots = start_bots(&config(&[
+        "--connect",
+        &server.addr,
+        "--bots",
+        "3",
+        "--bot-rate",
+        "40",
+        "--bot-seed",
+        "1",
+    ]));
+
+    let mut timed = 0;
+    wait_until("the bots time each other's strokes", || {
+        let report = bots.report();
+        timed += report.latency.map_or(0, |latency| latency.samples);
+        report.joined == 3 && timed >= 30
+    });
+    let mut latency = None;
+    wait_until("another round of arrivals", || {
+        latency = bots.report().latency;
+        latency.is_some()
+    });
+    let latency = latency.unwrap();
+    assert!(latency.p50 <= latency.p90 && latency.p90 <= latency.p99);
+    assert!(latency.p99 <= latency.max);
+
+    let report = bots.stop();
+    assert_eq!(report.joined, 3);
+    // every stroke reaches the two other bots at most
+    assert!(report.received <= 2 * report.sent);
+    assert!(report.received >= (timed + latency.samples) as u64);
+
+    // the server has every stroke, appends and all
+    wait_until("the server has every stroke", || {
+        server.canvas.len() as u64 == report.sent
+            && server.canvas.iter().all(|entity| {
+                matches!(&entity.shape, Shape::Stroke { points, .. } if points.len() == STROKE_POINTS)
+            })
+    });
+    server.stop();
+}
+
+#[test]
+fn bot_options_go_with_bots_and_a_server_to_connect_to() {
+    let parse = |args: &[&str]| {
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        parse_args(&args).err()
+    };
+    assert!(parse(&["--bot-rate", "5"]).is_some());
+    assert!(parse(&["--bot-seed", "5"]).is_some());
+    assert!(parse(&["--bots", "0"]).is_some());
+    assert!(parse(&["--bots", "2", "--bot-rate", "0"]).is_some());
+    assert!(parse(&["--bots", "2", "--listen", "127.0.0.1:0"]).is_some());
+    assert!(parse(&["--bots", "2", "--spectate"]).is_some());
+
+    let bots = config(&["--bots", "2", "127.0.0.1:9"]);
+    assert_eq!(bots.role, Role::Connect("127.0.0.1:9".to_string()));
+    assert_eq!(bots.bots, Some(2));
+}
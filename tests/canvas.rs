@@ -0,0 +1,235 @@
+//! Finding what is in view through the canvas grid.
+
+use macroquad::math::Rect;
+use macroquad::prelude::Circle;
+use tcp_drawing::entity::{
+    Canvas, Create, Entity, EntityColor, EntityEvent, Eraser, SUBSCRIBER_CAPACITY, next_id,
+};
+
+/// Everything overlapping `area`, the slow way.
+fn overlapping(canvas: &Canvas, area: &Rect) -> Vec<usize> {
+    let mut ids: Vec<usize> = canvas
+        .iter()
+        .filter(|entry| entry.value().bounds().overlaps(area))
+        .map(|entry| *entry.key())
+        .collect();
+    ids.sort_unstable();
+    ids
+}
+
+#[test]
+fn the_grid_finds_what_a_full_scan_finds() {
+    let canvas = Canvas::new();
+    for i in 0..2000 {
+        let (x, y) = ((i % 50) as f32 * 37.0, (i / 50) as f32 * 41.0);
+        Entity::spawn(x, y, 3.0, EntityColor::BLACK, &canvas);
+    }
+    for i in 0..20 {
+        let x = i as f32 * 90.0;
+        let rect = Entity::rect(
+            next_id(),
+            (x, -40.0),
+            (x + 30.0, 1700.0),
+            EntityColor::BLACK,
+        );
+        canvas.insert(rect);
+    }
+
+    for area in [
+        Rect::new(100.0, 100.0, 300.0, 200.0),
+        Rect::new(-500.0, -500.0, 10.0, 10.0),
+        // zoomed far out: many more cells than are filled
+        Rect::new(-1e6, -1e6, 2e6, 2e6),
+    ] {
+        let mut found = canvas.query_rect(&area);
+        found.sort_unstable();
+        assert_eq!(found, overlapping(&canvas, &area), "{:?}", area);
+    }
+}
+
+#[test]
+fn wide_dots_and_long_segments_show_from_outside_their_cells() {
+    let canvas = Canvas::new();
+    // a fat dot whose center is well off to the left of the view
+    let dot = Entity::spawn(-150.0, 50.0, 200.0, EntityColor::BLACK, &canvas).unwrap();
+    // a stroke with one segment right across the view, both points outside it
+    let stroke = Entity::spawn(-1000.0, 500.0, 2.0, EntityColor::BLACK, &canvas).unwrap();
+    canvas.update(stroke, |entity| {
+        entity.extend_stroke(&[(1000.0, 500.0)], &Default::default())
+    });
+
+    let view = Rect::new(0.0, 0.0, 100.0, 600.0);
+    assert_eq!(canvas.query_rect(&view), vec![dot, stroke]);
+
+    canvas.remove(dot);
+    assert_eq!(canvas.query_rect(&view), vec![stroke]);
+}
+
+#[test]
+fn the_later_drawn_is_on_top_whatever_order_it_arrives_in() {
+    let older = Entity::rect(
+        next_id(),
+        (0.0, 0.0),
+        (50.0, 50.0),
+        EntityColor::rgb(0x0000FF),
+    );
+    let newer = Entity::rect(
+        next_id(),
+        (10.0, 10.0),
+        (60.0, 60.0),
+        EntityColor::rgb(0xFF0000),
+    );
+    let view = Rect::new(0.0, 0.0, 100.0, 100.0);
+
+    // two peers that got the same entities in opposite orders stack them alike
+    let (here, there) = (Canvas::new(), Canvas::new());
+    here.insert(older.clone());
+    here.insert(newer.clone());
+    there.insert(newer.clone());
+    there.insert(older.clone());
+    for canvas in [&here, &there] {
+        assert_eq!(canvas.query_rect(&view), vec![older.id, newer.id]);
+        assert_eq!(canvas.topmost((20.0, 20.0), 1.0, |_| true), Some(newer.id));
+        // nothing there
+        assert_eq!(canvas.topmost((90.0, 90.0), 1.0, |_| true), None);
+    }
+
+    // undoing an erase puts it back where it was, not on top
+    here.remove(older.id);
+    here.insert(older.clone());
+    assert_eq!(here.query_rect(&view), vec![older.id, newer.id]);
+}
+
+#[test]
+fn what_is_drawn_after_something_arrives_goes_over_it() {
+    let canvas = Canvas::new();
+    let mut from_a_peer = Entity::rect(
+        next_id(),
+        (0.0, 0.0),
+        (50.0, 50.0),
+        EntityColor::rgb(0x0000FF),
+    );
+    // a peer that has drawn far more than this process so far
+    from_a_peer.seq += 1_000_000;
+    canvas.insert(from_a_peer.clone());
+
+    let drawn_here = Entity::rect(
+        next_id(),
+        (10.0, 10.0),
+        (60.0, 60.0),
+        EntityColor::rgb(0xFF0000),
+    );
+    assert!(drawn_here.seq > from_a_peer.seq);
+    canvas.insert(drawn_here.clone());
+    assert_eq!(
+        canvas.topmost((20.0, 20.0), 1.0, |_| true),
+        Some(drawn_here.id)
+    );
+}
+
+#[test]
+fn higher_layers_stack_over_lower_ones_however_new() {
+    let canvas = Canvas::new();
+    let sketch = Entity {
+        layer: 1,
+        ..Entity::rect(
+            next_id(),
+            (0.0, 0.0),
+            (50.0, 50.0),
+            EntityColor::rgb(0x0000FF),
+        )
+    };
+    let background = Entity::rect(
+        next_id(),
+        (10.0, 10.0),
+        (60.0, 60.0),
+        EntityColor::rgb(0xFF0000),
+    );
+    canvas.insert(background.clone());
+    canvas.insert(sketch.clone());
+
+    let view = Rect::new(0.0, 0.0, 100.0, 100.0);
+    assert_eq!(canvas.query_rect(&view), vec![background.id, sketch.id]);
+    assert_eq!(canvas.topmost((20.0, 20.0), 1.0, |_| true), Some(sketch.id));
+    // with the sketch layer left out, the background is what is on top
+    let shown = |entity: &Entity| entity.layer != 1;
+    assert_eq!(
+        canvas.topmost((20.0, 20.0), 1.0, shown),
+        Some(background.id)
+    );
+}
+
+#[test]
+fn the_oldest_are_those_that_arrived_first_whatever_their_ids() {
+    let canvas = Canvas::new();
+    let dots: Vec<Entity> = (0..4)
+        .map(|i| {
+            Entity::rect(
+                next_id(),
+                (i as f32, 0.0),
+                (i as f32 + 1.0, 1.0),
+                EntityColor::BLACK,
+            )
+        })
+        .collect();
+    for dot in dots.iter().rev() {
+        canvas.insert(dot.clone());
+    }
+    assert_eq!(canvas.oldest(2), vec![dots[3].id, dots[2].id]);
+
+    // changed in place, it keeps its turn; taken out and back, it is the newest
+    canvas.update(dots[3].id, |dot| dot.color = EntityColor::rgb(0xFF0000));
+    canvas.remove(dots[2].id);
+    canvas.insert(dots[2].clone());
+    assert_eq!(
+        canvas.oldest(10),
+        vec![dots[3].id, dots[1].id, dots[0].id, dots[2].id]
+    );
+    canvas.clear();
+    assert!(canvas.oldest(10).is_empty());
+}
+
+#[test]
+fn subscribers_see_a_spawn_then_an_erase_in_order() {
+    let canvas = Canvas::new();
+    let first = canvas.subscribe();
+    let second = canvas.subscribe();
+
+    let id = Entity::spawn(10.0, 10.0, 4.0, EntityColor::BLACK, &canvas).unwrap();
+    canvas.update(id, |entity| entity.color = EntityColor::rgb(0xFF0000));
+    let mut stroke = canvas.get(id).unwrap().value().clone();
+    let erased = stroke.erase(Circle::new(10.0, 10.0, 1.0), &canvas);
+    assert!(erased.is_some());
+    canvas.clear();
+
+    for events in [&first, &second] {
+        let events: Vec<EntityEvent> = events.try_iter().collect();
+        assert!(
+            matches!(
+                events.as_slice(),
+                [
+                    EntityEvent::Added(added),
+                    EntityEvent::Updated(updated),
+                    EntityEvent::Removed(removed),
+                    EntityEvent::Cleared,
+                ] if added.id == id && added.color == EntityColor::BLACK && updated.color == EntityColor::rgb(0xFF0000) && *removed == id
+            ),
+            "{:?}",
+            events
+        );
+    }
+}
+
+#[test]
+fn a_subscriber_that_stops_reading_holds_up_nothing() {
+    let canvas = Canvas::new();
+    let stalled = canvas.subscribe();
+    for _ in 0..SUBSCRIBER_CAPACITY + 10 {
+        Entity::spawn(0.0, 0.0, 1.0, EntityColor::BLACK, &canvas);
+    }
+    assert_eq!(canvas.len(), SUBSCRIBER_CAPACITY + 10);
+    // once it catches up it gets what comes next
+    assert_eq!(stalled.try_iter().count(), SUBSCRIBER_CAPACITY);
+    canvas.clear();
+    assert!(matches!(stalled.try_recv(), Ok(EntityEvent::Cleared)));
+}
@@ -0,0 +1,96 @@
+//! Copied regions: where pastes land, and that each one is a drawing of its own.
+
+use tcp_drawing::clipboard::Clipboard;
+use tcp_drawing::entity::{Canvas, Create, Entity, EntityColor, Shape, next_id};
+
+/// A rectangle, a stroke and a label, stacked in that order.
+fn region() -> Vec<Entity> {
+    let rect = Entity::rect(next_id(), (100.0, 100.0), (140.0, 130.0), EntityColor::RED);
+    let canvas = Canvas::new();
+    let stroke = Entity::spawn(110.0, 120.0, 4.0, EntityColor::BLUE, &canvas).unwrap();
+    canvas.update(stroke, |entity| {
+        entity.extend_stroke(&[(130.0, 125.0), (150.0, 140.0)], &Default::default())
+    });
+    vec![
+        rect,
+        canvas.get(stroke).unwrap().clone(),
+        Entity::text(
+            next_id(),
+            (120.0, 150.0),
+            "hi".to_string(),
+            16.0,
+            EntityColor::BLACK,
+        ),
+    ]
+}
+
+#[test]
+fn a_paste_puts_the_region_center_where_it_goes() {
+    let copied = region();
+    let clipboard = Clipboard::copy(copied.clone(), (120.0, 125.0));
+    let pasted = clipboard.pasted_at((-500.0, 1000.0), 2);
+
+    assert_eq!(pasted.len(), copied.len());
+    for (original, copy) in copied.iter().zip(&pasted) {
+        assert_eq!(copy.x, original.x - 620.0);
+        assert_eq!(copy.y, original.y + 875.0);
+        assert_eq!(copy.color, original.color);
+        assert_eq!(copy.layer, 2);
+    }
+    let Shape::Stroke { points, .. } = &pasted[1].shape else {
+        panic!("not a stroke: {:?}", pasted[1].shape);
+    };
+    assert_eq!(
+        points,
+        &[(-510.0, 995.0), (-490.0, 1000.0), (-470.0, 1015.0)]
+    );
+}
+
+#[test]
+fn every_paste_is_a_copy_of_its_own() {
+    let copied = region();
+    let clipboard = Clipboard::copy(copied.clone(), (0.0, 0.0));
+    let first = clipboard.pasted_at((10.0, 10.0), 0);
+    let second = clipboard.pasted_at((10.0, 10.0), 0);
+
+    let mut ids: Vec<usize> = copied
+        .iter()
+        .chain(&first)
+        .chain(&second)
+        .map(|entity| entity.id)
+        .collect();
+    ids.sort_unstable();
+    ids.dedup();
+    assert_eq!(ids.len(), copied.len() * 3);
+
+    // each paste lands above the last, stacked as the region was
+    let seqs: Vec<u64> = first.iter().chain(&second).map(|e| e.seq).collect();
+    assert!(seqs.windows(2).all(|pair| pair[0] < pair[1]), "{seqs:?}");
+    assert!(copied.iter().all(|entity| entity.seq < seqs[0]));
+}
+
+#[test]
+fn the_clipboard_goes_through_json_to_another_instance() {
+    let clipboard = Clipboard::copy(region(), (120.0, 125.0));
+    let json = clipboard.to_json();
+    let read = Clipboard::from_json(&json).unwrap();
+
+    assert_eq!(read.entities().len(), clipboard.entities().len());
+    let at = |clipboard: &Clipboard| -> Vec<(f32, f32)> {
+        let pasted = clipboard.pasted_at((3.0, 4.0), 0);
+        pasted.iter().map(|entity| (entity.x, entity.y)).collect()
+    };
+    assert_eq!(at(&read), at(&clipboard));
+}
+
+#[test]
+fn other_text_on_the_system_clipboard_is_not_pasted() {
+    for text in [
+        "hello",
+        "",
+        "[1, 2, 3]",
+        r##"{"entities": [], "background": "#FFFFFF"}"##,
+    ] {
+        assert!(Clipboard::from_json(text).is_err(), "{text:?}");
+    }
+}
@@ -0,0 +1,117 @@
+//! Colors in both of their JSON forms, in bincode, and through macroquad.
+
+use macroquad::color::{self, Color};
+use tcp_drawing::entity::{Entity, EntityColor, next_id};
+
+#[test]
+fn json_writes_colors_as_hex_and_reads_them_back() {
+    let slate = EntityColor::rgb(0x2E3440);
+    assert_eq!(serde_json::to_string(&slate).unwrap(), r##""#2E3440""##);
+    let faint = slate.with_alpha(0.5);
+    assert_eq!(serde_json::to_string(&faint).unwrap(), r##""#2E34407F""##);
+    for color in [
+        slate,
+        faint,
+        EntityColor::BLACK,
+        EntityColor::WHITE.with_alpha(0.0),
+    ] {
+        let json = serde_json::to_string(&color).unwrap();
+        assert_eq!(serde_json::from_str::<EntityColor>(&json).unwrap(), color);
+    }
+    assert_eq!(
+        serde_json::from_str::<EntityColor>(r##""#2e3440""##).unwrap(),
+        slate
+    );
+}
+
+#[test]
+fn integers_from_older_builds_still_read() {
+    let slate = EntityColor::rgb(0x2E3440);
+    assert_eq!(
+        serde_json::from_str::<EntityColor>("3028032").unwrap(),
+        slate
+    );
+    // transparency in the top byte made older builds write a negative number
+    let faint = slate.with_alpha(0.5);
+    assert_eq!(faint.bits(), 0x802E3440);
+    assert_eq!(
+        serde_json::from_str::<EntityColor>("-2144455616").unwrap(),
+        faint
+    );
+    assert_eq!(
+        serde_json::from_str::<EntityColor>("2150511680").unwrap(),
+        faint
+    );
+
+    let mut saved = serde_json::to_value(Entity::rect(
+        next_id(),
+        (0.0, 0.0),
+        (4.0, 4.0),
+        EntityColor::RED,
+    ))
+    .unwrap();
+    saved["color"] = 0xFF0000.into();
+    let loaded: Entity = serde_json::from_value(saved).unwrap();
+    assert_eq!(loaded.color, EntityColor::rgb(0xFF0000));
+}
+
+#[test]
+fn colors_that_are_not_colors_are_refused() {
+    for json in [
+        "4294967296",
+        "-2147483649",
+        "1.5",
+        r#""red""#,
+        r#""2E3440""#,
+        r##""#2E344""##,
+        r##""#+E3440""##,
+        r##""#2E3440FF00""##,
+    ] {
+        assert!(
+            serde_json::from_str::<EntityColor>(json).is_err(),
+            "{}",
+            json
+        );
+    }
+}
+
+#[test]
+fn bincode_keeps_the_integer_older_builds_sent() {
+    let config = bincode::config::standard();
+    let faint = EntityColor::rgb(0x2E3440).with_alpha(0.5);
+    let encoded = bincode::serde::encode_to_vec(faint, config).unwrap();
+    assert_eq!(
+        encoded,
+        bincode::serde::encode_to_vec(0x802E3440u32 as i32, config).unwrap()
+    );
+    let (decoded, _): (EntityColor, usize) =
+        bincode::serde::decode_from_slice(&encoded, config).unwrap();
+    assert_eq!(decoded, faint);
+}
+
+#[test]
+fn colors_round_trip_through_macroquad() {
+    let named = [
+        color::RED,
+        color::GREEN,
+        color::BLUE,
+        color::BLACK,
+        color::ORANGE,
+        color::PURPLE,
+        color::YELLOW,
+        color::BROWN,
+    ];
+    for (macroquad, palette) in named.into_iter().zip(EntityColor::PALETTE) {
+        assert_eq!(EntityColor::from(macroquad), palette);
+        assert_eq!(EntityColor::from(Color::from(palette)), palette);
+    }
+    let faint = EntityColor::rgb(0x2E3440).with_alpha(0.3);
+    let back = Color::from(faint);
+    assert!((back.a - 0.3).abs() < 1.0 / 255.0);
+    assert_eq!(EntityColor::from(back), faint);
+    // out of range channels are clamped
+    assert_eq!(
+        EntityColor::from(Color::new(2.0, -1.0, 0.0, 1.5)),
+        EntityColor::rgb(0xFF0000)
+    );
+}
@@ -0,0 +1,149 @@
+//! Servers and clients on real sockets, shared by the integration tests.
+// every test binary compiles this, but none uses all of it
+#![allow(dead_code)]
+
+use crossbeam_channel::{Sender, unbounded};
+use dashmap::DashMap;
+use std::net::TcpListener;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tcp_drawing::chat::ChatLog;
+use tcp_drawing::config::{self, Command, Config, Role};
+use tcp_drawing::entity::{Canvas, Entity, EntityColor, next_id};
+use tcp_drawing::network::{self, ClientHandle, CursorMap, Message, ServerHandle};
+
+/// Longest any test waits for something to come across.
+pub const TIMEOUT: Duration = Duration::from_secs(5);
+
+pub fn config(args: &[&str]) -> Config {
+    let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+    match config::parse_args(&args) {
+        Ok(Command::Run(config)) => *config,
+        other => panic!("unexpected parse result for {:?}: {:?}", args, other.err()),
+    }
+}
+
+/// A server on an ephemeral port. Dropping `tx` is what stops it.
+pub struct Server {
+    pub addr: String,
+    pub canvas: Arc<Canvas>,
+    pub tx: Sender<Message>,
+    /// The pointers the host sees.
+    pub cursors: CursorMap,
+    pub handle: ServerHandle,
+}
+
+impl Server {
+    pub fn start() -> Self {
+        Self::start_with(&[])
+    }
+
+    /// A server run with `args` on top of `--listen`.
+    pub fn start_with(args: &[&str]) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        Self::run(vec![listener], Arc::new(Canvas::new()), args)
+    }
+
+    /// A server taking clients on every one of `listeners`; `addr` is the first.
+    pub fn start_on(listeners: Vec<TcpListener>) -> Self {
+        Self::run(listeners, Arc::new(Canvas::new()), &[])
+    }
+
+    /// A server on an address another one has just let go of, run with `args`.
+    pub fn start_at(addr: &str, args: &[&str]) -> Self {
+        let listener = TcpListener::bind(addr).unwrap();
+        Self::run(vec![listener], Arc::new(Canvas::new()), args)
+    }
+
+    fn run(listeners: Vec<TcpListener>, canvas: Arc<Canvas>, args: &[&str]) -> Self {
+        let addrs: Vec<String> = listeners
+            .iter()
+            .map(|listener| listener.local_addr().unwrap().to_string())
+            .collect();
+        let mut listen_args = Vec::new();
+        for addr in &addrs {
+            listen_args.extend(["--listen", addr.as_str()]);
+        }
+        let (tx, rx) = unbounded();
+        let cursors: CursorMap = Arc::new(DashMap::new());
+        let handle = network::start_server(
+            listeners,
+            canvas.clone(),
+            rx,
+            cursors.clone(),
+            ChatLog::default(),
+            &config(&[&listen_args, args].concat()),
+        );
+        let addr = addrs[0].clone();
+        Self {
+            addr,
+            canvas,
+            tx,
+            cursors,
+            handle,
+        }
+    }
+
+    pub fn stop(self) {
+        drop(self.tx);
+        self.handle.join();
+    }
+}
+
+/// A full client, as a window would run it, connected to `addr`.
+pub struct Client {
+    pub canvas: Arc<Canvas>,
+    pub tx: Sender<Message>,
+    pub handle: ClientHandle,
+}
+
+impl Client {
+    pub fn start(addr: &str, name: &str) -> Self {
+        Self::start_with(addr, name, &[])
+    }
+
+    /// A client run with `args` on top of `--name`.
+    pub fn start_with(addr: &str, name: &str, args: &[&str]) -> Self {
+        let canvas = Arc::new(Canvas::new());
+        let (tx, rx) = unbounded();
+        // set after parsing, which refuses --connect where there is no display
+        let mut config = config(&[&["--name", name], args].concat());
+        config.role = Role::Connect(addr.to_string());
+        let handle = network::start_client(
+            canvas.clone(),
+            rx,
+            Arc::new(DashMap::new()),
+            ChatLog::default(),
+            &config,
+        );
+        Self { canvas, tx, handle }
+    }
+
+    pub fn stop(self) {
+        let _ = self.tx.send(Message::Disconnect);
+        drop(self.tx);
+        self.handle.join();
+    }
+}
+
+pub fn wait_until(what: &str, mut done: impl FnMut() -> bool) {
+    let deadline = Instant::now() + TIMEOUT;
+    while !done() {
+        assert!(
+            Instant::now() < deadline,
+            "timed out waiting until {}",
+            what
+        );
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+pub fn dot(x: f32, y: f32) -> Entity {
+    Entity::rect(
+        next_id(),
+        (x, y),
+        (x + 4.0, y + 4.0),
+        EntityColor::rgb(0xFF0000),
+    )
+}
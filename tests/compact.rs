@@ -0,0 +1,162 @@
+//! New entities in the compact encoding: how close they come back, and how
+//! much smaller they are on the wire.
+
+use tcp_drawing::entity::{Canvas, Create, Entity, EntityColor, Shape};
+use tcp_drawing::network::compact::CompactBatch;
+use tcp_drawing::network::{self, FrameError, Message, MessageHandler, WireFormat};
+
+/// A stroke of `points` wobbling points from `(x, y)`, as a pointer leaves them.
+fn stroke(x: f32, y: f32, points: usize) -> Entity {
+    let canvas = Canvas::new();
+    let id = Entity::spawn(x, y, 5.3, EntityColor::BLUE, &canvas).unwrap();
+    canvas
+        .update(id, |entity| {
+            let more: Vec<(f32, f32)> = (1..points)
+                .map(|i| {
+                    let t = i as f32;
+                    (x + t * 2.37, y + (t * 0.7).sin() * 11.13)
+                })
+                .collect();
+            entity.extend_stroke(&more, &Default::default());
+            entity.clone()
+        })
+        .unwrap()
+}
+
+fn points(entity: &Entity) -> &[(f32, f32)] {
+    match &entity.shape {
+        Shape::Stroke { points, .. } => points,
+        other => panic!("not a stroke: {:?}", other),
+    }
+}
+
+fn round_trip(entities: &[Entity]) -> Vec<Entity> {
+    CompactBatch::try_from(entities).unwrap().into()
+}
+
+fn assert_close(sent: &Entity, received: &Entity) {
+    assert_eq!(received.id, sent.id);
+    assert_eq!(received.color, sent.color);
+    assert_eq!(received.seq, sent.seq);
+    let off = |a: (f32, f32), b: (f32, f32)| (a.0 - b.0).abs().max((a.1 - b.1).abs());
+    assert!(off((sent.x, sent.y), (received.x, received.y)) < 0.5);
+    assert!((sent.radius - received.radius).abs() < 0.5);
+    assert_eq!(points(received).len(), points(sent).len());
+    for (&a, &b) in points(sent).iter().zip(points(received)) {
+        assert!(off(a, b) < 0.5, "{:?} came back as {:?}", a, b);
+    }
+}
+
+#[test]
+fn entities_come_back_within_half_a_pixel() {
+    let sent = vec![stroke(12.345, 67.891, 40), stroke(-300.6, 1024.2, 8)];
+    for (sent, received) in sent.iter().zip(round_trip(&sent)) {
+        assert_close(sent, &received);
+    }
+}
+
+#[test]
+fn the_origin_keeps_a_drawing_far_out_on_the_canvas_precise() {
+    // panned a long way from the world's origin
+    let sent = vec![
+        stroke(81_234.56, -47_890.12, 30),
+        stroke(81_300.5, -47_850.0, 5),
+    ];
+    let batch = CompactBatch::try_from(sent.as_slice()).unwrap();
+    assert_eq!(batch.origin, (sent[0].x, sent[0].y));
+    for (sent, received) in sent.iter().zip(Vec::<Entity>::from(batch)) {
+        assert_close(sent, &received);
+    }
+}
+
+#[test]
+fn what_does_not_fit_in_steps_is_refused() {
+    let mut wide = stroke(0.0, 0.0, 2);
+    wide.radius = 200.0;
+    assert!(CompactBatch::try_from([wide].as_slice()).is_err());
+    let far = vec![stroke(0.0, 0.0, 2), stroke(1e30, 0.0, 2)];
+    assert!(CompactBatch::try_from(far.as_slice()).is_err());
+    assert!(CompactBatch::try_from([].as_slice()).is_err());
+}
+
+#[test]
+fn a_compact_frame_is_read_as_the_message_it_stands_for() {
+    let one = stroke(5.5, 6.25, 3);
+    let many = vec![stroke(1.0, 2.0, 4), stroke(3.0, 4.0, 4)];
+    for format in [WireFormat::Json, WireFormat::Bincode] {
+        let mut handler = MessageHandler::new();
+        for entities in [std::slice::from_ref(&one), many.as_slice()] {
+            let batch = CompactBatch::try_from(entities).unwrap();
+            let frame = network::frame_message(&Message::CompactEntities(batch), format).unwrap();
+            handler.extend_buffer(&frame);
+        }
+        let Some(Ok(Message::NewEntity(received))) = handler.next_message() else {
+            panic!("{:?}: one entity is not a NewEntity", format);
+        };
+        assert_close(&one, &received);
+        let Some(Ok(Message::EntityBatch(received))) = handler.next_message() else {
+            panic!("{:?}: several entities are not an EntityBatch", format);
+        };
+        for (sent, received) in many.iter().zip(&received) {
+            assert_close(sent, received);
+        }
+    }
+}
+
+#[test]
+fn a_batch_at_no_scale_is_dropped() {
+    let mut batch = CompactBatch::try_from([stroke(1.0, 1.0, 2)].as_slice()).unwrap();
+    batch.scale = 0.0;
+    let frame = network::frame_message(&Message::CompactEntities(batch), WireFormat::Json).unwrap();
+    let mut handler = MessageHandler::new();
+    handler.extend_buffer(&frame);
+    assert!(matches!(
+        handler.next_message(),
+        Some(Err(FrameError::Undecodable { .. }))
+    ));
+}
+
+#[test]
+fn compact_entities_take_fewer_bytes() {
+    let entities: Vec<Entity> = (0..20)
+        .map(|i| stroke(1234.567 + i as f32 * 13.1, 89.012 + i as f32 * 7.7, 25))
+        .collect();
+    let batch = CompactBatch::try_from(entities.as_slice()).unwrap();
+    let plain = Message::EntityBatch(entities.clone());
+    let compact = Message::CompactEntities(batch);
+    let sizes = [
+        (
+            "JSON",
+            serde_json::to_vec(&plain).unwrap().len(),
+            serde_json::to_vec(&compact).unwrap().len(),
+        ),
+        (
+            "bincode",
+            bincode::serde::encode_to_vec(&plain, bincode::config::standard())
+                .unwrap()
+                .len(),
+            bincode::serde::encode_to_vec(&compact, bincode::config::standard())
+                .unwrap()
+                .len(),
+        ),
+    ];
+    for (format, plain, compact) in sizes {
+        let per_entity = (plain - compact) as f32 / entities.len() as f32;
+        println!(
+            "{}: {} bytes per 25-point stroke, {} compact, {:.0} saved ({:.0}%)",
+            format,
+            plain / entities.len(),
+            compact / entities.len(),
+            per_entity,
+            100.0 * (plain - compact) as f32 / plain as f32
+        );
+        // a quarter of it at the very least
+        assert!(
+            compact * 4 < plain * 3,
+            "{}: {} against {}",
+            format,
+            compact,
+            plain
+        );
+    }
+}
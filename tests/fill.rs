@@ -0,0 +1,105 @@
+//! The fill tool's region finding, on canvases built by hand.
+
+use tcp_drawing::entity::{Canvas, Entity, EntityColor, next_id};
+use tcp_drawing::fill::{self, FillError};
+use tcp_drawing::transform::Transform;
+
+/// A square outline of lines from `(x, y)`, `size` across.
+fn outline(canvas: &Canvas, (x, y): (f32, f32), size: f32) {
+    let corners = [(x, y), (x + size, y), (x + size, y + size), (x, y + size)];
+    for i in 0..4 {
+        let line = Entity::line(
+            next_id(),
+            corners[i],
+            corners[(i + 1) % 4],
+            2.0,
+            EntityColor::BLACK,
+        );
+        canvas.insert(line);
+    }
+}
+
+fn area(rects: &[(f32, f32, f32, f32)]) -> f32 {
+    rects.iter().map(|&(_, _, w, h)| w * h).sum()
+}
+
+#[test]
+fn fills_the_inside_of_an_outline_and_nothing_else() {
+    let canvas = Canvas::new();
+    outline(&canvas, (100.0, 100.0), 200.0);
+
+    let rects = fill::flood_fill(&canvas, (200.0, 200.0), 4.0).unwrap();
+    for &(x, y, w, h) in &rects {
+        assert!(x >= 100.0 && y >= 100.0, "{:?}", (x, y, w, h));
+        assert!(x + w <= 300.0 && y + h <= 300.0, "{:?}", (x, y, w, h));
+    }
+    // all but a cell or so along each wall
+    let filled = area(&rects);
+    assert!(
+        filled > 180.0 * 180.0 && filled < 200.0 * 200.0,
+        "{}",
+        filled
+    );
+    // an empty square comes out as a few stacked rectangles, not one per row
+    assert!(rects.len() < 10, "{} rectangles", rects.len());
+}
+
+#[test]
+fn a_gap_in_the_outline_lets_the_fill_out() {
+    let canvas = Canvas::new();
+    let corners = [(0.0, 0.0), (100.0, 0.0), (100.0, 100.0), (0.0, 100.0)];
+    // three sides only
+    for i in 0..3 {
+        let line = Entity::line(
+            next_id(),
+            corners[i],
+            corners[i + 1],
+            2.0,
+            EntityColor::BLACK,
+        );
+        canvas.insert(line);
+    }
+    let result = fill::flood_fill(&canvas, (50.0, 50.0), 4.0);
+    assert!(
+        matches!(result, Err(FillError::Unbounded { .. })),
+        "{:?}",
+        result
+    );
+}
+
+#[test]
+fn empty_canvas_and_drawn_over_points_are_not_filled() {
+    let canvas = Canvas::new();
+    let result = fill::flood_fill(&canvas, (0.0, 0.0), 4.0);
+    assert_eq!(result, Err(FillError::Unbounded { size: 2048.0 }));
+
+    canvas.insert(Entity::rect(
+        next_id(),
+        (-10.0, -10.0),
+        (10.0, 10.0),
+        EntityColor::BLACK,
+    ));
+    assert_eq!(
+        fill::flood_fill(&canvas, (0.0, 0.0), 4.0),
+        Err(FillError::Covered)
+    );
+}
+
+#[test]
+fn a_fill_blocks_later_fills_and_moves_with_the_canvas() {
+    let canvas = Canvas::new();
+    outline(&canvas, (0.0, 0.0), 100.0);
+    let rects = fill::flood_fill(&canvas, (50.0, 50.0), 4.0).unwrap();
+    let mut region = Entity::fill(next_id(), rects, EntityColor::rgb(0x00FF00));
+    canvas.insert(region.clone());
+    assert_eq!(
+        fill::flood_fill(&canvas, (50.0, 50.0), 4.0),
+        Err(FillError::Covered)
+    );
+
+    let before = region.bounds();
+    Transform::rotate_about(1, (0.0, 0.0)).apply_to(&mut region);
+    let after = region.bounds();
+    assert!((after.w - before.h).abs() < 0.01 && (after.h - before.w).abs() < 0.01);
+    assert!((region.x - after.x).abs() < 0.01 && (region.y - after.y).abs() < 0.01);
+}
@@ -0,0 +1,441 @@
+//! `MessageHandler` fed frames in awkward pieces, and garbage between them.
+
+use tcp_drawing::entity::{
+    Canvas, Create, DEFAULT_THINNEST, Entity, EntityColor, PenSamples, Shape, next_id,
+};
+use tcp_drawing::network::{self, FrameError, Message, MessageHandler, WireFormat};
+
+fn dot(x: f32) -> Entity {
+    Entity::rect(
+        next_id(),
+        (x, 0.0),
+        (x + 4.0, 4.0),
+        EntityColor::rgb(0xFF0000),
+    )
+}
+
+fn frame(message: &Message, format: WireFormat) -> Vec<u8> {
+    network::frame_message(message, format).unwrap()
+}
+
+/// A frame carrying `payload` as it is, whether or not it decodes.
+fn raw_frame(payload: &[u8]) -> Vec<u8> {
+    let mut bytes = (payload.len() as u32).to_le_bytes().to_vec();
+    bytes.extend_from_slice(payload);
+    bytes
+}
+
+/// Feeds `bytes` in pieces of the given sizes, cycling through them, and
+/// collects everything the handler hands back.
+fn feed(bytes: &[u8], pieces: &[usize]) -> Vec<Result<Message, FrameError>> {
+    feed_to(&mut MessageHandler::new(), bytes, pieces)
+}
+
+/// `feed`, into a handler kept for looking at afterwards.
+fn feed_to(
+    handler: &mut MessageHandler,
+    bytes: &[u8],
+    pieces: &[usize],
+) -> Vec<Result<Message, FrameError>> {
+    let mut received = Vec::new();
+    let mut rest = bytes;
+    for &size in pieces.iter().cycle() {
+        if rest.is_empty() {
+            break;
+        }
+        let (piece, tail) = rest.split_at(size.clamp(1, rest.len()));
+        rest = tail;
+        handler.extend_buffer(piece);
+        received.extend(std::iter::from_fn(|| handler.next_message()));
+    }
+    received
+}
+
+fn ids(received: &[Result<Message, FrameError>]) -> Vec<Option<usize>> {
+    received
+        .iter()
+        .map(|result| match result {
+            Ok(Message::NewEntity(entity)) => Some(entity.id),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Three small frames and a large one that goes out compressed.
+fn stream() -> (Vec<u8>, Vec<usize>) {
+    let entities: Vec<Entity> = (0..3).map(|i| dot(i as f32)).collect();
+    let mut bytes = Vec::new();
+    for (entity, format) in
+        entities
+            .iter()
+            .zip([WireFormat::Json, WireFormat::Bincode, WireFormat::Json])
+    {
+        bytes.extend(frame(&Message::NewEntity(entity.clone()), format));
+    }
+    let many: Vec<Entity> = (0..500).map(|i| dot(i as f32)).collect();
+    bytes.extend(frame(&Message::AllEntities(many), WireFormat::Bincode));
+    (bytes, entities.iter().map(|entity| entity.id).collect())
+}
+
+fn assert_stream(received: &[Result<Message, FrameError>], expected_ids: &[usize]) {
+    assert_eq!(received.len(), 4, "{:?}", received);
+    let got = ids(&received[..3]);
+    let expected: Vec<Option<usize>> = expected_ids.iter().copied().map(Some).collect();
+    assert_eq!(got, expected);
+    assert!(matches!(&received[3], Ok(Message::AllEntities(all)) if all.len() == 500));
+}
+
+#[test]
+fn one_byte_at_a_time() {
+    let (bytes, expected_ids) = stream();
+    let mut handler = MessageHandler::new();
+    assert_stream(&feed_to(&mut handler, &bytes, &[1]), &expected_ids);
+    // replies go out in the format the peer spoke last
+    assert_eq!(handler.reply_format(WireFormat::Json), WireFormat::Bincode);
+}
+
+#[test]
+fn a_batch_of_1000_entities_comes_through_one_byte_at_a_time() {
+    let batch: Vec<Entity> = (0..1000).map(|i| dot(i as f32)).collect();
+    let sent: Vec<usize> = batch.iter().map(|entity| entity.id).collect();
+    for format in [WireFormat::Json, WireFormat::Bincode] {
+        let bytes = frame(&Message::EntityBatch(batch.clone()), format);
+        let received = feed(&bytes, &[1]);
+        let [Ok(Message::EntityBatch(unpacked))] = received.as_slice() else {
+            panic!("{:?}: {:?}", format, received);
+        };
+        assert_eq!(unpacked.len(), 1000, "{:?}", format);
+        let got: Vec<usize> = unpacked.iter().map(|entity| entity.id).collect();
+        assert_eq!(got, sent, "{:?}", format);
+    }
+}
+
+#[test]
+fn length_prefix_split_across_reads() {
+    let (bytes, expected_ids) = stream();
+    for split in [1, 2, 3, 5, 7] {
+        assert_stream(&feed(&bytes, &[split, 4096]), &expected_ids);
+    }
+}
+
+#[test]
+fn several_messages_in_one_read() {
+    let (bytes, expected_ids) = stream();
+    assert_stream(&feed(&bytes, &[bytes.len()]), &expected_ids);
+}
+
+#[test]
+fn corrupt_frame_is_dropped_alone() {
+    let (first, second) = (dot(1.0), dot(2.0));
+    let mut bytes = frame(&Message::NewEntity(first.clone()), WireFormat::Json);
+    bytes.extend(raw_frame(b"{\"NewEntity\": {\"id\": oops"));
+    bytes.extend(frame(&Message::NewEntity(second.clone()), WireFormat::Json));
+
+    for pieces in [&[1][..], &[3, 11], &[bytes.len()]] {
+        let received = feed(&bytes, pieces);
+        assert_eq!(ids(&received), [Some(first.id), None, Some(second.id)]);
+        assert!(matches!(
+            &received[1],
+            Err(FrameError::Undecodable {
+                format: WireFormat::Json,
+                ..
+            })
+        ));
+    }
+}
+
+#[test]
+fn bytes_after_a_bincode_message_spoil_the_frame() {
+    let (first, second) = (dot(1.0), dot(2.0));
+    let mut payload = frame(&Message::NewEntity(first), WireFormat::Bincode)[4..].to_vec();
+    payload.extend([0, 0, 0]);
+    let mut bytes = raw_frame(&payload);
+    bytes.extend(frame(
+        &Message::NewEntity(second.clone()),
+        WireFormat::Bincode,
+    ));
+
+    let received = feed(&bytes, &[bytes.len()]);
+    assert!(matches!(
+        &received[0],
+        Err(FrameError::Undecodable {
+            format: WireFormat::Bincode,
+            ..
+        })
+    ));
+    assert_eq!(ids(&received[1..]), [Some(second.id)]);
+}
+
+#[test]
+fn corrupt_compressed_frame_is_dropped_alone() {
+    let second = dot(2.0);
+    let mut bytes = raw_frame(&[0xFF, 1, 2, 3, 4]);
+    bytes.extend(frame(&Message::NewEntity(second.clone()), WireFormat::Json));
+
+    let received = feed(&bytes, &[bytes.len()]);
+    assert!(matches!(&received[0], Err(FrameError::BadCompression(_))));
+    assert_eq!(ids(&received[1..]), [Some(second.id)]);
+}
+
+#[test]
+fn oversized_length_resynchronizes_on_the_next_frame() {
+    let (first, second) = (dot(1.0), dot(2.0));
+    let mut bytes = vec![0xFF, 0xFF, 0xFF, 0x7F, 0x01, 0x02];
+    bytes.extend(frame(&Message::NewEntity(first.clone()), WireFormat::Json));
+    bytes.extend(frame(
+        &Message::NewEntity(second.clone()),
+        WireFormat::Bincode,
+    ));
+
+    for pieces in [&[1][..], &[5, 2], &[bytes.len()]] {
+        let received = feed(&bytes, pieces);
+        assert_eq!(ids(&received), [None, Some(first.id), Some(second.id)]);
+        assert!(matches!(
+            received[0],
+            Err(FrameError::TooLarge(0x7FFF_FFFF))
+        ));
+    }
+}
+
+#[test]
+fn garbage_read_piece_by_piece_is_looked_at_once() {
+    // a prefix promising a large frame holds the buffer until all of it has
+    // come, a piece per read, and only then turns out to be garbage
+    let claimed = 2 * 1024 * 1024;
+    let mut bytes = vec![0xEE; 5];
+    bytes.extend((claimed as u32).to_le_bytes());
+    bytes.resize(bytes.len() + claimed, 0xEE);
+    let after = dot(1.0);
+    bytes.extend(frame(
+        &Message::NewEntity(after.clone()),
+        WireFormat::Bincode,
+    ));
+
+    let started = std::time::Instant::now();
+    let received = feed(&bytes, &[1024]);
+    assert!(matches!(received[0], Err(FrameError::TooLarge(_))));
+    assert_eq!(ids(&received[1..]), [Some(after.id)]);
+    // scanning it all again on every read takes minutes
+    assert!(started.elapsed().as_secs() < 5, "{:?}", started.elapsed());
+}
+
+#[test]
+fn handshake_comes_off_before_any_frame() {
+    let entity = dot(1.0);
+    let mut bytes = network::handshake().to_vec();
+    bytes.extend(frame(&Message::NewEntity(entity.clone()), WireFormat::Json));
+
+    let mut handler = MessageHandler::expecting_handshake();
+    handler.extend_buffer(&bytes[..5]);
+    assert!(handler.take_handshake().is_none());
+    assert!(handler.next_message().is_none());
+    handler.extend_buffer(&bytes[5..]);
+    assert_eq!(handler.take_handshake(), Some(Ok(())));
+    let received: Vec<_> = std::iter::from_fn(|| handler.next_message()).collect();
+    assert_eq!(ids(&received), [Some(entity.id)]);
+}
+
+#[test]
+fn wrong_handshake_is_refused() {
+    let mut handler = MessageHandler::expecting_handshake();
+    handler.extend_buffer(b"TCPDRW\x63\x00");
+    assert!(handler.take_handshake().unwrap().is_err());
+}
+
+/// Small xorshift generator, so the fuzz run below is the same every time.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+/// Valid frames with garbage spliced in, fed in random pieces. Returns the
+/// ids received, every id sent, and the ids sent after the last garbage.
+fn fuzz_run(
+    rng: &mut Rng,
+    garbage_byte: fn(&mut Rng) -> u8,
+) -> (Vec<usize>, Vec<usize>, Vec<usize>) {
+    let mut bytes = Vec::new();
+    let (mut sent, mut tail) = (Vec::new(), Vec::new());
+    for round in 0..1 + rng.below(5) {
+        if round > 0 {
+            bytes.extend((0..4 + rng.below(40)).map(|_| garbage_byte(rng)));
+            tail.clear();
+        }
+        for _ in 0..1 + rng.below(3) {
+            let mut entity = dot(rng.below(1000) as f32);
+            // the same bytes every run, whatever this process's id tag, clock
+            // and count of entities made so far
+            entity.id = 0x5EED_0000_0000 + sent.len();
+            entity.created_at = 1_700_000_000_000;
+            entity.seq = 1 + sent.len() as u64;
+            let format = if rng.below(2) == 0 {
+                WireFormat::Json
+            } else {
+                WireFormat::Bincode
+            };
+            bytes.extend(frame(&Message::NewEntity(entity.clone()), format));
+            sent.push(entity.id);
+            tail.push(entity.id);
+        }
+    }
+
+    let mut handler = MessageHandler::new();
+    let mut received = Vec::new();
+    let mut rest = &bytes[..];
+    while !rest.is_empty() {
+        let (piece, next) = rest.split_at((1 + rng.below(64)).min(rest.len()));
+        rest = next;
+        handler.extend_buffer(piece);
+        received.extend(std::iter::from_fn(|| handler.next_message()));
+    }
+    let got = ids(&received).into_iter().flatten().collect();
+    (got, sent, tail)
+}
+
+/// Any garbage at all: the handler must not panic or make up entities, though
+/// garbage that happens to read as a plausible length can hold up what follows.
+#[test]
+fn fuzz_any_garbage() {
+    let mut rng = Rng(0x9E37_79B9_7F4A_7C15);
+    for _ in 0..500 {
+        let (got, sent, _) = fuzz_run(&mut rng, |rng| rng.next() as u8);
+        assert!(
+            got.iter().all(|id| sent.contains(id)),
+            "{:?} not in {:?}",
+            got,
+            sent
+        );
+    }
+}
+
+/// Garbage that cannot be read as a length: every frame after the last of it
+/// must come through.
+#[test]
+fn fuzz_recovers_after_unmistakable_garbage() {
+    let mut rng = Rng(0xD1B5_4A32_D192_ED03);
+    for _ in 0..500 {
+        let (got, _, tail) = fuzz_run(&mut rng, |rng| 0x90 | rng.next() as u8);
+        assert!(
+            got.ends_with(&tail),
+            "got {:?}, wanted it to end in {:?}",
+            got,
+            tail
+        );
+    }
+}
+
+#[test]
+fn a_label_comes_through_in_either_format() {
+    let label = Entity::text(
+        next_id(),
+        (10.0, 20.0),
+        "hello".to_string(),
+        24.0,
+        EntityColor::BLACK,
+    );
+    for format in [WireFormat::Json, WireFormat::Bincode] {
+        let received = feed(&frame(&Message::NewEntity(label.clone()), format), &[7]);
+        let [Ok(Message::NewEntity(entity))] = received.as_slice() else {
+            panic!("{:?}: {:?}", format, received);
+        };
+        let Shape::Text { text, size } = &entity.shape else {
+            panic!("{:?}: not a label: {:?}", format, entity.shape);
+        };
+        assert_eq!((text.as_str(), *size), ("hello", 24.0));
+        // five characters, about half as wide as they are high
+        let bounds = entity.bounds();
+        assert_eq!((bounds.x, bounds.y, bounds.h), (10.0, 20.0, 24.0));
+        assert!(bounds.w > 40.0 && bounds.w < 80.0, "{}", bounds.w);
+    }
+}
+
+#[test]
+fn a_stroke_keeps_its_pressure_widths_across_the_wire() {
+    let canvas = Canvas::new();
+    let id = Entity::spawn(0.0, 0.0, 4.0, EntityColor::BLACK, &canvas).unwrap();
+    let stroke = canvas
+        .update(id, |entity| {
+            entity.set_pen(PenSamples::single(Some(0.0)));
+            entity.set_pressure_range(12.0, 0.5);
+            entity.clone()
+        })
+        .unwrap();
+    for format in [WireFormat::Json, WireFormat::Bincode] {
+        let received = feed(&frame(&Message::NewEntity(stroke.clone()), format), &[5]);
+        let [Ok(Message::NewEntity(entity))] = received.as_slice() else {
+            panic!("{:?}: {:?}", format, received);
+        };
+        assert_eq!(entity.radius_at(0), 6.0, "{:?}", format);
+    }
+
+    // strokes saved before the range was carried thin out as they always did
+    let mut old = serde_json::to_value(&stroke).unwrap();
+    old["shape"]["Stroke"]
+        .as_object_mut()
+        .unwrap()
+        .remove("thinnest");
+    let old: Entity = serde_json::from_value(old).unwrap();
+    assert!((old.radius_at(0) - 12.0 * DEFAULT_THINNEST).abs() < 1e-6);
+}
+
+#[test]
+fn a_layer_crosses_the_wire_and_old_entities_are_on_the_first() {
+    let on_top = Entity {
+        layer: 2,
+        ..dot(5.0)
+    };
+    for format in [WireFormat::Json, WireFormat::Bincode] {
+        let all = Message::AllEntities(vec![on_top.clone()]);
+        let received = feed(&frame(&all, format), &[7]);
+        let [Ok(Message::AllEntities(all))] = received.as_slice() else {
+            panic!("{:?}: {:?}", format, received);
+        };
+        assert_eq!(all[0].layer, 2, "{:?}", format);
+    }
+
+    // from a peer that has never heard of layers
+    let mut old = serde_json::to_value(&on_top).unwrap();
+    old.as_object_mut().unwrap().remove("layer");
+    let old: Entity = serde_json::from_value(old).unwrap();
+    assert_eq!(old.layer, 0);
+}
+
+#[test]
+fn a_large_snapshot_is_smaller_in_bincode_either_way() {
+    let many: Vec<Entity> = (0..10_000).map(|i| dot(i as f32)).collect();
+    let all = Message::AllEntities(many);
+    let json = serde_json::to_vec(&all).unwrap();
+    let bincode = bincode::serde::encode_to_vec(&all, bincode::config::standard()).unwrap();
+    assert!(
+        bincode.len() * 2 < json.len(),
+        "bincode {} bytes, json {} bytes",
+        bincode.len(),
+        json.len()
+    );
+
+    // and still once frames this large go out compressed
+    let (json, bincode) = (
+        frame(&all, WireFormat::Json),
+        frame(&all, WireFormat::Bincode),
+    );
+    assert!(
+        bincode.len() < json.len(),
+        "bincode {} bytes, json {} bytes",
+        bincode.len(),
+        json.len()
+    );
+    for bytes in [json, bincode] {
+        let received = feed(&bytes, &[bytes.len()]);
+        assert!(matches!(&received[..], [Ok(Message::AllEntities(all))] if all.len() == 10_000));
+    }
+}
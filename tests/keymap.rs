@@ -0,0 +1,123 @@
+//! Keymap files: what they rebind, what they leave at the defaults, and what
+//! they get wrong.
+
+use macroquad::input::{KeyCode, MouseButton};
+use tcp_drawing::keymap::{Action, Binding, Input, Keymap};
+
+fn key(code: KeyCode) -> Binding {
+    Binding {
+        input: Input::Key(code),
+        ctrl: false,
+        shift: false,
+        alt: false,
+    }
+}
+
+#[test]
+fn bindings_read_with_their_modifiers_in_any_case() {
+    assert_eq!("g".parse(), Ok(key(KeyCode::G)));
+    assert_eq!(
+        "Ctrl+Shift+1".parse(),
+        Ok(Binding {
+            ctrl: true,
+            shift: true,
+            ..key(KeyCode::Key1)
+        })
+    );
+    assert_eq!(
+        "alt+mouse_right".parse(),
+        Ok(Binding {
+            input: Input::Mouse(MouseButton::Right),
+            alt: true,
+            ..key(KeyCode::A)
+        })
+    );
+    for wrong in ["", "ctrl+", "gg", "meta+g", "ctrl+shift"] {
+        assert!(wrong.parse::<Binding>().is_err(), "{wrong:?}");
+    }
+}
+
+#[test]
+fn a_keymap_rebinds_only_what_it_names() {
+    let (keymap, problems) =
+        Keymap::from_json(r#"{"undo": "ctrl+u", "toggle_grid": ["h", "f5"], "mirror": []}"#)
+            .unwrap();
+    assert!(problems.is_empty(), "{problems:?}");
+
+    assert_eq!(
+        keymap.bindings(Action::Undo),
+        &[Binding {
+            ctrl: true,
+            ..key(KeyCode::U)
+        }]
+    );
+    assert_eq!(
+        keymap.bindings(Action::ToggleGrid),
+        &[key(KeyCode::H), key(KeyCode::F5)]
+    );
+    assert!(keymap.bindings(Action::Mirror).is_empty());
+
+    let defaults = Keymap::default();
+    for action in [Action::Redo, Action::ClearCanvas, Action::Color(3)] {
+        assert_eq!(keymap.bindings(action), defaults.bindings(action));
+    }
+}
+
+#[test]
+fn every_bad_entry_is_reported_and_keeps_its_default() {
+    let (keymap, problems) = Keymap::from_json(
+        r#"{
+            "undo": "ctrl+zz",
+            "redo": ["ctrl+y", 5],
+            "eraser": "x",
+            "save": true,
+            "copy": "ctrl+k"
+        }"#,
+    )
+    .unwrap();
+
+    let mut named: Vec<&str> = problems
+        .iter()
+        .map(|problem| problem.split(':').next().unwrap())
+        .collect();
+    named.sort_unstable();
+    assert_eq!(named, ["eraser", "redo", "save", "undo"], "{problems:?}");
+    assert!(
+        problems.iter().any(|p| p.contains("\"zz\"")),
+        "{problems:?}"
+    );
+
+    let defaults = Keymap::default();
+    for action in [Action::Undo, Action::Redo, Action::Save] {
+        assert_eq!(keymap.bindings(action), defaults.bindings(action));
+    }
+    assert_ne!(
+        keymap.bindings(Action::Copy),
+        defaults.bindings(Action::Copy)
+    );
+}
+
+#[test]
+fn a_file_that_is_no_keymap_is_an_error() {
+    for json in ["", "[\"undo\"]", "{\"undo\": "] {
+        assert!(Keymap::from_json(json).is_err(), "{json:?}");
+    }
+}
+
+#[test]
+fn the_dumped_keymap_reads_back_as_it_was() {
+    let (edited, _) = Keymap::from_json(r#"{"paste": ["ctrl+shift+v", "insert"]}"#).unwrap();
+    let dumped = edited.to_json();
+    let (read, problems) = Keymap::from_json(&dumped).unwrap();
+    assert!(problems.is_empty(), "{problems:?}");
+    assert!(dumped.contains("\"clear_canvas\": \"c\""), "{dumped}");
+
+    for action in [
+        Action::Paste,
+        Action::OpacityUp,
+        Action::ToggleLayer(2),
+        Action::Eyedropper,
+    ] {
+        assert_eq!(read.bindings(action), edited.bindings(action));
+    }
+}
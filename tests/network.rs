@@ -0,0 +1,1721 @@
+//! End-to-end tests over real sockets: a server on an ephemeral port, with raw
+//! streams or full clients connected to it.
+
+mod common;
+
+use common::{Client, Server, TIMEOUT, dot, wait_until};
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+use tcp_drawing::config::Role;
+use tcp_drawing::entity::{Entity, EntityColor, HOST_OWNER, Shape, instance_tag, next_id};
+use tcp_drawing::network::compact::CompactBatch;
+use tcp_drawing::network::{
+    self, ClientStatus, JoinDecision, Message, MessageHandler, NetTotals, RateMeter, WireFormat,
+};
+use tcp_drawing::storage::SavedCanvas;
+use tcp_drawing::transform::Transform;
+
+/// A bare connection speaking the protocol by hand, for looking at exactly what the server sends.
+struct RawPeer {
+    stream: TcpStream,
+    handler: MessageHandler,
+    /// What it sends in, which is what the server answers in.
+    format: WireFormat,
+}
+
+impl RawPeer {
+    fn connect(addr: &str, name: &str) -> Self {
+        Self::connect_as(addr, name, 0)
+    }
+
+    /// Connects with a Hello `secret`, which gets the same owner back on every connection.
+    fn connect_as(addr: &str, name: &str, secret: u64) -> Self {
+        Self::connect_in(addr, name, secret, WireFormat::Json)
+    }
+
+    fn connect_in(addr: &str, name: &str, secret: u64, format: WireFormat) -> Self {
+        let mut peer = Self::connect_unnamed(addr, format);
+        peer.send(&Message::Hello {
+            name: name.to_string(),
+            spectator: false,
+            room: String::new(),
+            secret,
+            compact: false,
+        });
+        peer
+    }
+
+    /// Connects without a Hello, which is left to the caller.
+    fn connect_unnamed(addr: &str, format: WireFormat) -> Self {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(&network::handshake()).unwrap();
+        Self {
+            stream,
+            handler: MessageHandler::expecting_handshake(),
+            format,
+        }
+    }
+
+    /// Plays the server for the next client to connect to `listener`.
+    fn accept(listener: &TcpListener) -> Self {
+        let (mut stream, _) = listener.accept().unwrap();
+        stream.write_all(&network::handshake()).unwrap();
+        Self {
+            stream,
+            handler: MessageHandler::expecting_handshake(),
+            format: WireFormat::Json,
+        }
+    }
+
+    /// Everything that arrives within `wait`.
+    fn collect_for(&mut self, wait: Duration) -> Vec<Message> {
+        let deadline = Instant::now() + wait;
+        let mut received = Vec::new();
+        let mut chunk = [0u8; 4096];
+        loop {
+            let _ = self.handler.take_handshake();
+            while let Some(message) = self.handler.next_message() {
+                received.push(message.unwrap());
+            }
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return received;
+            }
+            self.stream.set_read_timeout(Some(left)).unwrap();
+            match self.stream.read(&mut chunk) {
+                Ok(0) => return received,
+                Ok(n) => self.handler.extend_buffer(&chunk[..n]),
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+                Err(e) => panic!("error reading from the client: {}", e),
+            }
+        }
+    }
+
+    /// The owner the server's Welcome hands out.
+    fn owner(&mut self) -> u64 {
+        self.expect(|message| match message {
+            Message::Welcome { owner, .. } => Some(owner),
+            _ => None,
+        })
+    }
+
+    fn send(&mut self, message: &Message) {
+        network::send_message(&mut self.stream, message, self.format).unwrap();
+    }
+
+    /// Reads until a message `wanted` accepts arrives, skipping the rest.
+    fn expect<T>(&mut self, mut wanted: impl FnMut(Message) -> Option<T>) -> T {
+        let deadline = Instant::now() + TIMEOUT;
+        let mut chunk = [0u8; 4096];
+        loop {
+            match self.handler.take_handshake() {
+                Some(Ok(())) => {}
+                Some(Err(e)) => panic!("bad handshake from the server: {}", e),
+                None => {}
+            }
+            while let Some(message) = self.handler.next_message() {
+                if let Some(found) = wanted(message.unwrap()) {
+                    return found;
+                }
+            }
+            let left = deadline.saturating_duration_since(Instant::now());
+            assert!(!left.is_zero(), "timed out waiting for a message");
+            self.stream.set_read_timeout(Some(left)).unwrap();
+            match self.stream.read(&mut chunk) {
+                Ok(0) => panic!("server closed the connection"),
+                Ok(n) => self.handler.extend_buffer(&chunk[..n]),
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+                Err(e) => panic!("error reading from the server: {}", e),
+            }
+        }
+    }
+}
+
+#[test]
+fn raw_client_gets_the_canvas_and_its_drawing_lands_on_the_server() {
+    let server = Server::start();
+    let existing = dot(10.0, 10.0);
+    server.canvas.insert(existing.clone());
+
+    let mut peer = RawPeer::connect(&server.addr, "raw");
+    peer.send(&Message::RequestAllEntities);
+    let all = peer.expect(|message| match message {
+        Message::AllEntities(all) => Some(all),
+        _ => None,
+    });
+    assert!(all.iter().any(|entity| entity.id == existing.id));
+
+    let drawn = dot(50.0, 60.0);
+    peer.send(&Message::NewEntity(drawn.clone()));
+    wait_until("the server has the new entity", || {
+        server.canvas.contains(drawn.id)
+    });
+    let stored = server.canvas.get(drawn.id).unwrap().value().clone();
+    assert_eq!((stored.x, stored.y), (50.0, 60.0));
+    assert_eq!(server.canvas.len(), 2);
+
+    server.stop();
+}
+
+#[test]
+fn drawing_is_relayed_between_clients_and_the_host() {
+    let server = Server::start();
+    let alice = Client::start(&server.addr, "alice");
+    let bob = Client::start(&server.addr, "bob");
+    wait_until("both clients are admitted", || {
+        server
+            .handle
+            .clients
+            .lock()
+            .is_ok_and(|clients| clients.iter().filter(|c| !c.pending).count() == 2)
+    });
+
+    let from_alice = dot(1.0, 1.0);
+    alice.canvas.insert(from_alice.clone());
+    alice
+        .tx
+        .send(Message::NewEntity(from_alice.clone()))
+        .unwrap();
+    wait_until("the server and bob have alice's entity", || {
+        server.canvas.contains(from_alice.id) && bob.canvas.contains(from_alice.id)
+    });
+
+    let from_host = dot(200.0, 100.0);
+    server.canvas.insert(from_host.clone());
+    server
+        .tx
+        .send(Message::NewEntity(from_host.clone()))
+        .unwrap();
+    wait_until("both clients have the host's entity", || {
+        alice.canvas.contains(from_host.id) && bob.canvas.contains(from_host.id)
+    });
+
+    for canvas in [&server.canvas, &alice.canvas, &bob.canvas] {
+        assert_eq!(canvas.len(), 2);
+    }
+
+    alice.stop();
+    bob.stop();
+    server.stop();
+}
+
+#[test]
+fn one_server_takes_clients_over_ipv4_and_ipv6() {
+    let listeners = match network::bind_all(&["127.0.0.1:0".into(), "[::1]:0".into()]) {
+        Ok(listeners) => listeners,
+        Err(e) => {
+            eprintln!("skipping, no IPv6 loopback here: {}", e);
+            return;
+        }
+    };
+    let addrs: Vec<String> = listeners
+        .iter()
+        .map(|listener| listener.local_addr().unwrap().to_string())
+        .collect();
+    assert!(addrs[1].starts_with("[::1]:"), "{:?}", addrs);
+    assert_eq!(
+        common::config(&["--listen", &addrs[0], "--listen", &addrs[1]]).role,
+        Role::Listen(addrs.clone())
+    );
+
+    let server = Server::start_on(listeners);
+    let over_v4 = Client::start(&addrs[0], "v4");
+    let over_v6 = Client::start(&addrs[1], "v6");
+    wait_until("a client on each listener is admitted", || {
+        server
+            .handle
+            .clients
+            .lock()
+            .is_ok_and(|clients| clients.iter().filter(|c| !c.pending).count() == 2)
+    });
+
+    let from_v6 = dot(5.0, 5.0);
+    over_v6.canvas.insert(from_v6.clone());
+    over_v6
+        .tx
+        .send(Message::NewEntity(from_v6.clone()))
+        .unwrap();
+    wait_until("the IPv4 client sees what the IPv6 one drew", || {
+        over_v4.canvas.contains(from_v6.id)
+    });
+
+    over_v4.stop();
+    over_v6.stop();
+    server.stop();
+}
+
+#[test]
+fn entities_from_the_server_are_not_sent_back() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let client = Client::start(&addr, "quiet");
+    let mut server = RawPeer::accept(&listener);
+    server.expect(|message| matches!(message, Message::Hello { .. }).then_some(()));
+    server.send(&Message::Welcome {
+        color: EntityColor::BLACK,
+        owner: 1,
+        change_any: false,
+        fade_ms: None,
+        compact: false,
+        session: 1,
+    });
+
+    let from_server = dot(1.0, 1.0);
+    let snapshot = dot(2.0, 2.0);
+    server.send(&Message::NewEntity(from_server.clone()));
+    server.send(&Message::AllEntities(vec![snapshot.clone()]));
+    wait_until("the client has both", || {
+        client.canvas.contains(from_server.id) && client.canvas.contains(snapshot.id)
+    });
+
+    let echoed: Vec<Message> = server
+        .collect_for(Duration::from_millis(500))
+        .into_iter()
+        .filter(|message| {
+            matches!(
+                message,
+                Message::NewEntity(_) | Message::AllEntities(_) | Message::StrokeAppend { .. }
+            )
+        })
+        .collect();
+    assert!(echoed.is_empty(), "the client sent back {:?}", echoed);
+
+    client.stop();
+}
+
+#[test]
+fn late_client_catches_up_on_the_canvas() {
+    let server = Server::start();
+    let drawn: Vec<Entity> = (0..20).map(|i| dot(i as f32 * 10.0, 0.0)).collect();
+    for entity in &drawn {
+        server.canvas.insert(entity.clone());
+    }
+
+    let late = Client::start(&server.addr, "late");
+    wait_until("the late client has the whole canvas", || {
+        drawn.iter().all(|entity| late.canvas.contains(entity.id))
+    });
+
+    late.stop();
+    server.stop();
+}
+
+#[test]
+fn the_host_sets_the_background_for_clients_and_late_joiners() {
+    let server = Server::start();
+    server.canvas.insert(dot(1.0, 1.0));
+    let client = Client::start(&server.addr, "early");
+    server
+        .tx
+        .send(Message::SetBackground(EntityColor::rgb(0x2E3440)))
+        .unwrap();
+    wait_until("the client has the new background", || {
+        client.handle.background.load(Ordering::Relaxed) == 0x2E3440
+    });
+
+    // a client may not change it; the answer to the request, after the canvas
+    // that comes with joining, shows it was read
+    let mut peer = RawPeer::connect(&server.addr, "late");
+    peer.send(&Message::SetBackground(EntityColor::BLACK));
+    peer.send(&Message::RequestAllEntities);
+    let mut seen = Vec::new();
+    peer.expect(|message| {
+        seen.push(message);
+        let canvases = seen
+            .iter()
+            .filter(|message| matches!(message, Message::AllEntities(_)))
+            .count();
+        (canvases == 2).then_some(())
+    });
+    assert_eq!(server.handle.background.load(Ordering::Relaxed), 0x2E3440);
+    let background = seen
+        .iter()
+        .position(|message| matches!(message, Message::SetBackground(color) if *color == EntityColor::rgb(0x2E3440)));
+    let canvas = seen
+        .iter()
+        .position(|message| matches!(message, Message::AllEntities(_)));
+    assert!(background < canvas, "got {:?}", seen);
+    assert!(background.is_some(), "got {:?}", seen);
+
+    client.stop();
+    server.stop();
+}
+
+#[test]
+fn past_max_entities_the_oldest_are_erased_for_everyone() {
+    let server = Server::start_with(&["--max-entities", "50"]);
+    let client = Client::start(&server.addr, "watching");
+    let mut peer = RawPeer::connect(&server.addr, "drawing");
+    wait_until("both are admitted", || {
+        server
+            .handle
+            .clients
+            .lock()
+            .is_ok_and(|clients| clients.iter().filter(|c| c.name.is_some()).count() == 2)
+    });
+
+    let drawn: Vec<Entity> = (0..55).map(|i| dot(i as f32 * 10.0, 0.0)).collect();
+    for entity in &drawn {
+        peer.send(&Message::NewEntity(entity.clone()));
+    }
+    let (evicted, kept) = drawn.split_at(5);
+    for (name, canvas) in [("server", &server.canvas), ("client", &client.canvas)] {
+        wait_until(&format!("the {} has the newest 50", name), || {
+            kept.iter().all(|entity| canvas.contains(entity.id))
+        });
+        wait_until(&format!("the {} lost the oldest 5", name), || {
+            evicted.iter().all(|entity| !canvas.contains(entity.id))
+        });
+        assert_eq!(canvas.len(), 50);
+    }
+
+    client.stop();
+    server.stop();
+}
+
+#[test]
+fn clients_drawing_at_once_all_get_through_past_one_that_never_reads() {
+    const CLIENTS: usize = 6;
+    const EACH: usize = 200;
+    let server = Server::start();
+    // a big canvas the stalled peer asks for and then leaves unread
+    for i in 0..5000 {
+        server
+            .canvas
+            .insert(dot((i % 200) as f32 * 5.0, (i / 200) as f32 * 5.0));
+    }
+    let mut stalled = RawPeer::connect(&server.addr, "stalled");
+    stalled.send(&Message::RequestAllEntities);
+
+    let clients: Vec<Client> = (0..CLIENTS)
+        .map(|i| Client::start(&server.addr, &format!("busy{}", i)))
+        .collect();
+    wait_until("every client is admitted", || {
+        server
+            .handle
+            .clients
+            .lock()
+            .is_ok_and(|list| list.iter().filter(|c| !c.pending).count() == CLIENTS + 1)
+    });
+
+    let drawn: Vec<Vec<Entity>> = std::thread::scope(|scope| {
+        let senders: Vec<_> = clients
+            .iter()
+            .enumerate()
+            .map(|(i, client)| {
+                scope.spawn(move || {
+                    let mine: Vec<Entity> = (0..EACH)
+                        .map(|j| dot(j as f32 * 3.0, 2000.0 + i as f32 * 10.0))
+                        .collect();
+                    for entity in &mine {
+                        client.canvas.insert(entity.clone());
+                        client.tx.send(Message::NewEntity(entity.clone())).unwrap();
+                    }
+                    mine
+                })
+            })
+            .collect();
+        senders
+            .into_iter()
+            .map(|sender| sender.join().unwrap())
+            .collect()
+    });
+
+    wait_until("every client has everyone's drawing", || {
+        drawn.iter().flatten().all(|entity| {
+            server.canvas.contains(entity.id)
+                && clients
+                    .iter()
+                    .all(|client| client.canvas.contains(entity.id))
+        })
+    });
+
+    drop(stalled);
+    for client in clients {
+        client.stop();
+    }
+    server.stop();
+}
+
+/// A label of noise that fills most of a frame too short to be deflated, to
+/// fill queues quickly. The canvas these add up to deflates to about half,
+/// and their size keeps them to a few cells of the canvas's grid.
+fn noise(x: f32, y: f32, seed: u32) -> Entity {
+    let mut state = seed.wrapping_mul(2_654_435_761) | 1;
+    let text = (0..800)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            char::from(b'a' + (state % 16) as u8)
+        })
+        .collect();
+    Entity {
+        shape: Shape::Text { text, size: 0.01 },
+        ..dot(x, y)
+    }
+}
+
+#[test]
+fn a_client_that_falls_behind_skips_drawing_and_catches_up_from_the_canvas() {
+    // bincode, which is quick to read and write even in a debug build
+    let server = Server::start_with(&["--wire", "bincode"]);
+    let mut slow = RawPeer::connect_in(&server.addr, "slow", 0, WireFormat::Bincode);
+    slow.owner();
+    let mut drawer = RawPeer::connect_in(&server.addr, "drawer", 0, WireFormat::Bincode);
+    drawer.owner();
+    let fast = Client::start_with(&server.addr, "fast", &["--wire", "bincode"]);
+    wait_until("every client is admitted", || {
+        server.handle.clients.lock().is_ok_and(|list| {
+            list.iter()
+                .filter(|c| c.name.is_some() && !c.pending)
+                .count()
+                == 3
+        })
+    });
+
+    // drawing until the slow peer, which pings but reads nothing, is held back from
+    let falling_behind = || {
+        let notices = server.handle.notices.lock().unwrap();
+        notices
+            .iter()
+            .any(|notice| notice.text.contains("slow") && notice.text.contains("falling behind"))
+    };
+    let mut drawn = Vec::new();
+    while !falling_behind() {
+        assert!(drawn.len() < 20_000, "the slow peer was never held back");
+        for i in 0..20 {
+            let entity = noise(i as f32 * 10.0, drawn.len() as f32, drawn.len() as u32);
+            drawer.send(&Message::NewEntity(entity.clone()));
+            drawn.push(entity);
+        }
+        slow.send(&Message::Ping(0));
+    }
+    wait_until("the fast client has all of the drawing", || {
+        drawn.iter().all(|entity| fast.canvas.contains(entity.id))
+    });
+
+    // what it reads once it gets going ends with its room's canvas
+    let mut seen = std::collections::HashSet::new();
+    let mut relayed = 0;
+    slow.expect(|message| match message {
+        Message::NewEntity(entity) => {
+            seen.insert(entity.id);
+            relayed += 1;
+            None
+        }
+        Message::EntityBatch(batch) => {
+            seen.extend(batch.iter().map(|entity| entity.id));
+            None
+        }
+        Message::AllEntities(all) => {
+            seen.extend(all.iter().map(|entity| entity.id));
+            Some(())
+        }
+        _ => None,
+    });
+    assert!(relayed < drawn.len(), "nothing was held back");
+    assert!(drawn.iter().all(|entity| seen.contains(&entity.id)));
+
+    fast.stop();
+    server.stop();
+}
+
+#[test]
+fn a_canvas_past_the_budget_is_sent_again_only_for_drawing_that_was_held_back() {
+    let server = Server::start_with(&["--wire", "bincode"]);
+    // well over OUTBOUND_BUDGET, even compressed
+    for i in 0..4000 {
+        server.canvas.insert(noise(i as f32, 0.0, i));
+    }
+    let mut slow = RawPeer::connect_in(&server.addr, "slow", 0, WireFormat::Bincode);
+    slow.owner();
+    let mut drawer = RawPeer::connect_in(&server.addr, "drawer", 0, WireFormat::Bincode);
+    drawer.owner();
+    // each ping would otherwise wait out a delayed ack
+    drawer.stream.set_nodelay(true).unwrap();
+    let falling_behind = || {
+        let notices = server.handle.notices.lock().unwrap();
+        notices
+            .iter()
+            .any(|notice| notice.text.contains("slow") && notice.text.contains("falling behind"))
+    };
+    let mut drawn = 0;
+    while !falling_behind() {
+        assert!(drawn < 20_000, "the slow peer was never held back");
+        for i in 0..20 {
+            let entity = noise(i as f32 * 10.0, 100.0 + drawn as f32, 10_000 + drawn);
+            drawer.send(&Message::NewEntity(entity));
+            drawn += 1;
+        }
+        // so the server has relayed the batch before the notice is looked for
+        drawer.send(&Message::Ping(drawn as u64));
+        drawer.expect(|message| match message {
+            Message::Pong(payload) if payload == drawn as u64 => Some(()),
+            _ => None,
+        });
+        slow.send(&Message::Ping(0));
+    }
+
+    // reading a few megabytes a second with a little drawing going on, it
+    // gets the canvas on joining and once more for what was held back
+    let mut canvases = 0;
+    let mut chunk = [0u8; 16 * 1024];
+    let deadline = Instant::now() + 4 * TIMEOUT;
+    let mut repaired_at = None;
+    let mut last_drawn = Instant::now();
+    while repaired_at.is_none_or(|at: Instant| at.elapsed() < Duration::from_secs(2)) {
+        assert!(Instant::now() < deadline, "the slow peer never caught up");
+        if last_drawn.elapsed() >= Duration::from_millis(20) {
+            let entity = noise(0.0, 100.0 + drawn as f32, 10_000 + drawn);
+            drawer.send(&Message::NewEntity(entity));
+            drawn += 1;
+            last_drawn = Instant::now();
+            slow.send(&Message::Ping(0));
+        }
+        slow.stream
+            .set_read_timeout(Some(Duration::from_millis(1)))
+            .unwrap();
+        match slow.stream.read(&mut chunk) {
+            Ok(0) => panic!("the server dropped the slow peer"),
+            Ok(n) => slow.handler.extend_buffer(&chunk[..n]),
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+            Err(e) => panic!("error reading from the server: {}", e),
+        }
+        while let Some(message) = slow.handler.next_message() {
+            if let Message::AllEntities(_) = message.unwrap() {
+                canvases += 1;
+                if canvases == 2 {
+                    repaired_at = Some(Instant::now());
+                }
+            }
+        }
+        std::thread::sleep(Duration::from_millis(2));
+    }
+    assert_eq!(canvases, 2, "sent the canvas {} times", canvases);
+
+    server.stop();
+}
+
+#[test]
+fn traffic_is_counted_on_both_ends() {
+    let server = Server::start();
+    server.canvas.insert(dot(10.0, 10.0));
+
+    let client = Client::start(&server.addr, "counted");
+    wait_until("the client has the canvas", || {
+        client.handle.stats.totals().last_snapshot > 0
+    });
+    let on_client = client.handle.stats.totals();
+    assert!(on_client.messages_sent >= 2, "{:?}", on_client);
+    assert!(on_client.bytes_received > on_client.last_snapshot);
+
+    let on_server = server.handle.clients.lock().unwrap()[0].stats.totals();
+    // at least the hello, which the canvas went out in answer to
+    assert!(on_server.messages_received >= 1, "{:?}", on_server);
+    assert!(on_server.bytes_received > 0);
+    assert_eq!(on_server.last_snapshot, on_client.last_snapshot);
+
+    client.stop();
+    server.stop();
+}
+
+#[test]
+fn rates_start_over_when_only_the_message_counts_go_down() {
+    let before = NetTotals {
+        messages_sent: 50,
+        bytes_sent: 1000,
+        messages_received: 40,
+        bytes_received: 1000,
+        ..NetTotals::default()
+    };
+    let mut meter = RateMeter::new(before);
+    std::thread::sleep(Duration::from_millis(1100));
+    // a new connection that has sent fewer, larger messages than the last one
+    let after = NetTotals {
+        messages_sent: 2,
+        bytes_sent: 5000,
+        messages_received: 1,
+        bytes_received: 5000,
+        ..NetTotals::default()
+    };
+    assert_eq!(meter.update(after), NetTotals::default());
+    std::thread::sleep(Duration::from_millis(1100));
+    let rates = meter.update(NetTotals {
+        messages_sent: 4,
+        ..after
+    });
+    assert!(
+        rates.messages_sent > 0 && rates.messages_sent <= 2,
+        "{:?}",
+        rates
+    );
+}
+
+#[test]
+fn kicked_client_is_told_why_and_stays_away() {
+    let server = Server::start();
+    let guest = Client::start(&server.addr, "guest");
+    wait_until("the guest is admitted", || {
+        server
+            .handle
+            .clients
+            .lock()
+            .is_ok_and(|clients| clients.iter().any(|c| !c.pending))
+    });
+
+    let addr = server.handle.clients.lock().unwrap()[0].addr;
+    server.handle.kick(addr, "too many circles".to_string());
+    wait_until("the guest knows it was kicked", || {
+        guest.handle.status.lock().is_ok_and(|status| {
+            matches!(&*status, ClientStatus::Kicked { reason } if reason == "too many circles")
+        })
+    });
+    wait_until("the guest is off the client list", || {
+        server
+            .handle
+            .clients
+            .lock()
+            .is_ok_and(|clients| clients.is_empty())
+    });
+    std::thread::sleep(std::time::Duration::from_millis(500));
+    assert!(server.handle.clients.lock().unwrap().is_empty());
+
+    guest.stop();
+    server.stop();
+}
+
+fn status(client: &Client) -> ClientStatus {
+    client.handle.status.lock().unwrap().clone()
+}
+
+/// Waits until `count` guests are listed, all waiting for approval.
+fn pending_guests(server: &Server, count: usize) -> Vec<std::net::SocketAddr> {
+    wait_until("the guests wait for approval", || {
+        server.handle.clients.lock().is_ok_and(|clients| {
+            clients.len() == count && clients.iter().all(|c| c.pending && c.name.is_some())
+        })
+    });
+    let clients = server.handle.clients.lock().unwrap();
+    clients.iter().map(|client| client.addr).collect()
+}
+
+#[test]
+fn an_approved_guest_gets_the_canvas_only_once_approved() {
+    let server = Server::start_with(&["--approve-joins"]);
+    let existing = dot(10.0, 10.0);
+    server.canvas.insert(existing.clone());
+    let mut raw = RawPeer::connect(&server.addr, "raw");
+    let guest = Client::start(&server.addr, "guest");
+    raw.expect(|message| matches!(message, Message::AwaitingApproval).then_some(()));
+    wait_until("the guest shows it is waiting", || {
+        status(&guest) == ClientStatus::AwaitingApproval
+    });
+    let guests = pending_guests(&server, 2);
+
+    // nothing of the canvas while the host decides
+    let early = raw.collect_for(Duration::from_millis(300));
+    assert!(
+        !early
+            .iter()
+            .any(|message| matches!(message, Message::AllEntities(_) | Message::EntityBatch(_))),
+        "{:?}",
+        early
+    );
+    assert!(guest.canvas.is_empty());
+
+    for addr in guests {
+        server.handle.decide(addr, JoinDecision::Approve);
+    }
+    let mut approved = false;
+    let all = raw.expect(|message| match message {
+        Message::Approved => {
+            approved = true;
+            None
+        }
+        Message::AllEntities(all) | Message::EntityBatch(all) => {
+            assert!(approved, "the canvas came before the approval");
+            Some(all)
+        }
+        _ => None,
+    });
+    assert!(all.iter().any(|entity| entity.id == existing.id));
+    wait_until("the guest is in with the canvas", || {
+        status(&guest) == ClientStatus::Connected && guest.canvas.contains(existing.id)
+    });
+    assert!(
+        server
+            .handle
+            .clients
+            .lock()
+            .unwrap()
+            .iter()
+            .all(|client| !client.pending)
+    );
+
+    guest.stop();
+    server.stop();
+}
+
+#[test]
+fn a_denied_guest_is_told_so_and_let_go() {
+    let server = Server::start_with(&["--approve-joins"]);
+    server.canvas.insert(dot(10.0, 10.0));
+    let guest = Client::start(&server.addr, "guest");
+    wait_until("the guest shows it is waiting", || {
+        status(&guest) == ClientStatus::AwaitingApproval
+    });
+
+    let guests = pending_guests(&server, 1);
+    server.handle.decide(guests[0], JoinDecision::Deny);
+    let declined = ClientStatus::Rejected {
+        reason: "The host declined the join".to_string(),
+    };
+    wait_until("the guest knows it was declined", || {
+        status(&guest) == declined
+    });
+    wait_until("the guest is off the client list", || {
+        server
+            .handle
+            .clients
+            .lock()
+            .is_ok_and(|clients| clients.is_empty())
+    });
+    assert!(guest.canvas.is_empty());
+
+    guest.stop();
+    server.stop();
+}
+
+#[test]
+fn a_guest_nobody_approves_is_turned_away_without_hearing_the_room() {
+    let server = Server::start_with(&["--approve-joins", "--approval-timeout", "1"]);
+    let mut raw = RawPeer::connect(&server.addr, "raw");
+    let guest = Client::start(&server.addr, "guest");
+    raw.expect(|message| matches!(message, Message::AwaitingApproval).then_some(()));
+    pending_guests(&server, 2);
+
+    // the host draws while they wait
+    server.tx.send(Message::NewEntity(dot(10.0, 10.0))).unwrap();
+    let mut heard = Vec::new();
+    let reason = raw.expect(|message| match message {
+        Message::Rejected { reason } => Some(reason),
+        other => {
+            heard.push(other);
+            None
+        }
+    });
+    assert_eq!(reason, "Nobody approved the join in time");
+    // its own welcome, but nothing the room was sent
+    assert!(
+        heard
+            .iter()
+            .all(|message| matches!(message, Message::Welcome { .. } | Message::Pong(_))),
+        "{:?}",
+        heard
+    );
+
+    let timed_out = ClientStatus::Rejected { reason };
+    wait_until("the guest knows it timed out", || {
+        status(&guest) == timed_out
+    });
+    wait_until("both are off the client list", || {
+        server
+            .handle
+            .clients
+            .lock()
+            .is_ok_and(|clients| clients.is_empty())
+    });
+    assert!(guest.canvas.is_empty());
+
+    guest.stop();
+    server.stop();
+}
+
+#[test]
+fn reconnecting_client_gets_only_what_it_is_missing() {
+    // kept in a file, and with it the session, so the restarted server is the same one
+    let path = scratch_path("resume.json");
+    let persist = path.to_str().unwrap();
+    let server = Server::start_with(&["--persist", persist]);
+    let seen: Vec<Entity> = (0..20).map(|i| dot(i as f32 * 10.0, 0.0)).collect();
+    for entity in &seen {
+        server.canvas.insert(entity.clone());
+    }
+    let client = Client::start(&server.addr, "returning");
+    wait_until("the client has the canvas", || {
+        seen.iter().all(|entity| client.canvas.contains(entity.id))
+    });
+
+    let addr = server.addr.clone();
+    server.stop();
+    wait_until("the client notices the server is gone", || {
+        client
+            .handle
+            .status
+            .lock()
+            .is_ok_and(|status| matches!(*status, ClientStatus::Reconnecting { .. }))
+    });
+
+    // drawn on each side while they were apart
+    let offline = dot(300.0, 300.0);
+    client.canvas.insert(offline.clone());
+    client.tx.send(Message::NewEntity(offline.clone())).unwrap();
+    let missed = dot(400.0, 400.0);
+    let file = std::fs::File::open(&path).unwrap();
+    let mut saved: SavedCanvas = serde_json::from_reader(file).unwrap();
+    saved.entities.push(missed.clone());
+    serde_json::to_writer(std::fs::File::create(&path).unwrap(), &saved).unwrap();
+
+    let server = Server::start_at(&addr, &["--persist", persist]);
+    wait_until("both sides have both new entities", || {
+        server.canvas.contains(offline.id) && client.canvas.contains(missed.id)
+    });
+    assert!(seen.iter().all(|entity| client.canvas.contains(entity.id)));
+    assert_eq!(client.canvas.len(), seen.len() + 2);
+
+    // only `missed` came down, compacted for this client; the offline entity
+    // may or may not have gone up yet
+    let on_server = server.handle.clients.lock().unwrap()[0].stats.totals();
+    let batch = Message::CompactEntities(CompactBatch::try_from([missed].as_slice()).unwrap());
+    let expected = network::frame_message(&batch, WireFormat::Json).unwrap();
+    assert_eq!(on_server.last_snapshot, expected.len() as u64);
+
+    client.stop();
+    server.stop();
+    let _ = std::fs::remove_file(&path);
+}
+
+/// A server with 1000 entities, a client holding all of them, and then the
+/// server restarting empty under the client, which is run with `args`.
+fn restart_under(args: &[&str]) -> (Server, Client, Vec<Entity>) {
+    let server = Server::start();
+    let before: Vec<Entity> = (0..1000)
+        .map(|i| dot((i % 40) as f32 * 10.0, (i / 40) as f32 * 10.0))
+        .collect();
+    for entity in &before {
+        server.canvas.insert(entity.clone());
+    }
+    let client = Client::start_with(&server.addr, "steady", args);
+    wait_until("the client has the canvas", || {
+        client.canvas.len() == before.len()
+    });
+
+    let addr = server.addr.clone();
+    server.stop();
+    wait_until("the client notices the server is gone", || {
+        client
+            .handle
+            .status
+            .lock()
+            .is_ok_and(|status| matches!(*status, ClientStatus::Reconnecting { .. }))
+    });
+    (Server::start_at(&addr, &[]), client, before)
+}
+
+fn client_notices(client: &Client) -> Vec<String> {
+    let notices = client.handle.notices.lock().unwrap();
+    notices.iter().map(|notice| notice.text.clone()).collect()
+}
+
+#[test]
+fn a_client_drops_what_a_restarted_server_no_longer_has() {
+    let (server, client, before) = restart_under(&[]);
+    let after = dot(-50.0, -50.0);
+    server.canvas.insert(after.clone());
+
+    wait_until("the client takes the new server's canvas", || {
+        client.canvas.contains(after.id) && client.canvas.len() == 1
+    });
+    assert!(
+        client_notices(&client)
+            .iter()
+            .any(|text| text.contains("restarted") && text.contains("dropped")),
+        "{:?}",
+        client_notices(&client)
+    );
+    // nothing from before went up either
+    std::thread::sleep(Duration::from_millis(300));
+    assert_eq!(server.canvas.len(), 1);
+    assert!(
+        before
+            .iter()
+            .all(|entity| !server.canvas.contains(entity.id))
+    );
+
+    client.stop();
+    server.stop();
+}
+
+#[test]
+fn a_client_restores_its_canvas_to_a_restarted_server_under_new_ids() {
+    let (server, client, before) = restart_under(&["--restore-on-reconnect"]);
+    let after = dot(-50.0, -50.0);
+    server.canvas.insert(after.clone());
+
+    wait_until("the server has everything from before again", || {
+        server.canvas.len() == before.len() + 1
+    });
+    wait_until("the client has the new server's entity too", || {
+        client.canvas.contains(after.id)
+    });
+    assert_eq!(client.canvas.len(), server.canvas.len());
+    assert!(
+        client
+            .canvas
+            .iter()
+            .all(|entity| server.canvas.contains(*entity.key()))
+    );
+    // the same drawings, under ids the old server never handed out
+    assert!(
+        before
+            .iter()
+            .all(|entity| !server.canvas.contains(entity.id))
+    );
+    let mut positions: Vec<(i32, i32)> = server
+        .canvas
+        .iter()
+        .filter(|entity| *entity.key() != after.id)
+        .map(|entity| (entity.x as i32, entity.y as i32))
+        .collect();
+    let mut expected: Vec<(i32, i32)> = before
+        .iter()
+        .map(|entity| (entity.x as i32, entity.y as i32))
+        .collect();
+    positions.sort_unstable();
+    expected.sort_unstable();
+    assert_eq!(positions, expected);
+    assert!(
+        client_notices(&client)
+            .iter()
+            .any(|text| text.contains("restarted") && text.contains("sending"))
+    );
+
+    client.stop();
+    server.stop();
+}
+
+#[test]
+fn clients_may_only_change_what_they_drew() {
+    let server = Server::start();
+    let from_host = dot(0.0, 0.0);
+    server.canvas.insert(from_host.clone());
+
+    let mut peer = RawPeer::connect(&server.addr, "mallory");
+    let owner = peer.owner();
+    assert_ne!(owner, HOST_OWNER);
+    peer.send(&Message::RemoveEntity { id: from_host.id });
+    peer.send(&Message::UpdateEntity {
+        id: from_host.id,
+        color: EntityColor::rgb(0x00FF00),
+    });
+    let mut forged = from_host.clone();
+    forged.x = 100.0;
+    peer.send(&Message::NewEntity(forged));
+    // passed off as the host's, but stamped with the sender's owner anyway
+    let mut own = dot(50.0, 50.0);
+    own.owner = HOST_OWNER;
+    peer.send(&Message::NewEntity(own.clone()));
+    wait_until("the server has the peer's own entity", || {
+        server.canvas.contains(own.id)
+    });
+
+    let kept = server.canvas.get(from_host.id).unwrap().value().clone();
+    assert_eq!((kept.x, kept.color), (from_host.x, from_host.color));
+    assert_eq!(server.canvas.get(own.id).unwrap().owner, owner);
+    peer.send(&Message::RemoveEntity { id: own.id });
+    wait_until("the peer erased its own entity", || {
+        !server.canvas.contains(own.id)
+    });
+
+    server.stop();
+}
+
+#[test]
+fn moved_entities_land_where_they_were_dropped_for_everyone() {
+    let server = Server::start();
+    let from_host = dot(0.0, 0.0);
+    server.canvas.insert(from_host.clone());
+    let watcher = Client::start(&server.addr, "watcher");
+    let mut mover = RawPeer::connect(&server.addr, "mover");
+    mover.owner();
+    let line = Entity::line(
+        next_id(),
+        (10.0, 10.0),
+        (30.0, 10.0),
+        2.0,
+        EntityColor::BLACK,
+    );
+    mover.send(&Message::NewEntity(line.clone()));
+    wait_until("the watcher has the line", || {
+        watcher.canvas.contains(line.id)
+    });
+
+    mover.send(&Message::MoveEntities {
+        moves: vec![(line.id, 110.0, 60.0), (from_host.id, 500.0, 500.0)],
+        generation: 0,
+    });
+    // the host's dot is not the mover's to move, so it is sent back as it stands
+    let sent_back = mover.expect(|message| match message {
+        Message::NewEntity(entity) if entity.id == from_host.id => Some(entity),
+        _ => None,
+    });
+    assert_eq!((sent_back.x, sent_back.y), (0.0, 0.0));
+    wait_until("the watcher has the line moved", || {
+        watcher
+            .canvas
+            .get(line.id)
+            .is_some_and(|entity| (entity.x, entity.y) == (110.0, 60.0))
+    });
+    for canvas in [&server.canvas, &watcher.canvas] {
+        let moved = canvas.get(line.id).unwrap().value().clone();
+        assert!(
+            matches!(
+                moved.shape,
+                Shape::Line {
+                    x2: 130.0,
+                    y2: 60.0,
+                    ..
+                }
+            ),
+            "{:?}",
+            moved.shape
+        );
+        let kept = canvas.get(from_host.id).unwrap().value().clone();
+        assert_eq!((kept.x, kept.y), (0.0, 0.0));
+    }
+
+    watcher.stop();
+    server.stop();
+}
+
+#[test]
+fn drawing_from_before_a_transform_lands_where_the_transform_put_everything() {
+    let server = Server::start();
+    let mut drawer = RawPeer::connect(&server.addr, "drawer");
+    drawer.owner();
+    let mut watcher = RawPeer::connect(&server.addr, "watcher");
+    watcher.owner();
+    let stroke = fractional_stroke(10.0, 10.0);
+    drawer.send(&Message::NewEntity(stroke.clone()));
+    watcher.expect(|message| match message {
+        Message::NewEntity(entity) if entity.id == stroke.id => Some(()),
+        _ => None,
+    });
+
+    // the host turns the canvas before the drawer has heard of it
+    let turn = Transform::rotate_about(1, (100.0, 100.0));
+    let transform_all = Message::TransformAll {
+        transform: turn,
+        generation: 0,
+    };
+    server.tx.send(transform_all).unwrap();
+    let generation = watcher.expect(|message| match message {
+        Message::TransformAll { generation, .. } => Some(generation),
+        _ => None,
+    });
+    assert_eq!(generation, 1);
+    drawer.send(&Message::StrokeAppend {
+        id: stroke.id,
+        points: vec![(20.0, 30.0)],
+        pen: Default::default(),
+        generation: 0,
+    });
+    let late = dot(50.0, 10.0);
+    assert_eq!(late.generation, 0);
+    drawer.send(&Message::NewEntity(late.clone()));
+
+    let (points, generation) = watcher.expect(|message| match message {
+        Message::StrokeAppend {
+            id,
+            points,
+            generation,
+            ..
+        } if id == stroke.id => Some((points, generation)),
+        _ => None,
+    });
+    assert_eq!((points, generation), (vec![turn.apply((20.0, 30.0))], 1));
+    let mut turned = late.clone();
+    turn.apply_to(&mut turned);
+    let relayed = watcher.expect(|message| match message {
+        Message::NewEntity(entity) if entity.id == late.id => Some(entity),
+        _ => None,
+    });
+    assert_eq!(
+        (relayed.x, relayed.y, relayed.generation),
+        (turned.x, turned.y, 1)
+    );
+
+    // and the server keeps them that way for whoever joins next
+    let stored = server.canvas.get(late.id).unwrap().value().clone();
+    assert_eq!(
+        (stored.x, stored.y, stored.generation),
+        (turned.x, turned.y, 1)
+    );
+    assert!(
+        matches!(
+            (&stored.shape, &turned.shape),
+            (Shape::Rect { w, h }, Shape::Rect { w: w2, h: h2 }) if (w, h) == (w2, h2)
+        ),
+        "{:?}",
+        stored.shape
+    );
+    let stored = server.canvas.get(stroke.id).unwrap().value().clone();
+    let Shape::Stroke { points, .. } = &stored.shape else {
+        panic!("{:?}", stored.shape);
+    };
+    assert_eq!(points.first(), Some(&turn.apply((10.0, 10.0))));
+    assert_eq!(points.last(), Some(&turn.apply((20.0, 30.0))));
+
+    server.stop();
+}
+
+#[test]
+fn a_move_from_before_a_transform_lands_where_the_transform_put_it() {
+    let server = Server::start();
+    let mut mover = RawPeer::connect(&server.addr, "mover");
+    mover.owner();
+    let watcher = Client::start(&server.addr, "watcher");
+    // longer than it is tall, so a turn moves which corner is its top left
+    let bar = Entity::rect(
+        next_id(),
+        (10.0, 10.0),
+        (40.0, 20.0),
+        EntityColor::rgb(0x00FF00),
+    );
+    mover.send(&Message::NewEntity(bar.clone()));
+    wait_until("the watcher has the bar", || {
+        watcher.canvas.contains(bar.id)
+    });
+
+    // the host turns the canvas while the mover is dragging the bar
+    let turn = Transform::rotate_about(1, (100.0, 100.0));
+    server
+        .tx
+        .send(Message::TransformAll {
+            transform: turn,
+            generation: 0,
+        })
+        .unwrap();
+    let generation = mover.expect(|message| match message {
+        Message::TransformAll { generation, .. } => Some(generation),
+        _ => None,
+    });
+    assert_eq!(generation, 1);
+    mover.send(&Message::MoveEntities {
+        moves: vec![(bar.id, 50.0, 60.0)],
+        generation: 0,
+    });
+
+    // dropped at (50, 60) on the canvas the mover saw, then turned with the rest
+    let mut expected = bar.clone();
+    expected.move_to((50.0, 60.0));
+    turn.apply_to(&mut expected);
+    wait_until("the watcher has the bar moved", || {
+        watcher
+            .canvas
+            .get(bar.id)
+            .is_some_and(|entity| (entity.x, entity.y) == (expected.x, expected.y))
+    });
+    let stored = server.canvas.get(bar.id).unwrap().value().clone();
+    assert_eq!((stored.x, stored.y), (expected.x, expected.y));
+    assert!(
+        matches!(
+            (&stored.shape, &expected.shape),
+            (Shape::Rect { w, h }, Shape::Rect { w: w2, h: h2 }) if (w, h) == (w2, h2)
+        ),
+        "{:?}",
+        stored.shape
+    );
+
+    server.stop();
+}
+
+#[test]
+fn another_drawing_under_a_taken_id_is_rekeyed_and_a_refused_change_undone() {
+    let server = Server::start();
+    let from_host = dot(0.0, 0.0);
+    server.canvas.insert(from_host.clone());
+
+    let mut peer = RawPeer::connect(&server.addr, "loader");
+    let owner = peer.owner();
+    // say from a file saved in another session, where the id meant something else
+    let mut loaded = dot(40.0, 40.0);
+    loaded.id = from_host.id;
+    peer.send(&Message::NewEntity(loaded.clone()));
+    let corrected = peer.expect(|message| match message {
+        Message::NewEntity(entity) if entity.id == from_host.id => Some(entity),
+        _ => None,
+    });
+    assert_eq!((corrected.x, corrected.owner), (from_host.x, HOST_OWNER));
+    let rekeyed = peer.expect(|message| match message {
+        Message::NewEntity(entity) if entity.id != from_host.id => Some(entity),
+        _ => None,
+    });
+    assert_eq!(
+        (rekeyed.x, rekeyed.seq, rekeyed.owner),
+        (loaded.x, loaded.seq, owner)
+    );
+    assert_eq!(server.canvas.get(from_host.id).unwrap().x, from_host.x);
+    assert_eq!(server.canvas.get(rekeyed.id).unwrap().x, loaded.x);
+
+    // the window would not try, but a stale one might: its copy is put back
+    peer.send(&Message::UpdateEntity {
+        id: from_host.id,
+        color: EntityColor::rgb(0x00FF00),
+    });
+    let restored = peer.expect(|message| match message {
+        Message::NewEntity(entity) if entity.id == from_host.id => Some(entity),
+        _ => None,
+    });
+    assert_eq!(restored.color, from_host.color);
+
+    server.stop();
+}
+
+#[test]
+fn allow_erase_any_lets_clients_change_anything() {
+    let server = Server::start_with(&["--allow-erase-any"]);
+    let from_host = dot(0.0, 0.0);
+    server.canvas.insert(from_host.clone());
+
+    let mut peer = RawPeer::connect(&server.addr, "trusted");
+    let can_change_any = peer.expect(|message| match message {
+        Message::Welcome { change_any, .. } => Some(change_any),
+        _ => None,
+    });
+    assert!(can_change_any);
+    peer.send(&Message::RemoveEntity { id: from_host.id });
+    wait_until("the host's entity is gone", || {
+        !server.canvas.contains(from_host.id)
+    });
+
+    server.stop();
+}
+
+#[test]
+fn a_returning_client_keeps_its_owner() {
+    let server = Server::start();
+    let mut first = RawPeer::connect_as(&server.addr, "returning", 42);
+    let owner = first.owner();
+    let drawn = dot(0.0, 0.0);
+    first.send(&Message::NewEntity(drawn.clone()));
+    wait_until("the server has the entity", || {
+        server.canvas.contains(drawn.id)
+    });
+    first.send(&Message::Disconnect);
+
+    let mut other = RawPeer::connect(&server.addr, "other");
+    assert_ne!(other.owner(), owner);
+    let mut again = RawPeer::connect_as(&server.addr, "returning", 42);
+    assert_eq!(again.owner(), owner);
+    again.send(&Message::RequestAllEntities);
+    let all = again.expect(|message| match message {
+        Message::AllEntities(all) => Some(all),
+        _ => None,
+    });
+    assert!(
+        all.iter()
+            .any(|entity| entity.id == drawn.id && entity.owner == owner)
+    );
+    again.send(&Message::RemoveEntity { id: drawn.id });
+    wait_until("the returning client erased its entity", || {
+        !server.canvas.contains(drawn.id)
+    });
+
+    server.stop();
+}
+
+#[test]
+fn silent_clients_are_dropped_and_pinging_ones_kept() {
+    let server = Server::start_with(&["--client-timeout", "3"]);
+    let alive = Client::start(&server.addr, "alive");
+    let mut silent = RawPeer::connect(&server.addr, "silent");
+    wait_until("both clients are listed", || {
+        server
+            .handle
+            .clients
+            .lock()
+            .is_ok_and(|clients| clients.len() == 2)
+    });
+
+    // the raw peer never pings, so it gets a goodbye
+    silent.expect(|message| matches!(message, Message::Disconnect).then_some(()));
+    wait_until("only the pinging client is left", || {
+        server
+            .handle
+            .clients
+            .lock()
+            .is_ok_and(|clients| clients.len() == 1 && clients[0].name.as_deref() == Some("alive"))
+    });
+    // and the host is told in the window, not just on stderr
+    let notices = server.handle.notices.lock().unwrap();
+    assert!(
+        notices
+            .iter()
+            .any(|notice| notice.text.contains("silent") && notice.opacity() > 0.0),
+        "{:?}",
+        notices
+    );
+    drop(notices);
+
+    alive.stop();
+    server.stop();
+}
+
+#[test]
+fn a_client_whose_hello_is_too_late_is_turned_away_and_frees_its_place() {
+    let server = Server::start_with(&["--max-clients", "1"]);
+    let mut nameless = RawPeer::connect_unnamed(&server.addr, WireFormat::Json);
+    wait_until("the client is listed", || {
+        server
+            .handle
+            .clients
+            .lock()
+            .is_ok_and(|clients| clients.len() == 1)
+    });
+
+    // pinging keeps it from going silent, but it never joins a room
+    let started = Instant::now();
+    let reason = loop {
+        assert!(
+            started.elapsed() < 2 * TIMEOUT,
+            "the client was never turned away"
+        );
+        nameless.send(&Message::Ping(0));
+        let rejected = nameless
+            .collect_for(Duration::from_millis(200))
+            .into_iter()
+            .find_map(|message| match message {
+                Message::Rejected { reason } => Some(reason),
+                _ => None,
+            });
+        if let Some(reason) = rejected {
+            break reason;
+        }
+    };
+    assert_eq!(reason, "No hello arrived in time");
+    assert!(
+        started.elapsed() >= Duration::from_secs(4),
+        "{:?}",
+        started.elapsed()
+    );
+
+    // the place it held is free for a client that does say hello
+    let mut named = RawPeer::connect(&server.addr, "named");
+    named.owner();
+    wait_until("only the named client is left", || {
+        server
+            .handle
+            .clients
+            .lock()
+            .is_ok_and(|clients| clients.len() == 1 && clients[0].name.as_deref() == Some("named"))
+    });
+
+    server.stop();
+}
+
+#[test]
+fn faded_drawings_are_erased_for_everyone() {
+    let server = Server::start_with(&["--fade-seconds", "1"]);
+    let client = Client::start(&server.addr, "fading");
+    wait_until("the client hears how long fading takes", || {
+        client
+            .handle
+            .fade
+            .lock()
+            .is_ok_and(|fade| *fade == Some(Duration::from_secs(1)))
+    });
+
+    let drawn = dot(3.0, 3.0);
+    client.canvas.insert(drawn.clone());
+    client.tx.send(Message::NewEntity(drawn.clone())).unwrap();
+    wait_until("the server has the dot", || {
+        server.canvas.contains(drawn.id)
+    });
+    wait_until("the dot fades off both canvases", || {
+        !server.canvas.contains(drawn.id) && !client.canvas.contains(drawn.id)
+    });
+
+    client.stop();
+    server.stop();
+}
+
+/// A path in the temp directory no other test uses.
+fn scratch_path(name: &str) -> std::path::PathBuf {
+    let path = std::env::temp_dir().join(format!("tcp-drawing-{}-{}", std::process::id(), name));
+    let _ = std::fs::remove_file(&path);
+    path
+}
+
+#[test]
+fn persisted_canvas_comes_back_after_a_restart() {
+    let path = scratch_path("persist.json");
+    let persist = path.to_str().unwrap();
+    let server = Server::start_with(&["--persist", persist]);
+    let mut peer = RawPeer::connect(&server.addr, "before");
+    let drawn = dot(10.0, 20.0);
+    peer.send(&Message::NewEntity(drawn.clone()));
+    server
+        .tx
+        .send(Message::SetBackground(EntityColor::rgb(0x1F3A2E)))
+        .unwrap();
+    wait_until("the server has the entity", || {
+        server.canvas.contains(drawn.id)
+    });
+    // saved on the way out, well before the next periodic save
+    server.stop();
+    assert!(path.exists());
+    assert!(
+        !path
+            .with_file_name(format!("{}.tmp", path.file_name().unwrap().display()))
+            .exists()
+    );
+
+    let server = Server::start_with(&["--persist", persist]);
+    assert!(
+        server.canvas.contains(drawn.id),
+        "restored before any client"
+    );
+    assert_eq!(server.handle.background.load(Ordering::Relaxed), 0x1F3A2E);
+    let mut peer = RawPeer::connect(&server.addr, "after");
+    peer.send(&Message::RequestAllEntities);
+    let all = peer.expect(|message| match message {
+        Message::AllEntities(all) => Some(all),
+        _ => None,
+    });
+    assert!(all.iter().any(|entity| entity.id == drawn.id));
+    server.stop();
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn a_corrupt_persist_file_is_moved_aside() {
+    let path = scratch_path("corrupt.json");
+    std::fs::write(&path, b"{ not a canvas").unwrap();
+    let server = Server::start_with(&["--persist", path.to_str().unwrap()]);
+    assert!(server.canvas.is_empty());
+    server.stop();
+
+    let aside = path.with_file_name(format!("{}.bad", path.file_name().unwrap().display()));
+    assert_eq!(std::fs::read(&aside).unwrap(), b"{ not a canvas");
+    let _ = std::fs::remove_file(&aside);
+}
+
+/// A two-point stroke at `(x, y)` whose second point lies between quarter pixels.
+fn fractional_stroke(x: f32, y: f32) -> Entity {
+    Entity {
+        radius: 3.0,
+        shape: Shape::Stroke {
+            points: vec![(x, y), (x + 2.1, y + 0.35)],
+            pen: Default::default(),
+            thinnest: 0.2,
+        },
+        ..dot(x, y)
+    }
+}
+
+fn second_point(entity: &Entity) -> (f32, f32) {
+    match &entity.shape {
+        Shape::Stroke { points, .. } => points[1],
+        other => panic!("not a stroke: {:?}", other),
+    }
+}
+
+#[test]
+fn compact_and_plain_peers_see_the_same_strokes() {
+    let server = Server::start();
+    let client = Client::start(&server.addr, "compact");
+    let mut plain = RawPeer::connect(&server.addr, "plain");
+    plain.owner();
+    wait_until("the client is welcomed", || {
+        client
+            .handle
+            .color
+            .lock()
+            .is_ok_and(|color| color.is_some())
+    });
+    // counted in steps, so close to where it was drawn but not exactly there
+    let stepped = |drawn: (f32, f32), arrived: (f32, f32)| {
+        let off = (drawn.0 - arrived.0).abs().max((drawn.1 - arrived.1).abs());
+        off < 0.5 && off > 0.01
+    };
+
+    let drawn = fractional_stroke(10.3, 20.7);
+    client.tx.send(Message::NewEntity(drawn.clone())).unwrap();
+    let relayed = plain.expect(|message| match message {
+        Message::NewEntity(entity) if entity.id == drawn.id => Some(entity),
+        _ => None,
+    });
+    let on_server = server.canvas.get(drawn.id).unwrap().value().clone();
+    assert_eq!((on_server.x, on_server.y), (10.3, 20.7));
+    assert!(stepped(second_point(&drawn), second_point(&on_server)));
+    assert_eq!(second_point(&relayed), second_point(&on_server));
+
+    let from_plain = fractional_stroke(-40.6, 5.9);
+    plain.send(&Message::NewEntity(from_plain.clone()));
+    wait_until("the client has the plain peer's stroke", || {
+        client.canvas.contains(from_plain.id)
+    });
+    let arrived = client.canvas.get(from_plain.id).unwrap().value().clone();
+    assert!(stepped(second_point(&from_plain), second_point(&arrived)));
+    client.stop();
+    server.stop();
+}
+
+/// Names in the client's roster, in the order they joined.
+fn roster(client: &Client) -> Vec<String> {
+    let roster = client.handle.roster.lock().unwrap();
+    roster.iter().map(|(_, name)| name.clone()).collect()
+}
+
+/// Whether the server has had `name`'s hello, and so lists it to whoever joins.
+fn said_hello(server: &Server, name: &str) -> bool {
+    server
+        .handle
+        .clients
+        .lock()
+        .unwrap()
+        .iter()
+        .any(|client| client.name.as_deref() == Some(name))
+}
+
+#[test]
+fn clients_hear_who_joins_and_leaves_however_they_go() {
+    let server = Server::start_with(&["--client-timeout", "3"]);
+    let watcher = Client::start(&server.addr, "watcher");
+    wait_until("the server has the watcher's hello", || {
+        said_hello(&server, "watcher")
+    });
+
+    // the raw peer never pings, so it times out in the end
+    let mut silent = RawPeer::connect(&server.addr, "silent");
+    let told = silent.expect(|message| match message {
+        Message::Roster(peers) => Some(peers),
+        _ => None,
+    });
+    let told: Vec<&str> = told.iter().map(|(_, name)| name.as_str()).collect();
+    assert_eq!(told, ["watcher"]);
+    wait_until("the watcher hears silent joined", || {
+        roster(&watcher) == ["silent"]
+    });
+
+    let mut polite = RawPeer::connect(&server.addr, "polite");
+    let kicked = Client::start(&server.addr, "kicked");
+    wait_until("the newcomer is told who was there first", || {
+        roster(&kicked) == ["watcher", "silent", "polite"]
+    });
+    wait_until("the watcher hears both join", || {
+        roster(&watcher) == ["silent", "polite", "kicked"]
+    });
+
+    polite.send(&Message::Disconnect);
+    wait_until("the watcher hears polite left", || {
+        roster(&watcher) == ["silent", "kicked"]
+    });
+    let addr = server
+        .handle
+        .clients
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|client| client.name.as_deref() == Some("kicked"))
+        .unwrap()
+        .addr;
+    server.handle.kick(addr, "testing".to_string());
+    wait_until("the watcher hears the kicked client left", || {
+        roster(&watcher) == ["silent"]
+    });
+    wait_until("the watcher hears silent timed out", || {
+        roster(&watcher).is_empty()
+    });
+    let notices = client_notices(&watcher);
+    for text in ["kicked left", "silent left"] {
+        assert!(notices.iter().any(|notice| notice == text), "{notices:?}");
+    }
+
+    kicked.stop();
+    watcher.stop();
+    server.stop();
+}
+
+#[test]
+fn a_cursor_goes_by_the_id_its_sender_joined_under() {
+    let server = Server::start();
+    let mut watcher = RawPeer::connect(&server.addr, "watcher");
+    let mut pointer = RawPeer::connect(&server.addr, "pointer");
+    let id = watcher.expect(|message| match message {
+        Message::PeerJoined { id, name } if name == "pointer" => Some(id),
+        _ => None,
+    });
+
+    // whatever id the pointer claims, and this one is the host's own
+    let claimed = instance_tag();
+    assert_ne!(claimed, id);
+    pointer.send(&Message::Cursor {
+        peer_id: claimed,
+        x: 10.0,
+        y: 20.0,
+        color: EntityColor::rgb(0x0000FF),
+    });
+    let relayed = watcher.expect(|message| match message {
+        Message::Cursor { peer_id, x, y, .. } => Some((peer_id, x, y)),
+        _ => None,
+    });
+    assert_eq!(relayed, (id, 10.0, 20.0));
+    wait_until("the host sees the pointer", || {
+        server.cursors.contains_key(&id)
+    });
+    assert!(!server.cursors.contains_key(&claimed));
+
+    server.stop();
+}
+
+#[test]
+fn the_roster_stays_right_through_churn() {
+    let server = Server::start();
+    let watcher = Client::start(&server.addr, "watcher");
+    wait_until("the server has the watcher's hello", || {
+        said_hello(&server, "watcher")
+    });
+
+    let mut stayed = Vec::new();
+    for i in 0..30 {
+        let mut peer = RawPeer::connect(&server.addr, &format!("peer{}", i));
+        match i % 3 {
+            0 => stayed.push(peer),
+            1 => peer.send(&Message::Disconnect),
+            // gone without a goodbye, maybe before the server even reads the Hello
+            _ => drop(peer),
+        }
+    }
+    let mut expected: Vec<String> = (0..30).step_by(3).map(|i| format!("peer{}", i)).collect();
+    // each connection's thread reports its hello on its own, so peers can be
+    // admitted in another order than they connected in
+    expected.sort();
+    let sorted_roster = |client: &Client| {
+        let mut names = roster(client);
+        names.sort();
+        names
+    };
+    wait_until("the watcher has just the peers that stayed", || {
+        sorted_roster(&watcher) == expected
+    });
+    std::thread::sleep(Duration::from_millis(300));
+    assert_eq!(sorted_roster(&watcher), expected);
+
+    let late = Client::start(&server.addr, "late");
+    let mut everyone = vec!["watcher".to_string()];
+    everyone.extend(expected);
+    everyone.sort();
+    wait_until("a late client gets the same roster", || {
+        sorted_roster(&late) == everyone
+    });
+
+    drop(stayed);
+    late.stop();
+    watcher.stop();
+    server.stop();
+}
@@ -0,0 +1,233 @@
+//! A client's write queue against a socket that takes a few bytes at a time.
+
+use std::io::{self, Write};
+use std::sync::Arc;
+use tcp_drawing::entity::{Entity, EntityColor, next_id};
+use tcp_drawing::network::{
+    self, MAX_OUTBOUND_BYTES, Message, MessageHandler, OUTBOUND_BUDGET, Outbound, WireFormat,
+};
+
+/// Takes at most `per_write` bytes a call and refuses every other call with
+/// WouldBlock, like a non-blocking socket with a full send buffer.
+struct Trickle {
+    written: Vec<u8>,
+    per_write: usize,
+    calls: usize,
+    /// Fails every write with this once set.
+    broken: Option<io::ErrorKind>,
+}
+
+impl Trickle {
+    fn new(per_write: usize) -> Self {
+        Self {
+            written: Vec::new(),
+            per_write,
+            calls: 0,
+            broken: None,
+        }
+    }
+}
+
+impl Write for Trickle {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(kind) = self.broken {
+            return Err(kind.into());
+        }
+        self.calls += 1;
+        if self.calls.is_multiple_of(2) {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        let n = buf.len().min(self.per_write);
+        self.written.extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn framed(x: f32) -> Arc<[u8]> {
+    let dot = Entity::rect(next_id(), (x, 0.0), (x + 4.0, 4.0), EntityColor::BLACK);
+    network::frame_message(&Message::NewEntity(dot), WireFormat::Json)
+        .unwrap()
+        .into()
+}
+
+#[test]
+fn partial_writes_resume_where_they_stopped() {
+    let frames: Vec<Arc<[u8]>> = (0..5).map(|i| framed(i as f32)).collect();
+    let mut outbound = Outbound::new();
+    for frame in &frames {
+        outbound.push(frame.clone()).unwrap();
+    }
+    let total: usize = frames.iter().map(|frame| frame.len()).sum();
+    assert_eq!(outbound.queued(), total);
+
+    let mut socket = Trickle::new(7);
+    let mut flushes = 0;
+    while outbound.queued() > 0 {
+        // a WouldBlock ends the call without an error
+        outbound.flush(&mut socket).unwrap();
+        flushes += 1;
+        assert!(flushes < 10_000, "the queue never drained");
+    }
+    assert!(flushes > frames.len(), "only {} flushes", flushes);
+
+    // the other end reads every frame whole and in order
+    let expected: Vec<u8> = frames
+        .iter()
+        .flat_map(|frame| frame.iter().copied())
+        .collect();
+    assert_eq!(socket.written, expected);
+    let mut handler = MessageHandler::new();
+    handler.extend_buffer(&socket.written);
+    let mut received = 0;
+    while let Some(message) = handler.next_message() {
+        assert!(
+            matches!(message, Ok(Message::NewEntity(_))),
+            "{:?}",
+            message
+        );
+        received += 1;
+    }
+    assert_eq!(received, frames.len());
+}
+
+#[test]
+fn clients_sharing_frames_each_get_the_same_bytes_at_their_own_pace() {
+    let frames: Vec<Arc<[u8]>> = (0..20).map(|i| framed(i as f32)).collect();
+    let widths = [1, 3, 7, 64, usize::MAX];
+    let mut clients: Vec<(Outbound, Trickle)> = widths
+        .iter()
+        .map(|&width| (Outbound::new(), Trickle::new(width)))
+        .collect();
+    for frame in &frames {
+        for (outbound, _) in &mut clients {
+            outbound.push(frame.clone()).unwrap();
+        }
+        // every queue holds the one buffer, not a copy of it
+        assert_eq!(Arc::strong_count(frame), 1 + clients.len());
+    }
+
+    let mut flushes = 0;
+    while clients.iter().any(|(outbound, _)| outbound.queued() > 0) {
+        for (outbound, socket) in &mut clients {
+            outbound.flush(socket).unwrap();
+        }
+        flushes += 1;
+        assert!(flushes < 100_000, "the queues never drained");
+    }
+
+    let expected: Vec<u8> = frames
+        .iter()
+        .flat_map(|frame| frame.iter().copied())
+        .collect();
+    for ((_, socket), width) in clients.iter().zip(widths) {
+        assert!(socket.written == expected, "{} bytes a write", width);
+    }
+    // a written frame is let go by every queue
+    assert!(frames.iter().all(|frame| Arc::strong_count(frame) == 1));
+}
+
+#[test]
+fn a_broken_socket_is_an_error_and_a_full_queue_refuses_more() {
+    let mut outbound = Outbound::new();
+    outbound.push(framed(0.0)).unwrap();
+    let mut socket = Trickle::new(3);
+    socket.broken = Some(io::ErrorKind::BrokenPipe);
+    let error = outbound.flush(&mut socket).unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::BrokenPipe);
+
+    // a client that reads nothing fills its queue and is then refused
+    let mut outbound = Outbound::new();
+    let frame: Arc<[u8]> = vec![0u8; 64 * 1024].into();
+    for _ in 0..MAX_OUTBOUND_BYTES / frame.len() {
+        outbound.push(frame.clone()).unwrap();
+    }
+    assert!(outbound.push(frame.clone()).is_err());
+    assert_eq!(outbound.queued(), MAX_OUTBOUND_BYTES);
+}
+
+#[test]
+fn past_the_budget_the_oldest_drawing_goes_and_everything_else_stays() {
+    let mut outbound = Outbound::new();
+    let chunk = 64 * 1024;
+    let control: Arc<[u8]> = vec![1u8; chunk].into();
+    outbound.push(control.clone()).unwrap();
+    // the socket takes the first few bytes of a drawing frame, which has to stay whole
+    let mut socket = Trickle::new(3);
+    let begun: Arc<[u8]> = vec![2u8; chunk].into();
+    outbound.push_drawing(begun.clone());
+    while socket.written.len() <= chunk {
+        outbound.flush(&mut socket).unwrap();
+    }
+
+    let drawing: Vec<Arc<[u8]>> = (3..3 + 2 * OUTBOUND_BUDGET / chunk)
+        .map(|i| vec![i as u8; chunk].into())
+        .collect();
+    let mut controls = 0;
+    for (i, frame) in drawing.iter().enumerate() {
+        outbound.push_drawing(frame.clone());
+        if i % 4 == 0 {
+            outbound.push(control.clone()).unwrap();
+            controls += 1;
+        }
+        // only drawing counts against the budget
+        assert!(outbound.queued() <= OUTBOUND_BUDGET + controls * chunk);
+    }
+    assert!(outbound.skipped() > 0);
+    assert!(!outbound.caught_up(), "caught up while still full");
+
+    socket.per_write = usize::MAX;
+    socket.calls = 0;
+    while outbound.queued() > 0 {
+        outbound.flush(&mut socket).unwrap();
+    }
+    assert!(outbound.caught_up());
+    assert!(!outbound.caught_up(), "caught up twice");
+    assert_eq!(outbound.skipped(), 0);
+
+    let written: Vec<u8> = socket.written.chunks(chunk).map(|frame| frame[0]).collect();
+    let controls = 1 + drawing.len().div_ceil(4);
+    assert_eq!(written.iter().filter(|&&byte| byte == 1).count(), controls);
+    assert_eq!(written[1], 2, "the frame that was begun went out whole");
+    // what drawing is left is the newest, in order
+    let kept: Vec<u8> = written.iter().copied().filter(|&byte| byte > 2).collect();
+    let newest: Vec<u8> = drawing[drawing.len() - kept.len()..]
+        .iter()
+        .map(|frame| frame[0])
+        .collect();
+    assert_eq!(kept, newest);
+}
+
+#[test]
+fn a_canvas_past_the_budget_holds_no_drawing_back_and_has_to_arrive_first() {
+    let mut outbound = Outbound::new();
+    let chunk = 64 * 1024;
+    let canvas: Arc<[u8]> = vec![1u8; 3 * OUTBOUND_BUDGET].into();
+    outbound.push_canvas(canvas).unwrap();
+    for i in 0..OUTBOUND_BUDGET / chunk {
+        outbound.push_drawing(vec![i as u8 + 2; chunk].into());
+    }
+    assert_eq!(outbound.skipped(), 0, "drawing was dropped for the canvas");
+
+    // falling behind on drawing on top of it
+    for _ in 0..4 {
+        outbound.push_drawing(vec![0xEE; chunk].into());
+    }
+    assert_eq!(outbound.skipped(), 4);
+    let mut socket = Trickle::new(chunk);
+    while socket.written.len() < 3 * OUTBOUND_BUDGET - chunk {
+        outbound.flush(&mut socket).unwrap();
+        // a canvas sent now would only queue up behind this one
+        assert!(
+            !outbound.caught_up(),
+            "caught up with the canvas still going out"
+        );
+    }
+    while outbound.queued() > OUTBOUND_BUDGET / 4 {
+        outbound.flush(&mut socket).unwrap();
+    }
+    assert!(outbound.caught_up());
+}
@@ -0,0 +1,61 @@
+//! The curve smoothed strokes are drawn along.
+
+use tcp_drawing::smooth::catmull_rom;
+
+fn curve(points: &[(f32, f32)], radii: &[f32]) -> Vec<(f32, f32, f32)> {
+    let mut out = Vec::new();
+    catmull_rom(points, |i| radii[i], &mut out);
+    out
+}
+
+#[test]
+fn the_curve_passes_through_every_point_exactly() {
+    let points = [(0.0, 0.0), (40.0, 10.0), (55.0, 60.0), (10.0, 90.0)];
+    let radii = [2.0, 3.0, 4.0, 5.0];
+    let samples = curve(&points, &radii);
+    assert_eq!(samples.first(), Some(&(0.0, 0.0, 2.0)));
+    assert_eq!(samples.last(), Some(&(10.0, 90.0, 5.0)));
+    for (&(x, y), &radius) in points.iter().zip(&radii) {
+        assert!(samples.contains(&(x, y, radius)), "{x} {y} missing");
+    }
+    assert!(samples.len() > points.len(), "nothing was filled in");
+}
+
+#[test]
+fn two_points_make_a_straight_line() {
+    let samples = curve(&[(0.0, 0.0), (30.0, 0.0)], &[1.0, 1.0]);
+    assert!(samples.len() > 2);
+    let mut last_x = -1.0;
+    for &(x, y, _) in &samples {
+        assert!(y.abs() < 1e-4, "{y} is off the line");
+        assert!(x > last_x, "the line doubled back at {x}");
+        last_x = x;
+    }
+}
+
+#[test]
+fn the_radius_goes_evenly_from_point_to_point() {
+    let samples = curve(&[(0.0, 0.0), (30.0, 0.0)], &[2.0, 8.0]);
+    for pair in samples.windows(2) {
+        assert!(pair[1].2 > pair[0].2);
+    }
+    let middle = samples[samples.len() / 2];
+    assert!((middle.2 - (2.0 + 6.0 * middle.0 / 30.0)).abs() < 1e-3);
+}
+
+#[test]
+fn every_peer_works_out_the_same_curve() {
+    let points = [(3.5, 1.25), (17.0, 40.0), (-12.0, 8.0), (60.0, 61.5)];
+    let radii = [1.0, 6.0, 2.5, 4.0];
+    assert_eq!(curve(&points, &radii), curve(&points, &radii));
+}
+
+#[test]
+fn short_strokes_are_just_their_points() {
+    assert!(curve(&[], &[]).is_empty());
+    assert_eq!(curve(&[(5.0, 6.0)], &[3.0]), vec![(5.0, 6.0, 3.0)]);
+    // a scratch buffer that was in use is emptied first
+    let mut out = vec![(1.0, 1.0, 1.0)];
+    catmull_rom(&[], |_| 1.0, &mut out);
+    assert!(out.is_empty());
+}
@@ -0,0 +1,108 @@
+//! Clients over TLS, and what each side says when only one of them uses it.
+
+mod common;
+
+use common::{Client, Server, dot, wait_until};
+use tcp_drawing::network::{ClientStatus, Message};
+
+/// Self-signed for `localhost` and 127.0.0.1, and good for a hundred years.
+const CERT: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/cert.pem");
+const KEY: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/key.pem");
+
+fn tls_server() -> Server {
+    Server::start_with(&["--cert", CERT, "--key", KEY])
+}
+
+fn status(client: &Client) -> ClientStatus {
+    client.handle.status.lock().unwrap().clone()
+}
+
+#[test]
+fn drawing_is_relayed_between_tls_clients() {
+    let server = tls_server();
+    let existing = dot(10.0, 10.0);
+    server.canvas.insert(existing.clone());
+    let alice = Client::start_with(&server.addr, "alice", &["--tls", "--ca", CERT]);
+    let bob = Client::start_with(&server.addr, "bob", &["--tls", "--ca", CERT]);
+    wait_until("both clients are connected and have the canvas", || {
+        [&alice, &bob].iter().all(|client| {
+            status(client) == ClientStatus::Connected && client.canvas.contains(existing.id)
+        })
+    });
+
+    let from_alice = dot(1.0, 1.0);
+    alice.canvas.insert(from_alice.clone());
+    alice
+        .tx
+        .send(Message::NewEntity(from_alice.clone()))
+        .unwrap();
+    wait_until("the server and bob have alice's entity", || {
+        server.canvas.contains(from_alice.id) && bob.canvas.contains(from_alice.id)
+    });
+
+    let from_host = dot(200.0, 100.0);
+    server.canvas.insert(from_host.clone());
+    server
+        .tx
+        .send(Message::NewEntity(from_host.clone()))
+        .unwrap();
+    wait_until("both clients have the host's entity", || {
+        alice.canvas.contains(from_host.id) && bob.canvas.contains(from_host.id)
+    });
+
+    alice.stop();
+    bob.stop();
+    server.stop();
+}
+
+#[test]
+fn plain_client_is_told_the_server_requires_tls() {
+    let server = tls_server();
+    let client = Client::start(&server.addr, "plain");
+    wait_until(
+        "the client is rejected",
+        || matches!(status(&client), ClientStatus::Rejected { reason } if reason.contains("--tls")),
+    );
+    client.stop();
+    server.stop();
+}
+
+#[test]
+fn tls_client_is_told_the_server_does_not_use_tls() {
+    let server = Server::start();
+    let client = Client::start_with(&server.addr, "tls", &["--tls", "--ca", CERT]);
+    wait_until(
+        "the client gives up",
+        || matches!(status(&client), ClientStatus::Incompatible { reason } if reason.contains("does not use TLS")),
+    );
+    client.stop();
+    server.stop();
+}
+
+#[test]
+fn self_signed_certificate_is_refused_without_ca() {
+    let server = tls_server();
+    let client = Client::start_with(&server.addr, "trusting", &["--tls"]);
+    wait_until(
+        "the client gives up",
+        || matches!(status(&client), ClientStatus::Incompatible { reason } if reason.contains("certificate")),
+    );
+    assert!(server.canvas.is_empty());
+    client.stop();
+    server.stop();
+}
+
+#[test]
+fn late_tls_client_gets_a_large_canvas() {
+    let server = tls_server();
+    let drawn: Vec<_> = (0..5000).map(|i| dot(i as f32, (i % 97) as f32)).collect();
+    for entity in &drawn {
+        server.canvas.insert(entity.clone());
+    }
+    let late = Client::start_with(&server.addr, "late", &["--tls", "--ca", CERT]);
+    wait_until("the late client has the whole canvas", || {
+        drawn.iter().all(|entity| late.canvas.contains(entity.id))
+    });
+    late.stop();
+    server.stop();
+}
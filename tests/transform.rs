@@ -0,0 +1,140 @@
+//! Canvas transforms: the matrix math, and mirror images and turns of
+//! entities as mirror drawing and the host's transforms make them.
+
+use tcp_drawing::entity::{Canvas, Create, Entity, EntityColor, Shape, next_id};
+use tcp_drawing::transform::Transform;
+
+fn close(got: (f32, f32), want: (f32, f32)) -> bool {
+    (got.0 - want.0).abs() < 1e-4 && (got.1 - want.1).abs() < 1e-4
+}
+
+#[test]
+fn then_applies_the_first_transform_first() {
+    let shift = Transform::translate(10.0, 0.0);
+    let double = Transform::scale_about(2.0, (0.0, 0.0));
+    assert_eq!(shift.then(double).apply((1.0, 1.0)), (22.0, 2.0));
+    assert_eq!(double.then(shift).apply((1.0, 1.0)), (12.0, 2.0));
+
+    let turn = Transform::rotate_about(1, (3.0, -2.0));
+    for point in [(0.0, 0.0), (1.5, -7.0), (100.0, 40.0)] {
+        let combined = shift.then(turn).then(double).apply(point);
+        assert!(close(
+            combined,
+            double.apply(turn.apply(shift.apply(point)))
+        ));
+    }
+}
+
+#[test]
+fn an_inverse_undoes_its_transform_and_a_collapse_has_none() {
+    let transform = Transform::rotate_about(1, (5.0, 5.0))
+        .then(Transform::scale_about(3.0, (1.0, 2.0)))
+        .then(Transform::mirror_about(true, false, (-8.0, 0.0)))
+        .then(Transform::translate(-4.0, 7.0));
+    let inverse = transform.inverse().unwrap();
+    for point in [(0.0, 0.0), (1.5, -7.0), (100.0, 40.0)] {
+        assert!(close(inverse.apply(transform.apply(point)), point));
+        assert!(close(transform.apply(inverse.apply(point)), point));
+    }
+    let round_trip = transform.then(inverse);
+    assert!(close((round_trip.a, round_trip.b), (1.0, 0.0)));
+    assert!(close((round_trip.c, round_trip.d), (0.0, 1.0)));
+    assert!(close((round_trip.tx, round_trip.ty), (0.0, 0.0)));
+
+    assert_eq!(Transform::scale_about(0.0, (4.0, 4.0)).inverse(), None);
+}
+
+#[test]
+fn turns_and_scales_leave_their_origin_where_it_is() {
+    for origin in [(0.0, 0.0), (960.0, 540.0), (-12.5, 33.0)] {
+        for quarter_turns in -1..=4 {
+            let turn = Transform::rotate_about(quarter_turns, origin);
+            assert!(close(turn.apply(origin), origin), "{} turns", quarter_turns);
+        }
+        for factor in [0.5, 2.0, 3.25] {
+            let scale = Transform::scale_about(factor, origin);
+            assert!(close(scale.apply(origin), origin), "scaled by {}", factor);
+        }
+    }
+
+    // clockwise on screen, where y points down
+    let turn = Transform::rotate_about(1, (10.0, 10.0));
+    assert!(close(turn.apply((11.0, 10.0)), (10.0, 11.0)));
+    assert!(close(turn.apply((10.0, 11.0)), (9.0, 10.0)));
+    let whole = turn.then(turn).then(turn).then(turn);
+    assert!(close(whole.apply((123.0, -4.0)), (123.0, -4.0)));
+    assert!(close(
+        Transform::rotate_about(-1, (10.0, 10.0)).apply((11.0, 10.0)),
+        (10.0, 9.0)
+    ));
+    assert!(close(
+        Transform::scale_about(2.0, (10.0, 10.0)).apply((11.0, 12.0)),
+        (12.0, 14.0)
+    ));
+}
+
+#[test]
+fn a_turned_rect_keeps_a_positive_size() {
+    let mut rect = Entity::rect(next_id(), (10.0, 20.0), (40.0, 30.0), EntityColor::BLACK);
+    Transform::rotate_about(1, (0.0, 0.0)).apply_to(&mut rect);
+    assert_eq!((rect.x, rect.y), (-30.0, 10.0));
+    assert!(
+        matches!(rect.shape, Shape::Rect { w: 10.0, h: 30.0 }),
+        "{:?}",
+        rect.shape
+    );
+
+    for quarter_turns in [-1, 2, 3] {
+        let mut rect = Entity::rect(next_id(), (10.0, 20.0), (40.0, 30.0), EntityColor::BLACK);
+        Transform::rotate_about(quarter_turns, (5.0, 5.0)).apply_to(&mut rect);
+        let Shape::Rect { w, h } = rect.shape else {
+            panic!("{:?}", rect.shape);
+        };
+        assert!(w > 0.0 && h > 0.0, "{} turns: {}x{}", quarter_turns, w, h);
+        assert!(
+            (w * h - 300.0).abs() < 1e-3,
+            "{} turns: {}x{}",
+            quarter_turns,
+            w,
+            h
+        );
+    }
+}
+
+#[test]
+fn a_mirrored_rect_keeps_its_size_on_the_other_side() {
+    let mut rect = Entity::rect(next_id(), (110.0, 20.0), (130.0, 60.0), EntityColor::BLACK);
+    Transform::mirror_about(true, false, (100.0, 0.0)).apply_to(&mut rect);
+    assert_eq!((rect.x, rect.y), (70.0, 20.0));
+    assert!(matches!(rect.shape, Shape::Rect { w: 20.0, h: 40.0 }));
+
+    let mut rect = Entity::rect(next_id(), (110.0, 20.0), (130.0, 60.0), EntityColor::BLACK);
+    Transform::mirror_about(true, true, (100.0, 0.0)).apply_to(&mut rect);
+    assert_eq!((rect.x, rect.y), (70.0, -60.0));
+}
+
+#[test]
+fn a_stroke_mirrored_twice_is_back_where_it_was() {
+    let canvas = Canvas::new();
+    let id = Entity::spawn(10.0, 10.0, 2.0, EntityColor::BLACK, &canvas).unwrap();
+    canvas.update(id, |entity| {
+        entity.extend_stroke(&[(20.0, 15.0), (30.0, 40.0)], &Default::default())
+    });
+    let stroke = canvas.get(id).unwrap().value().clone();
+    let reflection = Transform::mirror_about(false, true, (0.0, 50.0));
+
+    let mut mirrored = stroke.clone();
+    reflection.apply_to(&mut mirrored);
+    let Shape::Stroke { points, .. } = &mirrored.shape else {
+        panic!("{:?}", mirrored.shape);
+    };
+    assert_eq!(points, &[(10.0, 90.0), (20.0, 85.0), (30.0, 60.0)]);
+    assert_eq!(mirrored.radius, stroke.radius);
+
+    reflection.apply_to(&mut mirrored);
+    assert_eq!((mirrored.x, mirrored.y), (stroke.x, stroke.y));
+    assert!(matches!(
+        (&mirrored.shape, &stroke.shape),
+        (Shape::Stroke { points: back, .. }, Shape::Stroke { points, .. }) if back == points
+    ));
+}
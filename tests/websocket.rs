@@ -0,0 +1,119 @@
+//! A browser-style WebSocket client next to a TCP client on the same server.
+#![cfg(feature = "websocket")]
+
+mod common;
+
+use common::{Client, Server, TIMEOUT, dot, wait_until};
+use std::net::{TcpListener, TcpStream};
+use std::time::Instant;
+use tcp_drawing::network::Message;
+use tungstenite::{Message as WsMessage, WebSocket};
+
+/// A WebSocket client that speaks JSON text, as a page in a browser would.
+struct WsPeer {
+    socket: WebSocket<TcpStream>,
+}
+
+impl WsPeer {
+    fn connect(port: u16, name: &str) -> Self {
+        let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let url = format!("ws://127.0.0.1:{}/", port);
+        let (socket, _) = tungstenite::client(url, stream).unwrap();
+        let mut peer = Self { socket };
+        peer.send(&Message::Hello {
+            name: name.to_string(),
+            spectator: false,
+            room: String::new(),
+            secret: 0,
+            compact: false,
+        });
+        peer
+    }
+
+    fn send(&mut self, message: &Message) {
+        let text = serde_json::to_string(message).unwrap();
+        self.socket.send(WsMessage::text(text)).unwrap();
+    }
+
+    /// Reads until a message `wanted` accepts arrives, skipping the rest.
+    fn expect<T>(&mut self, mut wanted: impl FnMut(Message) -> Option<T>) -> T {
+        let deadline = Instant::now() + TIMEOUT;
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            assert!(!left.is_zero(), "timed out waiting for a message");
+            self.socket.get_ref().set_read_timeout(Some(left)).unwrap();
+            let text = match self.socket.read() {
+                Ok(WsMessage::Text(text)) => text,
+                Ok(WsMessage::Binary(_)) => panic!("the server sent binary, not JSON text"),
+                Ok(_) => continue,
+                Err(e) => panic!("error reading from the server: {}", e),
+            };
+            let message = serde_json::from_str(&text).unwrap();
+            if let Some(found) = wanted(message) {
+                return found;
+            }
+        }
+    }
+}
+
+/// A port nothing is listening on, as far as anyone can tell.
+fn free_port() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().port()
+}
+
+fn admitted(server: &Server) -> usize {
+    server
+        .handle
+        .clients
+        .lock()
+        .map(|clients| clients.iter().filter(|c| !c.pending).count())
+        .unwrap_or(0)
+}
+
+#[test]
+fn websocket_and_tcp_clients_draw_together() {
+    let port = free_port();
+    let server = Server::start_with(&["--ws-port", &port.to_string()]);
+    let existing = dot(10.0, 10.0);
+    server.canvas.insert(existing.clone());
+
+    let mut browser = WsPeer::connect(port, "browser");
+    browser.expect(|message| matches!(message, Message::Welcome { .. }).then_some(()));
+    let all = browser.expect(|message| match message {
+        Message::AllEntities(all) => Some(all),
+        _ => None,
+    });
+    assert!(all.iter().any(|entity| entity.id == existing.id));
+
+    let native = Client::start(&server.addr, "native");
+    wait_until("both clients are admitted", || admitted(&server) == 2);
+
+    let from_browser = dot(50.0, 60.0);
+    browser.send(&Message::NewEntity(from_browser.clone()));
+    wait_until(
+        "the server and the TCP client have the browser's entity",
+        || server.canvas.contains(from_browser.id) && native.canvas.contains(from_browser.id),
+    );
+
+    let from_native = dot(200.0, 100.0);
+    native.canvas.insert(from_native.clone());
+    native
+        .tx
+        .send(Message::NewEntity(from_native.clone()))
+        .unwrap();
+    let relayed = browser.expect(|message| match message {
+        Message::NewEntity(entity) if entity.id == from_native.id => Some(entity),
+        _ => None,
+    });
+    assert_eq!((relayed.x, relayed.y), (200.0, 100.0));
+
+    browser.socket.close(None).unwrap();
+    let _ = browser.socket.flush();
+    wait_until("the browser is off the client list", || {
+        admitted(&server) == 1
+    });
+
+    native.stop();
+    server.stop();
+}